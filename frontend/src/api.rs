@@ -5,6 +5,8 @@
 
 mod v2;
 
+pub use v2::CallInfo;
+
 use std::{
     collections::HashMap,
     net::{IpAddr, SocketAddr},
@@ -31,8 +33,16 @@ use crate::{
     authenticator::{AuthToken, Authenticator},
     frontend::{Frontend, FrontendError},
     metrics::histogram::Histogram,
+    storage,
 };
 
+/// The header with which callers may give the id that ties the logs of a request to
+/// their own.
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The longest request id that is taken from a caller, longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
 #[derive(Default)]
 pub struct ApiMetrics {
     pub latencies: HashMap<String, Histogram<u64>>,
@@ -167,6 +177,39 @@ async fn metrics<B>(
     Ok(response)
 }
 
+/// Middleware to run the request with a correlation id, so that the logs of the storage
+/// operations it performs can be tied back to it. The caller's request id is used if it
+/// sent a usable one, otherwise a random one is made up.
+async fn correlate<B>(req: Request<B>, next: Next<B>) -> axum::response::Response {
+    let correlation_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|header| header.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+
+    storage::with_correlation_id(correlation_id, next.run(req)).await
+}
+
+/// Middleware to run the request with a deadline of request_timeout_ms from now, if set,
+/// that the storage operations it performs give up at.
+async fn deadline<B>(
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<axum::response::Response, StatusCode> {
+    let request_timeout_ms = get_frontend(&req)?.config.request_timeout_ms;
+
+    Ok(match request_timeout_ms {
+        Some(timeout_ms) => {
+            let deadline =
+                tokio::time::Instant::now() + std::time::Duration::from_millis(timeout_ms);
+            storage::with_deadline(deadline, next.run(req)).await
+        }
+        None => next.run(req).await,
+    })
+}
+
 /// Middleware to handle the authorization header.
 async fn authorize<B>(
     mut req: Request<B>,
@@ -239,6 +282,25 @@ async fn get_health() {
     trace!("get_health():");
 }
 
+/// Handler for the GET /ready route. Unlike /health, this also checks that storage is
+/// reachable and that identity tokens for it are being fetched.
+async fn get_ready(Extension(frontend): Extension<Arc<Frontend>>) -> StatusCode {
+    trace!("get_ready():");
+
+    if !frontend.identity_readiness.is_healthy() {
+        warn!("get_ready(): identity token fetching is not healthy");
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+
+    match frontend.storage.health_check().await {
+        Ok(()) => StatusCode::OK,
+        Err(err) => {
+            warn!("get_ready(): storage is not healthy: {}", err);
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+    }
+}
+
 /// For any unexpected requests, return 503 without any middleware processing.
 async fn unknown_request_handler() -> impl IntoResponse {
     event!("calling.frontend.api.unexpected.request");
@@ -246,7 +308,10 @@ async fn unknown_request_handler() -> impl IntoResponse {
 }
 
 fn app(frontend: Arc<Frontend>) -> Router {
-    let health_route = Router::new().route("/health", get(get_health));
+    let health_route = Router::new()
+        .route("/health", get(get_health))
+        .route("/ready", get(get_ready))
+        .layer(Extension(frontend.clone()));
 
     let routes = Router::new()
         .route(
@@ -256,6 +321,8 @@ fn app(frontend: Arc<Frontend>) -> Router {
         .layer(
             ServiceBuilder::new()
                 .layer(Extension(frontend))
+                .layer(middleware::from_fn(correlate))
+                .layer(middleware::from_fn(deadline))
                 .layer(middleware::from_fn(metrics))
                 .layer(middleware::from_fn(authorize)),
         );
@@ -19,6 +19,7 @@ use crate::{
     authenticator::UserAuthorization,
     frontend::{Frontend, JoinRequestWrapper, UserId},
     metrics::Timer,
+    storage::CallRecord,
 };
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -36,6 +37,8 @@ pub struct ParticipantsResponse {
     pub max_devices: u32,
     pub participants: Vec<Participant>,
     pub creator: String,
+    /// Counts the calls of the group, see CallRecord::era.
+    pub era: u64,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -58,6 +61,30 @@ pub struct JoinResponse {
     pub call_creator: String,
 }
 
+/// The parts of a call that may be sent to clients, under public names that don't
+/// depend on how calls are stored. The creator, the backends and the internal timestamps
+/// are left out.
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CallInfo {
+    pub call_id: String,
+    pub region: String,
+    pub locked: bool,
+    /// Counts the calls of the group, see CallRecord::era.
+    pub era: u64,
+}
+
+impl From<&CallRecord> for CallInfo {
+    fn from(call: &CallRecord) -> Self {
+        Self {
+            call_id: call.call_id.clone(),
+            region: call.backend_region.clone(),
+            locked: call.locked,
+            era: call.era,
+        }
+    }
+}
+
 fn temporary_redirect(uri: &str) -> Result<axum::response::Response, StatusCode> {
     if http::HeaderValue::try_from(uri).is_ok() {
         Ok(Redirect::temporary(uri).into_response())
@@ -106,6 +133,7 @@ pub async fn get_participants(
         max_devices: frontend.config.max_clients_per_call,
         participants,
         creator: call.creator,
+        era: call.era,
     })
     .into_response())
 }
@@ -185,7 +213,7 @@ mod api_server_v2_tests {
         backend::{self, BackendError, MockBackend},
         config,
         frontend::{DemuxId, FrontendIdGenerator, GroupId, MockIdGenerator},
-        storage::{CallRecord, MockStorage},
+        storage::{current_correlation_id, current_deadline, MockStorage},
     };
 
     const AUTH_KEY: &str = "f00f0014fe091de31827e8d686969fad65013238aadd25ef8629eb8a9e5ef69b";
@@ -274,6 +302,18 @@ mod api_server_v2_tests {
             backend_ip: "127.0.0.1".to_string(),
             backend_region: backend_region.to_string(),
             creator: USER_ID_1.to_string(),
+            backup_backends: vec![],
+            backend_version: None,
+            created_at: None,
+            expires_at: None,
+            last_heartbeat_at: None,
+            preferred_region: None,
+            reserved_region: None,
+            locked: false,
+            locked_by: None,
+            version: 0,
+            era: 0,
+            metadata: Default::default(),
         }
     }
 
@@ -346,10 +386,11 @@ mod api_server_v2_tests {
         Arc::new(Frontend {
             config,
             authenticator: Authenticator::from_hex_key(AUTH_KEY).unwrap(),
-            storage,
+            storage: Arc::new(*storage),
             backend,
             id_generator: Box::new(FrontendIdGenerator),
             api_metrics: Default::default(),
+            identity_readiness: Default::default(),
         })
     }
 
@@ -362,10 +403,11 @@ mod api_server_v2_tests {
         Arc::new(Frontend {
             config,
             authenticator: Authenticator::from_hex_key(AUTH_KEY).unwrap(),
-            storage,
+            storage: Arc::new(*storage),
             backend,
             id_generator,
             api_metrics: Default::default(),
+            identity_readiness: Default::default(),
         })
     }
 
@@ -398,6 +440,113 @@ mod api_server_v2_tests {
         // Submit the request.
         let response = app.oneshot(request).await.unwrap();
         assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Let the removal run in the background.
+        tokio::task::yield_now().await;
+    }
+
+    /// Invoke the "GET /v2/conference/participants" and check that storage is used with
+    /// the request id of the caller as the correlation id, or else a made up one.
+    #[tokio::test]
+    async fn test_get_sets_correlation_id() {
+        let config = &CONFIG;
+
+        for (request_id, expected) in [(Some("request-1234"), Some("request-1234")), (None, None)] {
+            let correlation_id = Arc::new(parking_lot::Mutex::new(None));
+            let seen = correlation_id.clone();
+            let mut storage = Box::new(MockStorage::new());
+            storage
+                .expect_get_call_record()
+                .with(eq(GroupId::from(GROUP_ID_1)))
+                .once()
+                .returning(move |_| {
+                    *seen.lock() = current_correlation_id();
+                    Ok(None)
+                });
+            let backend = create_mocked_backend_unused();
+
+            let frontend = create_frontend(config, storage, backend);
+            let app = app(frontend);
+
+            let mut request = Request::builder()
+                .method(http::Method::GET)
+                .uri("/v2/conference/participants")
+                .header(header::USER_AGENT, "test/user/agent")
+                .header(
+                    header::AUTHORIZATION,
+                    create_authorization_header_for_user(USER_ID_1),
+                );
+            if let Some(request_id) = request_id {
+                request = request.header("x-request-id", request_id);
+            }
+            let response = app
+                .oneshot(request.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            let correlation_id = correlation_id.lock().clone().unwrap();
+            match expected {
+                Some(expected) => assert_eq!(correlation_id, expected),
+                None => assert_eq!(correlation_id.len(), 16),
+            }
+        }
+    }
+
+    /// Invoke the "GET /v2/conference/participants" and check that storage is used with a
+    /// deadline of request_timeout_ms, if set.
+    #[tokio::test]
+    async fn test_get_sets_deadline() {
+        static CONFIG_WITH_REQUEST_TIMEOUT: Lazy<config::Config> = Lazy::new(|| {
+            let mut config = CONFIG.clone();
+            config.request_timeout_ms = Some(1000);
+            config
+        });
+
+        for (config, expect_deadline) in [(&*CONFIG_WITH_REQUEST_TIMEOUT, true), (&*CONFIG, false)]
+        {
+            let deadline = Arc::new(parking_lot::Mutex::new(None));
+            let seen = deadline.clone();
+            let mut storage = Box::new(MockStorage::new());
+            storage
+                .expect_get_call_record()
+                .with(eq(GroupId::from(GROUP_ID_1)))
+                .once()
+                .returning(move |_| {
+                    *seen.lock() = Some(current_deadline());
+                    Ok(None)
+                });
+            let backend = create_mocked_backend_unused();
+
+            let frontend = create_frontend(config, storage, backend);
+            let app = app(frontend);
+
+            let request = Request::builder()
+                .method(http::Method::GET)
+                .uri("/v2/conference/participants")
+                .header(header::USER_AGENT, "test/user/agent")
+                .header(
+                    header::AUTHORIZATION,
+                    create_authorization_header_for_user(USER_ID_1),
+                )
+                .body(Body::empty())
+                .unwrap();
+            let sent_at = tokio::time::Instant::now();
+            let response = app.oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            let deadline = deadline.lock().unwrap();
+            if expect_deadline {
+                let deadline = deadline.unwrap();
+                assert!(deadline > sent_at);
+                assert!(
+                    deadline
+                        <= tokio::time::Instant::now() + std::time::Duration::from_millis(1000)
+                );
+            } else {
+                assert_eq!(deadline, None);
+            }
+        }
     }
 
     /// Invoke the "GET /v2/conference/participants" in the case where there is a call
@@ -533,12 +682,11 @@ mod api_server_v2_tests {
             .in_sequence(&mut seq);
 
         storage
-            .expect_remove_call_record()
+            .expect_remove_call_record_with_retries()
             // group_id: &GroupId, call_id: &str
             .with(eq(GroupId::from(GROUP_ID_1)), eq(CALL_ID_1))
             .once()
-            // Result<()>
-            .returning(|_, _| Ok(()))
+            .return_const(())
             .in_sequence(&mut seq);
 
         let frontend = create_frontend(config, storage, backend);
@@ -581,6 +729,7 @@ mod api_server_v2_tests {
             .returning(|| {
                 Ok(backend::InfoResponse {
                     backend_direct_ip: "127.0.0.1".to_string(),
+                    version: Some("1.2.3".to_string()),
                 })
             });
 
@@ -590,7 +739,11 @@ mod api_server_v2_tests {
             .once()
             .returning(|_| CALL_ID_1.to_string());
 
-        let expected_call_record = create_call_record(&config.region);
+        let expected_call_record = CallRecord {
+            preferred_region: Some(config.region.to_string()),
+            backend_version: Some("1.2.3".to_string()),
+            ..create_call_record(&config.region)
+        };
 
         storage
             .expect_get_or_add_call_record()
@@ -1034,4 +1187,34 @@ mod api_server_v2_tests {
         let response = app.oneshot(request).await.unwrap();
         assert_eq!(response.status(), StatusCode::BAD_REQUEST);
     }
+
+    #[test]
+    fn test_call_info_from_call_record() {
+        let call = CallRecord {
+            created_at: Some(1000),
+            expires_at: Some(2000),
+            last_heartbeat_at: Some(1500),
+            locked: true,
+            locked_by: Some(USER_ID_1.to_string()),
+            version: 3,
+            era: 2,
+            ..create_call_record("us-west1")
+        };
+
+        let info = CallInfo::from(&call);
+        assert_eq!(
+            info,
+            CallInfo {
+                call_id: CALL_ID_1.to_string(),
+                region: "us-west1".to_string(),
+                locked: true,
+                era: 2,
+            }
+        );
+
+        let json = serde_json::to_value(&info).unwrap();
+        let mut names: Vec<_> = json.as_object().unwrap().keys().cloned().collect();
+        names.sort();
+        assert_eq!(names, vec!["callId", "era", "locked", "region"]);
+    }
 }
@@ -193,9 +193,10 @@ impl Authenticator {
 #[cfg(test)]
 mod authenticator_tests {
     use super::*;
-    use env_logger::Env;
     use hex::ToHex;
 
+    use crate::test_logging;
+
     const AUTH_KEY_1: &str = "f00f0014fe091de31827e8d686969fad65013238aadd25ef8629eb8a9e5ef69b";
     const AUTH_KEY_2: &str = "f00f0072f8ee256b9ba24255897230342cc83b76a3964d6288a7ac8ae4e8e9ca";
 
@@ -208,14 +209,7 @@ mod authenticator_tests {
     const GROUP_ID_1: &str = "aaaaaaaaaaaaaaaa";
 
     fn initialize_logging() {
-        let _ = env_logger::Builder::from_env(
-            Env::default()
-                .default_filter_or("calling_frontend=info")
-                .default_write_style_or("never"),
-        )
-        .format_timestamp_millis()
-        .is_test(true)
-        .try_init();
+        test_logging::start_capture();
     }
 
     #[test]
@@ -55,6 +55,9 @@ impl TryFrom<&str> for Address {
 pub struct InfoResponse {
     #[serde(rename = "directAccessIp")]
     pub backend_direct_ip: String,
+    /// The software version of the backend, if it reports one.
+    #[serde(default)]
+    pub version: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -3,7 +3,80 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
 use clap;
+use http::{
+    header::{HeaderName, HeaderValue},
+    Uri,
+};
+
+use crate::storage::DYNAMODB_MAX_ITEM_BYTES;
+
+/// Where the identity fetcher gets the web identity tokens used for storage access.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum IdentitySource {
+    /// Tokens aren't fetched, such as when static credentials are used.
+    Disabled,
+    /// Tokens are fetched from the GCP metadata server at the given URL.
+    GcpMetadata { url: String },
+    /// Tokens aren't fetched, storage access uses the credentials of the instance's IAM
+    /// role from the AWS instance metadata service instead.
+    AwsImds,
+    /// Tokens are read from the given file, which something else keeps up to date.
+    File { path: String },
+}
+
+impl Default for IdentitySource {
+    fn default() -> Self {
+        IdentitySource::Disabled
+    }
+}
+
+impl FromStr for IdentitySource {
+    type Err = anyhow::Error;
+
+    /// Parses "disabled", "aws-imds", "gcp-metadata:<url>" or "file:<path>".
+    fn from_str(value: &str) -> Result<Self> {
+        match value.split_once(':') {
+            _ if value == "disabled" => Ok(IdentitySource::Disabled),
+            _ if value == "aws-imds" => Ok(IdentitySource::AwsImds),
+            Some(("gcp-metadata", url)) => Ok(IdentitySource::GcpMetadata {
+                url: url.to_string(),
+            }),
+            Some(("file", path)) => Ok(IdentitySource::File {
+                path: path.to_string(),
+            }),
+            _ => Err(anyhow!(
+                "identity_source `{}` must be one of disabled, aws-imds, gcp-metadata:<url> or file:<path>",
+                value
+            )),
+        }
+    }
+}
+
+/// A "name=value" pair given on the command line, such as a header to send with each
+/// identity fetch. The value is everything after the first '='.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NameValue {
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for NameValue {
+    type Err = anyhow::Error;
+
+    fn from_str(pair: &str) -> Result<Self> {
+        match pair.split_once('=') {
+            Some((name, value)) if !name.is_empty() => Ok(NameValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(anyhow!("`{}` must be of the form name=value", pair)),
+        }
+    }
+}
 
 /// Configuration options from command line arguments.
 #[derive(Default, clap::Parser, Debug, Clone)]
@@ -17,6 +90,12 @@ pub struct Config {
     #[clap(long, default_value = "8080")]
     pub server_port: u16,
 
+    /// How long the storage operations of an API request may take in all, after which
+    /// they fail with DeadlineExceeded rather than holding the request past the time that
+    /// clients wait for it. Unbounded if not set.
+    #[clap(long)]
+    pub request_timeout_ms: Option<u64>,
+
     /// GCP region of the frontend. Appears as a tag in metrics and logging.
     #[clap(long)]
     pub region: String,
@@ -48,42 +127,576 @@ pub struct Config {
     #[clap(long)]
     pub calling_server_url: String,
 
-    /// Interval for fetching a new identity token for storage support via DynamodDB.
+    /// Interval for fetching a new identity token for storage support via DynamodDB. Only
+    /// used while the expiry of the token isn't known, since tokens with an exp claim are
+    /// fetched again part way through their remaining lifetime.
     #[clap(long, default_value = "600000")]
     pub identity_fetcher_interval_ms: u64,
 
-    /// Where to fetch identity tokens from for storage support via DynamodDB.
+    /// How long fetching a single identity token may take before it is abandoned.
+    #[clap(long, default_value = "10000")]
+    pub identity_fetch_timeout_ms: u64,
+
+    /// How long the connection to the identity source is kept open between fetches, or 0
+    /// to open a new connection for every fetch. The default is longer than the default
+    /// identity_fetcher_interval_ms, so that the next fetch can reuse the connection.
+    #[clap(long, default_value = "900000")]
+    pub identity_fetch_pool_idle_timeout_ms: u64,
+
+    /// The interval of TCP keep-alive probes on the connection to the identity source,
+    /// which stop it from being dropped as idle along the way between fetches, or 0 to
+    /// send none.
+    #[clap(long, default_value = "60000")]
+    pub identity_fetch_tcp_keepalive_ms: u64,
+
+    /// How many identity fetches may fail in a row before the frontend reports that it
+    /// isn't ready, or 0 to never do so. A later successful fetch makes it ready again.
+    #[clap(long, default_value = "3")]
+    pub identity_fetch_max_failures: u32,
+
+    /// Whether to shut down, rather than keep retrying, once identity fetching has failed
+    /// identity_fetch_max_failures times in a row.
     #[clap(long)]
-    pub identity_token_url: Option<String>,
+    pub identity_fetch_exit_when_unhealthy: bool,
+
+    /// Where to get identity tokens from for storage support via DynamodDB. One of
+    /// "disabled", "aws-imds", "gcp-metadata:<url>" or "file:<path>".
+    /// Example: "gcp-metadata:http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/identity?audience=<audience>"
+    #[clap(long, default_value = "disabled")]
+    pub identity_source: IdentitySource,
+
+    /// A header to send with each gcp-metadata identity fetch, as "name=value". May be
+    /// given more than once. A header given here replaces the default one of the same
+    /// name, such as "Metadata-Flavor=Google".
+    /// Example: "Authorization=Bearer <token>"
+    #[clap(long = "identity-fetch-header")]
+    pub identity_fetch_headers: Vec<NameValue>,
+
+    /// A query parameter to add to the gcp-metadata identity fetch URL, as "name=value".
+    /// May be given more than once, and replaces a parameter of the same name already in
+    /// the URL. The value is sent as given, so it must already be URL-encoded.
+    /// Example: "audience=<audience>"
+    #[clap(long = "identity-fetch-query-param")]
+    pub identity_fetch_query_params: Vec<NameValue>,
 
     /// The name of the table that provides the list of calls being tracked.
     #[clap(long)]
     pub storage_table: String,
 
+    /// A prefix for the partition keys of every item that this frontend stores, so that
+    /// several deployments can share a table without seeing each other's calls. Callers
+    /// still use bare group_ids. Deployments without a prefix see the items of all
+    /// others in region queries and scans, so every deployment sharing a table needs one.
+    /// It must end with "#" and have no other "#", so that no prefix is the start of
+    /// another and the items of one deployment never match the prefix of another.
+    /// Example: "staging#"
+    #[clap(long)]
+    pub storage_key_prefix: Option<String>,
+
+    /// The number of tables that calls are sharded across. When greater than 1, the table
+    /// names come from storage_shard_table_template instead of storage_table.
+    #[clap(long, default_value = "1")]
+    pub storage_shard_count: u32,
+
+    /// A template string for the names of the shard tables.
+    /// '<shard>' will be substituted with the index of the shard, starting at 0.
+    /// Example: "CallRecords-<shard>"
+    #[clap(long)]
+    pub storage_shard_table_template: Option<String>,
+
+    /// The number of write shards that the calls of each region are spread across in the
+    /// region index, to keep a busy region from becoming a hot partition. When greater
+    /// than 1, calls are indexed by "<region>#<shard>" in the region-shard-index GSI,
+    /// which the table must have, and region queries are sent to every shard.
+    #[clap(long, default_value = "1")]
+    pub storage_region_index_shards: u32,
+
+    /// Move the calls that are indexed under another number of region index shards to
+    /// their shards at startup. Without this, starting with a different
+    /// storage_region_index_shards than the table was last backfilled for is refused,
+    /// since region queries wouldn't find the calls that weren't moved.
+    #[clap(long)]
+    pub storage_backfill_region_shards: bool,
+
+    /// How many segments full table scans, such as exports and garbage collection, are
+    /// split into. DynamoDB assigns every item to exactly one segment.
+    #[clap(long, default_value = "1")]
+    pub storage_scan_segments: u32,
+
+    /// How many segments of a full table scan are scanned at the same time.
+    #[clap(long, default_value = "4")]
+    pub storage_scan_concurrency: u32,
+
+    /// The most items that each page of a paginated region query or table scan reads,
+    /// trading more round trips for smaller pages. If not set, DynamoDB fills each page
+    /// up to 1MB.
+    #[clap(long)]
+    pub storage_query_page_size: Option<u32>,
+
+    /// The largest call record, in bytes, that may be added to storage. This should stay
+    /// safely below DynamoDB's hard limit of 400KB per item.
+    #[clap(long, default_value = "358400")]
+    pub storage_max_item_bytes: usize,
+
+    /// Store calls in the compact format, which packs the fields that are never updated
+    /// in place into a single "record" attribute. Calls in either format are always read,
+    /// but the region indexes must project the "record" attribute before this is set.
+    #[clap(long)]
+    pub storage_compact_records: bool,
+
+    /// A 32 byte hex key to encrypt the creator and the locker of each call with before
+    /// they are stored. Values stored in plaintext are still read, but once set, this
+    /// can't be unset until the calls with encrypted values have ended.
+    #[clap(long)]
+    pub storage_creator_encryption_key: Option<String>,
+
+    /// How many seconds the clock of this host may be ahead of the others before calls
+    /// they wrote are treated as expired or dead early.
+    #[clap(long, default_value = "5")]
+    pub storage_clock_skew_tolerance_secs: u64,
+
+    /// Read calls by group_id with eventually consistent reads, which cost half as much
+    /// but may miss a call that was just created. Reads that decide which of two racing
+    /// calls won are always strongly consistent.
+    #[clap(long)]
+    pub storage_eventually_consistent_reads: bool,
+
+    /// The longest random delay, in milliseconds, before reading the winning call after
+    /// losing the race to add a call. When many frontends create the same call at once,
+    /// this spreads out their strongly consistent reads. 0 reads right away.
+    #[clap(long, default_value = "5")]
+    pub storage_conflict_read_jitter_ms: u64,
+
+    /// Fail region queries on a stored call that can't be read instead of skipping it and
+    /// returning the others.
+    #[clap(long)]
+    pub storage_strict_region_queries: bool,
+
+    /// The most requests to send to storage at a time, unbounded if not set. Bounding
+    /// this protects storage and the network from load spikes.
+    #[clap(long)]
+    pub storage_max_concurrent_requests: Option<usize>,
+
+    /// How long to wait to send a request when storage_max_concurrent_requests are
+    /// already in flight, before failing the operation.
+    #[clap(long, default_value = "500")]
+    pub storage_request_permit_timeout_ms: u64,
+
+    /// How long a single storage request may take before it is abandoned. If not set, a
+    /// request is only abandoned when the deadline of the caller passes.
+    #[clap(long)]
+    pub storage_operation_timeout_ms: Option<u64>,
+
+    /// The most attempts at a storage operation that failed transiently, such as by being
+    /// throttled, for the operations that are safe to retry. 1 disables retries.
+    #[clap(long, default_value = "3")]
+    pub storage_retry_max_attempts: usize,
+
+    /// The longest random wait before the first retry of a storage operation, which
+    /// doubles for each later one.
+    #[clap(long, default_value = "50")]
+    pub storage_retry_initial_backoff_ms: u64,
+
+    /// Serve reads from storage but reject every write, such as creating or removing a
+    /// call, so that storage stays frozen during maintenance.
+    #[clap(long)]
+    pub storage_read_only: bool,
+
+    /// How long to wait at shutdown for the storage operations in flight to finish, after
+    /// new ones are refused.
+    #[clap(long, default_value = "5000")]
+    pub storage_drain_timeout_ms: u64,
+
+    /// The regions whose number of active calls is periodically reported as a gauge,
+    /// separated by commas. Nothing is reported if empty.
+    #[clap(long, value_delimiter = ',')]
+    pub storage_metrics_regions: Vec<String>,
+
+    /// Interval for counting the calls in each of the storage_metrics_regions.
+    #[clap(long, default_value = "60000")]
+    pub storage_metrics_interval_ms: u64,
+
+    /// The URL of a Redis server to store calls in instead of DynamoDB, such as
+    /// "redis://redis:6379". The DynamoDB settings are then unused and no identity tokens
+    /// are fetched. Only in builds with the redis feature.
+    #[cfg(feature = "redis")]
+    #[clap(long)]
+    pub storage_redis_url: Option<String>,
+
     /// The AWS region in which the DynamoDB server resides.
     #[clap(long)]
     pub storage_region: String,
 
+    /// The AWS region whose copy of the storage_table serves reads when DynamoDB fails in
+    /// a storage_region, as "primary=failover". May be given more than once, and only the
+    /// one for the storage_region is used. Writes always go to the storage_region. Not
+    /// allowed with a storage_endpoint.
+    /// Example: "us-east-1=us-west-2"
+    #[clap(long = "storage-failover-region")]
+    pub storage_failover_regions: Vec<NameValue>,
+
     /// The storage endpoint used only for testing. Typically something like "http://dynamodb:8000".
     /// Do not specify anything for production.
     #[clap(long)]
     pub storage_endpoint: Option<String>,
 
+    /// Accept any TLS certificate from the storage_endpoint, such as the self-signed one
+    /// of a local proxy or emulator. This is insecure and is refused without an endpoint,
+    /// or in builds without the insecure-test-endpoint feature.
+    #[clap(long)]
+    pub storage_endpoint_allow_invalid_certs: bool,
+
+    /// Create the storage_table, or each shard table, and their region indexes at startup
+    /// if they don't exist, for local and CI environments. This is refused without a storage_endpoint.
+    #[clap(long)]
+    pub storage_create_table: bool,
+
+    /// Check at startup that the storage_table, or each shard table, has the partition
+    /// key, region indexes and TTL that storage relies on, and refuse to start if not.
+    #[clap(long)]
+    pub storage_verify_schema: bool,
+
+    /// Connect to the FIPS endpoint of DynamoDB in the storage_region, in the partition
+    /// the region belongs to. Not allowed with a storage_endpoint.
+    #[clap(long)]
+    pub storage_use_fips: bool,
+
+    /// Connect to the dualstack endpoint of DynamoDB in the storage_region, which is
+    /// reachable over IPv6 as well as IPv4. Fails at startup in partitions without
+    /// dualstack endpoints. Not allowed with a storage_endpoint.
+    #[clap(long)]
+    pub storage_use_dualstack: bool,
+
+    /// The access key id to sign requests with when a storage_endpoint is used for testing.
+    /// Defaults to a dummy key, which is enough for emulators that don't check signatures.
+    #[clap(long)]
+    pub storage_test_access_key_id: Option<String>,
+
+    /// The secret access key that goes with storage_test_access_key_id.
+    #[clap(long)]
+    pub storage_test_secret_access_key: Option<String>,
+
+    /// The session token to sign requests with when a storage_endpoint is used for testing.
+    #[clap(long)]
+    pub storage_test_session_token: Option<String>,
+
+    /// Where to write identity tokens when a storage_endpoint is used for testing. Defaults
+    /// to a per-process file in the temp directory so that concurrent test processes don't
+    /// collide. Not used in production, where AWS_WEB_IDENTITY_TOKEN_FILE is used instead.
+    #[clap(long)]
+    pub identity_token_path: Option<String>,
+
     /// IP and port of Datadog StatsD agent. Typically 127.0.0.1:8125. If not
     /// present, metrics will be disabled.
     #[clap(long)]
     pub metrics_datadog_host: Option<String>,
 }
 
+impl Config {
+    /// Checks that the storage settings are usable so that a misconfiguration is reported
+    /// at startup rather than as a confusing error on the first storage access.
+    pub fn validate_storage(&self) -> Result<()> {
+        if !is_valid_table_name(&self.storage_table) {
+            return Err(anyhow!(
+                "storage_table `{}` is not a valid DynamoDB table name",
+                self.storage_table
+            ));
+        }
+
+        if let Some(key_prefix) = &self.storage_key_prefix {
+            if key_prefix.is_empty() {
+                return Err(anyhow!("storage_key_prefix must not be empty if set"));
+            }
+            if !key_prefix.ends_with('#') || key_prefix[..key_prefix.len() - 1].contains('#') {
+                return Err(anyhow!(
+                    "storage_key_prefix `{}` must end with `#` and not have any other `#`",
+                    key_prefix
+                ));
+            }
+        }
+
+        if self.storage_shard_count == 0 {
+            return Err(anyhow!("storage_shard_count must be greater than 0"));
+        }
+        if self.storage_region_index_shards == 0 {
+            return Err(anyhow!(
+                "storage_region_index_shards must be greater than 0"
+            ));
+        }
+        if self.storage_scan_segments == 0 || self.storage_scan_concurrency == 0 {
+            return Err(anyhow!(
+                "storage_scan_segments and storage_scan_concurrency must be greater than 0"
+            ));
+        }
+        if let Some(page_size) = self.storage_query_page_size {
+            // DynamoDB takes the limit of a page as a positive 32 bit integer.
+            if page_size == 0 || page_size > i32::MAX as u32 {
+                return Err(anyhow!(
+                    "storage_query_page_size must be between 1 and {}",
+                    i32::MAX
+                ));
+            }
+        }
+        if self.storage_max_item_bytes == 0 || self.storage_max_item_bytes > DYNAMODB_MAX_ITEM_BYTES
+        {
+            return Err(anyhow!(
+                "storage_max_item_bytes must be between 1 and {}",
+                DYNAMODB_MAX_ITEM_BYTES
+            ));
+        }
+        if self.storage_max_concurrent_requests == Some(0) {
+            return Err(anyhow!(
+                "storage_max_concurrent_requests must be greater than 0"
+            ));
+        }
+        if self.storage_retry_max_attempts == 0 {
+            return Err(anyhow!("storage_retry_max_attempts must be greater than 0"));
+        }
+        if self.storage_shard_count > 1 {
+            match &self.storage_shard_table_template {
+                Some(template) if template.contains("<shard>") => {
+                    for table_name in self.storage_shard_table_names() {
+                        if !is_valid_table_name(&table_name) {
+                            return Err(anyhow!(
+                                "storage_shard_table_template `{}` gives `{}`, which is not a valid DynamoDB table name",
+                                template,
+                                table_name
+                            ));
+                        }
+                    }
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "storage_shard_table_template must contain '<shard>' when storage_shard_count is greater than 1"
+                    ));
+                }
+            }
+        }
+
+        // The region is stored on every call that is created as its preferred region, so
+        // a typo would steer reconnecting clients nowhere.
+        if !is_valid_gcp_region(&self.region) {
+            return Err(anyhow!(
+                "region `{}` is not a valid GCP region",
+                self.region
+            ));
+        }
+
+        if !is_valid_aws_region(&self.storage_region) {
+            return Err(anyhow!(
+                "storage_region `{}` is not a valid AWS region",
+                self.storage_region
+            ));
+        }
+
+        match &self.identity_source {
+            IdentitySource::GcpMetadata { url } => {
+                let uri = url
+                    .parse::<Uri>()
+                    .map_err(|err| anyhow!("identity_source url `{}` is invalid: {}", url, err))?;
+                if !matches!(uri.scheme_str(), Some("http") | Some("https")) || uri.host().is_none()
+                {
+                    return Err(anyhow!(
+                        "identity_source url `{}` must be an absolute http(s) URL",
+                        url
+                    ));
+                }
+            }
+            IdentitySource::File { path } if path.is_empty() => {
+                return Err(anyhow!("identity_source file path must not be empty"));
+            }
+            _ => {}
+        }
+
+        for header in &self.identity_fetch_headers {
+            if HeaderName::from_str(&header.name).is_err()
+                || HeaderValue::from_str(&header.value).is_err()
+            {
+                return Err(anyhow!(
+                    "identity_fetch_header `{}` is not a valid HTTP header",
+                    header.name
+                ));
+            }
+        }
+        for param in &self.identity_fetch_query_params {
+            if param.name.contains(['&', '#', ' ']) || param.value.contains(['&', '#', ' ']) {
+                return Err(anyhow!(
+                    "identity_fetch_query_param `{}` must be URL-encoded",
+                    param.name
+                ));
+            }
+        }
+
+        if self.storage_endpoint_allow_invalid_certs && self.storage_endpoint.is_none() {
+            return Err(anyhow!(
+                "storage_endpoint_allow_invalid_certs is only allowed with a storage_endpoint"
+            ));
+        }
+        if self.storage_endpoint_allow_invalid_certs && !cfg!(feature = "insecure-test-endpoint") {
+            return Err(anyhow!(
+                "storage_endpoint_allow_invalid_certs needs a build with the insecure-test-endpoint feature"
+            ));
+        }
+        if self.storage_create_table && self.storage_endpoint.is_none() {
+            return Err(anyhow!(
+                "storage_create_table is only allowed with a storage_endpoint"
+            ));
+        }
+        if (self.storage_use_fips || self.storage_use_dualstack) && self.storage_endpoint.is_some()
+        {
+            return Err(anyhow!(
+                "storage_use_fips and storage_use_dualstack are not allowed with a storage_endpoint"
+            ));
+        }
+
+        for failover in &self.storage_failover_regions {
+            if !is_valid_aws_region(&failover.name) || !is_valid_aws_region(&failover.value) {
+                return Err(anyhow!(
+                    "storage_failover_region `{}={}` must name two valid AWS regions",
+                    failover.name,
+                    failover.value
+                ));
+            }
+            if failover.name == failover.value {
+                return Err(anyhow!(
+                    "storage_failover_region `{}` can't fail over to itself",
+                    failover.name
+                ));
+            }
+        }
+        if self.storage_failover_region().is_some() && self.storage_endpoint.is_some() {
+            return Err(anyhow!(
+                "storage_failover_region is not allowed with a storage_endpoint"
+            ));
+        }
+
+        if self.storage_test_access_key_id.is_some()
+            != self.storage_test_secret_access_key.is_some()
+        {
+            return Err(anyhow!(
+                "storage_test_access_key_id and storage_test_secret_access_key must be given together"
+            ));
+        }
+
+        if self.identity_fetcher_interval_ms == 0 {
+            return Err(anyhow!(
+                "identity_fetcher_interval_ms must be greater than 0"
+            ));
+        }
+
+        if self.identity_fetch_timeout_ms == 0 {
+            return Err(anyhow!("identity_fetch_timeout_ms must be greater than 0"));
+        }
+
+        if self.request_timeout_ms == Some(0) {
+            return Err(anyhow!("request_timeout_ms must be greater than 0"));
+        }
+
+        if self.storage_metrics_interval_ms == 0 {
+            return Err(anyhow!(
+                "storage_metrics_interval_ms must be greater than 0"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the settings are usable for storage in Redis, which has no support for
+    /// encrypting creators.
+    #[cfg(feature = "redis")]
+    pub fn validate_redis_storage(&self) -> Result<()> {
+        if let Some(url) = &self.storage_redis_url {
+            if !url.starts_with("redis://") && !url.starts_with("rediss://") {
+                return Err(anyhow!(
+                    "storage_redis_url `{}` must be a redis:// or rediss:// URL",
+                    url
+                ));
+            }
+            if self.storage_creator_encryption_key.is_some() {
+                return Err(anyhow!(
+                    "storage_creator_encryption_key is not supported with a storage_redis_url"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the failover region of the storage_region, if it has one.
+    pub fn storage_failover_region(&self) -> Option<&str> {
+        self.storage_failover_regions
+            .iter()
+            .find(|failover| failover.name == self.storage_region)
+            .map(|failover| failover.value.as_str())
+    }
+
+    /// Returns the names of the tables that calls are stored in, one per shard.
+    pub fn storage_shard_table_names(&self) -> Vec<String> {
+        match &self.storage_shard_table_template {
+            Some(template) if self.storage_shard_count > 1 => (0..self.storage_shard_count)
+                .map(|shard| template.replace("<shard>", &shard.to_string()))
+                .collect(),
+            _ => vec![self.storage_table.clone()],
+        }
+    }
+}
+
+/// See https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/HowItWorks.NamingRulesDataTypes.html
+fn is_valid_table_name(table_name: &str) -> bool {
+    table_name.len() >= 3
+        && table_name.len() <= 255
+        && table_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+/// Checks for the general shape of a GCP region, such as "us-west1" or
+/// "northamerica-northeast2".
+fn is_valid_gcp_region(region: &str) -> bool {
+    match region.split_once('-') {
+        Some((continent, location)) => {
+            let name = location.trim_end_matches(|c: char| c.is_ascii_digit());
+            !continent.is_empty()
+                && continent.chars().all(|c| c.is_ascii_lowercase())
+                && !name.is_empty()
+                && name.len() < location.len()
+                && name.chars().all(|c| c.is_ascii_lowercase())
+        }
+        None => false,
+    }
+}
+
+/// Checks for the general shape of an AWS region, such as "us-east-1" or "us-gov-west-1".
+fn is_valid_aws_region(region: &str) -> bool {
+    match region.rsplit_once('-') {
+        Some((name, number)) => {
+            !number.is_empty()
+                && number.chars().all(|c| c.is_ascii_digit())
+                && name.split('-').count() >= 2
+                && name
+                    .split('-')
+                    .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_lowercase()))
+        }
+        None => false,
+    }
+}
+
 #[cfg(test)]
 pub fn default_test_config() -> Config {
     Config {
         server_ip: "127.0.0.1".to_string(),
         server_port: 8080,
+        request_timeout_ms: None,
         max_clients_per_call: 8,
         cleanup_interval_ms: 5000,
         identity_fetcher_interval_ms: 1000 * 60 * 10,
-        identity_token_url: None,
+        identity_fetch_timeout_ms: 10000,
+        identity_fetch_pool_idle_timeout_ms: 900000,
+        identity_fetch_tcp_keepalive_ms: 60000,
+        identity_fetch_max_failures: 3,
+        identity_fetch_exit_when_unhealthy: false,
+        identity_source: IdentitySource::Disabled,
+        identity_fetch_headers: vec![],
+        identity_fetch_query_params: vec![],
         authentication_key: "f00f0014fe091de31827e8d686969fad65013238aadd25ef8629eb8a9e5ef69b"
             .to_string(),
         region: "us-west1".to_string(),
@@ -91,8 +704,394 @@ pub fn default_test_config() -> Config {
         regional_url_template: "".to_string(),
         calling_server_url: "http://127.0.0.1:8080".to_string(),
         storage_table: "CallRecords".to_string(),
+        storage_key_prefix: None,
+        storage_shard_count: 1,
+        storage_shard_table_template: None,
+        storage_region_index_shards: 1,
+        storage_backfill_region_shards: false,
+        storage_scan_segments: 1,
+        storage_scan_concurrency: 4,
+        storage_query_page_size: None,
+        storage_max_item_bytes: 358400,
+        storage_compact_records: false,
+        storage_creator_encryption_key: None,
+        storage_clock_skew_tolerance_secs: 5,
+        storage_eventually_consistent_reads: false,
+        storage_conflict_read_jitter_ms: 5,
+        storage_strict_region_queries: false,
+        storage_max_concurrent_requests: None,
+        storage_request_permit_timeout_ms: 500,
+        storage_operation_timeout_ms: None,
+        storage_retry_max_attempts: 3,
+        storage_retry_initial_backoff_ms: 50,
+        storage_read_only: false,
+        storage_drain_timeout_ms: 5000,
+        storage_metrics_regions: vec![],
+        storage_metrics_interval_ms: 60000,
+        #[cfg(feature = "redis")]
+        storage_redis_url: None,
         storage_region: "us-east-1".to_string(),
+        storage_failover_regions: vec![],
         storage_endpoint: Some("localhost:9010".to_string()),
+        storage_endpoint_allow_invalid_certs: false,
+        storage_create_table: false,
+        storage_verify_schema: false,
+        storage_use_fips: false,
+        storage_use_dualstack: false,
+        storage_test_access_key_id: None,
+        storage_test_secret_access_key: None,
+        storage_test_session_token: None,
+        identity_token_path: None,
         metrics_datadog_host: None,
     }
 }
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_storage_valid() {
+        let config = default_test_config();
+        assert!(config.validate_storage().is_ok());
+
+        let config = Config {
+            storage_region: "us-gov-west-1".to_string(),
+            identity_source: IdentitySource::GcpMetadata {
+                url: "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/identity?audience=test".to_string(),
+            },
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_ok());
+    }
+
+    #[test]
+    fn test_validate_storage_invalid_table() {
+        for storage_table in ["", "ab", "Call Records", "Call/Records"] {
+            let config = Config {
+                storage_table: storage_table.to_string(),
+                ..default_test_config()
+            };
+            assert!(config.validate_storage().is_err(), "{}", storage_table);
+        }
+    }
+
+    #[test]
+    fn test_validate_storage_key_prefix() {
+        let config = Config {
+            storage_key_prefix: Some("staging#".to_string()),
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_ok());
+
+        let config = Config {
+            storage_key_prefix: Some("".to_string()),
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+
+        // Without the delimiter, "staging" would match the items of "staging2" too.
+        for key_prefix in ["staging", "staging#2", "staging#2#", "staging##"] {
+            let config = Config {
+                storage_key_prefix: Some(key_prefix.to_string()),
+                ..default_test_config()
+            };
+            assert!(config.validate_storage().is_err(), "{}", key_prefix);
+        }
+    }
+
+    #[test]
+    fn test_validate_storage_query_page_size() {
+        for page_size in [1, 100, i32::MAX as u32] {
+            let config = Config {
+                storage_query_page_size: Some(page_size),
+                ..default_test_config()
+            };
+            assert!(config.validate_storage().is_ok(), "{}", page_size);
+        }
+
+        for page_size in [0, i32::MAX as u32 + 1] {
+            let config = Config {
+                storage_query_page_size: Some(page_size),
+                ..default_test_config()
+            };
+            assert!(config.validate_storage().is_err(), "{}", page_size);
+        }
+    }
+
+    #[test]
+    fn test_validate_storage_invalid_frontend_region() {
+        for region in [
+            "",
+            "us",
+            "us-west",
+            "uswest1",
+            "US-WEST1",
+            "us-west-1",
+            "-west1",
+        ] {
+            let config = Config {
+                region: region.to_string(),
+                ..default_test_config()
+            };
+            assert!(config.validate_storage().is_err(), "{}", region);
+        }
+        for region in ["us-west1", "europe-west3", "northamerica-northeast2"] {
+            let config = Config {
+                region: region.to_string(),
+                ..default_test_config()
+            };
+            assert!(config.validate_storage().is_ok(), "{}", region);
+        }
+    }
+
+    #[test]
+    fn test_validate_storage_invalid_region() {
+        for storage_region in ["", "us-east", "US-EAST-1", "us--1", "us-east-x", "us-east-"] {
+            let config = Config {
+                storage_region: storage_region.to_string(),
+                ..default_test_config()
+            };
+            assert!(config.validate_storage().is_err(), "{}", storage_region);
+        }
+    }
+
+    #[test]
+    fn test_storage_failover_region() {
+        let failover_regions = |pairs: &[&str]| -> Vec<NameValue> {
+            pairs.iter().map(|pair| pair.parse().unwrap()).collect()
+        };
+
+        let config = Config {
+            storage_failover_regions: failover_regions(&["eu-west-1=eu-central-1"]),
+            storage_endpoint: None,
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_ok());
+        assert_eq!(config.storage_failover_region(), None);
+
+        let config = Config {
+            storage_failover_regions: failover_regions(&[
+                "eu-west-1=eu-central-1",
+                "us-east-1=us-west-2",
+            ]),
+            ..config
+        };
+        assert!(config.validate_storage().is_ok());
+        assert_eq!(config.storage_failover_region(), Some("us-west-2"));
+
+        // The endpoint of a test storage has no regions to fail over between.
+        let with_endpoint = Config {
+            storage_endpoint: Some("localhost:9010".to_string()),
+            ..config.clone()
+        };
+        assert!(with_endpoint.validate_storage().is_err());
+
+        for pairs in [
+            ["us-east-1=us-east-1"],
+            ["us-east-1=nowhere"],
+            ["nowhere=us-east-1"],
+        ] {
+            let config = Config {
+                storage_failover_regions: failover_regions(&pairs),
+                ..config.clone()
+            };
+            assert!(config.validate_storage().is_err(), "{:?}", pairs);
+        }
+    }
+
+    #[test]
+    fn test_validate_storage_invalid_identity_source() {
+        for url in ["", "not a url", "/relative/path", "ftp://example.com/token"] {
+            let config = Config {
+                identity_source: IdentitySource::GcpMetadata {
+                    url: url.to_string(),
+                },
+                ..default_test_config()
+            };
+            assert!(config.validate_storage().is_err(), "{}", url);
+        }
+
+        let config = Config {
+            identity_source: IdentitySource::File {
+                path: "".to_string(),
+            },
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+    }
+
+    #[test]
+    fn test_parse_identity_source() {
+        assert_eq!(
+            "disabled".parse::<IdentitySource>().unwrap(),
+            IdentitySource::Disabled
+        );
+        assert_eq!(
+            "aws-imds".parse::<IdentitySource>().unwrap(),
+            IdentitySource::AwsImds
+        );
+        assert_eq!(
+            "gcp-metadata:http://metadata.google.internal/identity?audience=test"
+                .parse::<IdentitySource>()
+                .unwrap(),
+            IdentitySource::GcpMetadata {
+                url: "http://metadata.google.internal/identity?audience=test".to_string()
+            }
+        );
+        assert_eq!(
+            "file:/var/run/secrets/token"
+                .parse::<IdentitySource>()
+                .unwrap(),
+            IdentitySource::File {
+                path: "/var/run/secrets/token".to_string()
+            }
+        );
+        for value in ["", "gcp", "http://example.com/token", "Disabled"] {
+            assert!(value.parse::<IdentitySource>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_parse_name_value() {
+        assert_eq!(
+            "Authorization=Bearer abc==".parse::<NameValue>().unwrap(),
+            NameValue {
+                name: "Authorization".to_string(),
+                value: "Bearer abc==".to_string()
+            }
+        );
+        assert_eq!(
+            "audience=".parse::<NameValue>().unwrap(),
+            NameValue {
+                name: "audience".to_string(),
+                value: "".to_string()
+            }
+        );
+        for value in ["", "audience", "=value"] {
+            assert!(value.parse::<NameValue>().is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_validate_storage_invalid_identity_fetch_params() {
+        let config = Config {
+            identity_fetch_headers: vec!["Bad Header=value".parse().unwrap()],
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+
+        let config = Config {
+            identity_fetch_headers: vec!["Authorization=bad\nvalue".parse().unwrap()],
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+
+        let config = Config {
+            identity_fetch_query_params: vec!["audience=a&b".parse().unwrap()],
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+
+        let config = Config {
+            identity_fetch_headers: vec!["Authorization=Bearer abc".parse().unwrap()],
+            identity_fetch_query_params: vec!["audience=https%3A%2F%2Fbroker".parse().unwrap()],
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_ok());
+    }
+
+    #[test]
+    fn test_validate_storage_zero_fetcher_interval() {
+        let config = Config {
+            identity_fetcher_interval_ms: 0,
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+
+        let config = Config {
+            identity_fetch_timeout_ms: 0,
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+    }
+
+    #[test]
+    fn test_validate_storage_partial_test_credentials() {
+        let config = Config {
+            storage_test_access_key_id: Some("KEY".to_string()),
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+
+        let config = Config {
+            storage_test_secret_access_key: Some("SECRET".to_string()),
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+    }
+
+    #[test]
+    fn test_storage_shard_table_names() {
+        let config = default_test_config();
+        assert_eq!(config.storage_shard_table_names(), vec!["CallRecords"]);
+
+        let config = Config {
+            storage_shard_count: 3,
+            storage_shard_table_template: Some("CallRecords-<shard>".to_string()),
+            ..default_test_config()
+        };
+        assert!(config.validate_storage().is_ok());
+        assert_eq!(
+            config.storage_shard_table_names(),
+            vec!["CallRecords-0", "CallRecords-1", "CallRecords-2"]
+        );
+    }
+
+    #[test]
+    fn test_validate_storage_invalid_shards() {
+        for (storage_shard_count, storage_shard_table_template) in [
+            (0, None),
+            (2, None),
+            (2, Some("CallRecords")),
+            (2, Some("Call Records <shard>")),
+        ] {
+            let config = Config {
+                storage_shard_count,
+                storage_shard_table_template: storage_shard_table_template.map(String::from),
+                ..default_test_config()
+            };
+            assert!(
+                config.validate_storage().is_err(),
+                "{} {:?}",
+                storage_shard_count,
+                storage_shard_table_template
+            );
+        }
+    }
+
+    #[cfg(feature = "redis")]
+    #[test]
+    fn test_validate_redis_storage() {
+        let config = Config {
+            storage_redis_url: Some("redis://127.0.0.1:6379".to_string()),
+            ..default_test_config()
+        };
+        assert!(config.validate_redis_storage().is_ok());
+
+        let config = Config {
+            storage_redis_url: Some("127.0.0.1:6379".to_string()),
+            ..default_test_config()
+        };
+        assert!(config.validate_redis_storage().is_err());
+
+        let config = Config {
+            storage_redis_url: Some("redis://127.0.0.1:6379".to_string()),
+            storage_creator_encryption_key: Some(
+                "f00f0014fe091de31827e8d686969fad65013238aadd25ef8629eb8a9e5ef69b".to_string(),
+            ),
+            ..default_test_config()
+        };
+        assert!(config.validate_redis_storage().is_err());
+    }
+}
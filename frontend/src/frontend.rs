@@ -25,12 +25,12 @@ use crate::{
     authenticator::{Authenticator, UserAuthorization},
     backend::{self, Backend, BackendError},
     config,
-    storage::{CallRecord, Storage},
+    storage::{CallRecord, DynStorage, IdentityReadiness, Storage},
 };
 
 pub type UserId = String;
 
-#[derive(Clone, Deserialize, Serialize, Eq, PartialEq)]
+#[derive(Clone, Default, Deserialize, Serialize, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub struct GroupId(String);
 
 impl From<String> for GroupId {
@@ -51,6 +51,30 @@ impl AsRef<str> for GroupId {
     }
 }
 
+impl GroupId {
+    /// Get a hash of the group_id that is the same in every process and every version,
+    /// unlike the std Hash, so that it can decide which shard the calls of the group are
+    /// kept in. It is the first 8 bytes of the SHA-256 digest of the group_id, read as
+    /// big-endian.
+    ///
+    /// Changing the algorithm reshards the data: calls already in storage would no longer
+    /// be found where they are looked for.
+    ///
+    /// ```
+    /// use calling_frontend::frontend::GroupId;
+    ///
+    /// assert_eq!(GroupId::from("aaaaaaaaaaaaaaaa").stable_hash(), 868045527852743615);
+    /// assert_eq!(GroupId::from("0000000000000000").stable_hash(), 18220239465359495811);
+    /// assert_eq!(GroupId::from("").stable_hash(), 16406829232824261652);
+    /// ```
+    pub fn stable_hash(&self) -> u64 {
+        let digest = Sha256::digest(self.0.as_bytes());
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&digest[..8]);
+        u64::from_be_bytes(prefix)
+    }
+}
+
 /// Implement Display for GroupId to redact most of the string.
 impl fmt::Display for GroupId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -144,10 +168,11 @@ pub enum FrontendError {
 pub struct Frontend {
     pub config: &'static config::Config,
     pub authenticator: Authenticator,
-    pub storage: Box<dyn Storage>,
+    pub storage: DynStorage,
     pub backend: Box<dyn Backend>,
     pub id_generator: Box<dyn IdGenerator>,
     pub api_metrics: Mutex<ApiMetrics>,
+    pub identity_readiness: IdentityReadiness,
 }
 
 impl Frontend {
@@ -250,17 +275,10 @@ impl Frontend {
         {
             Ok(clients_response) => Ok(clients_response.client_ids),
             Err(BackendError::CallNotFound) => {
-                if let Err(err) = self
-                    .storage
-                    .remove_call_record(&call.group_id, &call.call_id)
-                    .await
-                {
-                    // Warn about the error, but keep going.
-                    Frontend::log_warning(
-                        "get_client_ids_in_call: failed to remove call record not found on backend",
-                        err.into(),
-                    );
-                }
+                // The call is gone either way, so the request doesn't wait for its record
+                // to be removed.
+                self.storage
+                    .remove_call_record_best_effort(&call.group_id, &call.call_id);
                 Err(FrontendError::CallNotFound)
             }
             Err(BackendError::UnexpectedError(err)) => {
@@ -304,6 +322,18 @@ impl Frontend {
             backend_ip: info_response.backend_direct_ip,
             backend_region: self.config.region.to_string(),
             creator: user_authorization.user_id.to_string(),
+            backup_backends: vec![],
+            backend_version: info_response.version,
+            created_at: None,
+            expires_at: None,
+            last_heartbeat_at: None,
+            preferred_region: Some(self.config.region.to_string()),
+            reserved_region: None,
+            locked: false,
+            locked_by: None,
+            version: 0,
+            era: 0,
+            metadata: Default::default(),
         };
 
         // Allow for up to 5 retries to add the call to storage before giving up.
@@ -406,7 +436,7 @@ impl Frontend {
         &self,
         group_id: &GroupId,
         call_id: &str,
-    ) -> Result<(), FrontendError> {
+    ) -> Result<Option<CallRecord>, FrontendError> {
         self.storage
             .remove_call_record(group_id, call_id)
             .await
@@ -425,8 +455,4 @@ impl Frontend {
         });
         error!("{}: {}", context, error_string);
     }
-
-    fn log_warning(context: &str, err: Error) {
-        warn!("{}: {}", context, err.root_cause());
-    }
 }
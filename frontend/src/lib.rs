@@ -13,3 +13,6 @@ pub mod cleaner;
 pub mod config;
 pub mod frontend;
 pub mod storage;
+
+#[cfg(test)]
+mod test_logging;
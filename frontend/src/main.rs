@@ -10,9 +10,22 @@ use std::sync::Arc;
 
 use anyhow::Result;
 use calling_common::Duration;
+#[cfg(feature = "redis")]
+use calling_frontend::storage::RedisStorage;
 use calling_frontend::{
-    api, authenticator::Authenticator, backend::BackendHttpClient, cleaner, config,
-    frontend::Frontend, frontend::FrontendIdGenerator, metrics, storage::DynamoDb,
+    api,
+    authenticator::Authenticator,
+    backend::BackendHttpClient,
+    cleaner, config,
+    frontend::Frontend,
+    frontend::FrontendIdGenerator,
+    metrics,
+    storage::{
+        CompactCodec, DrainingStorage, DynStorage, DynamoDb, EncryptingCodec, EncryptionProvider,
+        FailoverStorage, FieldCodec, IdentityFetcher, LocalKeyEncryption, MeasuredStorage,
+        ReadOnlyStorage, RecordCodec, RetryingStorage, ShardedStorage, StorageMetricsReporter,
+        SystemClock,
+    },
 };
 use clap::Parser;
 use env_logger::Env;
@@ -32,6 +45,7 @@ fn print_config(config: &'static config::Config) {
     info!("config:");
     info!("  {:38}{}", "server_ip:", config.server_ip);
     info!("  {:38}{}", "server_port:", config.server_port);
+    info!("  {:38}{:?}", "request_timeout_ms:", config.request_timeout_ms);
     info!("  {:38}{}", "max_clients_per_call:", config.max_clients_per_call);
     info!("  {:38}{}", "cleanup_interval_ms:", config.cleanup_interval_ms);
     info!("  {:38}{}", "region:", config.region);
@@ -39,8 +53,53 @@ fn print_config(config: &'static config::Config) {
     info!("  {:38}{}", "regional_url_template:", config.regional_url_template);
     info!("  {:38}{}", "calling_server_url:", config.calling_server_url);
     info!("  {:38}{}", "storage_table:", config.storage_table);
-    info!("  {:38}{:?}", "identity_url:", config.identity_token_url);
+    info!("  {:38}{:?}", "storage_key_prefix:", config.storage_key_prefix);
+    info!("  {:38}{}", "storage_shard_count:", config.storage_shard_count);
+    info!("  {:38}{:?}", "storage_shard_table_template:", config.storage_shard_table_template);
+    info!("  {:38}{}", "storage_region_index_shards:", config.storage_region_index_shards);
+    info!(
+        "  {:38}{}",
+        "storage_backfill_region_shards:", config.storage_backfill_region_shards
+    );
+    info!("  {:38}{}", "storage_scan_segments:", config.storage_scan_segments);
+    info!("  {:38}{}", "storage_scan_concurrency:", config.storage_scan_concurrency);
+    info!("  {:38}{:?}", "storage_query_page_size:", config.storage_query_page_size);
+    info!("  {:38}{}", "storage_max_item_bytes:", config.storage_max_item_bytes);
+    info!("  {:38}{}", "storage_compact_records:", config.storage_compact_records);
+    info!("  {:38}{}", "storage_creator_encryption_key:",
+          if config.storage_creator_encryption_key.is_some() { "<redacted>" } else { "None" });
+    info!("  {:38}{}", "storage_clock_skew_tolerance_secs:", config.storage_clock_skew_tolerance_secs);
+    info!("  {:38}{}", "storage_eventually_consistent_reads:", config.storage_eventually_consistent_reads);
+    info!("  {:38}{}", "storage_conflict_read_jitter_ms:", config.storage_conflict_read_jitter_ms);
+    info!("  {:38}{}", "storage_strict_region_queries:", config.storage_strict_region_queries);
+    info!("  {:38}{:?}", "storage_max_concurrent_requests:", config.storage_max_concurrent_requests);
+    info!("  {:38}{}", "storage_request_permit_timeout_ms:", config.storage_request_permit_timeout_ms);
+    info!("  {:38}{:?}", "storage_operation_timeout_ms:", config.storage_operation_timeout_ms);
+    info!("  {:38}{}", "storage_retry_max_attempts:", config.storage_retry_max_attempts);
+    info!("  {:38}{}", "storage_retry_initial_backoff_ms:", config.storage_retry_initial_backoff_ms);
+    info!("  {:38}{}", "storage_read_only:", config.storage_read_only);
+    info!("  {:38}{}", "storage_drain_timeout_ms:", config.storage_drain_timeout_ms);
+    info!("  {:38}{:?}", "storage_metrics_regions:", config.storage_metrics_regions);
+    info!("  {:38}{}", "storage_metrics_interval_ms:", config.storage_metrics_interval_ms);
+    #[cfg(feature = "redis")]
+    info!("  {:38}{:?}", "storage_redis_url:", config.storage_redis_url);
+    info!("  {:38}{:?}", "identity_source:", config.identity_source);
+    // Only the names of the headers are logged, since their values may be secrets.
+    info!("  {:38}{:?}", "identity_fetch_headers:",
+          config.identity_fetch_headers.iter().map(|header| &header.name).collect::<Vec<_>>());
+    info!("  {:38}{:?}", "identity_fetch_query_params:", config.identity_fetch_query_params);
+    info!("  {:38}{}", "identity_fetch_pool_idle_timeout_ms:", config.identity_fetch_pool_idle_timeout_ms);
+    info!("  {:38}{}", "identity_fetch_tcp_keepalive_ms:", config.identity_fetch_tcp_keepalive_ms);
+    info!("  {:38}{}", "identity_fetch_max_failures:", config.identity_fetch_max_failures);
+    info!("  {:38}{}", "identity_fetch_exit_when_unhealthy:", config.identity_fetch_exit_when_unhealthy);
+    info!("  {:38}{:?}", "storage_failover_region:", config.storage_failover_region());
     info!("  {:38}{:?}", "storage_endpoint:", config.storage_endpoint);
+    info!("  {:38}{}", "storage_endpoint_allow_invalid_certs:", config.storage_endpoint_allow_invalid_certs);
+    info!("  {:38}{}", "storage_create_table:", config.storage_create_table);
+    info!("  {:38}{}", "storage_verify_schema:", config.storage_verify_schema);
+    info!("  {:38}{}", "storage_use_fips:", config.storage_use_fips);
+    info!("  {:38}{}", "storage_use_dualstack:", config.storage_use_dualstack);
+    info!("  {:38}{:?}", "identity_token_path:", config.identity_token_path);
     info!("  {:38}{}", "metrics_datadog:",
           match &config.metrics_datadog_host {
               Some(host) => host,
@@ -68,6 +127,54 @@ pub async fn wait_for_signal(mut canceller: mpsc::Receiver<()>) {
     )
 }
 
+/// Creates the DynamoDB storage, sharded and with failover as configured, and the fetcher
+/// of the identity tokens it is accessed with.
+fn dynamodb_storage(
+    config: &'static config::Config,
+    threaded_rt: &runtime::Runtime,
+    codec: Arc<dyn RecordCodec>,
+) -> Result<(DynStorage, IdentityFetcher)> {
+    let (storage, identity_fetcher) =
+        threaded_rt.block_on(DynamoDb::new(config, Arc::new(SystemClock), codec))?;
+
+    for table_name in config.storage_shard_table_names() {
+        let storage = storage.for_table(table_name);
+        if config.storage_create_table {
+            threaded_rt.block_on(storage.ensure_table_exists())?;
+        }
+        // Fail fast on a table that doesn't exist rather than on the first call.
+        threaded_rt.block_on(storage.check_table_exists())?;
+        if config.storage_verify_schema {
+            threaded_rt.block_on(storage.verify_schema())?;
+        }
+        threaded_rt
+            .block_on(storage.check_region_index_shards(config.storage_backfill_region_shards))?;
+    }
+
+    // Establish the storage connection before serving any requests.
+    threaded_rt.block_on(storage.warm_up());
+
+    let shard = |storage: DynamoDb| -> Result<DynStorage> {
+        Ok(if config.storage_shard_count > 1 {
+            Arc::new(ShardedStorage::from_config(config, &storage)?)
+        } else {
+            Arc::new(storage)
+        })
+    };
+    let storage: DynStorage = match config.storage_failover_region() {
+        Some(region) => {
+            let failover = threaded_rt.block_on(storage.for_region(config, region))?;
+            Arc::new(FailoverStorage::new(
+                shard(storage)?,
+                shard(failover)?,
+                region.to_string(),
+            ))
+        }
+        None => shard(storage)?,
+    };
+    Ok((storage, identity_fetcher))
+}
+
 fn main() -> Result<()> {
     std::env::set_var("RUST_BACKTRACE", "full");
 
@@ -112,25 +219,86 @@ fn main() -> Result<()> {
     let (cleaner_ender_tx, cleaner_ender_rx) = oneshot::channel();
     let (metrics_ender_tx, metrics_ender_rx) = oneshot::channel();
     let (identity_fetcher_ender_tx, identity_fetcher_ender_rx) = oneshot::channel();
+    let (storage_metrics_ender_tx, storage_metrics_ender_rx) = oneshot::channel();
     let (signal_canceller_tx, signal_canceller_rx) = mpsc::channel(1);
 
     let signal_canceller_tx_clone_for_cleaner = signal_canceller_tx.clone();
     let signal_canceller_tx_clone_for_metrics = signal_canceller_tx.clone();
     let signal_canceller_tx_clone_for_identity_fetcher = signal_canceller_tx.clone();
+    let signal_canceller_tx_clone_for_storage_metrics = signal_canceller_tx.clone();
 
     // Create frontend entities that might fail.
     let authenticator = Authenticator::from_hex_key(&config.authentication_key)?;
-    let (storage, identity_fetcher) = threaded_rt.block_on(DynamoDb::new(config))?;
+    let codec: Arc<dyn RecordCodec> = if config.storage_compact_records {
+        Arc::new(CompactCodec)
+    } else {
+        Arc::new(FieldCodec)
+    };
+    let creator_encryption = match &config.storage_creator_encryption_key {
+        Some(key) => {
+            Some(Arc::new(LocalKeyEncryption::from_hex_key(key)?) as Arc<dyn EncryptionProvider>)
+        }
+        None => None,
+    };
+    let codec = Arc::new(EncryptingCodec::new(codec, creator_encryption));
+
+    #[cfg(feature = "redis")]
+    let redis_storage: Option<DynStorage> = match &config.storage_redis_url {
+        Some(url) => {
+            config.validate_redis_storage()?;
+            info!("Using Redis for storage: {}", url);
+            let storage = threaded_rt
+                .block_on(RedisStorage::new(url, Arc::new(SystemClock)))?
+                .with_clock_skew_tolerance(Duration::from_secs(
+                    config.storage_clock_skew_tolerance_secs,
+                ));
+            Some(Arc::new(storage) as DynStorage)
+        }
+        None => None,
+    };
+    #[cfg(not(feature = "redis"))]
+    let redis_storage: Option<DynStorage> = None;
+
+    // Identity tokens are only fetched for DynamoDB.
+    let (storage, identity_fetcher) = match redis_storage {
+        Some(storage) => (storage, None),
+        None => {
+            let (storage, identity_fetcher) = dynamodb_storage(config, &threaded_rt, codec)?;
+            (storage, Some(identity_fetcher))
+        }
+    };
+    // Throttled and otherwise transient failures are retried where that is safe, before
+    // they reach callers.
+    let storage: DynStorage = Arc::new(RetryingStorage::new(
+        storage,
+        config.storage_retry_max_attempts,
+        Duration::from_millis(config.storage_retry_initial_backoff_ms),
+    ));
+    let storage: DynStorage = if config.storage_read_only {
+        warn!("storage is read-only, calls can't be created or changed");
+        Arc::new(ReadOnlyStorage::new(storage))
+    } else {
+        storage
+    };
+    let storage = Arc::new(DrainingStorage::new(storage));
+    let draining_storage = storage.clone();
+    let storage: DynStorage = Arc::new(MeasuredStorage::new(storage));
 
-    threaded_rt.block_on(async {
+    let storage_clone_for_metrics = storage.clone();
+
+    let result = threaded_rt.block_on(async {
         // Create the shared Frontend state.
         let frontend: Arc<Frontend> = Arc::new(Frontend {
             config,
             authenticator,
-            storage: Box::new(storage),
+            storage,
             backend: Box::new(BackendHttpClient::from_config(config)),
             id_generator: Box::new(FrontendIdGenerator),
             api_metrics: Mutex::new(Default::default()),
+            identity_readiness: identity_fetcher
+                .as_ref()
+                .map(IdentityFetcher::readiness)
+                .unwrap_or_default(),
         });
 
         let frontend_clone_for_cleaner = frontend.clone();
@@ -154,12 +322,32 @@ fn main() -> Result<()> {
             let _ = signal_canceller_tx_clone_for_metrics.send(()).await;
         });
 
-        // Start the identity token fetcher.
+        // Start the identity token fetcher. It failing stops the frontend, and its error
+        // is returned once everything else has exited.
         let fetcher_handle = tokio::spawn(async move {
-            let _ = identity_fetcher.start(identity_fetcher_ender_rx).await;
+            let result = match identity_fetcher {
+                Some(identity_fetcher) => identity_fetcher.start(identity_fetcher_ender_rx).await,
+                None => {
+                    let _ = identity_fetcher_ender_rx.await;
+                    Ok(())
+                }
+            };
             let _ = signal_canceller_tx_clone_for_identity_fetcher
                 .send(())
                 .await;
+            result
+        });
+
+        // Start the storage metrics reporter.
+        let storage_metrics_handle = tokio::spawn(async move {
+            let _ = StorageMetricsReporter::start(
+                storage_clone_for_metrics,
+                config.storage_metrics_regions.clone(),
+                Duration::from_millis(config.storage_metrics_interval_ms),
+                storage_metrics_ender_rx,
+            )
+            .await;
+            let _ = signal_canceller_tx_clone_for_storage_metrics.send(()).await;
         });
 
         // Wait for any signals to be detected, or cancel due to one of the
@@ -171,13 +359,34 @@ fn main() -> Result<()> {
         let _ = cleaner_ender_tx.send(());
         let _ = metrics_ender_tx.send(());
         let _ = identity_fetcher_ender_tx.send(());
+        let _ = storage_metrics_ender_tx.send(());
+
+        // Refuse new storage operations and let the ones in flight finish, so that no
+        // write is cut off halfway.
+        draining_storage.begin_shutdown();
+        if !draining_storage
+            .drain(Duration::from_millis(config.storage_drain_timeout_ms))
+            .await
+        {
+            warn!(
+                "{} storage operations were still in flight at shutdown",
+                draining_storage.in_flight()
+            );
+        }
 
         // Wait for the servers to exit.
-        let _ = tokio::join!(api_handle, cleaner_handle, metrics_handle, fetcher_handle);
+        let (_, _, _, fetcher_result, _) = tokio::join!(
+            api_handle,
+            cleaner_handle,
+            metrics_handle,
+            fetcher_handle,
+            storage_metrics_handle
+        );
+        fetcher_result?
     });
 
     info!("shutting down the runtime");
     threaded_rt.shutdown_timeout(Duration::from_millis(500).into());
 
-    Ok(())
+    result
 }
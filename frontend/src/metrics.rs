@@ -92,7 +92,20 @@ pub async fn start(frontend: Arc<Frontend>, shutdown_signal_rx: Receiver<()>) ->
                         datadog.send_timer_histogram(&report, &None);
                     }
                     for report in report.events {
-                        datadog.count(report.name(), report.event_count() as f64, &None);
+                        let tags = if report.tags().is_empty() {
+                            None
+                        } else {
+                            Some(report.tags().iter().map(String::as_str).collect())
+                        };
+                        datadog.count(report.name(), report.event_count() as f64, &tags);
+                    }
+                    for report in report.gauges {
+                        let tags = if report.tags().is_empty() {
+                            None
+                        } else {
+                            Some(report.tags().iter().map(String::as_str).collect())
+                        };
+                        datadog.gauge(report.name(), report.value(), &tags);
                     }
 
                     let mut api_metrics = frontend.api_metrics.lock();
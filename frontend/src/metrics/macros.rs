@@ -15,7 +15,8 @@ use once_cell::sync::Lazy;
 use parking_lot::Mutex;
 
 use crate::metrics::{
-    EventCountReporter, EventReport, HistogramReport, NumericValueReporter, TimingOptions,
+    EventCountReporter, EventReport, GaugeReport, HistogramReport, NumericValueReporter,
+    TaggedEventCountReporter, TaggedGaugeReporter, TimingOptions,
 };
 
 /// A global structure that contains a map to each of the registered Timing Reporters.
@@ -32,11 +33,14 @@ struct Registry {
     registered_names: HashSet<&'static str>,
     numeric_reporters: Vec<Arc<NumericValueReporter>>,
     event_reporters: Vec<Arc<EventCountReporter>>,
+    tagged_event_reporters: Vec<Arc<TaggedEventCountReporter>>,
+    tagged_gauge_reporters: Vec<Arc<TaggedGaugeReporter>>,
 }
 
 pub struct Report {
     pub histograms: Vec<HistogramReport>,
     pub events: Vec<EventReport>,
+    pub gauges: Vec<GaugeReport>,
 }
 
 pub static __METRICS: Lazy<Metrics> = Lazy::new(Metrics::new_enabled);
@@ -55,6 +59,85 @@ impl Metrics {
         *registry = Default::default();
     }
 
+    /// Returns the current count of the named event, across all tags, without resetting
+    /// it.
+    #[cfg(test)]
+    pub fn peek_event_count(&self, name: &str) -> usize {
+        self.peek_event_reports(name)
+            .iter()
+            .map(|report| report.event_count())
+            .sum()
+    }
+
+    /// Returns the current count of the named event with exactly the given tags without
+    /// resetting it.
+    #[cfg(test)]
+    pub fn peek_event_count_with_tags(&self, name: &str, tags: &[&str]) -> usize {
+        self.peek_event_reports(name)
+            .iter()
+            .filter(|report| report.tags() == tags)
+            .map(|report| report.event_count())
+            .sum()
+    }
+
+    /// Returns the number of times the named timer was started, sampled or not, since it
+    /// was registered.
+    #[cfg(test)]
+    pub fn peek_timer_count(&self, name: &str) -> usize {
+        self.registry
+            .lock()
+            .numeric_reporters
+            .iter()
+            .map(|reporter| reporter.peek_event_count())
+            .filter(|(reporter_name, _)| *reporter_name == name)
+            .map(|(_, count)| count)
+            .sum()
+    }
+
+    /// Returns each value of the named histogram that was sampled since the last report
+    /// along with the number of times it was sampled, sorted by value.
+    #[cfg(test)]
+    pub fn peek_histogram_samples(&self, name: &str) -> Vec<(usize, usize)> {
+        self.registry
+            .lock()
+            .numeric_reporters
+            .iter()
+            .map(|reporter| reporter.peek_samples())
+            .filter(|(reporter_name, _)| *reporter_name == name)
+            .flat_map(|(_, samples)| samples)
+            .collect()
+    }
+
+    /// Returns the latest value of the named gauge with exactly the given tags, if it was
+    /// ever set.
+    #[cfg(test)]
+    pub fn peek_gauge_with_tags(&self, name: &str, tags: &[&str]) -> Option<f64> {
+        self.registry
+            .lock()
+            .tagged_gauge_reporters
+            .iter()
+            .flat_map(|reporter| reporter.report())
+            .find(|report| report.name() == name && report.tags() == tags)
+            .map(|report| report.value())
+    }
+
+    #[cfg(test)]
+    fn peek_event_reports(&self, name: &str) -> Vec<EventReport> {
+        let registry = self.registry.lock();
+        registry
+            .event_reporters
+            .iter()
+            .map(|reporter| reporter.peek())
+            .chain(
+                registry
+                    .tagged_event_reporters
+                    .iter()
+                    .flat_map(|reporter| reporter.peek()),
+            )
+            .filter(|report| report.name() == name)
+            .collect()
+    }
+
     /// Locks the internal structure and adds a new timer.
     pub fn create_and_register_timer(
         &self,
@@ -93,6 +176,41 @@ impl Metrics {
         event_reporter
     }
 
+    /// Locks the internal structure and adds a new tagged event.
+    pub fn create_and_register_tagged_event(
+        &self,
+        name: &'static str,
+    ) -> Arc<TaggedEventCountReporter> {
+        let event_reporter = Arc::new(TaggedEventCountReporter::new(name));
+
+        let mut registry = self.registry.lock();
+
+        if !registry.registered_names.insert(name) {
+            panic!("The metric name \"{}\" has been used elsewhere.", name);
+        }
+
+        registry
+            .tagged_event_reporters
+            .push(Arc::clone(&event_reporter));
+        event_reporter
+    }
+
+    /// Locks the internal structure and adds a new tagged gauge.
+    pub fn create_and_register_tagged_gauge(&self, name: &'static str) -> Arc<TaggedGaugeReporter> {
+        let gauge_reporter = Arc::new(TaggedGaugeReporter::new(name));
+
+        let mut registry = self.registry.lock();
+
+        if !registry.registered_names.insert(name) {
+            panic!("The metric name \"{}\" has been used elsewhere.", name);
+        }
+
+        registry
+            .tagged_gauge_reporters
+            .push(Arc::clone(&gauge_reporter));
+        gauge_reporter
+    }
+
     pub fn enabled(&self) -> bool {
         self.enabled.load(Ordering::Relaxed)
     }
@@ -114,10 +232,27 @@ impl Metrics {
             .event_reporters
             .iter()
             .map(|reporter| reporter.report())
+            .chain(
+                registry
+                    .tagged_event_reporters
+                    .iter()
+                    .flat_map(|reporter| reporter.report()),
+            )
             .collect::<Vec<_>>();
         events.sort_unstable_by_key(|report| report.name());
 
-        Report { histograms, events }
+        let mut gauges = registry
+            .tagged_gauge_reporters
+            .iter()
+            .flat_map(|reporter| reporter.report())
+            .collect::<Vec<_>>();
+        gauges.sort_unstable_by_key(|report| report.name());
+
+        Report {
+            histograms,
+            events,
+            gauges,
+        }
     }
 
     pub fn disable(&self) {
@@ -210,6 +345,53 @@ macro_rules! event {
     };
 }
 
+#[macro_export]
+macro_rules! tagged_event_reporter {
+    ($name:expr) => {{
+        pub static __REPORTER: once_cell::sync::Lazy<
+            std::sync::Arc<$crate::metrics::TaggedEventCountReporter>,
+        > = once_cell::sync::Lazy::new(|| {
+            $crate::metrics::__METRICS.create_and_register_tagged_event($name)
+        });
+
+        &__REPORTER
+    }};
+}
+
+/// Count an event with a Vec of "key:value" tags. Each distinct set of tags is reported
+/// separately.
+#[macro_export]
+macro_rules! tagged_event {
+    ($name:expr, $tags:expr) => {
+        tagged_event_reporter!($name).count($tags);
+    };
+    ($name:expr, $tags:expr, $count:expr) => {
+        tagged_event_reporter!($name).count_n($tags, $count);
+    };
+}
+
+#[macro_export]
+macro_rules! tagged_gauge_reporter {
+    ($name:expr) => {{
+        pub static __REPORTER: once_cell::sync::Lazy<
+            std::sync::Arc<$crate::metrics::TaggedGaugeReporter>,
+        > = once_cell::sync::Lazy::new(|| {
+            $crate::metrics::__METRICS.create_and_register_tagged_gauge($name)
+        });
+
+        &__REPORTER
+    }};
+}
+
+/// Set a gauge with a Vec of "key:value" tags to the given value. Each distinct set of
+/// tags is reported separately.
+#[macro_export]
+macro_rules! tagged_gauge {
+    ($name:expr, $tags:expr, $value:expr) => {
+        tagged_gauge_reporter!($name).set($tags, $value);
+    };
+}
+
 #[macro_export]
 macro_rules! metrics {
     () => {{
@@ -266,6 +448,70 @@ mod tests {
         metrics.create_and_register_event("A");
     }
 
+    #[test]
+    #[should_panic(expected = "The metric name \"A\" has been used elsewhere.")]
+    fn cant_register_same_name_for_an_event_and_tagged_event() {
+        let metrics = Metrics::new_enabled();
+
+        metrics.create_and_register_event("A");
+        metrics.create_and_register_tagged_event("A");
+    }
+
+    #[test]
+    fn tagged_events_are_reported_per_tags() {
+        let metrics = Metrics::new_enabled();
+
+        let event_reporter = metrics.create_and_register_tagged_event("A");
+        event_reporter.count(vec!["operation:get".to_string()]);
+        event_reporter.count_n(vec!["operation:get".to_string()], 2);
+        event_reporter.count(vec!["operation:put".to_string()]);
+
+        assert_eq!(
+            3,
+            metrics.peek_event_count_with_tags("A", &["operation:get"])
+        );
+        assert_eq!(4, metrics.peek_event_count("A"));
+
+        let mut events = metrics.report().events;
+        events.sort_unstable_by_key(|report| report.tags().to_vec());
+        assert_eq!(2, events.len());
+        assert_eq!(["operation:get"], events[0].tags());
+        assert_eq!(3, events[0].event_count());
+        assert_eq!(["operation:put"], events[1].tags());
+        assert_eq!(1, events[1].event_count());
+
+        // Reporting resets the counts.
+        assert!(metrics.report().events.is_empty());
+    }
+
+    #[test]
+    fn tagged_gauges_keep_their_latest_value() {
+        let metrics = Metrics::new_enabled();
+
+        let gauge_reporter = metrics.create_and_register_tagged_gauge("A");
+        gauge_reporter.set(vec!["region:us-west1".to_string()], 3.0);
+        gauge_reporter.set(vec!["region:us-west1".to_string()], 5.0);
+        gauge_reporter.set(vec!["region:us-east1".to_string()], 1.0);
+
+        assert_eq!(
+            Some(5.0),
+            metrics.peek_gauge_with_tags("A", &["region:us-west1"])
+        );
+        assert_eq!(
+            None,
+            metrics.peek_gauge_with_tags("A", &["region:eu-west1"])
+        );
+
+        let mut gauges = metrics.report().gauges;
+        gauges.sort_unstable_by_key(|report| report.tags().to_vec());
+        assert_eq!(2, gauges.len());
+        assert_eq!(["region:us-east1"], gauges[0].tags());
+        assert_eq!(1.0, gauges[0].value());
+
+        // Unlike events, reporting doesn't reset gauges.
+        assert_eq!(2, metrics.report().gauges.len());
+    }
+
     #[test]
     fn registrations_are_enabled() {
         let metrics = Metrics::new_enabled();
@@ -6,6 +6,7 @@
 #[cfg(not(test))]
 use std::time::Instant;
 use std::{
+    collections::HashMap,
     mem,
     sync::atomic::{AtomicUsize, Ordering},
     time::Duration,
@@ -93,6 +94,28 @@ impl NumericValueReporter {
             .push_sample(sample, sample_interval);
     }
 
+    /// Returns the number of timers started or values pushed since the reporter was
+    /// created, including those that weren't sampled.
+    #[cfg(test)]
+    pub fn peek_event_count(&self) -> (&'static str, usize) {
+        (self.name, self.event_counter.load(Ordering::Relaxed))
+    }
+
+    /// Returns each value sampled since the last report along with the number of times
+    /// it was sampled, sorted by value, without resetting the reporter.
+    #[cfg(test)]
+    pub fn peek_samples(&self) -> (&'static str, Vec<(usize, usize)>) {
+        let mut samples: Vec<_> = self
+            .measurements_since_last_report
+            .lock()
+            .histogram
+            .iter()
+            .map(|(value, count)| (*value, *count))
+            .collect();
+        samples.sort_unstable();
+        (self.name, samples)
+    }
+
     /// Creates a report of timings and resets the reporter.
     pub fn report(&self) -> HistogramReport {
         let event_count = self.event_counter.load(Ordering::Relaxed);
@@ -157,13 +180,112 @@ impl EventCountReporter {
         self.count_n(1);
     }
 
+    /// Grab the event count without resetting it.
+    #[cfg(test)]
+    pub fn peek(&self) -> EventReport {
+        EventReport {
+            name: self.name,
+            event_count: self.event_counter.load(Ordering::Relaxed),
+            tags: vec![],
+        }
+    }
+
     /// Grab the event count and reset to zero.
     pub fn report(&self) -> EventReport {
         EventReport {
             name: self.name,
             event_count: self.event_counter.swap(0, Ordering::Relaxed),
+            tags: vec![],
+        }
+    }
+}
+
+/// Counts events like the EventCountReporter, but separately for each set of tags that
+/// the events are counted with. Tags are "key:value" strings.
+pub struct TaggedEventCountReporter {
+    name: &'static str,
+    event_counters: Mutex<HashMap<Vec<String>, usize>>,
+}
+
+impl TaggedEventCountReporter {
+    pub fn new(name: &'static str) -> TaggedEventCountReporter {
+        TaggedEventCountReporter {
+            name,
+            event_counters: Default::default(),
+        }
+    }
+
+    /// This will count n events with the given tags.
+    pub fn count_n(&self, tags: Vec<String>, n: usize) {
+        *self.event_counters.lock().entry(tags).or_insert(0) += n;
+    }
+
+    /// This will count an event with the given tags.
+    pub fn count(&self, tags: Vec<String>) {
+        self.count_n(tags, 1);
+    }
+
+    /// Grab the event counts for each set of tags without resetting them.
+    #[cfg(test)]
+    pub fn peek(&self) -> Vec<EventReport> {
+        self.event_counters
+            .lock()
+            .iter()
+            .map(|(tags, event_count)| EventReport {
+                name: self.name,
+                event_count: *event_count,
+                tags: tags.clone(),
+            })
+            .collect()
+    }
+
+    /// Grab the event counts for each set of tags and reset them. Only the sets of tags
+    /// that were counted since the last report are included.
+    pub fn report(&self) -> Vec<EventReport> {
+        mem::take(&mut *self.event_counters.lock())
+            .into_iter()
+            .map(|(tags, event_count)| EventReport {
+                name: self.name,
+                event_count,
+                tags,
+            })
+            .collect()
+    }
+}
+
+/// Holds the latest value of a gauge separately for each set of tags that it was set
+/// with. Tags are "key:value" strings.
+pub struct TaggedGaugeReporter {
+    name: &'static str,
+    values: Mutex<HashMap<Vec<String>, f64>>,
+}
+
+impl TaggedGaugeReporter {
+    pub fn new(name: &'static str) -> TaggedGaugeReporter {
+        TaggedGaugeReporter {
+            name,
+            values: Default::default(),
         }
     }
+
+    /// This will set the value of the gauge with the given tags.
+    pub fn set(&self, tags: Vec<String>, value: f64) {
+        self.values.lock().insert(tags, value);
+    }
+
+    /// Grab the latest value for each set of tags. Unlike events, values aren't reset,
+    /// so a gauge keeps reporting its last value until it is set again.
+    pub fn report(&self) -> Vec<GaugeReport> {
+        self.values
+            .lock()
+            .iter()
+            .map(|(tags, value)| GaugeReport {
+                name: self.name,
+                value: *value,
+                tags: tags.clone(),
+            })
+            .collect()
+    }
 }
 
 struct RunningTimer<'a> {
@@ -250,6 +372,7 @@ impl HistogramReport {
 pub struct EventReport {
     name: &'static str,
     event_count: usize,
+    tags: Vec<String>,
 }
 
 impl EventReport {
@@ -260,6 +383,30 @@ impl EventReport {
     pub fn event_count(&self) -> usize {
         self.event_count
     }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+}
+
+pub struct GaugeReport {
+    name: &'static str,
+    value: f64,
+    tags: Vec<String>,
+}
+
+impl GaugeReport {
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
 }
 
 impl SinceLastReport {
@@ -3,25 +3,106 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+/// Logs like log::log! at the given level, with the message prefixed by the correlation id
+/// of the current task when the caller set one, so that the logs of storage operations
+/// can be tied back to the request they were made for.
+macro_rules! storage_log {
+    ($level:expr, $($arg:tt)+) => {
+        match $crate::storage::current_correlation_id() {
+            Some(correlation_id) => {
+                log::log!($level, "[{}] {}", correlation_id, format_args!($($arg)+))
+            }
+            None => log::log!($level, $($arg)+),
+        }
+    };
+}
+
+mod auditing;
+mod clock;
+mod codec;
+mod draining;
+mod encryption;
+mod failover;
+mod fault_injecting;
+mod guard;
+mod in_memory;
+mod measured;
+mod metrics_reporter;
+mod migrating;
+mod read_only;
+#[cfg(feature = "redis")]
+mod redis_storage;
+mod retrying;
+mod sharded;
+
+pub use auditing::{AuditEntry, AuditOutcome, AuditSink, AuditingStorage, JsonStdoutAuditSink};
+#[cfg(test)]
+pub use clock::MockClock;
+pub use clock::{Clock, SystemClock};
+pub use codec::{CompactCodec, FieldCodec, RecordCodec, PACKED_RECORD_ATTRIBUTE};
+pub use draining::DrainingStorage;
+pub use encryption::{EncryptingCodec, EncryptionProvider, LocalKeyEncryption};
+pub use failover::FailoverStorage;
+pub use fault_injecting::{Fault, FaultInjectingStorage};
+pub use guard::CallRecordGuard;
+pub use in_memory::InMemoryStorage;
+pub use measured::MeasuredStorage;
+pub use metrics_reporter::StorageMetricsReporter;
+pub use migrating::MigratingStorage;
+pub use read_only::ReadOnlyStorage;
+#[cfg(feature = "redis")]
+pub use redis_storage::RedisStorage;
+pub use retrying::RetryingStorage;
+pub use sharded::ShardedStorage;
+
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
+use aws_config::imds::credentials::ImdsCredentialsProvider;
 use aws_sdk_dynamodb::{
-    model::{AttributeValue, Select},
+    client::fluent_builders,
+    error::{DeleteItemErrorKind, DescribeTableError, QueryError, TransactWriteItemsErrorKind},
+    model::{
+        AttributeDefinition, AttributeValue, BillingMode, CancellationReason, ConsumedCapacity,
+        Delete, GlobalSecondaryIndex, KeySchemaElement, KeyType, Projection, ProjectionType, Put,
+        PutRequest, ReturnConsumedCapacity, ReturnValue, ScalarAttributeType, Select, TableStatus,
+        TimeToLiveSpecification, TimeToLiveStatus, TransactWriteItem, Update, WriteRequest,
+    },
+    output::{QueryOutput, ScanOutput},
     types::SdkError,
     Client, Config, Endpoint,
 };
 use aws_smithy_async::rt::sleep::default_async_sleep;
-use aws_smithy_types::retry::RetryConfigBuilder;
+use aws_smithy_types::retry::{ProvideErrorKind, RetryConfigBuilder};
 use aws_types::{region::Region, Credentials};
 use calling_common::Duration;
-use http::Uri;
+use futures::{future::try_join_all, stream::BoxStream, StreamExt, TryStreamExt};
+use http::{
+    header::{HeaderName, HeaderValue},
+    Uri,
+};
 use hyper::client::HttpConnector;
 use hyper::{Body, Method, Request};
 use log::*;
+use parking_lot::Mutex;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
-use serde_dynamo::{from_item, to_item};
-use std::{env, path::PathBuf};
-use tokio::{io::AsyncWriteExt, sync::oneshot::Receiver};
+use serde_dynamo::from_item;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{oneshot::Receiver, watch, OwnedSemaphorePermit, Semaphore},
+};
 
 #[cfg(test)]
 use mockall::{automock, predicate::*};
@@ -34,7 +115,133 @@ use crate::{
 
 const GROUP_CONFERENCE_ID_STRING: &str = "groupConferenceId";
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+/// Prefix for the key of the per-region items that count the calls reserved in a
+/// region. These items have no region attribute and so aren't part of the region-index.
+const REGION_CAPACITY_KEY_PREFIX: &str = "regionCapacity#";
+/// The prefix of the keys of the items that count the eras of each group's calls.
+const CALL_ERA_KEY_PREFIX: &str = "callEra#";
+/// The key of the item that records how many write shards the calls in the region index
+/// are indexed under. Like the counters, it has no region attribute.
+const REGION_INDEX_SHARDS_KEY: &str = "regionIndexShards";
+
+/// The condition that restricts queries and scans to the items with the key_prefix of
+/// a storage, which is bound to :key_prefix.
+const KEY_PREFIX_CONDITION: &str = "begins_with(groupConferenceId, :key_prefix)";
+
+/// The update of a call that increments its version, with #version bound to the version
+/// attribute and :zero and :one to 0 and 1. Every update of a call includes it, so that
+/// update_call_record, which writes the whole call, can't undo a change it didn't see.
+const INCREMENT_VERSION: &str = "#version = if_not_exists(#version, :zero) + :one";
+
+tokio::task_local! {
+    /// The id of the request on whose behalf storage operations are being performed, so
+    /// that storage logs can be tied back to it.
+    static CORRELATION_ID: String;
+}
+
+/// Runs the given future with a correlation id that is included in the logs of any
+/// storage operations it performs.
+pub async fn with_correlation_id<F: std::future::Future>(
+    correlation_id: String,
+    f: F,
+) -> F::Output {
+    CORRELATION_ID.scope(correlation_id, f).await
+}
+
+/// Returns the correlation id of the current task, if the caller set one.
+pub(crate) fn current_correlation_id() -> Option<String> {
+    CORRELATION_ID.try_with(|id| id.clone()).ok()
+}
+
+tokio::task_local! {
+    /// When the request on whose behalf storage operations are being performed must be
+    /// answered by, so that storage doesn't outlast it.
+    static DEADLINE: tokio::time::Instant;
+}
+
+/// Runs the given future with a deadline that any storage operations it performs give up
+/// at, failing with DeadlineExceeded.
+pub async fn with_deadline<F: std::future::Future>(
+    deadline: tokio::time::Instant,
+    f: F,
+) -> F::Output {
+    DEADLINE.scope(deadline, f).await
+}
+
+/// Returns the deadline of the current task, if the caller set one.
+pub(crate) fn current_deadline() -> Option<tokio::time::Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+/// How long a health check waits for the table to respond.
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to wait before the first retry of a region-index query that didn't yet
+/// include an expected call. The wait doubles on each subsequent retry.
+const REGION_INDEX_INITIAL_BACKOFF: Duration = Duration::from_millis(25);
+
+/// The most items that DynamoDB accepts in a single BatchWriteItem request.
+const BATCH_WRITE_MAX_ITEMS: usize = 25;
+
+/// How many times to send the items that a BatchWriteItem request left unprocessed
+/// before giving up.
+const BATCH_WRITE_MAX_ATTEMPTS: usize = 8;
+
+/// How long to wait before resending unprocessed items the first time. The wait doubles
+/// on each subsequent attempt.
+const BATCH_WRITE_INITIAL_BACKOFF: Duration = Duration::from_millis(25);
+
+/// How many times remove_call_record_best_effort tries to remove a call before leaving it
+/// to its TTL and the reaper.
+const BEST_EFFORT_REMOVAL_MAX_ATTEMPTS: usize = 3;
+
+/// How long to wait before retrying a best-effort removal the first time. The wait
+/// doubles on each subsequent attempt.
+const BEST_EFFORT_REMOVAL_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Error codes with which DynamoDB rejects requests that may succeed if sent again later.
+const THROTTLING_ERROR_CODES: &[&str] = &[
+    "ProvisionedThroughputExceededException",
+    "RequestLimitExceeded",
+    "ThrottlingException",
+];
+
+/// The hard limit that DynamoDB puts on the size of an item.
+pub const DYNAMODB_MAX_ITEM_BYTES: usize = 400 * 1024;
+
+/// The GSI used for region queries, whose hash key is the region.
+const REGION_INDEX_NAME: &str = "region-index";
+
+/// The GSI used for region queries when the region index is write sharded. Its hash key
+/// is REGION_SHARD_ATTRIBUTE rather than the region itself.
+const REGION_SHARD_INDEX_NAME: &str = "region-shard-index";
+
+/// The attribute holding the `<region>#<shard>` key of a call in the sharded region index.
+const REGION_SHARD_ATTRIBUTE: &str = "regionShard";
+
+/// The most bytes that the keys and values of the metadata of a call may add up to.
+pub const MAX_CALL_METADATA_BYTES: usize = 1024;
+
+/// How long a call record lives after it is created before it is considered expired.
+pub const CALL_RECORD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long the era counter of a group is kept after the last call was created for it.
+/// This is much longer than CALL_RECORD_TTL so that no call that is still remembered
+/// shares an era with a later one.
+pub const CALL_ERA_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// A reference to a backend Calling Server that is able to host a call.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct BackendRef {
+    /// The region of the backend Calling Server.
+    pub region: String,
+    /// The IP of the backend Calling Server.
+    pub ip: String,
+}
+
+/// Records are ordered by group_id and then call_id, with the remaining fields only
+/// breaking ties so that the order agrees with equality.
+#[derive(Clone, Default, Serialize, Deserialize, Eq, PartialEq, Hash, PartialOrd, Ord)]
 pub struct CallRecord {
     /// The group_id that the client is authorized to join and provided to the frontend
     /// by the client.
@@ -52,308 +259,8326 @@ pub struct CallRecord {
     pub backend_region: String,
     /// The user_id of the user that created the call.
     pub creator: UserId,
+    /// An ordered list of backends that the call can fail over to. The backend_ip and
+    /// backend_region always refer to the primary backend.
+    #[serde(
+        rename = "backupBackends",
+        default,
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub backup_backends: Vec<BackendRef>,
+    /// The software version of the backend that the call was created on, if the backend
+    /// reported one. Records written before this was tracked don't have it.
+    #[serde(
+        rename = "backendVersion",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub backend_version: Option<String>,
+    /// Seconds since the Unix epoch at which the record was created. Records written
+    /// before this was tracked don't have it.
+    #[serde(rename = "createdAt", default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    /// Seconds since the Unix epoch from which the record is considered expired. This
+    /// is also the TTL attribute of the table, but DynamoDB may take a while to actually
+    /// delete expired items.
+    #[serde(rename = "expiresAt", default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Seconds since the Unix epoch at which the backend last reported that the call is
+    /// alive. Records written before this was tracked don't have it.
+    #[serde(
+        rename = "lastHeartbeatAt",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub last_heartbeat_at: Option<u64>,
+    /// The region that clients reconnecting to the call should be steered to, which is
+    /// the region of the creator. Unlike backend_region, this doesn't change when the
+    /// call fails over. Records written before this was tracked don't have it.
+    #[serde(
+        rename = "preferredRegion",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub preferred_region: Option<String>,
+    /// The region in which create_call_reserving_capacity reserved capacity for the call,
+    /// if it did, which removing the call releases. This stays the region that was
+    /// reserved in even if the call fails over to another region.
+    #[serde(
+        rename = "reservedRegion",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub reserved_region: Option<String>,
+    /// Whether the call is locked, in which case only its creator may join it. Records
+    /// written before calls could be locked are unlocked.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub locked: bool,
+    /// The user that locked the call, if it is locked.
+    #[serde(rename = "lockedBy", default, skip_serializing_if = "Option::is_none")]
+    pub locked_by: Option<UserId>,
+    /// Incremented on every write so that read-modify-write updates can detect that the
+    /// record changed in the meantime. Records written before this was tracked are 0.
+    #[serde(default)]
+    pub version: u64,
+    /// Counts the calls that have been created for the group_id, starting from 1, so
+    /// unlike the random call_id it orders the calls of a group. Eras may skip values,
+    /// and start over once no call has been created for the group for CALL_ERA_TTL.
+    /// Calls whose era couldn't be recorded, and records written before this was
+    /// tracked, are 0.
+    #[serde(default)]
+    pub era: u64,
+    /// Extra fields for experimental flags, so that they don't each need a field of their
+    /// own. Entries are never indexed or used in conditions, and set_metadata keeps the
+    /// map within MAX_CALL_METADATA_BYTES. Records written before this was tracked have
+    /// none.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+/// Implement Debug for CallRecord to redact most of the creator, like the group_id.
+impl fmt::Debug for CallRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("CallRecord")
+            .field("group_id", &self.group_id)
+            .field("call_id", &self.call_id)
+            .field("backend_ip", &self.backend_ip)
+            .field("backend_region", &self.backend_region)
+            .field("creator", &format_args!("{:.4}", self.creator))
+            .field("backup_backends", &self.backup_backends)
+            .field("backend_version", &self.backend_version)
+            .field("created_at", &self.created_at)
+            .field("expires_at", &self.expires_at)
+            .field("last_heartbeat_at", &self.last_heartbeat_at)
+            .field("preferred_region", &self.preferred_region)
+            .field("reserved_region", &self.reserved_region)
+            .field("locked", &self.locked)
+            .field(
+                "locked_by",
+                &self
+                    .locked_by
+                    .as_ref()
+                    .map(|locked_by| format!("{:.4}", locked_by)),
+            )
+            .field("version", &self.version)
+            .field("era", &self.era)
+            .field("metadata", &self.metadata)
+            .finish()
+    }
+}
+
+/// The parts of a CallRecord that are returned by projected region queries, named by
+/// their attributes in the table. Attributes that weren't requested are None.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+pub struct CallRecordSummary {
+    #[serde(rename = "groupConferenceId", default)]
+    pub group_id: Option<GroupId>,
+    #[serde(rename = "jvbConferenceId", default)]
+    pub call_id: Option<String>,
+    #[serde(rename = "jvbHost", default)]
+    pub backend_ip: Option<String>,
+    #[serde(rename = "region", default)]
+    pub backend_region: Option<String>,
+}
+
+impl CallRecordSummary {
+    /// Keeps only the given attributes of the call.
+    fn project(call: CallRecord, attributes: &[&str]) -> Self {
+        let wanted = |attribute| attributes.contains(&attribute);
+        Self {
+            group_id: wanted(GROUP_CONFERENCE_ID_STRING).then(|| call.group_id),
+            call_id: wanted("jvbConferenceId").then(|| call.call_id),
+            backend_ip: wanted("jvbHost").then(|| call.backend_ip),
+            backend_region: wanted("region").then(|| call.backend_region),
+        }
+    }
+}
+
+impl CallRecord {
+    /// Returns the metadata value for the key, if it is set.
+    pub fn get_metadata(&self, key: &str) -> Option<&str> {
+        self.metadata.get(key).map(String::as_str)
+    }
+
+    /// Sets the metadata value for the key, replacing any previous one. Fails with
+    /// MetadataTooLarge, leaving the metadata unchanged, if the keys and values would add
+    /// up to more than MAX_CALL_METADATA_BYTES.
+    pub fn set_metadata(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Result<(), StorageError> {
+        let (key, value) = (key.into(), value.into());
+        let replaced = self
+            .metadata
+            .get_key_value(&key)
+            .map_or(0, |(key, value)| key.len() + value.len());
+        let size = self.metadata_size() - replaced + key.len() + value.len();
+        if size > MAX_CALL_METADATA_BYTES {
+            return Err(StorageError::MetadataTooLarge {
+                size,
+                limit: MAX_CALL_METADATA_BYTES,
+            });
+        }
+        self.metadata.insert(key, value);
+        Ok(())
+    }
+
+    /// Returns the number of bytes that the keys and values of the metadata add up to.
+    pub fn metadata_size(&self) -> usize {
+        self.metadata
+            .iter()
+            .map(|(key, value)| key.len() + value.len())
+            .sum()
+    }
+
+    /// Sets the creation time of the record to now and its expiration accordingly, and
+    /// sets the first version. A new call counts as having just sent a heartbeat, and
+    /// hasn't reserved any capacity yet.
+    fn start_lifetime(&mut self, now: u64) {
+        self.created_at = Some(now);
+        self.expires_at = Some(now + CALL_RECORD_TTL.as_secs());
+        self.last_heartbeat_at = Some(now);
+        self.version = 1;
+        self.reserved_region = None;
+    }
+
+    /// Returns true if nothing has been heard of the call for longer than max_silence by
+    /// the given time, allowing for the clock of this host being up to skew_tolerance
+    /// ahead of the clocks that set the record's times. Records without a heartbeat are
+    /// judged by their creation time, and records with neither are never considered dead.
+    pub fn is_dead(&self, now: u64, max_silence: Duration, skew_tolerance: Duration) -> bool {
+        self.last_heartbeat_at
+            .or(self.created_at)
+            .map_or(false, |alive_at| {
+                let threshold = now.saturating_sub(max_silence.as_secs());
+                let dead = alive_at < threshold.saturating_sub(skew_tolerance.as_secs());
+                if !dead && alive_at < threshold {
+                    storage_log!(
+                        Level::Info,
+                        "call {:.6} is only considered alive due to clock skew tolerance",
+                        self.call_id
+                    );
+                }
+                dead
+            })
+    }
+
+    /// Returns the region that clients should be routed to for the call. Records without
+    /// a preferred region prefer the region that hosts them.
+    pub fn preferred_region(&self) -> &str {
+        self.preferred_region
+            .as_deref()
+            .unwrap_or(&self.backend_region)
+    }
+
+    /// Returns true if the record has expired by the given time, allowing for the clock
+    /// of this host being up to skew_tolerance ahead of the clock that set the expiration.
+    /// Records without an expiration never expire.
+    pub fn is_expired(&self, now: u64, skew_tolerance: Duration) -> bool {
+        self.expires_at.map_or(false, |expires_at| {
+            let expired = now >= expires_at.saturating_add(skew_tolerance.as_secs());
+            if !expired && now >= expires_at {
+                storage_log!(
+                    Level::Info,
+                    "call {:.6} is only considered unexpired due to clock skew tolerance",
+                    self.call_id
+                );
+            }
+            expired
+        })
+    }
+}
+
+/// The differences between the calls in storage for a region and the calls that were
+/// expected there, as found by reconcile_region.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RegionDiff {
+    /// Calls that are in storage but weren't expected, sorted like region queries.
+    pub orphaned: Vec<CallRecord>,
+    /// The (group_id, call_id) pairs that were expected but aren't in storage, sorted.
+    pub missing: Vec<(GroupId, String)>,
+}
+
+/// How a call was read, returned by get_call_record_with_meta.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ReadMeta {
+    /// Whether the read was strongly consistent and so saw every write that completed
+    /// before it. An eventually consistent read may miss a call that was just created.
+    pub consistent: bool,
+    /// Whether the read was served by the failover region because the primary region
+    /// failed. The failover region may not have every call of the primary.
+    pub failover: bool,
+}
+
+/// What get_or_add_call_record_expecting found in storage for the group of the call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum GetOrAddOutcome {
+    /// The call stored for the group has the given call_id, so it is the given call,
+    /// whether it was added now or by an earlier attempt to add the same call.
+    Matched(CallRecord),
+    /// A different call was already stored for the group and is returned. It must not be
+    /// treated as the given call.
+    Mismatched(CallRecord),
+    /// A call already stored for the group kept the given call from being added, but it
+    /// was gone by the time it was read.
+    Missing,
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum StorageError {
+    #[error("a call already exists for the group")]
+    CallAlreadyExists,
+    #[error("region {0} has no capacity for another call")]
+    RegionFull(String),
+    #[error("the call was changed by someone else")]
+    VersionConflict,
+    #[error("the call record is {size} bytes, more than the {limit} bytes allowed")]
+    ItemTooLarge { size: usize, limit: usize },
+    #[error("the call metadata is {size} bytes, more than the {limit} bytes allowed")]
+    MetadataTooLarge { size: usize, limit: usize },
+    #[error("the table doesn't have the expected schema: {}", .0.join("; "))]
+    SchemaMismatch(Vec<String>),
+    #[error("table {0} doesn't exist, check storage_table and the account and region of the credentials")]
+    TableNotFound(String),
+    #[error("storage is read-only")]
+    ReadOnly,
+    #[error("the deadline of the request passed before storage could answer")]
+    DeadlineExceeded,
+    #[error("storage is shutting down")]
+    ShuttingDown,
+    #[error("the {0} index is missing or still backfilling, so calls can't be queried by region until it is created and ACTIVE")]
+    RegionIndexUnavailable(String),
+    #[error("the storage request was throttled or failed transiently: {0:#}")]
+    Throttled(anyhow::Error),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
 
+impl StorageError {
+    /// A stable name for the kind of error, suitable for logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        StorageErrorKind::from(self).code()
+    }
+
+    /// Returns true if the operation may succeed if it is tried again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, StorageError::Throttled(_))
+    }
+}
+
+/// The classification of a StorageError that is safe to return to clients. It carries
+/// only a stable code, never the message or source of the error, which may include
+/// table names and other internal details. Serializes as `{"code":"<code>"}`.
+#[derive(Clone, Copy, Debug, Serialize, Eq, PartialEq)]
+#[serde(tag = "code", rename_all = "snake_case")]
+pub enum StorageErrorKind {
+    CallAlreadyExists,
+    RegionFull,
+    VersionConflict,
+    ItemTooLarge,
+    MetadataTooLarge,
+    SchemaMismatch,
+    TableNotFound,
+    ReadOnly,
+    DeadlineExceeded,
+    ShuttingDown,
+    RegionIndexUnavailable,
+    Throttled,
+    Unexpected,
+}
+
+impl StorageErrorKind {
+    /// The same code that the kind is serialized with.
+    pub fn code(&self) -> &'static str {
+        match self {
+            StorageErrorKind::CallAlreadyExists => "call_already_exists",
+            StorageErrorKind::RegionFull => "region_full",
+            StorageErrorKind::VersionConflict => "version_conflict",
+            StorageErrorKind::ItemTooLarge => "item_too_large",
+            StorageErrorKind::MetadataTooLarge => "metadata_too_large",
+            StorageErrorKind::SchemaMismatch => "schema_mismatch",
+            StorageErrorKind::TableNotFound => "table_not_found",
+            StorageErrorKind::ReadOnly => "read_only",
+            StorageErrorKind::DeadlineExceeded => "deadline_exceeded",
+            StorageErrorKind::ShuttingDown => "shutting_down",
+            StorageErrorKind::RegionIndexUnavailable => "region_index_unavailable",
+            StorageErrorKind::Throttled => "throttled",
+            StorageErrorKind::Unexpected => "unexpected",
+        }
+    }
+}
+
+impl From<&StorageError> for StorageErrorKind {
+    fn from(err: &StorageError) -> Self {
+        match err {
+            StorageError::CallAlreadyExists => StorageErrorKind::CallAlreadyExists,
+            StorageError::RegionFull(_) => StorageErrorKind::RegionFull,
+            StorageError::VersionConflict => StorageErrorKind::VersionConflict,
+            StorageError::ItemTooLarge { .. } => StorageErrorKind::ItemTooLarge,
+            StorageError::MetadataTooLarge { .. } => StorageErrorKind::MetadataTooLarge,
+            StorageError::SchemaMismatch(_) => StorageErrorKind::SchemaMismatch,
+            StorageError::TableNotFound(_) => StorageErrorKind::TableNotFound,
+            StorageError::ReadOnly => StorageErrorKind::ReadOnly,
+            StorageError::DeadlineExceeded => StorageErrorKind::DeadlineExceeded,
+            StorageError::ShuttingDown => StorageErrorKind::ShuttingDown,
+            StorageError::RegionIndexUnavailable(_) => StorageErrorKind::RegionIndexUnavailable,
+            StorageError::Throttled(_) => StorageErrorKind::Throttled,
+            StorageError::UnexpectedError(_) => StorageErrorKind::Unexpected,
+        }
+    }
+}
+
 #[cfg_attr(test, automock)]
 #[async_trait]
 pub trait Storage: Sync + Send {
     /// Gets an existing call from the table matching the given group_id or returns None.
+    /// Expired calls are treated as if they don't exist.
     async fn get_call_record(&self, group_id: &GroupId)
         -> Result<Option<CallRecord>, StorageError>;
+    /// Like get_call_record, but also tells how the call was read, so that callers can
+    /// tell a stale read apart from a missing call. Storage whose reads are always
+    /// consistent doesn't need to override this.
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        Ok((
+            self.get_call_record(group_id).await?,
+            ReadMeta {
+                consistent: true,
+                failover: false,
+            },
+        ))
+    }
     /// Adds the given call to the table but if there is already a call with the same
-    /// group_id, returns that instead.
+    /// group_id, returns that instead. An existing call that has expired is replaced
+    /// as if it didn't exist. The creation and expiration times of the given call are
+    /// set by the storage.
     async fn get_or_add_call_record(
         &self,
         call: CallRecord,
     ) -> Result<Option<CallRecord>, StorageError>;
+    /// Like get_or_add_call_record, but for callers that generate the call_id themselves:
+    /// tells an existing call with the same call_id, which is theirs, apart from a
+    /// different call. If expected_absent, finding a different call is unexpected, so it
+    /// is counted in a metric.
+    async fn get_or_add_call_record_expecting(
+        &self,
+        call: CallRecord,
+        expected_absent: bool,
+    ) -> Result<GetOrAddOutcome, StorageError> {
+        let call_id = call.call_id.clone();
+        Ok(match self.get_or_add_call_record(call).await? {
+            None => GetOrAddOutcome::Missing,
+            Some(existing) if existing.call_id == call_id => GetOrAddOutcome::Matched(existing),
+            Some(existing) => {
+                if expected_absent {
+                    storage_log!(
+                        Level::Warn,
+                        "get_or_add_call_record_expecting: found call {} of era {} in place of {}",
+                        existing.call_id,
+                        existing.era,
+                        call_id
+                    );
+                    event!("calling.frontend.storage.get_or_add_call_record.unexpected_call");
+                }
+                GetOrAddOutcome::Mismatched(existing)
+            }
+        })
+    }
     /// Removes the given call from the table as long as the call_id of the record that
-    /// exists in the table is the same.
+    /// exists in the table is the same. Returns the record that was removed, or None if
+    /// there was no record with the given call_id, either because the record doesn't
+    /// exist or because it belongs to a different call.
     async fn remove_call_record(
         &self,
         group_id: &GroupId,
         call_id: &str,
-    ) -> Result<(), StorageError>;
-    /// Returns a list of all calls in the table that are in the given region.
+    ) -> Result<Option<CallRecord>, StorageError>;
+    /// Starts removing the given call like remove_call_record but returns right away, so
+    /// that teardown paths don't wait on a slow storage. The removal is retried a few
+    /// times, and a call that still isn't removed is left for its TTL or reap_dead_calls.
+    /// The outcome is only reported in metrics, so paths that need to know whether the
+    /// call was removed must use remove_call_record instead.
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str);
+    /// Removes the given call for remove_call_record_best_effort, retrying a few times, and
+    /// reports the outcome in metrics. This is what the background task runs, on the
+    /// shared handle that the caller of remove_call_record_best_effort holds, so every
+    /// decorator sees the removal. By default each attempt goes through
+    /// remove_call_record, and decorators that see the removal as a whole forward it.
+    async fn remove_call_record_with_retries(&self, group_id: &GroupId, call_id: &str) {
+        let mut backoff = BEST_EFFORT_REMOVAL_INITIAL_BACKOFF;
+        for attempt in 1..=BEST_EFFORT_REMOVAL_MAX_ATTEMPTS {
+            match self.remove_call_record(group_id, call_id).await {
+                Ok(_) => {
+                    event!("calling.frontend.storage.remove_best_effort.success");
+                    return;
+                }
+                Err(err) if attempt < BEST_EFFORT_REMOVAL_MAX_ATTEMPTS => {
+                    storage_log!(
+                        Level::Debug,
+                        "retrying best-effort removal of call {:.6} after attempt {} failed: {}",
+                        call_id,
+                        attempt,
+                        err
+                    );
+                    tokio::time::sleep(backoff.into()).await;
+                    backoff = backoff * 2;
+                }
+                Err(err) => {
+                    storage_log!(
+                        Level::Warn,
+                        "gave up on removing call {:.6} of group {} after {} attempts: {}",
+                        call_id,
+                        group_id,
+                        attempt,
+                        err
+                    );
+                    event!("calling.frontend.storage.remove_best_effort.failure");
+                }
+            }
+        }
+    }
+    /// Removes the call of the given group from the table whatever its call_id, and
+    /// returns the record that was removed, if any. This is only for operator cleanup of
+    /// records whose call_id is unknown or corrupt: it may remove a call that was just
+    /// created in place of the one meant, so serving requests must use
+    /// remove_call_record instead.
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError>;
+    /// Returns a list of all calls in the table that are in the given region, sorted by
+    /// group_id and then call_id so that results can be compared.
     async fn get_call_records_for_region(
         &self,
         region: &str,
     ) -> Result<Vec<CallRecord>, StorageError>;
-}
-
-pub struct DynamoDb {
-    client: Client,
-    table_name: String,
-}
-
-impl DynamoDb {
-    pub async fn new(config: &'static config::Config) -> Result<(Self, IdentityFetcher)> {
-        let sleep_impl =
-            default_async_sleep().ok_or_else(|| anyhow!("failed to create sleep_impl"))?;
-
-        let identity_fetcher;
-
-        let client = match &config.storage_endpoint {
-            Some(endpoint) => {
-                const KEY: &str = "DUMMY_KEY";
-                const PASSWORD: &str = "DUMMY_PASSWORD";
-
-                info!("Using endpoint for DynamodDB testing: {}", endpoint);
+    /// Returns all calls in the table that are in the given region as a stream, fetching
+    /// them a page at a time so that memory use doesn't grow with the size of the region.
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>>;
+    /// Returns the number of calls hosted by each backend in the given region, keyed by
+    /// backend_ip. Backends without calls are not included.
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError>;
+    /// Returns the number of calls in the given region, for metrics. This is cheaper than
+    /// fetching the calls when only their number is needed.
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        Ok(self.count_calls_per_backend(region).await?.values().sum())
+    }
+    /// Returns the number of calls in the given region by the version of the backend they
+    /// were created on, such as to watch the load of a canary build. Calls without a
+    /// backend_version are counted under None.
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        let mut counts = HashMap::new();
+        let mut calls = self.stream_call_records_for_region(region);
+        while let Some(call) = calls.next().await {
+            *counts.entry(call?.backend_version).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+    /// Returns the number of calls in each region that has any, keyed by region, in a
+    /// single pass rather than with a count per region. Storage that can't do better
+    /// than reading every call doesn't need to override this.
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        let mut counts = HashMap::new();
+        let mut calls = self.export_all();
+        while let Some(call) = calls.next().await {
+            *counts.entry(call?.backend_region).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+    /// Returns whichever of the candidate backends, given by backend_ip, hosts the fewest
+    /// calls in the given region, or None if there are no candidates. Ties go to the
+    /// candidate that comes first, so in a region without calls that is the first one.
+    async fn least_loaded_backend(
+        &self,
+        region: &str,
+        candidate_ips: &[&str],
+    ) -> Result<Option<String>, StorageError> {
+        if candidate_ips.is_empty() {
+            return Ok(None);
+        }
 
-                // Create an identity fetcher with a dummy token path, which isn't used
-                // for testing.
-                identity_fetcher = IdentityFetcher::new(config, "/tmp/token");
+        let counts = self.count_calls_per_backend(region).await?;
+        Ok(candidate_ips
+            .iter()
+            .min_by_key(|ip| counts.get(**ip).copied().unwrap_or(0))
+            .map(|ip| ip.to_string()))
+    }
+    /// Like get_call_records_for_region, but only fetches the given attributes (such as
+    /// "groupConferenceId" and "jvbHost") of at most limit calls, in no particular order.
+    /// This is cheaper for callers that don't need whole records.
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        Ok(self
+            .get_call_records_for_region(region)
+            .await?
+            .into_iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|call| CallRecordSummary::project(call, attributes))
+            .collect())
+    }
+    /// Compares the calls in storage for the given region with the expected
+    /// (group_id, call_id) pairs, such as those reported by the backends of the region.
+    /// Like stream_call_records_for_region, this pages through the region without
+    /// holding all of its calls in memory, other than the orphans.
+    async fn reconcile_region(
+        &self,
+        region: &str,
+        expected: &HashSet<(GroupId, String)>,
+    ) -> Result<RegionDiff, StorageError> {
+        let mut remaining = expected.clone();
+        let mut orphaned = vec![];
 
-                let aws_config = Config::builder()
-                    .credentials_provider(Credentials::from_keys(KEY, PASSWORD, None))
-                    .endpoint_resolver(Endpoint::immutable(Uri::from_static(endpoint)))
-                    .sleep_impl(sleep_impl)
-                    .region(Region::new(&config.storage_region))
-                    .build();
-                Client::from_conf(aws_config)
+        let mut calls = self.stream_call_records_for_region(region);
+        while let Some(call) = calls.next().await {
+            let call = call?;
+            if !remaining.remove(&(call.group_id.clone(), call.call_id.clone())) {
+                orphaned.push(call);
             }
-            _ => {
-                info!(
-                    "Using region for DynamodDB access: {}",
-                    config.storage_region.as_str()
-                );
-
-                // Get the location of the identity token file from the environment variable,
-                // the same location that the client will try to get it from for credentials.
-                let identity_token_path = env::var("AWS_WEB_IDENTITY_TOKEN_FILE")?;
-                identity_fetcher = IdentityFetcher::new(config, &identity_token_path);
-
-                // Fetch an identity token once before connecting for the first time.
-                identity_fetcher.fetch_token().await?;
-
-                let retry_config = RetryConfigBuilder::new()
-                    .max_attempts(4)
-                    .initial_backoff(std::time::Duration::from_millis(100))
-                    .build();
+        }
 
-                let aws_config = aws_config::from_env()
-                    .sleep_impl(sleep_impl)
-                    .retry_config(retry_config)
-                    .region(Region::new(&config.storage_region))
-                    .load()
-                    .await;
+        sort_call_records(&mut orphaned);
+        let mut missing: Vec<_> = remaining.into_iter().collect();
+        missing.sort();
+        Ok(RegionDiff { orphaned, missing })
+    }
+    /// Like get_call_records_for_region, but because the region-index is only eventually
+    /// consistent, retries with backoff until the given call is among the results or
+    /// until the timeout passes. Returns the last results either way.
+    async fn get_call_records_for_region_with_retry(
+        &self,
+        region: &str,
+        group_id: &GroupId,
+        call_id: &str,
+        timeout: Duration,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        let deadline = tokio::time::Instant::now() + std::time::Duration::from(timeout);
+        let mut backoff = REGION_INDEX_INITIAL_BACKOFF;
 
-                Client::new(&aws_config)
+        loop {
+            let calls = self.get_call_records_for_region(region).await?;
+            if calls
+                .iter()
+                .any(|call| &call.group_id == group_id && call.call_id == call_id)
+            {
+                return Ok(calls);
             }
-        };
 
-        Ok((
-            Self {
-                client,
-                table_name: config.storage_table.to_string(),
-            },
-            identity_fetcher,
-        ))
+            let now = tokio::time::Instant::now();
+            if now >= deadline {
+                return Ok(calls);
+            }
+            tokio::time::sleep(std::cmp::min(backoff.into(), deadline - now)).await;
+            backoff = backoff * 2;
+        }
     }
+    /// Replaces the primary backend of the given call with the first of its backup
+    /// backends as long as the call_id of the record that exists in the table is the
+    /// same. Returns the updated call, or None if there was nothing to promote.
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError>;
+    /// Adds the given call to the table and reserves capacity for it in the call's
+    /// region in a single all-or-nothing operation. Fails with CallAlreadyExists if there
+    /// is already a call with the same group_id, or with RegionFull if the region already
+    /// has max_calls_per_region calls reserved. The reservation is released when the call
+    /// is removed, reaped or replaced after it expired.
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError>;
+    /// Returns every call in the table regardless of region, including expired calls that
+    /// haven't been deleted yet, for backups. For DynamoDB this is an eventually
+    /// consistent Scan that reads the whole table, so it is expensive in capacity units.
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>>;
+    /// Records that the backend of the given call reported it as alive just now, as long as
+    /// the call_id of the record that exists in the table is the same. Returns false if
+    /// there is no such call. Like every write, heartbeats increment the version of the call.
+    async fn heartbeat_call(&self, group_id: &GroupId, call_id: &str)
+        -> Result<bool, StorageError>;
+    /// Locks or unlocks the given call, as long as the call_id of the record that exists
+    /// in the table is the same, so that a lock never carries over to a later call for
+    /// the group. Locked calls only accept joins from their creator. Returns false if
+    /// there is no such call.
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError>;
+    /// Removes all calls that have had no heartbeat for longer than max_silence, so that
+    /// calls whose backend died are cleaned up, and returns their group_ids. With dry_run,
+    /// nothing is removed and the group_ids of the calls that would be are returned.
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError>;
+    /// Adds all of the given calls, overwriting any existing calls for the same group_id.
+    /// If several of the given calls have the same group_id, the last of them is added.
+    /// Unlike get_or_add_call_record this doesn't check for an existing call, so it is
+    /// only meant for bulk loads of trusted records such as test fixtures and imports,
+    /// not for creating calls on behalf of clients.
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError>;
+    /// Replaces the stored call with the given one as long as the stored call still has
+    /// the same call_id and version, and returns it with its version incremented. Fails
+    /// with VersionConflict if the call was changed or removed since it was read.
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError>;
+    /// Checks that the storage is reachable and ready to serve requests.
+    async fn health_check(&self) -> Result<(), StorageError>;
 }
 
+/// A shared handle to any Storage implementation.
+pub type DynStorage = Arc<dyn Storage>;
+
+/// Lets shared handles be used anywhere a Storage is expected.
 #[async_trait]
-impl Storage for DynamoDb {
+impl<S: Storage + ?Sized + 'static> Storage for Arc<S> {
     async fn get_call_record(
         &self,
         group_id: &GroupId,
     ) -> Result<Option<CallRecord>, StorageError> {
-        let response = self
-            .client
-            .get_item()
-            .table_name(&self.table_name)
-            .key(
-                GROUP_CONFERENCE_ID_STRING,
-                AttributeValue::S(group_id.as_ref().to_string()),
-            )
-            .consistent_read(true)
-            .send()
-            .await
-            .context("failed to get_item from storage")?;
+        (**self).get_call_record(group_id).await
+    }
 
-        Ok(response
-            .item
-            .map(|item| from_item(item).context("failed to convert item to CallRecord"))
-            .transpose()?)
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        (**self).get_call_record_with_meta(group_id).await
     }
 
     async fn get_or_add_call_record(
         &self,
         call: CallRecord,
     ) -> Result<Option<CallRecord>, StorageError> {
-        let response = self
-            .client
-            .put_item()
-            .table_name(&self.table_name)
-            .set_item(Some(
-                to_item(&call).context("failed to convert CallRecord to item")?,
-            ))
-            // Don't overwrite the item if it already exists.
-            .condition_expression("attribute_not_exists(groupConferenceId)".to_string())
-            .send()
-            .await;
+        (**self).get_or_add_call_record(call).await
+    }
 
-        match response {
-            Ok(_) => Ok(Some(call)),
-            Err(SdkError::ServiceError { err: e, raw: _ })
-                if e.is_conditional_check_failed_exception() =>
-            {
-                Ok(self
-                    .get_call_record(&call.group_id)
-                    .await
-                    .context("failed to get call from storage after conditional check failed")?)
-            }
-            Err(err) => Err(StorageError::UnexpectedError(
-                anyhow::Error::from(err)
-                    .context("failed to put_item to storage for get_or_add_call_record"),
-            )),
-        }
+    async fn get_or_add_call_record_expecting(
+        &self,
+        call: CallRecord,
+        expected_absent: bool,
+    ) -> Result<GetOrAddOutcome, StorageError> {
+        (**self)
+            .get_or_add_call_record_expecting(call, expected_absent)
+            .await
     }
 
     async fn remove_call_record(
         &self,
         group_id: &GroupId,
         call_id: &str,
-    ) -> Result<(), StorageError> {
-        let response = self
-            .client
-            .delete_item()
-            .table_name(&self.table_name)
-            // Delete the item for the given key.
-            .key(
-                GROUP_CONFERENCE_ID_STRING,
-                AttributeValue::S(group_id.as_ref().to_string()),
-            )
-            // But only if the given call_id matches the expected value, otherwise the
-            // previous call was removed and a new one created already.
-            .condition_expression("jvbConferenceId = :value".to_string())
-            .expression_attribute_values(
-                ":value".to_string(),
-                AttributeValue::S(call_id.to_string()),
-            )
-            .send()
-            .await;
+    ) -> Result<Option<CallRecord>, StorageError> {
+        (**self).remove_call_record(group_id, call_id).await
+    }
 
-        match response {
-            Ok(_) => Ok(()),
-            Err(SdkError::ServiceError { err: e, raw: _ })
-                if e.is_conditional_check_failed_exception() =>
-            {
-                Ok(())
-            }
-            Err(err) => Err(StorageError::UnexpectedError(err.into())),
-        }
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        // The removal runs on this handle rather than on the storage behind it, so that
+        // it goes through every decorator of the caller.
+        spawn_best_effort_removal(self.clone(), group_id, call_id)
     }
 
-    async fn get_call_records_for_region(
+    async fn remove_call_record_with_retries(&self, group_id: &GroupId, call_id: &str) {
+        (**self)
+            .remove_call_record_with_retries(group_id, call_id)
+            .await
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        (**self).force_remove_call_record(group_id).await
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        (**self).get_call_records_for_region(region).await
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        (**self).stream_call_records_for_region(region)
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        (**self).count_calls_per_backend(region).await
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        (**self).count_call_records_for_region(region).await
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        (**self).count_calls_by_backend_version(region).await
+    }
+
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        (**self).count_all_regions().await
+    }
+
+    async fn least_loaded_backend(
+        &self,
+        region: &str,
+        candidate_ips: &[&str],
+    ) -> Result<Option<String>, StorageError> {
+        (**self).least_loaded_backend(region, candidate_ips).await
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        (**self)
+            .get_call_records_for_region_projected(region, attributes, limit)
+            .await
+    }
+
+    async fn reconcile_region(
+        &self,
+        region: &str,
+        expected: &HashSet<(GroupId, String)>,
+    ) -> Result<RegionDiff, StorageError> {
+        (**self).reconcile_region(region, expected).await
+    }
+
+    async fn get_call_records_for_region_with_retry(
         &self,
         region: &str,
+        group_id: &GroupId,
+        call_id: &str,
+        timeout: Duration,
     ) -> Result<Vec<CallRecord>, StorageError> {
+        (**self)
+            .get_call_records_for_region_with_retry(region, group_id, call_id, timeout)
+            .await
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        (**self).promote_backup_backend(group_id, call_id).await
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        (**self)
+            .create_call_reserving_capacity(call, max_calls_per_region)
+            .await
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        (**self).export_all()
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        (**self).heartbeat_call(group_id, call_id).await
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        (**self)
+            .set_call_locked(group_id, call_id, locked, locked_by)
+            .await
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        (**self).reap_dead_calls(max_silence, dry_run).await
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        (**self).add_call_records(records).await
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        (**self).update_call_record(call).await
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        (**self).health_check().await
+    }
+}
+
+pub struct DynamoDb {
+    client: Client,
+    table_name: String,
+    /// Prepended to the partition key of every item, so that deployments sharing the
+    /// table don't see each other's calls. Empty if not configured.
+    key_prefix: String,
+    /// The AWS region of the table, used to tag metrics.
+    region: String,
+    clock: Arc<dyn Clock>,
+    /// How calls are laid out in the items of the table.
+    codec: Arc<dyn RecordCodec>,
+    /// The number of write shards that the calls of a region are spread across in the
+    /// region index, or 1 to use the unsharded region-index.
+    region_index_shards: u32,
+    /// How many segments full table scans are split into, and how many of those are
+    /// scanned at a time.
+    scan_segments: u32,
+    scan_concurrency: u32,
+    /// The most items that each page of a paginated query or scan reads, if limited.
+    /// Requests that are sent only once aren't limited, since they would stop after the
+    /// first page.
+    page_size: Option<i32>,
+    /// The largest item, in bytes, that is written when adding a call.
+    max_item_bytes: usize,
+    /// How far the clock of this host may be ahead of others before records they wrote
+    /// are wrongly treated as expired or dead.
+    clock_skew_tolerance: Duration,
+    /// Whether get_call_record uses strongly consistent reads.
+    consistent_reads: bool,
+    /// The longest random delay before reading the winning call after losing the race to
+    /// add a call.
+    conflict_read_jitter: Duration,
+    /// Picks the delays within conflict_read_jitter.
+    jitter_rng: Arc<Mutex<StdRng>>,
+    /// Whether get_call_records_for_region and stream_call_records_for_region fail on an
+    /// item that can't be converted to a CallRecord instead of skipping it.
+    strict_region_queries: bool,
+    /// Bounds the number of requests in flight at a time, if set. Shared by all storages
+    /// that share the connection.
+    request_permits: Option<Arc<Semaphore>>,
+    /// How long to wait for a request permit before failing with Throttled.
+    request_permit_timeout: Duration,
+    /// How long a single request may take before it is abandoned, if limited. Requests
+    /// are also abandoned at the deadline of the caller, if it set one.
+    operation_timeout: Option<Duration>,
+    /// Whether ensure_table_exists may create the table, which is only the case for a
+    /// storage_endpoint.
+    create_table_allowed: bool,
+}
+
+impl DynamoDb {
+    pub async fn new(
+        config: &'static config::Config,
+        clock: Arc<dyn Clock>,
+        codec: Arc<dyn RecordCodec>,
+    ) -> Result<(Self, IdentityFetcher)> {
+        config.validate_storage()?;
+
+        let sleep_impl =
+            default_async_sleep().ok_or_else(|| anyhow!("failed to create sleep_impl"))?;
+
+        let identity_fetcher;
+
+        let client = match &config.storage_endpoint {
+            Some(endpoint) => {
+                info!("Using endpoint for DynamodDB testing: {}", endpoint);
+
+                // Create an identity fetcher with a token path that isn't shared with
+                // other processes, the token itself isn't used for testing.
+                identity_fetcher = IdentityFetcher::new(config, test_identity_token_path(config));
+
+                let aws_config = test_endpoint_aws_config(config, endpoint)?
+                    .sleep_impl(sleep_impl)
+                    .build();
+                match insecure_connector(config) {
+                    Some(connector) => {
+                        warn!(
+                            "INSECURE: TLS certificates from storage endpoint {} are not verified, \
+                             never use storage_endpoint_allow_invalid_certs in production",
+                            endpoint
+                        );
+                        Client::from_conf_conn(aws_config, connector)
+                    }
+                    None => Client::from_conf(aws_config),
+                }
+            }
+            _ => {
+                info!(
+                    "Using region for DynamodDB access: {}",
+                    config.storage_region.as_str()
+                );
+
+                // Get the location of the identity token file from the environment variable,
+                // the same location that the client will try to get it from for credentials.
+                // With aws-imds there is no token file, the client gets its credentials from
+                // the instance metadata service.
+                let identity_token_path = match config.identity_source {
+                    config::IdentitySource::AwsImds => PathBuf::new(),
+                    _ => env::var("AWS_WEB_IDENTITY_TOKEN_FILE")?.into(),
+                };
+                identity_fetcher = IdentityFetcher::new(config, identity_token_path);
+
+                // Fetch an identity token once before connecting for the first time.
+                identity_fetcher.fetch_token().await?;
+
+                region_client(config, &config.storage_region).await?
+            }
+        };
+
+        Ok((
+            Self {
+                client,
+                table_name: config.storage_table.to_string(),
+                key_prefix: config.storage_key_prefix.clone().unwrap_or_default(),
+                region: config.storage_region.to_string(),
+                clock,
+                codec,
+                region_index_shards: config.storage_region_index_shards,
+                scan_segments: config.storage_scan_segments,
+                scan_concurrency: config.storage_scan_concurrency,
+                page_size: config
+                    .storage_query_page_size
+                    .map(|page_size| page_size.try_into().unwrap_or(i32::MAX)),
+                max_item_bytes: config.storage_max_item_bytes,
+                clock_skew_tolerance: Duration::from_secs(config.storage_clock_skew_tolerance_secs),
+                consistent_reads: !config.storage_eventually_consistent_reads,
+                conflict_read_jitter: Duration::from_millis(config.storage_conflict_read_jitter_ms),
+                jitter_rng: Arc::new(Mutex::new(StdRng::from_entropy())),
+                strict_region_queries: config.storage_strict_region_queries,
+                request_permits: config
+                    .storage_max_concurrent_requests
+                    .map(|max| Arc::new(Semaphore::new(max))),
+                request_permit_timeout: Duration::from_millis(
+                    config.storage_request_permit_timeout_ms,
+                ),
+                operation_timeout: config
+                    .storage_operation_timeout_ms
+                    .map(Duration::from_millis),
+                create_table_allowed: config.storage_create_table
+                    && config.storage_endpoint.is_some(),
+            },
+            identity_fetcher,
+        ))
+    }
+
+    /// Like export_all, but scans the table in the given number of segments, at most
+    /// `concurrency` of them at a time. This is faster for large tables at the cost of
+    /// using capacity units faster.
+    pub fn export_segments(
+        &self,
+        total_segments: u32,
+        concurrency: u32,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        // The stream outlives the borrow of self, so errors are logged without it.
+        let table_name = self.table_name.clone();
+        let codec = self.codec.clone();
+        let key_prefix = self.key_prefix.clone();
+        let counter_key_prefix = self.key_prefix.clone();
+
+        self.parallel_scan("export_all", total_segments, concurrency, |scan| {
+            self.prefixed_scan(scan, None)
+        })
+        .filter(move |item| {
+            // The region capacity and era counters aren't calls.
+            futures::future::ready(item.as_ref().map_or(true, |item| {
+                !item
+                    .get(GROUP_CONFERENCE_ID_STRING)
+                    .and_then(|value| value.as_s().ok())
+                    .and_then(|key| key.strip_prefix(counter_key_prefix.as_str()))
+                    .map_or(false, is_counter_key)
+            }))
+        })
+        .map(move |item| {
+            item.and_then(|item| {
+                decode_prefixed(&*codec, &key_prefix, item).map_err(|err| {
+                    let err = StorageError::from(err);
+                    error!(
+                        "{}",
+                        storage_error_log_record("export_all", &table_name, &err)
+                    );
+                    err
+                })
+            })
+        })
+        .boxed()
+    }
+
+    /// Removes every call created before the given time, in seconds since the Unix
+    /// epoch, regardless of whether it expired, and returns their group_ids. This is for
+    /// garbage collecting orphaned records, so it scans the whole table using the
+    /// configured segments. Calls that don't have a creation time are kept. With
+    /// dry_run, nothing is removed and the group_ids of the calls that would be are
+    /// returned.
+    pub async fn remove_call_records_created_before(
+        &self,
+        cutoff: u64,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        let cutoff = AttributeValue::N(cutoff.to_string());
+
+        let mut items = self.parallel_scan(
+            "remove_call_records_created_before",
+            self.scan_segments,
+            self.scan_concurrency,
+            |scan| {
+                self.prefixed_scan(scan, Some("createdAt < :cutoff"))
+                    .expression_attribute_values(":cutoff".to_string(), cutoff.clone())
+                    .projection_expression("groupConferenceId, jvbConferenceId".to_string())
+            },
+        );
+
+        let mut removed = vec![];
+        while let Some(item) = items.next().await {
+            let item = item?;
+            let (key, call_id) = match (
+                item.get(GROUP_CONFERENCE_ID_STRING)
+                    .and_then(|v| v.as_s().ok()),
+                item.get("jvbConferenceId").and_then(|v| v.as_s().ok()),
+            ) {
+                (Some(key), Some(call_id)) => (key.clone(), call_id.clone()),
+                _ => continue,
+            };
+            let group_id = self.group_id(&key);
+            if dry_run {
+                removed.push(group_id);
+                continue;
+            }
+
+            // Only remove the call that was scanned, in case it was replaced since.
+            if self
+                .delete_call_item(
+                    "remove_call_records_created_before",
+                    AttributeValue::S(key),
+                    Some("jvbConferenceId = :value AND createdAt < :cutoff"),
+                    HashMap::from([
+                        (":value".to_string(), AttributeValue::S(call_id)),
+                        (":cutoff".to_string(), cutoff.clone()),
+                    ]),
+                )
+                .await?
+                .is_some()
+            {
+                removed.push(group_id);
+            }
+        }
+
+        if dry_run {
+            event!(
+                "calling.frontend.storage.remove_call_records_created_before.would_remove",
+                removed.len()
+            );
+        } else if !removed.is_empty() {
+            event!(
+                "calling.frontend.storage.remove_call_records_created_before.removed",
+                removed.len()
+            );
+        }
+        Ok(removed)
+    }
+
+    /// Checks that the calls in the region index are indexed under the configured number
+    /// of write shards, since calls indexed under another number have shard keys that
+    /// region queries don't send. With backfill, mismatched calls are first moved to
+    /// their shard keys and the new number is recorded, otherwise a mismatch is an error.
+    /// Tables without a recorded number count as unsharded.
+    pub async fn check_region_index_shards(&self, backfill: bool) -> Result<(), StorageError> {
+        let recorded = self.recorded_region_index_shards().await?;
+        if recorded == self.region_index_shards {
+            return Ok(());
+        }
+
+        // Unsharded queries use the region itself, which every call has, so there is
+        // nothing to move.
+        if self.region_index_shards > 1 {
+            if !backfill {
+                return Err(StorageError::UnexpectedError(anyhow!(
+                    "the region index of {} has {} shards but storage_region_index_shards is {}, start with storage_backfill_region_shards to move the calls to the new shards",
+                    self.table_name,
+                    recorded,
+                    self.region_index_shards
+                )));
+            }
+            let moved = self.backfill_region_shards().await?;
+            storage_log!(
+                Level::Info,
+                "moved {} calls in {} to {} region index shards",
+                moved,
+                self.table_name,
+                self.region_index_shards
+            );
+        }
+        self.record_region_index_shards().await
+    }
+
+    async fn recorded_region_index_shards(&self) -> Result<u32, StorageError> {
+        let _permit = self.request_permit("check_region_index_shards").await?;
         let response = self
             .client
-            .query()
+            .get_item()
             .table_name(&self.table_name)
-            .index_name("region-index")
-            .key_condition_expression("#region = :value".to_string())
-            .expression_attribute_names("#region".to_string(), "region".to_string())
-            .expression_attribute_values(
-                ":value".to_string(),
-                AttributeValue::S(region.to_string()),
+            .key(
+                GROUP_CONFERENCE_ID_STRING,
+                self.key(REGION_INDEX_SHARDS_KEY),
             )
-            .consistent_read(false)
-            .select(Select::AllAttributes)
+            .consistent_read(true)
             .send()
             .await
-            .context("failed to query for calls in a region")?;
+            .map_err(|err| {
+                self.log_error(
+                    "check_region_index_shards",
+                    request_error(err, "failed to get_item from storage"),
+                )
+            })?;
 
-        if let Some(items) = response.items {
-            return Ok(items
-                .into_iter()
-                .map(|item| from_item(item).context("failed to convert item to CallRecord"))
-                .collect::<Result<_>>()?);
+        match response.item.as_ref().and_then(|item| item.get("shards")) {
+            Some(shards) => shards
+                .as_n()
+                .ok()
+                .and_then(|shards| shards.parse().ok())
+                .ok_or_else(|| {
+                    self.log_error(
+                        "check_region_index_shards",
+                        anyhow!("the recorded number of region index shards isn't a number").into(),
+                    )
+                }),
+            None => Ok(1),
         }
+    }
 
-        Ok(vec![])
+    async fn record_region_index_shards(&self) -> Result<(), StorageError> {
+        let _permit = self.request_permit("check_region_index_shards").await?;
+        self.client
+            .put_item()
+            .table_name(&self.table_name)
+            .item(
+                GROUP_CONFERENCE_ID_STRING,
+                self.key(REGION_INDEX_SHARDS_KEY),
+            )
+            .item(
+                "shards",
+                AttributeValue::N(self.region_index_shards.to_string()),
+            )
+            .send()
+            .await
+            .map_err(|err| {
+                self.log_error(
+                    "check_region_index_shards",
+                    request_error(err, "failed to put_item in storage"),
+                )
+            })?;
+        Ok(())
     }
-}
 
-/// Supports the DynamoDB storage implementation by periodically refreshing an identity
-/// token file at the location given by `identity_token_path`.
-pub struct IdentityFetcher {
-    client: hyper::Client<HttpConnector>,
-    fetch_interval: Duration,
-    identity_token_path: PathBuf,
-    identity_token_url: Option<String>,
-}
+    /// Moves every call whose region index shard key doesn't match the configured number
+    /// of shards to the key it should have, and returns how many were moved. Calls that
+    /// change in the meantime are written with the right key anyway, so they are skipped.
+    pub async fn backfill_region_shards(&self) -> Result<usize, StorageError> {
+        if self.region_index_shards <= 1 {
+            return Ok(0);
+        }
 
-impl IdentityFetcher {
-    fn new(config: &'static config::Config, identity_token_path: &str) -> Self {
-        IdentityFetcher {
-            client: hyper::client::Client::builder().build_http(),
-            fetch_interval: Duration::from_millis(config.identity_fetcher_interval_ms),
-            identity_token_path: PathBuf::from(identity_token_path),
-            identity_token_url: config.identity_token_url.to_owned(),
+        let mut items = self.parallel_scan(
+            "backfill_region_shards",
+            self.scan_segments,
+            self.scan_concurrency,
+            |scan| {
+                self.prefixed_scan(scan, Some("attribute_exists(#region)"))
+                    .expression_attribute_names("#region".to_string(), "region".to_string())
+                    .projection_expression(format!(
+                        "groupConferenceId, jvbConferenceId, #region, {}",
+                        REGION_SHARD_ATTRIBUTE
+                    ))
+            },
+        );
+
+        let mut moved = 0;
+        while let Some(item) = items.next().await {
+            let item = item?;
+            let (key, call_id, region) = match (
+                item.get(GROUP_CONFERENCE_ID_STRING)
+                    .and_then(|v| v.as_s().ok()),
+                item.get("jvbConferenceId").and_then(|v| v.as_s().ok()),
+                item.get("region").and_then(|v| v.as_s().ok()),
+            ) {
+                (Some(key), Some(call_id), Some(region)) => (key, call_id, region),
+                _ => continue,
+            };
+            let shard_key = region_shard_key(
+                region,
+                region_index_shard(&self.group_id(key), self.region_index_shards),
+            );
+            if item.get(REGION_SHARD_ATTRIBUTE).and_then(|v| v.as_s().ok()) == Some(&shard_key) {
+                continue;
+            }
+
+            let _permit = self.request_permit("backfill_region_shards").await?;
+            match self
+                .client
+                .update_item()
+                .table_name(&self.table_name)
+                .key(GROUP_CONFERENCE_ID_STRING, AttributeValue::S(key.clone()))
+                .update_expression(format!(
+                    "SET {} = :shard, {}",
+                    REGION_SHARD_ATTRIBUTE, INCREMENT_VERSION
+                ))
+                .condition_expression("jvbConferenceId = :call_id AND #region = :region")
+                .expression_attribute_names("#region", "region")
+                .expression_attribute_names("#version", "version")
+                .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+                .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+                .expression_attribute_values(":shard", AttributeValue::S(shard_key))
+                .expression_attribute_values(":call_id", AttributeValue::S(call_id.clone()))
+                .expression_attribute_values(":region", AttributeValue::S(region.clone()))
+                .send()
+                .await
+            {
+                Ok(_) => moved += 1,
+                Err(SdkError::ServiceError { err, raw: _ })
+                    if err.is_conditional_check_failed_exception() => {}
+                Err(err) => {
+                    return Err(self.log_error(
+                        "backfill_region_shards",
+                        request_error(err, "failed to update_item in storage"),
+                    ))
+                }
+            }
+        }
+
+        if moved > 0 {
+            event!(
+                "calling.frontend.storage.backfill_region_shards.moved",
+                moved
+            );
         }
+        Ok(moved)
     }
 
-    async fn fetch_token(&self) -> Result<()> {
-        if let Some(url) = &self.identity_token_url {
-            let request = Request::builder()
-                .method(Method::GET)
-                .uri(url)
-                .header("Metadata-Flavor", "Google")
-                .body(Body::empty())?;
-
-            debug!("Fetching identity token from {}", url);
-
-            let body = self.client.request(request).await?;
-            let body = hyper::body::to_bytes(body).await?;
-            let temp_name = self.identity_token_path.with_extension("bak");
-            let mut temp_file = tokio::fs::File::create(&temp_name).await?;
-            temp_file.write_all(&body).await?;
-            tokio::fs::rename(temp_name, &self.identity_token_path).await?;
-
-            debug!(
-                "Successfully wrote identity token to {:?}",
-                &self.identity_token_path
+    /// Scans the table in the given number of segments, configuring the request for each
+    /// with `configure`. DynamoDB assigns every item to exactly one segment, so no item
+    /// is returned twice. The segments are dealt out to `concurrency` workers that each
+    /// scan their segments one after the other, which bounds how many are in flight.
+    fn parallel_scan(
+        &self,
+        operation: &'static str,
+        total_segments: u32,
+        concurrency: u32,
+        configure: impl Fn(fluent_builders::Scan) -> fluent_builders::Scan,
+    ) -> BoxStream<'static, Result<HashMap<String, AttributeValue>, StorageError>> {
+        let total_segments = total_segments.max(1);
+        let concurrency = concurrency.clamp(1, total_segments);
+
+        // The stream outlives the borrow of self, so errors are logged without it.
+        let table_name = Arc::new(self.table_name.clone());
+
+        // Pages aren't sent until they are polled, so creating the streams of all of the
+        // segments up front doesn't start the scans.
+        let mut workers: Vec<Vec<_>> = (0..concurrency).map(|_| vec![]).collect();
+        for segment in 0..total_segments {
+            let request = configure(
+                self.client
+                    .scan()
+                    .table_name(&self.table_name)
+                    .set_limit(self.page_size),
             );
+            let request = if total_segments > 1 {
+                request
+                    .segment(segment as i32)
+                    .total_segments(total_segments as i32)
+            } else {
+                request
+            };
+            let table_name = table_name.clone();
+            let pages = paged(
+                self.request_permits(operation),
+                move |start_key| request.clone().set_exclusive_start_key(start_key).send(),
+                ScanOutput::last_evaluated_key,
+                move |err| {
+                    let err = request_error(err, "failed to scan the table");
+                    error!("{}", storage_error_log_record(operation, &table_name, &err));
+                    err
+                },
+            );
+            workers[(segment % concurrency) as usize].push(page_items(pages, |page| page.items));
         }
-        Ok(())
+
+        futures::stream::select_all(
+            workers
+                .into_iter()
+                .map(|segments| futures::stream::iter(segments).flatten()),
+        )
+        .boxed()
     }
 
-    pub async fn start(self, ender_rx: Receiver<()>) -> Result<()> {
-        // Periodically fetch a new web identity from GCP.
-        let fetcher_handle = tokio::spawn(async move {
-            loop {
-                // Use sleep() instead of interval() so that we never wait *less* than one
-                // interval to do the next tick.
-                tokio::time::sleep(self.fetch_interval.into()).await;
+    /// Returns a storage for the same table in another AWS region, such as the failover
+    /// region of the storage_region. It uses the identity token that the fetcher of this
+    /// storage keeps fresh.
+    pub async fn for_region(&self, config: &config::Config, region: &str) -> Result<Self> {
+        info!("Using failover region for DynamodDB access: {}", region);
 
-                let timer = start_timer_us!("calling.frontend.identity_fetcher.timed");
+        Ok(Self {
+            client: region_client(config, region).await?,
+            region: region.to_string(),
+            ..self.for_table(self.table_name.clone())
+        })
+    }
 
-                let result = &self.fetch_token().await;
-                if let Err(e) = result {
-                    event!("calling.frontend.identity_fetcher.error");
-                    error!("Failed to fetch identity token : {:?}", e);
-                }
-                timer.stop();
+    /// Returns a storage for another table that shares the connection of this one.
+    pub fn for_table(&self, table_name: String) -> Self {
+        Self {
+            client: self.client.clone(),
+            table_name,
+            key_prefix: self.key_prefix.clone(),
+            region: self.region.clone(),
+            clock: self.clock.clone(),
+            codec: self.codec.clone(),
+            region_index_shards: self.region_index_shards,
+            scan_segments: self.scan_segments,
+            scan_concurrency: self.scan_concurrency,
+            page_size: self.page_size,
+            max_item_bytes: self.max_item_bytes,
+            clock_skew_tolerance: self.clock_skew_tolerance,
+            consistent_reads: self.consistent_reads,
+            conflict_read_jitter: self.conflict_read_jitter,
+            jitter_rng: self.jitter_rng.clone(),
+            strict_region_queries: self.strict_region_queries,
+            request_permits: self.request_permits.clone(),
+            request_permit_timeout: self.request_permit_timeout,
+            operation_timeout: self.operation_timeout,
+            create_table_allowed: self.create_table_allowed,
+        }
+    }
+
+    /// Moves a call to the shard key of its current region in the sharded region index,
+    /// after its region changed in place. The change itself already happened, so a
+    /// failure is logged rather than returned and the call stays findable through
+    /// get_call_record until it is next written. The version of the call is incremented
+    /// along with the stored one.
+    async fn update_region_shard(&self, call: &mut CallRecord) {
+        if self.region_index_shards <= 1 {
+            return;
+        }
+
+        let _permit = match self.request_permit("update_region_shard").await {
+            Ok(permit) => permit,
+            Err(_) => {
+                event!("calling.frontend.storage.update_region_shard.error");
+                return;
             }
-        });
+        };
+        match self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(GROUP_CONFERENCE_ID_STRING, self.key(call.group_id.as_ref()))
+            .update_expression(format!(
+                "SET {} = :shard, {}",
+                REGION_SHARD_ATTRIBUTE, INCREMENT_VERSION
+            ))
+            .condition_expression("jvbConferenceId = :value".to_string())
+            .expression_attribute_names("#version", "version")
+            .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .expression_attribute_values(
+                ":shard".to_string(),
+                AttributeValue::S(region_shard_key(
+                    &call.backend_region,
+                    region_index_shard(&call.group_id, self.region_index_shards),
+                )),
+            )
+            .expression_attribute_values(
+                ":value".to_string(),
+                AttributeValue::S(call.call_id.clone()),
+            )
+            .send()
+            .await
+        {
+            Ok(_) => call.version += 1,
+            Err(err) => {
+                event!("calling.frontend.storage.update_region_shard.error");
+                self.log_error(
+                    "update_region_shard",
+                    request_error(err, "failed to update the region shard"),
+                );
+            }
+        }
+    }
 
-        info!("fetcher ready");
+    /// Gives a call that was just created the next era of its group, which is counted by
+    /// a separate item per group that outlives its calls. The call already exists by now,
+    /// so failing to record the era is logged and leaves the era at 0 rather than failing
+    /// the creation.
+    async fn start_era(&self, call: &mut CallRecord) {
+        match self.record_next_era(call).await {
+            Ok(era) => {
+                call.era = era;
+                call.version += 1;
+            }
+            Err(err) => {
+                event!("calling.frontend.storage.start_era.error");
+                self.log_error("start_era", err);
+            }
+        }
+    }
 
-        // Wait for any task to complete and cancel the rest.
-        tokio::select!(
-            _ = fetcher_handle => {},
-            _ = ender_rx => {},
-        );
+    async fn record_next_era(&self, call: &CallRecord) -> Result<u64, StorageError> {
+        let permit = self.request_permit("start_era").await?;
+        let response = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(
+                GROUP_CONFERENCE_ID_STRING,
+                self.key(&format!(
+                    "{}{}",
+                    CALL_ERA_KEY_PREFIX,
+                    call.group_id.as_ref()
+                )),
+            )
+            // The counter expires long after the last call of the group, like calls do.
+            .update_expression("SET expiresAt = :expires_at ADD era :one")
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .expression_attribute_values(
+                ":expires_at",
+                AttributeValue::N((self.clock.now_secs() + CALL_ERA_TTL.as_secs()).to_string()),
+            )
+            .return_values(ReturnValue::UpdatedNew)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .map_err(|err| request_error(err, "failed to update_item in storage for start_era"))?;
+        drop(permit);
+        self.report_consumed_capacity("start_era", response.consumed_capacity());
 
-        info!("fetcher shutdown");
-        Ok(())
+        let era = response
+            .attributes()
+            .and_then(|attributes| attributes.get("era"))
+            .and_then(|era| era.as_n().ok())
+            .and_then(|era| era.parse::<u64>().ok())
+            .ok_or_else(|| anyhow!("the era counter didn't return the new era"))?;
+
+        let _permit = self.request_permit("start_era").await?;
+        let response = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(GROUP_CONFERENCE_ID_STRING, self.key(call.group_id.as_ref()))
+            .update_expression(format!("SET era = :era, {}", INCREMENT_VERSION))
+            .condition_expression("jvbConferenceId = :call_id")
+            .expression_attribute_names("#version", "version")
+            .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .expression_attribute_values(":era", AttributeValue::N(era.to_string()))
+            .expression_attribute_values(":call_id", AttributeValue::S(call.call_id.clone()))
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send()
+            .await
+            .map_err(|err| request_error(err, "failed to update_item in storage for start_era"))?;
+        self.report_consumed_capacity("start_era", response.consumed_capacity());
+
+        Ok(era)
+    }
+
+    /// Converts the call to an item with the codec, prefixing its key and adding the key
+    /// for the sharded region index if it is in use.
+    fn call_item(&self, call: &CallRecord) -> Result<HashMap<String, AttributeValue>> {
+        let mut item = self.codec.encode(call)?;
+        item.insert(
+            GROUP_CONFERENCE_ID_STRING.to_string(),
+            self.key(call.group_id.as_ref()),
+        );
+        if self.region_index_shards > 1 {
+            item.insert(
+                REGION_SHARD_ATTRIBUTE.to_string(),
+                AttributeValue::S(region_shard_key(
+                    &call.backend_region,
+                    region_index_shard(&call.group_id, self.region_index_shards),
+                )),
+            );
+        }
+        Ok(item)
+    }
+
+    /// Like call_item, for a call that is about to be written. Records that grew too large
+    /// are caught here, with a clear error, rather than from DynamoDB rejecting the write.
+    /// So is metadata that grew past MAX_CALL_METADATA_BYTES without set_metadata.
+    fn call_item_to_write(
+        &self,
+        operation: &'static str,
+        call: &CallRecord,
+    ) -> Result<HashMap<String, AttributeValue>, StorageError> {
+        let metadata_size = call.metadata_size();
+        if metadata_size > MAX_CALL_METADATA_BYTES {
+            return Err(self.log_error(
+                operation,
+                StorageError::MetadataTooLarge {
+                    size: metadata_size,
+                    limit: MAX_CALL_METADATA_BYTES,
+                },
+            ));
+        }
+
+        let item = self
+            .call_item(call)
+            .map_err(|err| self.log_error(operation, err.into()))?;
+
+        let size = item_size(&item);
+        sampling_histogram!("calling.frontend.storage.item_size_bytes", || size);
+        if size > self.max_item_bytes {
+            return Err(self.log_error(
+                operation,
+                StorageError::ItemTooLarge {
+                    size,
+                    limit: self.max_item_bytes,
+                },
+            ));
+        }
+        Ok(item)
+    }
+
+    /// Returns the partition key of the item with the given key, such as a group_id, with
+    /// the key_prefix prepended.
+    fn key(&self, key: &str) -> AttributeValue {
+        AttributeValue::S(format!("{}{}", self.key_prefix, key))
+    }
+
+    /// Returns the group_id of a partition key of this storage, without the key_prefix.
+    fn group_id(&self, key: &str) -> GroupId {
+        key.strip_prefix(self.key_prefix.as_str())
+            .unwrap_or(key)
+            .into()
+    }
+
+    /// Converts an item back to a call with the codec, after removing the key_prefix from
+    /// its key.
+    fn decode(&self, item: HashMap<String, AttributeValue>) -> Result<CallRecord> {
+        decode_prefixed(&*self.codec, &self.key_prefix, item)
+    }
+
+    /// Restricts a scan to the items with the key_prefix, in addition to the given
+    /// filter. Tables without a key_prefix are scanned in full.
+    fn prefixed_scan(
+        &self,
+        scan: fluent_builders::Scan,
+        filter: Option<&str>,
+    ) -> fluent_builders::Scan {
+        match (self.key_prefix.is_empty(), filter) {
+            (true, None) => scan,
+            (true, Some(filter)) => scan.filter_expression(filter.to_string()),
+            (false, filter) => scan
+                .filter_expression(match filter {
+                    Some(filter) => format!("({}) AND {}", filter, KEY_PREFIX_CONDITION),
+                    None => KEY_PREFIX_CONDITION.to_string(),
+                })
+                .expression_attribute_values(
+                    ":key_prefix".to_string(),
+                    AttributeValue::S(self.key_prefix.clone()),
+                ),
+        }
+    }
+
+    /// The name of the GSI that region queries are sent to.
+    fn region_index_name(&self) -> &'static str {
+        if self.region_index_shards <= 1 {
+            REGION_INDEX_NAME
+        } else {
+            REGION_SHARD_INDEX_NAME
+        }
+    }
+
+    /// Converts the error of a region query. A region index that is missing or still
+    /// backfilling is a provisioning problem rather than a storage failure, so it gets its
+    /// own error and metric to make it obvious.
+    fn region_query_error(
+        &self,
+        operation: &'static str,
+        err: SdkError<QueryError>,
+        context: &'static str,
+    ) -> StorageError {
+        if is_index_unavailable(&err) {
+            tagged_event!(
+                "calling.frontend.storage.region_index_unavailable",
+                self.metric_tags(operation, None)
+            );
+            return self.log_error(
+                operation,
+                StorageError::RegionIndexUnavailable(self.region_index_name().to_string()),
+            );
+        }
+        self.log_error(operation, request_error(err, context))
+    }
+
+    /// Returns the queries that together find every call in the region, one per write
+    /// shard of the region index. Callers add what to select and merge the results.
+    ///
+    /// With a key_prefix, the calls of other prefixes are filtered out. DynamoDB applies
+    /// a limit before the filter, so a limited query may then return fewer calls.
+    fn region_queries(&self, region: &str) -> Vec<fluent_builders::Query> {
+        self.unprefixed_region_queries(region)
+            .into_iter()
+            .map(|query| {
+                if self.key_prefix.is_empty() {
+                    query
+                } else {
+                    query
+                        .filter_expression(KEY_PREFIX_CONDITION.to_string())
+                        .expression_attribute_values(
+                            ":key_prefix".to_string(),
+                            AttributeValue::S(self.key_prefix.clone()),
+                        )
+                }
+            })
+            .collect()
+    }
+
+    fn unprefixed_region_queries(&self, region: &str) -> Vec<fluent_builders::Query> {
+        if self.region_index_shards <= 1 {
+            return vec![self
+                .client
+                .query()
+                .table_name(&self.table_name)
+                .index_name(REGION_INDEX_NAME)
+                .key_condition_expression("#region = :value".to_string())
+                .expression_attribute_names("#region".to_string(), "region".to_string())
+                .expression_attribute_values(
+                    ":value".to_string(),
+                    AttributeValue::S(region.to_string()),
+                )
+                .consistent_read(false)];
+        }
+
+        (0..self.region_index_shards)
+            .map(|shard| {
+                self.client
+                    .query()
+                    .table_name(&self.table_name)
+                    .index_name(REGION_SHARD_INDEX_NAME)
+                    .key_condition_expression(format!("{} = :value", REGION_SHARD_ATTRIBUTE))
+                    .expression_attribute_values(
+                        ":value".to_string(),
+                        AttributeValue::S(region_shard_key(region, shard)),
+                    )
+                    .consistent_read(false)
+            })
+            .collect()
+    }
+
+    /// Issues a cheap request against the table so that the connection to DynamoDB is
+    /// established before the first call is handled. This is best-effort, failures are
+    /// logged and otherwise ignored.
+    pub async fn warm_up(&self) {
+        let timer = start_timer_us!("calling.frontend.storage.warm_up.timed");
+
+        match self
+            .client
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await
+        {
+            Ok(_) => storage_log!(Level::Info, "storage connection warmed up"),
+            Err(err) => {
+                event!("calling.frontend.storage.warm_up.error");
+                storage_log!(Level::Warn, "failed to warm up storage connection: {}", err);
+            }
+        }
+
+        timer.stop();
+    }
+
+    /// Checks that the table exists, so that a storage_table with a typo, or in another
+    /// account or region, fails startup with TableNotFound rather than failing every call
+    /// later.
+    pub async fn check_table_exists(&self) -> Result<(), StorageError> {
+        self.client
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await
+            .map_err(|err| self.describe_table_error(err))?;
+        Ok(())
+    }
+
+    /// Converts a failed DescribeTable, telling a table that doesn't exist apart from
+    /// other failures.
+    fn describe_table_error(&self, err: SdkError<DescribeTableError>) -> StorageError {
+        match &err {
+            SdkError::ServiceError { err, .. } if err.is_resource_not_found_exception() => {
+                StorageError::TableNotFound(self.table_name.clone())
+            }
+            _ => request_error(err, "failed to describe the table"),
+        }
+    }
+
+    /// Creates the table with its key, both region indexes and the TTL attribute if it
+    /// doesn't exist yet, and waits for it to become active. This is for local and CI
+    /// environments, so it is refused unless storage_create_table is set along with a
+    /// storage_endpoint, and never runs against real AWS.
+    pub async fn ensure_table_exists(&self) -> Result<()> {
+        if !self.create_table_allowed {
+            return Err(anyhow!(
+                "tables are only created with storage_create_table and a storage_endpoint"
+            ));
+        }
+
+        match self
+            .client
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(SdkError::ServiceError { err, raw: _ })
+                if err.is_resource_not_found_exception() => {}
+            Err(err) => return Err(sdk_error(err).context("failed to describe the table")),
+        }
+
+        storage_log!(Level::Info, "creating table {}", self.table_name);
+        let string_attribute = |name: &str| {
+            AttributeDefinition::builder()
+                .attribute_name(name)
+                .attribute_type(ScalarAttributeType::S)
+                .build()
+        };
+        let hash_key = |name: &str| {
+            KeySchemaElement::builder()
+                .attribute_name(name)
+                .key_type(KeyType::Hash)
+                .build()
+        };
+        let index = |index_name: &str, key: &str| {
+            GlobalSecondaryIndex::builder()
+                .index_name(index_name)
+                .key_schema(hash_key(key))
+                .projection(
+                    Projection::builder()
+                        .projection_type(ProjectionType::All)
+                        .build(),
+                )
+                .build()
+        };
+
+        match self
+            .client
+            .create_table()
+            .table_name(&self.table_name)
+            .attribute_definitions(string_attribute(GROUP_CONFERENCE_ID_STRING))
+            .attribute_definitions(string_attribute("region"))
+            .attribute_definitions(string_attribute(REGION_SHARD_ATTRIBUTE))
+            .key_schema(hash_key(GROUP_CONFERENCE_ID_STRING))
+            .global_secondary_indexes(index(REGION_INDEX_NAME, "region"))
+            .global_secondary_indexes(index(REGION_SHARD_INDEX_NAME, REGION_SHARD_ATTRIBUTE))
+            .billing_mode(BillingMode::PayPerRequest)
+            .send()
+            .await
+        {
+            Ok(_) => {}
+            // Another instance created it in the meantime.
+            Err(SdkError::ServiceError { err, raw: _ }) if err.is_resource_in_use_exception() => {}
+            Err(err) => return Err(sdk_error(err).context("failed to create the table")),
+        }
+
+        for _ in 0..50 {
+            let response = self
+                .client
+                .describe_table()
+                .table_name(&self.table_name)
+                .send()
+                .await
+                .map_err(sdk_error)
+                .context("failed to describe the table")?;
+            if response.table().and_then(|table| table.table_status()) == Some(&TableStatus::Active)
+            {
+                self.client
+                    .update_time_to_live()
+                    .table_name(&self.table_name)
+                    .time_to_live_specification(
+                        TimeToLiveSpecification::builder()
+                            .attribute_name("expiresAt")
+                            .enabled(true)
+                            .build(),
+                    )
+                    .send()
+                    .await
+                    .map_err(sdk_error)
+                    .context("failed to enable TTL on the table")?;
+                storage_log!(Level::Info, "created table {}", self.table_name);
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(100).into()).await;
+        }
+        Err(anyhow!("table {} didn't become active", self.table_name))
+    }
+
+    /// Checks that the table has the key, region indexes and TTL that storage relies on,
+    /// so that a table that was set up wrong is reported at startup rather than through
+    /// confusing failures later. Fails with SchemaMismatch listing every difference.
+    pub async fn verify_schema(&self) -> Result<(), StorageError> {
+        let table = self
+            .client
+            .describe_table()
+            .table_name(&self.table_name)
+            .send()
+            .await
+            .map_err(|err| self.describe_table_error(err))?;
+        let ttl = self
+            .client
+            .describe_time_to_live()
+            .table_name(&self.table_name)
+            .send()
+            .await
+            .map_err(sdk_error)
+            .context("failed to describe the TTL of the table")?;
+
+        let mut mismatches = vec![];
+        let table = table.table();
+        match hash_key(table.and_then(|table| table.key_schema())) {
+            Some(GROUP_CONFERENCE_ID_STRING) => {}
+            Some(key) => mismatches.push(format!(
+                "the partition key is {:?} rather than {:?}",
+                key, GROUP_CONFERENCE_ID_STRING
+            )),
+            None => mismatches.push("the table has no partition key".to_string()),
+        }
+
+        let mut indexes = vec![(REGION_INDEX_NAME, "region")];
+        if self.region_index_shards > 1 {
+            indexes.push((REGION_SHARD_INDEX_NAME, REGION_SHARD_ATTRIBUTE));
+        }
+        let table_indexes = table
+            .and_then(|table| table.global_secondary_indexes())
+            .unwrap_or_default();
+        for (index_name, expected_key) in indexes {
+            match table_indexes
+                .iter()
+                .find(|index| index.index_name() == Some(index_name))
+            {
+                None => mismatches.push(format!("the {} index is missing", index_name)),
+                Some(index) => match hash_key(index.key_schema()) {
+                    Some(key) if key == expected_key => {}
+                    Some(key) => mismatches.push(format!(
+                        "the partition key of the {} index is {:?} rather than {:?}",
+                        index_name, key, expected_key
+                    )),
+                    None => {
+                        mismatches.push(format!("the {} index has no partition key", index_name))
+                    }
+                },
+            }
+        }
+
+        // Expired calls are ignored on read, but without TTL they are never deleted.
+        let ttl = ttl.time_to_live_description();
+        let ttl_enabled = matches!(
+            ttl.and_then(|ttl| ttl.time_to_live_status()),
+            Some(TimeToLiveStatus::Enabled | TimeToLiveStatus::Enabling)
+        );
+        match ttl.and_then(|ttl| ttl.attribute_name()) {
+            Some("expiresAt") if ttl_enabled => {}
+            Some(attribute) if ttl_enabled => mismatches.push(format!(
+                "TTL is enabled on {:?} rather than \"expiresAt\"",
+                attribute
+            )),
+            _ => mismatches.push("TTL isn't enabled on \"expiresAt\"".to_string()),
+        }
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(self.log_error("verify_schema", StorageError::SchemaMismatch(mismatches)))
+        }
+    }
+
+    /// Returns the tags for metrics about the given operation. The tag for the AWS region
+    /// is "storage_region" since "region" is already used for the frontend's own region.
+    fn metric_tags(&self, operation: &str, backend_region: Option<&str>) -> Vec<String> {
+        let mut tags = vec![
+            format!("storage_region:{}", self.region),
+            format!("operation:{}", operation),
+        ];
+        if let Some(backend_region) = backend_region {
+            tags.push(format!("backend_region:{}", backend_region));
+        }
+        tags
+    }
+
+    /// Reports the write capacity consumed by a mutating operation, rounded up to whole
+    /// units, so that we can alert before DynamoDB starts throttling.
+    fn report_consumed_capacity<'a>(
+        &self,
+        operation: &str,
+        capacities: impl IntoIterator<Item = &'a ConsumedCapacity>,
+    ) {
+        let units: f64 = capacities
+            .into_iter()
+            .filter_map(|capacity| capacity.capacity_units())
+            .sum();
+        tagged_event!(
+            "calling.frontend.storage.consumed_wcu",
+            self.metric_tags(operation, None),
+            units.ceil() as usize
+        );
+    }
+
+    /// Waits for a permit to send a request, if the number of requests in flight is
+    /// bounded. Callers hold the permit until the response arrives.
+    async fn request_permit(
+        &self,
+        operation: &'static str,
+    ) -> Result<Option<OwnedSemaphorePermit>, StorageError> {
+        self.request_permits(operation).acquire().await
+    }
+
+    /// Returns what waiting for request permits for the given operation needs, for
+    /// streams that send requests after the borrow of self ends.
+    fn request_permits(&self, operation: &'static str) -> RequestPermits {
+        RequestPermits {
+            permits: self.request_permits.clone(),
+            timeout: self.request_permit_timeout,
+            operation,
+            table_name: self.table_name.clone(),
+            metric_tags: self.metric_tags(operation, None),
+        }
+    }
+
+    /// Runs the request, abandoning it once the operation timeout or the deadline of the
+    /// caller passes, whichever comes first. If the deadline already passed, the request
+    /// isn't even started and DeadlineExceeded is returned, since there would be no one
+    /// to answer.
+    async fn within_deadline<F: std::future::Future>(
+        &self,
+        operation: &str,
+        request: F,
+    ) -> Result<F::Output, StorageError> {
+        let now = tokio::time::Instant::now();
+        let timeout_at = self
+            .operation_timeout
+            .map(|timeout| now + std::time::Duration::from(timeout));
+        let deadline = current_deadline();
+
+        let limit = match (deadline, timeout_at) {
+            (Some(deadline), _) if deadline <= now => {
+                return Err(self.log_error(operation, StorageError::DeadlineExceeded));
+            }
+            (Some(deadline), Some(timeout_at)) => std::cmp::min(deadline, timeout_at),
+            (Some(limit), None) | (None, Some(limit)) => limit,
+            (None, None) => return Ok(request.await),
+        };
+
+        match tokio::time::timeout_at(limit, request).await {
+            Ok(output) => Ok(output),
+            Err(_) if deadline == Some(limit) => {
+                Err(self.log_error(operation, StorageError::DeadlineExceeded))
+            }
+            Err(_) => Err(self.log_error(
+                operation,
+                StorageError::Throttled(anyhow!("timed out waiting for storage to respond")),
+            )),
+        }
+    }
+
+    /// Logs the error as a structured record for the given operation and returns it.
+    fn log_error(&self, operation: &str, err: StorageError) -> StorageError {
+        error!(
+            "{}",
+            storage_error_log_record(operation, &self.table_name, &err)
+        );
+        err
+    }
+
+    /// Returns a random delay of up to conflict_read_jitter.
+    fn conflict_read_delay(&self) -> Duration {
+        let max = self.conflict_read_jitter.as_millis() as u64;
+        if max == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(self.jitter_rng.lock().gen_range(0..=max))
+    }
+
+    /// Gets the call for the given group_id, if it hasn't expired, with a strongly or
+    /// eventually consistent read.
+    async fn read_call_record(
+        &self,
+        group_id: &GroupId,
+        consistent_read: bool,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let _permit = self.request_permit("get_call_record").await?;
+        let request = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key(GROUP_CONFERENCE_ID_STRING, self.key(group_id.as_ref()))
+            .consistent_read(consistent_read)
+            .send();
+        let response = self
+            .within_deadline("get_call_record", request)
+            .await?
+            .map_err(|err| {
+                self.log_error(
+                    "get_call_record",
+                    request_error(err, "failed to get_item from storage"),
+                )
+            })?;
+
+        let call: Option<CallRecord> = response
+            .item
+            .map(|item| self.decode(item))
+            .transpose()
+            .map_err(|err| self.log_error("get_call_record", err.into()))?;
+
+        Ok(call.filter(|call| !call.is_expired(self.clock.now_secs(), self.clock_skew_tolerance)))
+    }
+
+    /// Deletes the item with the given key if it meets the condition, if any, whose
+    /// values are bound by `values`, and returns the call that it held. A call that
+    /// reserved capacity in a region releases it in the same transaction as its deletion.
+    /// Returns None if there was no call or it didn't meet the condition.
+    async fn delete_call_item(
+        &self,
+        operation: &'static str,
+        key: AttributeValue,
+        condition: Option<&str>,
+        mut values: HashMap<String, AttributeValue>,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let with_condition = |extra: &str| match condition {
+            Some(condition) => format!("({}) AND {}", condition, extra),
+            None => extra.to_string(),
+        };
+
+        // Most calls don't reserve capacity, so they are deleted on their own, which also
+        // returns them.
+        let permit = self.request_permit(operation).await?;
+        let request = self
+            .client
+            .delete_item()
+            .table_name(&self.table_name)
+            .key(GROUP_CONFERENCE_ID_STRING, key.clone())
+            .condition_expression(with_condition("attribute_not_exists(reservedRegion)"))
+            .set_expression_attribute_values(
+                Some(values.clone()).filter(|values| !values.is_empty()),
+            )
+            .return_values(ReturnValue::AllOld)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send();
+        let response = self.within_deadline(operation, request).await?;
+        drop(permit);
+
+        match response {
+            Ok(response) => {
+                self.report_consumed_capacity(operation, response.consumed_capacity());
+                return response
+                    .attributes
+                    .map(|item| self.decode(item))
+                    .transpose()
+                    .map_err(|err| self.log_error(operation, err.into()));
+            }
+            Err(SdkError::ServiceError { err: e, raw: _ })
+                if matches!(
+                    e.kind,
+                    DeleteItemErrorKind::ConditionalCheckFailedException(_)
+                ) => {}
+            Err(err) => {
+                return Err(self.log_error(
+                    operation,
+                    request_error(err, "failed to delete_item from storage"),
+                ))
+            }
+        }
+
+        // Either the condition wasn't met or the call reserved capacity, which only
+        // reading the call tells apart. Expired calls are read too, since they still hold
+        // their reservation.
+        let permit = self.request_permit(operation).await?;
+        let request = self
+            .client
+            .get_item()
+            .table_name(&self.table_name)
+            .key(GROUP_CONFERENCE_ID_STRING, key.clone())
+            .consistent_read(true)
+            .send();
+        let response = self
+            .within_deadline(operation, request)
+            .await?
+            .map_err(|err| {
+                self.log_error(
+                    operation,
+                    request_error(err, "failed to get_item from storage"),
+                )
+            })?;
+        drop(permit);
+        let call = match response
+            .item
+            .map(|item| self.decode(item))
+            .transpose()
+            .map_err(|err| self.log_error(operation, err.into()))?
+        {
+            Some(call) => call,
+            None => return Ok(None),
+        };
+        let reserved_region = match &call.reserved_region {
+            Some(reserved_region) => reserved_region.clone(),
+            None => return Ok(None),
+        };
+
+        // Only the call that was read is deleted, so that it is the one returned.
+        values.insert(
+            ":reserved_call_id".to_string(),
+            AttributeValue::S(call.call_id.clone()),
+        );
+        values.insert(
+            ":reserved_region".to_string(),
+            AttributeValue::S(reserved_region.clone()),
+        );
+        let delete = Delete::builder()
+            .table_name(&self.table_name)
+            .key(GROUP_CONFERENCE_ID_STRING, key)
+            .condition_expression(with_condition(
+                "jvbConferenceId = :reserved_call_id AND reservedRegion = :reserved_region",
+            ))
+            .set_expression_attribute_values(Some(values))
+            .build();
+        let release = Update::builder()
+            .table_name(&self.table_name)
+            .key(
+                GROUP_CONFERENCE_ID_STRING,
+                self.key(&format!(
+                    "{}{}",
+                    REGION_CAPACITY_KEY_PREFIX, reserved_region
+                )),
+            )
+            .update_expression("ADD activeCalls :minus_one")
+            .expression_attribute_values(":minus_one", AttributeValue::N("-1".to_string()))
+            .build();
+
+        let permit = self.request_permit(operation).await?;
+        let request = self
+            .client
+            .transact_write_items()
+            .transact_items(TransactWriteItem::builder().delete(delete).build())
+            .transact_items(TransactWriteItem::builder().update(release).build())
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send();
+        let response = self.within_deadline(operation, request).await?;
+        drop(permit);
+
+        match response {
+            Ok(response) => {
+                self.report_consumed_capacity(
+                    operation,
+                    response.consumed_capacity().unwrap_or_default(),
+                );
+                Ok(Some(call))
+            }
+            Err(SdkError::ServiceError { err: e, raw: _ })
+                if e.is_transaction_canceled_exception() =>
+            {
+                let reasons = match &e.kind {
+                    TransactWriteItemsErrorKind::TransactionCanceledException(e) => {
+                        e.cancellation_reasons().unwrap_or_default()
+                    }
+                    _ => &[],
+                };
+                // The call changed since it was read, so there is nothing to delete.
+                if condition_failed(reasons, 0) {
+                    Ok(None)
+                } else {
+                    Err(self.log_error(
+                        operation,
+                        StorageError::UnexpectedError(anyhow!(
+                            "transaction canceled: {:?}",
+                            reasons
+                        )),
+                    ))
+                }
+            }
+            Err(err) => Err(self.log_error(
+                operation,
+                request_error(err, "failed to transact_write_items to storage"),
+            )),
+        }
+    }
+}
+
+/// Converts a failed request into a StorageError, telling throttling and other failures
+/// that are worth retrying apart from unexpected ones.
+fn request_error<E>(err: SdkError<E>, context: &'static str) -> StorageError
+where
+    E: ProvideErrorKind + std::error::Error + Send + Sync + 'static,
+{
+    let transient = match &err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+        SdkError::ServiceError { err, .. } => err
+            .code()
+            .map_or(false, |code| THROTTLING_ERROR_CODES.contains(&code)),
+        _ => false,
+    };
+
+    let err = sdk_error(err).context(context);
+    if transient {
+        StorageError::Throttled(err)
+    } else {
+        StorageError::UnexpectedError(err)
+    }
+}
+
+/// The request permits of a storage, which bound the number of requests in flight to
+/// DynamoDB, if any, for one operation.
+#[derive(Clone)]
+struct RequestPermits {
+    permits: Option<Arc<Semaphore>>,
+    timeout: Duration,
+    operation: &'static str,
+    table_name: String,
+    metric_tags: Vec<String>,
+}
+
+impl RequestPermits {
+    /// Waits for a permit to send a request. If none is released within the timeout,
+    /// storage is considered overloaded and Throttled is returned so that callers back
+    /// off instead of queueing up. Waiting also stops at the deadline of the caller, if
+    /// that comes first, with DeadlineExceeded.
+    async fn acquire(&self) -> Result<Option<OwnedSemaphorePermit>, StorageError> {
+        let permits = match &self.permits {
+            Some(permits) => permits.clone(),
+            None => return Ok(None),
+        };
+
+        let timeout_at = tokio::time::Instant::now() + std::time::Duration::from(self.timeout);
+        let deadline = current_deadline().filter(|deadline| *deadline < timeout_at);
+        let err =
+            match tokio::time::timeout_at(deadline.unwrap_or(timeout_at), permits.acquire_owned())
+                .await
+            {
+                Ok(Ok(permit)) => return Ok(Some(permit)),
+                Ok(Err(err)) => StorageError::UnexpectedError(
+                    anyhow::Error::from(err).context("request permits were closed"),
+                ),
+                Err(_) if deadline.is_some() => StorageError::DeadlineExceeded,
+                Err(_) => {
+                    tagged_event!(
+                        "calling.frontend.storage.request_permit.timed_out",
+                        self.metric_tags.clone()
+                    );
+                    StorageError::Throttled(anyhow!(
+                        "timed out waiting to send a request to storage"
+                    ))
+                }
+            };
+        error!(
+            "{}",
+            storage_error_log_record(self.operation, &self.table_name, &err)
+        );
+        Err(err)
+    }
+}
+
+/// Sends the request for each page, starting each from the key that the previous page
+/// ended at, until a page doesn't end at one. Every page waits for a request permit of
+/// its own, so that a long scan or query doesn't hold one between pages. Errors of the
+/// requests are converted with `convert_error`.
+fn paged<'a, O, E, F>(
+    permits: RequestPermits,
+    send: impl Fn(Option<HashMap<String, AttributeValue>>) -> F + Send + Sync + 'a,
+    last_evaluated_key: fn(&O) -> Option<&HashMap<String, AttributeValue>>,
+    convert_error: impl Fn(SdkError<E>) -> StorageError + Send + Sync + 'a,
+) -> BoxStream<'a, Result<O, StorageError>>
+where
+    O: Send + 'a,
+    E: Send + 'a,
+    F: std::future::Future<Output = Result<O, SdkError<E>>> + Send + 'a,
+{
+    let send = Arc::new(send);
+    let convert_error = Arc::new(convert_error);
+
+    // The state is the key to start the next page from, or None once there is none.
+    futures::stream::try_unfold(Some(None), move |start_key| {
+        let permits = permits.clone();
+        let send = send.clone();
+        let convert_error = convert_error.clone();
+        async move {
+            let start_key = match start_key {
+                Some(start_key) => start_key,
+                None => return Ok(None),
+            };
+            let page = {
+                let _permit = permits.acquire().await?;
+                send(start_key).await.map_err(|err| convert_error(err))?
+            };
+            let next_start_key = last_evaluated_key(&page)
+                .filter(|key| !key.is_empty())
+                .cloned();
+            Ok::<_, StorageError>(Some((page, next_start_key.map(Some))))
+        }
+    })
+    .boxed()
+}
+
+/// Flattens pages into the items that `items` takes from each.
+fn page_items<'a, O: Send + 'a>(
+    pages: BoxStream<'a, Result<O, StorageError>>,
+    items: fn(O) -> Option<Vec<HashMap<String, AttributeValue>>>,
+) -> BoxStream<'a, Result<HashMap<String, AttributeValue>, StorageError>> {
+    pages
+        .map_ok(move |page| {
+            futures::stream::iter(items(page).unwrap_or_default().into_iter().map(Ok))
+        })
+        .try_flatten()
+        .boxed()
+}
+
+/// Returns true if the query failed because its index doesn't exist or is still being
+/// backfilled, for which DynamoDB only returns a ValidationException with a message.
+fn is_index_unavailable(err: &SdkError<QueryError>) -> bool {
+    match err {
+        SdkError::ServiceError { err, .. } => {
+            err.code() == Some("ValidationException")
+                && err.message().map_or(false, |message| {
+                    message.contains("does not have the specified index")
+                        || message.contains("backfilling global secondary index")
+                })
+        }
+        _ => false,
+    }
+}
+
+/// Converts an error from the SDK, adding the ids that AWS gave the failed request, if it
+/// got a response, so that they can be quoted when opening a support case.
+fn sdk_error<E>(err: SdkError<E>) -> anyhow::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let headers = match &err {
+        SdkError::ServiceError { raw, .. } | SdkError::ResponseError { raw, .. } => {
+            Some(raw.http().headers())
+        }
+        _ => None,
+    };
+    let header = |name: &str| {
+        headers
+            .and_then(|headers| headers.get(name))
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+    let request_ids = match (header("x-amzn-RequestId"), header("x-amz-id-2")) {
+        (Some(request_id), Some(extended_request_id)) => Some(format!(
+            "request id {}, extended request id {}",
+            request_id, extended_request_id
+        )),
+        (Some(request_id), None) => Some(format!("request id {}", request_id)),
+        _ => None,
+    };
+
+    let err = anyhow::Error::from(err);
+    match request_ids {
+        Some(request_ids) => err.context(request_ids),
+        None => err,
+    }
+}
+
+/// Returns the client config for a storage_endpoint used for testing, signing requests
+/// with the configured test credentials or with dummy ones if none are configured.
+fn test_endpoint_aws_config(
+    config: &config::Config,
+    endpoint: &str,
+) -> Result<aws_sdk_dynamodb::config::Builder> {
+    const KEY: &str = "DUMMY_KEY";
+    const PASSWORD: &str = "DUMMY_PASSWORD";
+
+    let credentials = match (
+        &config.storage_test_access_key_id,
+        &config.storage_test_secret_access_key,
+    ) {
+        (Some(key), Some(password)) => {
+            Credentials::from_keys(key, password, config.storage_test_session_token.clone())
+        }
+        _ => Credentials::from_keys(KEY, PASSWORD, None),
+    };
+
+    let endpoint = endpoint
+        .parse::<Uri>()
+        .with_context(|| format!("storage_endpoint `{}` is invalid", endpoint))?;
+
+    Ok(Config::builder()
+        .credentials_provider(credentials)
+        .endpoint_resolver(Endpoint::immutable(endpoint))
+        .region(Region::new(config.storage_region.clone())))
+}
+
+/// Creates a client for DynamoDB in the given AWS region, with credentials from the
+/// environment, or from the instance metadata service for an aws-imds identity source.
+async fn region_client(config: &config::Config, region: &str) -> Result<Client> {
+    let sleep_impl = default_async_sleep().ok_or_else(|| anyhow!("failed to create sleep_impl"))?;
+    let retry_config = RetryConfigBuilder::new()
+        .max_attempts(4)
+        .initial_backoff(std::time::Duration::from_millis(100))
+        .build();
+
+    let mut loader = aws_config::from_env()
+        .sleep_impl(sleep_impl)
+        .retry_config(retry_config)
+        .region(Region::new(region.to_string()));
+    if config.identity_source == config::IdentitySource::AwsImds {
+        loader = loader.credentials_provider(ImdsCredentialsProvider::builder().build());
+    }
+    let aws_config = loader.load().await;
+
+    let aws_config = with_region_endpoint(
+        config,
+        region,
+        aws_sdk_dynamodb::config::Builder::from(&aws_config),
+    )?
+    .build();
+    Ok(Client::from_conf(aws_config))
+}
+
+/// Returns the name of the partition key in the given key schema.
+fn hash_key(key_schema: Option<&[KeySchemaElement]>) -> Option<&str> {
+    key_schema?
+        .iter()
+        .find(|element| element.key_type() == Some(&KeyType::Hash))?
+        .attribute_name()
+}
+
+/// The DNS suffixes of the AWS partitions that aren't the standard one, by the prefix of
+/// the names of their regions, as (region prefix, suffix, dualstack suffix). Partitions
+/// without dualstack endpoints have no dualstack suffix.
+const AWS_PARTITIONS: &[(&str, &str, Option<&str>)] = &[
+    (
+        "cn-",
+        "amazonaws.com.cn",
+        Some("api.amazonwebservices.com.cn"),
+    ),
+    ("us-iso-", "c2s.ic.gov", None),
+    ("us-isob-", "sc2s.sgov.gov", None),
+];
+
+/// Returns the DNS suffix and dualstack DNS suffix of the partition the given region is
+/// in. Regions of the standard partition, including GovCloud, use amazonaws.com.
+fn partition_dns_suffixes(region: &str) -> (&'static str, Option<&'static str>) {
+    AWS_PARTITIONS
+        .iter()
+        .find(|(prefix, _, _)| region.starts_with(prefix))
+        .map(|(_, suffix, dualstack_suffix)| (*suffix, *dualstack_suffix))
+        .unwrap_or(("amazonaws.com", Some("api.aws")))
+}
+
+/// Returns the FIPS and/or dualstack endpoint of DynamoDB in the given region, or None
+/// if neither was asked for and the default endpoint of the region is used. Fails if
+/// dualstack was asked for in a partition that doesn't have it.
+fn region_endpoint(config: &config::Config, region: &str) -> Result<Option<String>> {
+    let service = if config.storage_use_fips {
+        "dynamodb-fips"
+    } else {
+        "dynamodb"
+    };
+    let (suffix, dualstack_suffix) = partition_dns_suffixes(region);
+    Ok(
+        match (config.storage_use_fips, config.storage_use_dualstack) {
+            (false, false) => None,
+            (_, false) => Some(format!("https://{}.{}.{}", service, region, suffix)),
+            (_, true) => {
+                let dualstack_suffix = dualstack_suffix
+                    .ok_or_else(|| anyhow!("region `{}` has no dualstack endpoints", region))?;
+                Some(format!(
+                    "https://{}.{}.{}",
+                    service, region, dualstack_suffix
+                ))
+            }
+        },
+    )
+}
+
+/// Points the client config for the given region at its FIPS and/or dualstack endpoint
+/// if one was asked for.
+fn with_region_endpoint(
+    config: &config::Config,
+    region: &str,
+    builder: aws_sdk_dynamodb::config::Builder,
+) -> Result<aws_sdk_dynamodb::config::Builder> {
+    Ok(match region_endpoint(config, region)? {
+        Some(endpoint) => {
+            info!("Using endpoint for DynamoDB access: {}", endpoint);
+            let endpoint = endpoint
+                .parse::<Uri>()
+                .with_context(|| format!("region endpoint `{}` is invalid", endpoint))?;
+            builder.endpoint_resolver(Endpoint::immutable(endpoint))
+        }
+        None => builder,
+    })
+}
+
+/// Returns true if certificates from the storage endpoint shouldn't be verified. This is
+/// never the case without an endpoint, even if validation was skipped.
+#[cfg(any(test, feature = "insecure-test-endpoint"))]
+fn allows_invalid_certs(config: &config::Config) -> bool {
+    config.storage_endpoint.is_some() && config.storage_endpoint_allow_invalid_certs
+}
+
+/// Accepts any server certificate. Only for test endpoints, via insecure_connector().
+#[cfg(feature = "insecure-test-endpoint")]
+struct NoCertificateVerification;
+
+#[cfg(feature = "insecure-test-endpoint")]
+impl rustls::client::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// Returns a connector that accepts any certificate from HTTPS endpoints, if the config
+/// allows invalid certificates. Builds without the insecure-test-endpoint feature never
+/// return one.
+#[cfg(feature = "insecure-test-endpoint")]
+fn insecure_connector(config: &config::Config) -> Option<aws_smithy_client::erase::DynConnector> {
+    if !allows_invalid_certs(config) {
+        return None;
+    }
+
+    let tls_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+        .with_no_client_auth();
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+    Some(aws_smithy_client::erase::DynConnector::new(
+        aws_smithy_client::hyper_ext::Adapter::builder().build(https),
+    ))
+}
+
+#[cfg(not(feature = "insecure-test-endpoint"))]
+fn insecure_connector(_config: &config::Config) -> Option<aws_smithy_client::erase::DynConnector> {
+    None
+}
+
+/// Checks that a fetched token could be used, so that an error response or an empty file
+/// doesn't replace the last good token.
+fn validate_token(token: &[u8]) -> Result<()> {
+    if token.iter().all(|b| b.is_ascii_whitespace()) {
+        return Err(anyhow!("identity token is empty"));
+    }
+    if !token.is_ascii() {
+        return Err(anyhow!("identity token is not ASCII"));
+    }
+    Ok(())
+}
+
+/// Reports how long a call that was just removed lasted, as
+/// calling.frontend.call_duration_ms. Calls written before created_at was tracked are
+/// skipped.
+pub(crate) fn report_call_duration(call: &CallRecord, now: SystemTime) {
+    if let Some(created_at) = call.created_at {
+        // A clock that is behind the one that created the call counts as no time passing.
+        let duration_ms = now
+            .duration_since(UNIX_EPOCH + std::time::Duration::from_secs(created_at))
+            .unwrap_or_default()
+            .as_millis() as usize;
+        sampling_histogram!("calling.frontend.call_duration_ms", || duration_ms);
+    }
+}
+
+/// Removes the given call with the storage in a background task, for storages whose
+/// remove_call_record_best_effort can hand a handle to the task. Removal is conditional
+/// on the call_id, so every failure is safe to retry.
+pub(crate) fn spawn_best_effort_removal<S: Storage + 'static>(
+    storage: S,
+    group_id: &GroupId,
+    call_id: &str,
+) {
+    let group_id = group_id.clone();
+    let call_id = call_id.to_string();
+    // Task locals don't follow the spawn, so the removal is logged under the caller's
+    // correlation id explicitly.
+    let correlation_id = current_correlation_id();
+
+    tokio::spawn(async move {
+        let removal = storage.remove_call_record_with_retries(&group_id, &call_id);
+        match correlation_id {
+            Some(correlation_id) => with_correlation_id(correlation_id, removal).await,
+            None => removal.await,
+        }
+    });
+}
+
+/// Returns the exp claim of the token if it is a JWT. Other tokens, such as opaque ones
+/// read from a file, have no expiry that can be read.
+fn token_expiry(token: &[u8]) -> Option<SystemTime> {
+    let token = std::str::from_utf8(token).ok()?.trim();
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()?;
+    let exp = serde_json::from_slice::<serde_json::Value>(&payload)
+        .ok()?
+        .get("exp")?
+        .as_u64()?;
+    Some(UNIX_EPOCH + std::time::Duration::from_secs(exp))
+}
+
+/// Writes every call in the given region to the writer as newline-delimited JSON, one
+/// CallRecord per line, and returns the number of calls written.
+pub async fn dump_region_to_writer<W: AsyncWrite + Unpin>(
+    storage: &dyn Storage,
+    region: &str,
+    writer: &mut W,
+) -> Result<usize> {
+    let mut calls = storage.stream_call_records_for_region(region);
+    let mut count = 0;
+
+    while let Some(call) = calls.next().await {
+        let mut line = serde_json::to_vec(&call?)?;
+        line.push(b'\n');
+        writer.write_all(&line).await?;
+        count += 1;
+    }
+
+    writer.flush().await?;
+    Ok(count)
+}
+
+/// Returns the configured identity token path to use with a testing endpoint, or else a
+/// file in the temp directory that is unique to this process.
+fn test_identity_token_path(config: &config::Config) -> PathBuf {
+    match &config.identity_token_path {
+        Some(path) => PathBuf::from(path),
+        None => env::temp_dir().join(format!("calling_frontend_token_{}", std::process::id())),
+    }
+}
+
+/// Returns the write shard of the region index that the calls of the given group are
+/// indexed under. Like the table shard, this must never change for a given number of
+/// shards, but it is taken from the high half of the stable hash so that it doesn't
+/// follow the table shard.
+fn region_index_shard(group_id: &GroupId, shard_count: u32) -> u32 {
+    ((group_id.stable_hash() >> 32) % shard_count.max(1) as u64) as u32
+}
+
+fn region_shard_key(region: &str, shard: u32) -> String {
+    format!("{}#{}", region, shard)
+}
+
+/// Approximates the size that DynamoDB counts an item as, which is the length of every
+/// attribute name plus the size of its value.
+/// See https://docs.aws.amazon.com/amazondynamodb/latest/developerguide/CapacityUnitCalculations.html
+fn item_size(item: &HashMap<String, AttributeValue>) -> usize {
+    item.iter()
+        .map(|(name, value)| name.len() + attribute_value_size(value))
+        .sum()
+}
+
+fn attribute_value_size(value: &AttributeValue) -> usize {
+    // Numbers take about one byte for every two significant digits, plus one.
+    let number_size = |number: &String| number.len() / 2 + 1;
+
+    match value {
+        AttributeValue::S(value) => value.len(),
+        AttributeValue::N(value) => number_size(value),
+        AttributeValue::B(value) => value.as_ref().len(),
+        AttributeValue::Ss(values) => values.iter().map(String::len).sum(),
+        AttributeValue::Ns(values) => values.iter().map(number_size).sum(),
+        AttributeValue::Bs(values) => values.iter().map(|value| value.as_ref().len()).sum(),
+        // Lists and maps take 3 bytes plus 1 byte for each element.
+        AttributeValue::L(values) => {
+            3 + values
+                .iter()
+                .map(|value| 1 + attribute_value_size(value))
+                .sum::<usize>()
+        }
+        AttributeValue::M(values) => {
+            3 + values
+                .iter()
+                .map(|(name, value)| 1 + name.len() + attribute_value_size(value))
+                .sum::<usize>()
+        }
+        _ => 1,
+    }
+}
+
+/// Sorts calls by group_id and then call_id, the order in which lists of calls are
+/// returned from storage.
+fn sort_call_records(calls: &mut [CallRecord]) {
+    calls.sort_by(|a, b| (a.group_id.as_ref(), &a.call_id).cmp(&(b.group_id.as_ref(), &b.call_id)));
+}
+
+/// Converts an item to a call with the codec, after removing the key_prefix from its key
+/// so that callers only ever see bare group_ids.
+fn decode_prefixed(
+    codec: &dyn RecordCodec,
+    key_prefix: &str,
+    mut item: HashMap<String, AttributeValue>,
+) -> Result<CallRecord> {
+    strip_key_prefix(key_prefix, &mut item);
+    codec.decode(item)
+}
+
+fn strip_key_prefix(key_prefix: &str, item: &mut HashMap<String, AttributeValue>) {
+    if let Some(AttributeValue::S(key)) = item.get_mut(GROUP_CONFERENCE_ID_STRING) {
+        if let Some(group_id) = key.strip_prefix(key_prefix) {
+            *key = group_id.to_string();
+        }
+    }
+}
+
+/// Returns true if the key is that of one of the counter or bookkeeping items kept
+/// alongside the calls.
+fn is_counter_key(key: &str) -> bool {
+    key.starts_with(REGION_CAPACITY_KEY_PREFIX)
+        || key.starts_with(CALL_ERA_KEY_PREFIX)
+        || key == REGION_INDEX_SHARDS_KEY
+}
+
+/// Returns true if the transaction item at the given index was canceled because its
+/// condition wasn't met.
+fn condition_failed(reasons: &[CancellationReason], index: usize) -> bool {
+    reasons.get(index).and_then(|reason| reason.code()) == Some("ConditionalCheckFailed")
+}
+
+/// Formats a storage failure as a single line of JSON so that it can be indexed by the
+/// log pipeline. The message includes the whole chain of causes, and the correlation id
+/// is included when the caller set one.
+fn storage_error_log_record(operation: &str, table: &str, err: &StorageError) -> String {
+    let message = std::iter::successors(Some(err as &dyn std::error::Error), |err| err.source())
+        .map(|err| err.to_string())
+        .collect::<Vec<_>>()
+        .join(": ");
+
+    serde_json::json!({
+        "operation": operation,
+        "table": table,
+        "error_kind": err.kind(),
+        "message": message,
+        "correlation_id": current_correlation_id(),
+    })
+    .to_string()
+}
+
+#[async_trait]
+impl Storage for DynamoDb {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.read_call_record(group_id, self.consistent_reads).await
+    }
+
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        let consistent = self.consistent_reads;
+        let call = self.read_call_record(group_id, consistent).await?;
+        Ok((
+            call,
+            ReadMeta {
+                consistent,
+                failover: false,
+            },
+        ))
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        mut call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let now = self.clock.now_secs();
+        call.start_lifetime(now);
+
+        let item = self.call_item_to_write("get_or_add_call_record", &call)?;
+
+        let expired_by = AttributeValue::N(
+            now.saturating_sub(self.clock_skew_tolerance.as_secs())
+                .to_string(),
+        );
+        let mut released_expired = false;
+        let response = loop {
+            let permit = self.request_permit("get_or_add_call_record").await?;
+            let request = self
+                .client
+                .put_item()
+                .table_name(&self.table_name)
+                .set_item(Some(item.clone()))
+                // Don't overwrite the item if it already exists, unless it has expired and
+                // just hasn't been deleted yet, in which case the new call replaces it. An
+                // expired call that reserved capacity must release it first.
+                .condition_expression(
+                    "attribute_not_exists(groupConferenceId) OR \
+                     (expiresAt <= :expired_by AND attribute_not_exists(reservedRegion))"
+                        .to_string(),
+                )
+                .expression_attribute_values(":expired_by".to_string(), expired_by.clone())
+                .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                .send();
+            let response = self
+                .within_deadline("get_or_add_call_record", request)
+                .await?;
+            drop(permit);
+
+            match response {
+                Err(SdkError::ServiceError { err: e, raw: _ })
+                    if e.is_conditional_check_failed_exception() => {}
+                response => break response,
+            }
+
+            tagged_event!(
+                "calling.frontend.storage.get_or_add.conditional_failed",
+                self.metric_tags("get_or_add_call_record", Some(&call.backend_region))
+            );
+            // Frontends that lost the same race would otherwise all read at once.
+            let delay = self.conflict_read_delay();
+            if delay > Duration::ZERO {
+                tokio::time::sleep(delay.into()).await;
+            }
+            // The winning call was only just written, so it must be read consistently
+            // even if plain gets aren't.
+            let existing = self.read_call_record(&call.group_id, true).await?;
+            if existing.is_some() || released_expired {
+                return Ok(existing);
+            }
+
+            // The call in the way expired, so it must have reserved capacity. It is
+            // removed, releasing its reservation, and the new call is put again.
+            self.delete_call_item(
+                "get_or_add_call_record",
+                self.key(call.group_id.as_ref()),
+                Some("expiresAt <= :expired_by"),
+                HashMap::from([(":expired_by".to_string(), expired_by.clone())]),
+            )
+            .await?;
+            released_expired = true;
+        };
+
+        match response {
+            Ok(response) => {
+                self.report_consumed_capacity(
+                    "get_or_add_call_record",
+                    response.consumed_capacity(),
+                );
+                self.start_era(&mut call).await;
+                Ok(Some(call))
+            }
+            Err(err) => Err(self.log_error(
+                "get_or_add_call_record",
+                request_error(
+                    err,
+                    "failed to put_item to storage for get_or_add_call_record",
+                ),
+            )),
+        }
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let call = self
+            .delete_call_item(
+                "remove_call_record",
+                self.key(group_id.as_ref()),
+                // Only if the given call_id matches the expected value, otherwise the
+                // previous call was removed and a new one created already.
+                Some("jvbConferenceId = :value"),
+                HashMap::from([(":value".to_string(), AttributeValue::S(call_id.to_string()))]),
+            )
+            .await?;
+
+        match &call {
+            Some(call) => report_call_duration(call, self.clock.now()),
+            None => tagged_event!(
+                "calling.frontend.storage.remove.conditional_failed",
+                self.metric_tags("remove_call_record", None)
+            ),
+        }
+        Ok(call)
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        // A copy of the storage shares the connection and request permits of this one.
+        spawn_best_effort_removal(self.for_table(self.table_name.clone()), group_id, call_id);
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        storage_log!(
+            Level::Warn,
+            "forcing the removal of the call for group {}",
+            group_id
+        );
+
+        // There is no condition on the call_id.
+        self.delete_call_item(
+            "force_remove_call_record",
+            self.key(group_id.as_ref()),
+            None,
+            HashMap::new(),
+        )
+        .await
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        // Each shard is queried with a permit of its own, like any other request.
+        let requests = try_join_all(self.region_queries(region).into_iter().map(
+            |query| async move {
+                let _permit = self.request_permit("get_call_records_for_region").await?;
+                query
+                    .select(Select::AllAttributes)
+                    .send()
+                    .await
+                    .map_err(|err| {
+                        self.region_query_error(
+                            "get_call_records_for_region",
+                            err,
+                            "failed to query for calls in a region",
+                        )
+                    })
+            },
+        ));
+        let responses = self
+            .within_deadline("get_call_records_for_region", requests)
+            .await??;
+
+        // A malformed item is skipped rather than hiding all of the other calls of the
+        // region, unless strict region queries are configured.
+        let mut calls = vec![];
+        for item in responses
+            .into_iter()
+            .flat_map(|response| response.items.unwrap_or_default())
+        {
+            match self.decode(item) {
+                Ok(call) => calls.push(call),
+                Err(err) if self.strict_region_queries => {
+                    return Err(self.log_error("get_call_records_for_region", err.into()));
+                }
+                Err(err) => {
+                    tagged_event!(
+                        "calling.frontend.storage.malformed_item",
+                        self.metric_tags("get_call_records_for_region", None)
+                    );
+                    storage_log!(
+                        Level::Warn,
+                        "skipping malformed call in region {} of table {}: {:?}",
+                        region,
+                        self.table_name,
+                        err
+                    );
+                }
+            }
+        }
+
+        sort_call_records(&mut calls);
+        Ok(calls)
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        // The stream outlives the borrow of self, so errors are logged without it.
+        let table_name = self.table_name.clone();
+        let codec = self.codec.clone();
+        let key_prefix = self.key_prefix.clone();
+        let index_name = self.region_index_name();
+        let metric_tags = self.metric_tags("stream_call_records_for_region", None);
+        let strict = self.strict_region_queries;
+        let region = region.to_string();
+
+        let log_table_name = table_name.clone();
+        let log_error = move |err: StorageError| {
+            error!(
+                "{}",
+                storage_error_log_record("stream_call_records_for_region", &log_table_name, &err)
+            );
+            err
+        };
+
+        futures::stream::select_all(self.region_queries(&region).into_iter().map(|query| {
+            let query = query
+                .select(Select::AllAttributes)
+                .set_limit(self.page_size);
+            let metric_tags = metric_tags.clone();
+            let log_error = log_error.clone();
+            let pages = paged(
+                self.request_permits("stream_call_records_for_region"),
+                move |start_key| query.clone().set_exclusive_start_key(start_key).send(),
+                QueryOutput::last_evaluated_key,
+                move |err| {
+                    log_error(if is_index_unavailable(&err) {
+                        tagged_event!(
+                            "calling.frontend.storage.region_index_unavailable",
+                            metric_tags.clone()
+                        );
+                        StorageError::RegionIndexUnavailable(index_name.to_string())
+                    } else {
+                        request_error(err, "failed to query for calls in a region")
+                    })
+                },
+            );
+            page_items(pages, |page| page.items)
+        }))
+        // Malformed items are skipped like in get_call_records_for_region.
+        .filter_map(move |item| {
+            futures::future::ready(
+                match item.map(|item| decode_prefixed(&*codec, &key_prefix, item)) {
+                    Ok(Ok(call)) => Some(Ok(call)),
+                    Ok(Err(err)) if strict => Some(Err(log_error(err.into()))),
+                    Ok(Err(err)) => {
+                        tagged_event!(
+                            "calling.frontend.storage.malformed_item",
+                            metric_tags.clone()
+                        );
+                        storage_log!(
+                            Level::Warn,
+                            "skipping malformed call in region {} of table {}: {:?}",
+                            region,
+                            table_name,
+                            err
+                        );
+                        None
+                    }
+                    Err(err) => Some(Err(err)),
+                },
+            )
+        })
+        .boxed()
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        let mut items =
+            futures::stream::select_all(self.region_queries(region).into_iter().map(|query| {
+                let query = query
+                    // Only the backend is needed, so there is no need to fetch whole records.
+                    .select(Select::SpecificAttributes)
+                    .projection_expression("jvbHost".to_string())
+                    .set_limit(self.page_size);
+                let pages = paged(
+                    self.request_permits("count_calls_per_backend"),
+                    move |start_key| query.clone().set_exclusive_start_key(start_key).send(),
+                    QueryOutput::last_evaluated_key,
+                    move |err| {
+                        self.region_query_error(
+                            "count_calls_per_backend",
+                            err,
+                            "failed to query for backends in a region",
+                        )
+                    },
+                );
+                page_items(pages, |page| page.items)
+            }));
+
+        let mut counts = HashMap::new();
+        while let Some(item) = items.next().await {
+            let item = item?;
+            let backend_ip = item
+                .get("jvbHost")
+                .and_then(|value| value.as_s().ok())
+                .ok_or_else(|| anyhow!("item in region-index is missing jvbHost"))
+                .map_err(|err| self.log_error("count_calls_per_backend", err.into()))?;
+            *counts.entry(backend_ip.to_string()).or_insert(0) += 1;
+        }
+
+        Ok(counts)
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        let mut items =
+            futures::stream::select_all(self.region_queries(region).into_iter().map(|query| {
+                let query = query
+                    // Only the version is needed, so there is no need to fetch whole records.
+                    .select(Select::SpecificAttributes)
+                    .projection_expression("backendVersion".to_string())
+                    .set_limit(self.page_size);
+                let pages = paged(
+                    self.request_permits("count_calls_by_backend_version"),
+                    move |start_key| query.clone().set_exclusive_start_key(start_key).send(),
+                    QueryOutput::last_evaluated_key,
+                    move |err| {
+                        self.region_query_error(
+                            "count_calls_by_backend_version",
+                            err,
+                            "failed to query for backend versions in a region",
+                        )
+                    },
+                );
+                page_items(pages, |page| page.items)
+            }));
+
+        let mut counts = HashMap::new();
+        while let Some(item) = items.next().await {
+            let item = item?;
+            // Calls written before the version was tracked come back as empty items.
+            let backend_version = item
+                .get("backendVersion")
+                .and_then(|value| value.as_s().ok())
+                .cloned();
+            *counts.entry(backend_version).or_insert(0) += 1;
+        }
+
+        Ok(counts)
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        let mut pages =
+            futures::stream::select_all(self.region_queries(region).into_iter().map(|query| {
+                let query = query
+                    // Only the count is returned, so no items are transferred at all.
+                    .select(Select::Count)
+                    .set_limit(self.page_size);
+                paged(
+                    self.request_permits("count_call_records_for_region"),
+                    move |start_key| query.clone().set_exclusive_start_key(start_key).send(),
+                    QueryOutput::last_evaluated_key,
+                    move |err| {
+                        self.region_query_error(
+                            "count_call_records_for_region",
+                            err,
+                            "failed to count calls in a region",
+                        )
+                    },
+                )
+            }));
+
+        let mut count = 0;
+        while let Some(page) = pages.next().await {
+            let page = page?;
+            count += page.count() as usize;
+        }
+
+        Ok(count)
+    }
+
+    /// Scans the region-index for the region of every call, rather than sending a count
+    /// query per region. Both read every call in the index once, and the projection
+    /// doesn't lower the read capacity used, so they cost about the same. But a count per
+    /// region needs a request for each region, and each write shard of a region, and
+    /// misses the regions that nobody thought to ask about. The scan is split into the
+    /// configured scan segments like other full scans.
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        let mut items = self.parallel_scan(
+            "count_all_regions",
+            self.scan_segments,
+            self.scan_concurrency,
+            |scan| {
+                self.prefixed_scan(scan.index_name(self.region_index_name()), None)
+                    .projection_expression("#region".to_string())
+                    .expression_attribute_names("#region".to_string(), "region".to_string())
+            },
+        );
+
+        let mut counts = HashMap::new();
+        while let Some(item) = items.next().await {
+            let item = item?;
+            let region = item
+                .get("region")
+                .and_then(|value| value.as_s().ok())
+                .ok_or_else(|| anyhow!("item in region-index is missing region"))
+                .map_err(|err| self.log_error("count_all_regions", err.into()))?;
+            *counts.entry(region.to_string()).or_insert(0) += 1;
+        }
+
+        Ok(counts)
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        // Use placeholders for all of the attributes since some, like region, are
+        // reserved words.
+        let placeholders: Vec<_> = (0..attributes.len()).map(|i| format!("#a{}", i)).collect();
+        let mut remaining = limit.unwrap_or(usize::MAX);
+        let mut calls = vec![];
+
+        // The shards are read one after another and a page at a time, with each page
+        // limited to the calls still wanted, so that no more calls than the limit are
+        // fetched and shards after it is reached aren't read at all.
+        for query in self.region_queries(region) {
+            let query = placeholders.iter().zip(attributes).fold(
+                query
+                    .select(Select::SpecificAttributes)
+                    .projection_expression(placeholders.join(", ")),
+                |request, (placeholder, attribute)| {
+                    request.expression_attribute_names(placeholder, attribute.to_string())
+                },
+            );
+            let mut exclusive_start_key = None;
+            while remaining > 0 {
+                let page_limit = match (limit, self.page_size) {
+                    (Some(_), Some(page_size)) => Some(remaining.min(page_size as usize)),
+                    (Some(_), None) => Some(remaining),
+                    (None, page_size) => page_size.map(|page_size| page_size as usize),
+                };
+
+                let permit = self
+                    .request_permit("get_call_records_for_region_projected")
+                    .await?;
+                let request = query
+                    .clone()
+                    .set_limit(page_limit.map(|limit| limit.try_into().unwrap_or(i32::MAX)))
+                    .set_exclusive_start_key(exclusive_start_key.take())
+                    .send();
+                let response = self
+                    .within_deadline("get_call_records_for_region_projected", request)
+                    .await?
+                    .map_err(|err| {
+                        self.region_query_error(
+                            "get_call_records_for_region_projected",
+                            err,
+                            "failed to query for projected calls in a region",
+                        )
+                    })?;
+                drop(permit);
+
+                for mut item in response
+                    .items
+                    .unwrap_or_default()
+                    .into_iter()
+                    .take(remaining)
+                {
+                    strip_key_prefix(&self.key_prefix, &mut item);
+                    calls.push(
+                        from_item(item)
+                            .context("failed to convert item to CallRecordSummary")
+                            .map_err(|err| {
+                                self.log_error("get_call_records_for_region_projected", err.into())
+                            })?,
+                    );
+                    remaining -= 1;
+                }
+
+                exclusive_start_key = response.last_evaluated_key;
+                if exclusive_start_key.is_none() {
+                    break;
+                }
+            }
+        }
+
+        Ok(calls)
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let permit = self.request_permit("promote_backup_backend").await?;
+        let request = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(GROUP_CONFERENCE_ID_STRING, self.key(group_id.as_ref()))
+            // Move the first backup into the primary slot and drop it from the list, all
+            // in a single update so that concurrent promotions can't skip a backup.
+            .update_expression(
+                "SET jvbHost = backupBackends[0].ip, #region = backupBackends[0].#region, \
+                 #version = if_not_exists(#version, :zero) + :one \
+                 REMOVE backupBackends[0]"
+                    .to_string(),
+            )
+            // But only if the call is still the expected one and has a backup to promote.
+            .condition_expression(
+                "jvbConferenceId = :value AND size(backupBackends) > :zero".to_string(),
+            )
+            .expression_attribute_names("#region".to_string(), "region".to_string())
+            .expression_attribute_values(
+                ":value".to_string(),
+                AttributeValue::S(call_id.to_string()),
+            )
+            .expression_attribute_names("#version".to_string(), "version".to_string())
+            .expression_attribute_values(":zero".to_string(), AttributeValue::N("0".to_string()))
+            .expression_attribute_values(":one".to_string(), AttributeValue::N("1".to_string()))
+            .return_values(ReturnValue::AllNew)
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send();
+        let response = self
+            .within_deadline("promote_backup_backend", request)
+            .await?;
+        drop(permit);
+
+        match response {
+            Ok(response) => {
+                self.report_consumed_capacity(
+                    "promote_backup_backend",
+                    response.consumed_capacity(),
+                );
+                let mut call = response
+                    .attributes
+                    .map(|item| self.decode(item))
+                    .transpose()
+                    .map_err(|err| self.log_error("promote_backup_backend", err.into()))?;
+                if let Some(call) = &mut call {
+                    self.update_region_shard(call).await;
+                }
+                Ok(call)
+            }
+            Err(SdkError::ServiceError { err: e, raw: _ })
+                if e.is_conditional_check_failed_exception() =>
+            {
+                Ok(None)
+            }
+            Err(err) => Err(self.log_error(
+                "promote_backup_backend",
+                request_error(
+                    err,
+                    "failed to update_item in storage for promote_backup_backend",
+                ),
+            )),
+        }
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        mut call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        call.start_lifetime(self.clock.now_secs());
+        // Marks the call as holding the reservation, which removing it releases.
+        call.reserved_region = Some(call.backend_region.clone());
+
+        let item = self.call_item_to_write("create_call_reserving_capacity", &call)?;
+
+        let put = Put::builder()
+            .table_name(&self.table_name)
+            .set_item(Some(item))
+            // Don't overwrite the item if it already exists.
+            .condition_expression("attribute_not_exists(groupConferenceId)")
+            .build();
+
+        let update = Update::builder()
+            .table_name(&self.table_name)
+            .key(
+                GROUP_CONFERENCE_ID_STRING,
+                self.key(&format!(
+                    "{}{}",
+                    REGION_CAPACITY_KEY_PREFIX, call.backend_region
+                )),
+            )
+            .update_expression("ADD activeCalls :one")
+            // But only if the region has room for another call.
+            .condition_expression("attribute_not_exists(activeCalls) OR activeCalls < :max")
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .expression_attribute_values(
+                ":max",
+                AttributeValue::N(max_calls_per_region.to_string()),
+            )
+            .build();
+
+        let permit = self
+            .request_permit("create_call_reserving_capacity")
+            .await?;
+        let request = self
+            .client
+            .transact_write_items()
+            .transact_items(TransactWriteItem::builder().put(put).build())
+            .transact_items(TransactWriteItem::builder().update(update).build())
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send();
+        let response = self
+            .within_deadline("create_call_reserving_capacity", request)
+            .await?;
+        drop(permit);
+
+        match response {
+            Ok(response) => {
+                self.report_consumed_capacity(
+                    "create_call_reserving_capacity",
+                    response.consumed_capacity().unwrap_or_default(),
+                );
+                self.start_era(&mut call).await;
+                Ok(call)
+            }
+            Err(SdkError::ServiceError { err: e, raw: _ })
+                if e.is_transaction_canceled_exception() =>
+            {
+                let reasons = match &e.kind {
+                    TransactWriteItemsErrorKind::TransactionCanceledException(e) => {
+                        e.cancellation_reasons().unwrap_or_default()
+                    }
+                    _ => &[],
+                };
+
+                // The reasons are in the same order as the items in the transaction.
+                if condition_failed(reasons, 0) {
+                    Err(StorageError::CallAlreadyExists)
+                } else if condition_failed(reasons, 1) {
+                    Err(StorageError::RegionFull(call.backend_region))
+                } else {
+                    Err(self.log_error(
+                        "create_call_reserving_capacity",
+                        StorageError::UnexpectedError(anyhow!(
+                            "transaction canceled: {:?}",
+                            reasons
+                        )),
+                    ))
+                }
+            }
+            Err(err) => Err(self.log_error(
+                "create_call_reserving_capacity",
+                request_error(
+                    err,
+                    "failed to transact_write_items to storage for create_call_reserving_capacity",
+                ),
+            )),
+        }
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        let permit = self.request_permit("health_check").await?;
+        let response = match tokio::time::timeout(
+            HEALTH_CHECK_TIMEOUT.into(),
+            self.client
+                .describe_table()
+                .table_name(&self.table_name)
+                .send(),
+        )
+        .await
+        {
+            Ok(response) => response.map_err(|err| self.describe_table_error(err)),
+            Err(_) => Err(anyhow!("timed out describing the table").into()),
+        }
+        .map_err(|err| self.log_error("health_check", err))?;
+        drop(permit);
+
+        match response.table().and_then(|table| table.table_status()) {
+            Some(TableStatus::Active) => Ok(()),
+            status => Err(self.log_error(
+                "health_check",
+                StorageError::UnexpectedError(anyhow!("table is not active: {:?}", status)),
+            )),
+        }
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        let expected_version = call.version;
+        let call = CallRecord {
+            version: expected_version + 1,
+            ..call
+        };
+
+        let request = self
+            .client
+            .put_item()
+            .table_name(&self.table_name)
+            .set_item(Some(self.call_item_to_write("update_call_record", &call)?))
+            .expression_attribute_names("#version".to_string(), "version".to_string())
+            .expression_attribute_values(
+                ":call_id".to_string(),
+                AttributeValue::S(call.call_id.clone()),
+            )
+            .return_consumed_capacity(ReturnConsumedCapacity::Total);
+
+        // Only replace the call that was read, and only if nobody else changed it since.
+        // Records without a version yet, or that were written with a version of 0, read
+        // as 0, and haven't been changed since they were read either.
+        let request = if expected_version == 0 {
+            request
+                .condition_expression(
+                    "jvbConferenceId = :call_id AND \
+                     (attribute_not_exists(#version) OR #version = :zero)",
+                )
+                .expression_attribute_values(
+                    ":zero".to_string(),
+                    AttributeValue::N("0".to_string()),
+                )
+        } else {
+            request
+                .condition_expression("jvbConferenceId = :call_id AND #version = :expected")
+                .expression_attribute_values(
+                    ":expected".to_string(),
+                    AttributeValue::N(expected_version.to_string()),
+                )
+        };
+
+        let _permit = self.request_permit("update_call_record").await?;
+        match self
+            .within_deadline("update_call_record", request.send())
+            .await?
+        {
+            Ok(response) => {
+                self.report_consumed_capacity("update_call_record", response.consumed_capacity());
+                Ok(call)
+            }
+            Err(SdkError::ServiceError { err: e, raw: _ })
+                if e.is_conditional_check_failed_exception() =>
+            {
+                Err(StorageError::VersionConflict)
+            }
+            Err(err) => Err(self.log_error(
+                "update_call_record",
+                request_error(err, "failed to put_item to storage for update_call_record"),
+            )),
+        }
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        // A batch can't write the same key twice, so only the last call of each group is
+        // kept, in the place of the first.
+        let mut indexes = HashMap::with_capacity(records.len());
+        let mut unique: Vec<CallRecord> = Vec::with_capacity(records.len());
+        for call in records {
+            match indexes.get(&call.group_id) {
+                Some(&index) => unique[index] = call,
+                None => {
+                    indexes.insert(call.group_id.clone(), unique.len());
+                    unique.push(call);
+                }
+            }
+        }
+
+        let now = self.clock.now_secs();
+        let mut requests = Vec::with_capacity(unique.len());
+        for mut call in unique {
+            // Imported records keep their own lifetime.
+            if call.created_at.is_none() {
+                call.start_lifetime(now);
+            }
+            let item = self.call_item_to_write("add_call_records", &call)?;
+            requests.push(
+                WriteRequest::builder()
+                    .put_request(PutRequest::builder().set_item(Some(item)).build())
+                    .build(),
+            );
+        }
+
+        for chunk in requests.chunks(BATCH_WRITE_MAX_ITEMS) {
+            let mut pending = chunk.to_vec();
+            let mut backoff = BATCH_WRITE_INITIAL_BACKOFF;
+
+            for attempt in 1.. {
+                let permit = self.request_permit("add_call_records").await?;
+                let request = self
+                    .client
+                    .batch_write_item()
+                    .request_items(&self.table_name, pending)
+                    .return_consumed_capacity(ReturnConsumedCapacity::Total)
+                    .send();
+                let response = self
+                    .within_deadline("add_call_records", request)
+                    .await?
+                    .map_err(|err| {
+                        self.log_error(
+                            "add_call_records",
+                            request_error(
+                                err,
+                                "failed to batch_write_item to storage for add_call_records",
+                            ),
+                        )
+                    })?;
+                drop(permit);
+
+                self.report_consumed_capacity(
+                    "add_call_records",
+                    response.consumed_capacity().unwrap_or_default(),
+                );
+
+                // Items are left unprocessed when the table is throttled, so they are
+                // resent after a wait.
+                pending = response
+                    .unprocessed_items()
+                    .and_then(|unprocessed| unprocessed.get(&self.table_name))
+                    .cloned()
+                    .unwrap_or_default();
+                if pending.is_empty() {
+                    break;
+                }
+                if attempt == BATCH_WRITE_MAX_ATTEMPTS {
+                    return Err(self.log_error(
+                        "add_call_records",
+                        StorageError::UnexpectedError(anyhow!(
+                            "{} items were still unprocessed after {} attempts",
+                            pending.len(),
+                            attempt
+                        )),
+                    ));
+                }
+
+                event!(
+                    "calling.frontend.storage.add_call_records.unprocessed",
+                    pending.len()
+                );
+                // There is no use waiting to resend if the caller is gone by then.
+                if current_deadline().map_or(false, |deadline| {
+                    tokio::time::Instant::now() + std::time::Duration::from(backoff) >= deadline
+                }) {
+                    return Err(self.log_error("add_call_records", StorageError::DeadlineExceeded));
+                }
+                tokio::time::sleep(backoff.into()).await;
+                backoff = backoff * 2;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        let _permit = self.request_permit("heartbeat_call").await?;
+        let request = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(GROUP_CONFERENCE_ID_STRING, self.key(group_id.as_ref()))
+            .update_expression(format!("SET lastHeartbeatAt = :now, {}", INCREMENT_VERSION))
+            .condition_expression("jvbConferenceId = :value".to_string())
+            .expression_attribute_names("#version", "version")
+            .expression_attribute_values(":zero", AttributeValue::N("0".to_string()))
+            .expression_attribute_values(":one", AttributeValue::N("1".to_string()))
+            .expression_attribute_values(
+                ":value".to_string(),
+                AttributeValue::S(call_id.to_string()),
+            )
+            .expression_attribute_values(
+                ":now".to_string(),
+                AttributeValue::N(self.clock.now_secs().to_string()),
+            )
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send();
+        let response = self.within_deadline("heartbeat_call", request).await?;
+
+        match response {
+            Ok(response) => {
+                self.report_consumed_capacity("heartbeat_call", response.consumed_capacity());
+                Ok(true)
+            }
+            Err(SdkError::ServiceError { err: e, raw: _ })
+                if e.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(self.log_error(
+                "heartbeat_call",
+                request_error(err, "failed to update_item in storage for heartbeat_call"),
+            )),
+        }
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        // Unlocking removes the attributes, since records without them are unlocked.
+        let update_expression = match (locked, &locked_by) {
+            (true, Some(_)) => format!(
+                "SET locked = :locked, lockedBy = :locked_by, {}",
+                INCREMENT_VERSION
+            ),
+            (true, None) => format!(
+                "SET locked = :locked, {} REMOVE lockedBy",
+                INCREMENT_VERSION
+            ),
+            (false, _) => format!("SET {} REMOVE locked, lockedBy", INCREMENT_VERSION),
+        };
+
+        let request = self
+            .client
+            .update_item()
+            .table_name(&self.table_name)
+            .key(GROUP_CONFERENCE_ID_STRING, self.key(group_id.as_ref()))
+            .update_expression(update_expression)
+            .condition_expression("jvbConferenceId = :value".to_string())
+            .expression_attribute_names("#version".to_string(), "version".to_string())
+            .expression_attribute_values(
+                ":value".to_string(),
+                AttributeValue::S(call_id.to_string()),
+            )
+            .expression_attribute_values(":zero".to_string(), AttributeValue::N("0".to_string()))
+            .expression_attribute_values(":one".to_string(), AttributeValue::N("1".to_string()));
+        let locked_by = match locked_by {
+            Some(locked_by) => Some(
+                self.codec
+                    .encode_locked_by(&locked_by)
+                    .map_err(|err| self.log_error("set_call_locked", err.into()))?,
+            ),
+            None => None,
+        };
+        let request = match (locked, locked_by) {
+            (true, Some(locked_by)) => request
+                .expression_attribute_values(":locked".to_string(), AttributeValue::Bool(true))
+                .expression_attribute_values(
+                    ":locked_by".to_string(),
+                    AttributeValue::S(locked_by),
+                ),
+            (true, None) => request
+                .expression_attribute_values(":locked".to_string(), AttributeValue::Bool(true)),
+            (false, _) => request,
+        };
+
+        let _permit = self.request_permit("set_call_locked").await?;
+        let request = request
+            .return_consumed_capacity(ReturnConsumedCapacity::Total)
+            .send();
+        let response = self.within_deadline("set_call_locked", request).await?;
+
+        match response {
+            Ok(response) => {
+                self.report_consumed_capacity("set_call_locked", response.consumed_capacity());
+                Ok(true)
+            }
+            Err(SdkError::ServiceError { err: e, raw: _ })
+                if e.is_conditional_check_failed_exception() =>
+            {
+                Ok(false)
+            }
+            Err(err) => Err(self.log_error(
+                "set_call_locked",
+                request_error(err, "failed to update_item in storage for set_call_locked"),
+            )),
+        }
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        // The same condition selects the calls to reap and guards their removal, so that
+        // a call that sends a heartbeat in between isn't removed.
+        const DEAD_CONDITION: &str = "lastHeartbeatAt < :threshold OR \
+             (attribute_not_exists(lastHeartbeatAt) AND createdAt < :threshold)";
+
+        let threshold = AttributeValue::N(
+            self.clock
+                .now_secs()
+                .saturating_sub(max_silence.as_secs())
+                .saturating_sub(self.clock_skew_tolerance.as_secs())
+                .to_string(),
+        );
+
+        // Dead calls can be in any region, so the whole table is scanned.
+        let scan = self
+            .prefixed_scan(
+                self.client
+                    .scan()
+                    .table_name(&self.table_name)
+                    .set_limit(self.page_size),
+                Some(DEAD_CONDITION),
+            )
+            .expression_attribute_values(":threshold".to_string(), threshold.clone())
+            .projection_expression("groupConferenceId, jvbConferenceId".to_string());
+        let mut items = page_items(
+            paged(
+                self.request_permits("reap_dead_calls"),
+                move |start_key| scan.clone().set_exclusive_start_key(start_key).send(),
+                ScanOutput::last_evaluated_key,
+                |err| {
+                    self.log_error(
+                        "reap_dead_calls",
+                        request_error(err, "failed to scan for dead calls"),
+                    )
+                },
+            ),
+            |page| page.items,
+        );
+
+        let mut reaped = vec![];
+        while let Some(item) = items.next().await {
+            let item = item?;
+            let (key, call_id) = match (
+                item.get(GROUP_CONFERENCE_ID_STRING)
+                    .and_then(|v| v.as_s().ok()),
+                item.get("jvbConferenceId").and_then(|v| v.as_s().ok()),
+            ) {
+                (Some(key), Some(call_id)) => (key.clone(), call_id.clone()),
+                _ => continue,
+            };
+            let group_id = self.group_id(&key);
+            if dry_run {
+                reaped.push(group_id);
+                continue;
+            }
+
+            if self
+                .delete_call_item(
+                    "reap_dead_calls",
+                    AttributeValue::S(key),
+                    Some(&format!(
+                        "jvbConferenceId = :value AND ({})",
+                        DEAD_CONDITION
+                    )),
+                    HashMap::from([
+                        (":value".to_string(), AttributeValue::S(call_id)),
+                        (":threshold".to_string(), threshold.clone()),
+                    ]),
+                )
+                .await?
+                .is_some()
+            {
+                reaped.push(group_id);
+            }
+        }
+
+        if dry_run {
+            event!(
+                "calling.frontend.storage.reap_dead_calls.would_reap",
+                reaped.len()
+            );
+        } else if !reaped.is_empty() {
+            event!(
+                "calling.frontend.storage.reap_dead_calls.reaped",
+                reaped.len()
+            );
+        }
+        Ok(reaped)
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.export_segments(self.scan_segments, self.scan_concurrency)
+    }
+}
+
+/// The share of the remaining lifetime of the identity token after which it is fetched
+/// again, when its expiry is known.
+const IDENTITY_REFRESH_LIFETIME_FRACTION: std::ops::RangeInclusive<f64> = 0.5..=0.75;
+
+/// The shortest wait between identity fetches based on the expiry of the token, so that a
+/// token that is about to expire, or already has, isn't fetched in a tight loop.
+const MIN_IDENTITY_FETCH_DELAY: Duration = Duration::from_secs(5);
+
+/// A handle for observing whether the identity fetcher is keeping the identity token
+/// fresh, for readiness checks. Clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct IdentityReadiness {
+    unhealthy: Arc<AtomicBool>,
+}
+
+impl IdentityReadiness {
+    /// Returns false after too many identity fetches failed in a row, until the next one
+    /// succeeds.
+    pub fn is_healthy(&self) -> bool {
+        !self.unhealthy.load(Ordering::Relaxed)
+    }
+}
+
+/// A successful refresh of the identity token file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenRefresh {
+    pub refreshed_at: SystemTime,
+    /// When the token expires, if it is a JWT with an exp claim.
+    pub expires_at: Option<SystemTime>,
+}
+
+/// A handle for following the refreshes of the identity token, for consumers that keep
+/// their own copy of it. Each subscriber sees every refresh that happens after it last
+/// looked, independently of the others.
+#[derive(Clone, Debug)]
+pub struct IdentityFreshness {
+    refreshed: watch::Receiver<Option<TokenRefresh>>,
+}
+
+impl IdentityFreshness {
+    /// Returns the latest refresh, or None if the token hasn't been refreshed yet.
+    pub fn latest(&self) -> Option<TokenRefresh> {
+        *self.refreshed.borrow()
+    }
+
+    /// Returns the expiry of the latest token, if it is known.
+    pub fn latest_expiry(&self) -> Option<SystemTime> {
+        self.latest().and_then(|refresh| refresh.expires_at)
+    }
+
+    /// Waits for the next refresh and returns it, or returns None once the fetcher has
+    /// been dropped.
+    pub async fn changed(&mut self) -> Option<TokenRefresh> {
+        self.refreshed.changed().await.ok()?;
+        *self.refreshed.borrow()
+    }
+}
+
+/// Creates the client that IdentityFetcher fetches with. hyper closes pooled connections
+/// that are idle for longer than 90 seconds by default, which is less than the interval
+/// between fetches, so every fetch would pay for a new connection without a longer
+/// pool_idle_timeout_ms. Either setting can be 0 to turn it off.
+fn create_identity_client(
+    pool_idle_timeout_ms: u64,
+    tcp_keepalive_ms: u64,
+) -> hyper::Client<HttpConnector> {
+    let mut connector = HttpConnector::new();
+    if tcp_keepalive_ms > 0 {
+        connector.set_keepalive(Some(std::time::Duration::from_millis(tcp_keepalive_ms)));
+    }
+    let mut builder = hyper::client::Client::builder();
+    if pool_idle_timeout_ms > 0 {
+        builder.pool_idle_timeout(std::time::Duration::from_millis(pool_idle_timeout_ms));
+    } else {
+        builder.pool_max_idle_per_host(0);
+    }
+    builder.build(connector)
+}
+
+/// Supports the DynamoDB storage implementation by periodically refreshing an identity
+/// token file at the location given by `identity_token_path`.
+pub struct IdentityFetcher {
+    client: hyper::Client<HttpConnector>,
+    fetch_interval: Duration,
+    fetch_timeout: Duration,
+    identity_token_path: PathBuf,
+    identity_source: config::IdentitySource,
+    /// Headers sent with gcp-metadata fetches, replacing the defaults of the same name.
+    fetch_headers: Vec<config::NameValue>,
+    /// Query parameters added to the gcp-metadata URL, replacing any of the same name.
+    fetch_query_params: Vec<config::NameValue>,
+    /// How many fetches may fail in a row before the fetcher is unhealthy, or 0 to never
+    /// become unhealthy.
+    max_consecutive_failures: u32,
+    /// Whether the fetcher gives up, ending start(), once it is unhealthy.
+    exit_when_unhealthy: bool,
+    consecutive_failures: AtomicU32,
+    readiness: IdentityReadiness,
+    refreshed: watch::Sender<Option<TokenRefresh>>,
+}
+
+impl IdentityFetcher {
+    fn new(config: &'static config::Config, identity_token_path: PathBuf) -> Self {
+        IdentityFetcher {
+            client: create_identity_client(
+                config.identity_fetch_pool_idle_timeout_ms,
+                config.identity_fetch_tcp_keepalive_ms,
+            ),
+            fetch_interval: Duration::from_millis(config.identity_fetcher_interval_ms),
+            fetch_timeout: Duration::from_millis(config.identity_fetch_timeout_ms),
+            identity_token_path,
+            identity_source: config.identity_source.clone(),
+            fetch_headers: config.identity_fetch_headers.clone(),
+            fetch_query_params: config.identity_fetch_query_params.clone(),
+            max_consecutive_failures: config.identity_fetch_max_failures,
+            exit_when_unhealthy: config.identity_fetch_exit_when_unhealthy,
+            consecutive_failures: AtomicU32::new(0),
+            readiness: IdentityReadiness::default(),
+            refreshed: watch::channel(None).0,
+        }
+    }
+
+    /// Returns a handle for observing the health of this fetcher after it is started.
+    pub fn readiness(&self) -> IdentityReadiness {
+        self.readiness.clone()
+    }
+
+    /// Returns a handle that is notified each time this fetcher writes a new token.
+    pub fn subscribe(&self) -> IdentityFreshness {
+        IdentityFreshness {
+            refreshed: self.refreshed.subscribe(),
+        }
+    }
+
+    /// Returns how long to wait before the next fetch. When the expiry of the latest token
+    /// is known, the fetch happens once a random 50-75% of its remaining lifetime has
+    /// passed, so that fetches are only as frequent as the token needs and instances
+    /// spread out. Otherwise the fetch_interval is used.
+    fn next_fetch_delay(&self, now: SystemTime) -> Duration {
+        let latest = *self.refreshed.borrow();
+        let expires_at = match latest.and_then(|refresh| refresh.expires_at) {
+            Some(expires_at) => expires_at,
+            None => return self.fetch_interval,
+        };
+        let remaining = expires_at.duration_since(now).unwrap_or_default();
+        let fraction = rand::thread_rng().gen_range(IDENTITY_REFRESH_LIFETIME_FRACTION);
+        std::cmp::max(
+            Duration::from_secs_f64(remaining.as_secs_f64() * fraction),
+            MIN_IDENTITY_FETCH_DELAY,
+        )
+    }
+
+    /// Fetches a token, keeping count of consecutive failures. After
+    /// max_consecutive_failures of them the fetcher is marked unhealthy, until a fetch
+    /// succeeds again. Returns true if the fetcher should give up.
+    async fn fetch_token_and_update_health(&self) -> bool {
+        match self.fetch_token().await {
+            Ok(()) => {
+                self.consecutive_failures.store(0, Ordering::Relaxed);
+                if self.readiness.unhealthy.swap(false, Ordering::Relaxed) {
+                    info!("identity token fetching recovered");
+                }
+                false
+            }
+            Err(e) => {
+                event!("calling.frontend.identity_fetcher.error");
+                error!("Failed to fetch identity token : {:?}", e);
+
+                let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if self.max_consecutive_failures == 0 || failures < self.max_consecutive_failures {
+                    return false;
+                }
+                if !self.readiness.unhealthy.swap(true, Ordering::Relaxed) {
+                    event!("calling.frontend.identity_fetcher.unhealthy");
+                    error!(
+                        "identity token fetching is unhealthy after {} consecutive failures",
+                        failures
+                    );
+                }
+                self.exit_when_unhealthy
+            }
+        }
+    }
+
+    async fn fetch_token(&self) -> Result<()> {
+        // Failures are counted by stage so that network problems can be told apart from
+        // bad tokens and disk problems. Reading a file source isn't a network problem, so
+        // it has a stage of its own.
+        let count_fetch_failure = || match self.identity_source {
+            config::IdentitySource::File { .. } => {
+                event!("calling.frontend.identity_fetcher.file_read.error");
+            }
+            _ => {
+                event!("calling.frontend.identity_fetcher.fetch_http.error");
+            }
+        };
+        // A hung metadata server counts as a failed fetch so that the next one still
+        // happens on time.
+        let token = match tokio::time::timeout(self.fetch_timeout.into(), self.read_token()).await {
+            Ok(Ok(Some(token))) => token,
+            Ok(Ok(None)) => return Ok(()),
+            Ok(Err(err)) => {
+                count_fetch_failure();
+                return Err(err);
+            }
+            Err(_) => {
+                count_fetch_failure();
+                return Err(anyhow!(
+                    "timed out fetching identity token after {}ms",
+                    self.fetch_timeout.as_millis()
+                ));
+            }
+        };
+
+        if let Err(err) = validate_token(&token) {
+            event!("calling.frontend.identity_fetcher.validate.error");
+            return Err(err);
+        }
+
+        if let Err(err) = self.write_token(&token).await {
+            event!("calling.frontend.identity_fetcher.file_write.error");
+            return Err(err);
+        }
+
+        debug!(
+            "Successfully wrote identity token to {:?}",
+            &self.identity_token_path
+        );
+        // Replaced even without subscribers, so that later ones still see the latest.
+        self.refreshed.send_replace(Some(TokenRefresh {
+            refreshed_at: SystemTime::now(),
+            expires_at: token_expiry(&token),
+        }));
+        Ok(())
+    }
+
+    /// Gets a token from the identity source, or None if there is no token to fetch.
+    /// Reading from a file source counts as fetching it.
+    async fn read_token(&self) -> Result<Option<Vec<u8>>> {
+        let token = match &self.identity_source {
+            // The DynamoDB client gets aws-imds credentials from the instance metadata
+            // service itself, so there is no token file to keep fresh.
+            config::IdentitySource::Disabled | config::IdentitySource::AwsImds => return Ok(None),
+            config::IdentitySource::GcpMetadata { url } => {
+                let url = with_query_params(url, &self.fetch_query_params);
+                let mut request = Request::builder()
+                    .method(Method::GET)
+                    .uri(&url)
+                    .header("Metadata-Flavor", "Google")
+                    .body(Body::empty())?;
+                for header in &self.fetch_headers {
+                    request.headers_mut().insert(
+                        HeaderName::from_bytes(header.name.as_bytes())?,
+                        HeaderValue::from_str(&header.value)?,
+                    );
+                }
+
+                debug!("Fetching identity token from {}", url);
+
+                self.request_body(request).await?
+            }
+            config::IdentitySource::File { path } => {
+                debug!("Reading identity token from {}", path);
+
+                tokio::fs::read(path).await?
+            }
+        };
+        Ok(Some(token))
+    }
+
+    /// Sends the request and returns the body of a successful response.
+    async fn request_body(&self, request: Request<Body>) -> Result<Vec<u8>> {
+        let response = self.client.request(request).await?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "identity request failed with status {}",
+                response.status()
+            ));
+        }
+        Ok(hyper::body::to_bytes(response).await?.to_vec())
+    }
+
+    /// Writes the token to a temporary file that only the owner can access and then moves
+    /// it into place, so that the token is never exposed or partially written. If any
+    /// step fails, the temporary file is removed rather than left behind.
+    async fn write_token(&self, token: &[u8]) -> Result<()> {
+        let temp_name = self.identity_token_path.with_extension("bak");
+
+        let result: Result<()> = async {
+            let mut options = tokio::fs::OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+
+            let mut temp_file = options.open(&temp_name).await?;
+            // The mode only applies when the file is created, so also restrict any file
+            // that was left behind by a previous run.
+            #[cfg(unix)]
+            temp_file
+                .set_permissions(std::fs::Permissions::from_mode(0o600))
+                .await?;
+            temp_file.write_all(token).await?;
+            temp_file.flush().await?;
+            tokio::fs::rename(&temp_name, &self.identity_token_path).await?;
+            Ok(())
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&temp_name).await;
+        }
+        result
+    }
+
+    pub async fn start(self, ender_rx: Receiver<()>) -> Result<()> {
+        if matches!(
+            self.identity_source,
+            config::IdentitySource::Disabled | config::IdentitySource::AwsImds
+        ) {
+            // There is nothing to fetch, so don't poll and just wait to be ended.
+            info!("identity token fetching is disabled");
+            let _ = ender_rx.await;
+            info!("fetcher shutdown");
+            return Ok(());
+        }
+
+        // Periodically fetch a new web identity from the source.
+        let fetcher_handle = tokio::spawn(async move {
+            loop {
+                // Use sleep() instead of interval() so that we never wait *less* than one
+                // interval to do the next tick.
+                tokio::time::sleep(self.next_fetch_delay(SystemTime::now()).into()).await;
+
+                let timer = start_timer_us!("calling.frontend.identity_fetcher.timed");
+                let give_up = self.fetch_token_and_update_health().await;
+                timer.stop();
+
+                if give_up {
+                    break;
+                }
+            }
+        });
+
+        info!("fetcher ready");
+
+        // Wait for any task to complete and cancel the rest. The fetcher task only ends by
+        // giving up, which is reported as an error so that the process exits.
+        let result = tokio::select!(
+            _ = fetcher_handle => Err(anyhow!("gave up fetching identity tokens")),
+            _ = ender_rx => Ok(()),
+        );
+
+        info!("fetcher shutdown");
+        result
+    }
+}
+
+/// Adds the query parameters to the URL, replacing any that it already has with the same
+/// name. The values are expected to already be URL-encoded.
+fn with_query_params(url: &str, params: &[config::NameValue]) -> String {
+    if params.is_empty() {
+        return url.to_string();
+    }
+
+    let (base, query) = url.split_once('?').unwrap_or((url, ""));
+    let kept = query.split('&').filter(|pair| {
+        let name = pair.split_once('=').map_or(*pair, |(name, _)| name);
+        !pair.is_empty() && !params.iter().any(|param| param.name == name)
+    });
+    let added = params
+        .iter()
+        .map(|param| format!("{}={}", param.name, param.value));
+    let query: Vec<String> = kept.map(str::to_string).chain(added).collect();
+    format!("{}?{}", base, query.join("&"))
+}
+
+/// Returns a call for tests that don't care about most of its fields.
+#[cfg(test)]
+pub(crate) fn create_call_record(group_id: &str, call_id: &str) -> CallRecord {
+    CallRecord {
+        group_id: group_id.into(),
+        call_id: call_id.to_string(),
+        backend_ip: "127.0.0.1".to_string(),
+        backend_region: "us-west1".to_string(),
+        creator: "1111111111111111".to_string(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod storage_tests {
+    use super::*;
+
+    use crate::test_logging;
+
+    use aws_smithy_client::{erase::DynConnector, test_connection::TestConnection};
+    use aws_smithy_http::{body::SdkBody, result::ConnectorError};
+    use aws_smithy_types::retry::RetryConfig;
+    use serde_dynamo::to_item;
+
+    const CONDITIONAL_CHECK_FAILED_RESPONSE: &str = r#"{"__type":"com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException","message":"The conditional request failed"}"#;
+    const GET_ITEM_RESPONSE: &str = r#"{"Item":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"2222222222222222"}}}"#;
+
+    fn create_identity_fetcher(identity_token_path: PathBuf) -> IdentityFetcher {
+        IdentityFetcher {
+            client: hyper::client::Client::builder().build_http(),
+            fetch_interval: Duration::from_millis(1000),
+            fetch_timeout: Duration::from_millis(1000),
+            identity_token_path,
+            identity_source: config::IdentitySource::Disabled,
+            fetch_headers: vec![],
+            fetch_query_params: vec![],
+            max_consecutive_failures: 3,
+            exit_when_unhealthy: false,
+            consecutive_failures: AtomicU32::new(0),
+            readiness: IdentityReadiness::default(),
+            refreshed: watch::channel(None).0,
+        }
+    }
+
+    /// Serves each request with the response that the handler returns for it, and returns
+    /// the base URL of the server.
+    fn serve_metadata(
+        handler: impl Fn(hyper::Request<Body>) -> hyper::Response<Body> + Clone + Send + Sync + 'static,
+    ) -> String {
+        serve_metadata_counting_connections(handler).0
+    }
+
+    /// Like serve_metadata, but also returns the number of connections that have been
+    /// accepted.
+    fn serve_metadata_counting_connections(
+        handler: impl Fn(hyper::Request<Body>) -> hyper::Response<Body> + Clone + Send + Sync + 'static,
+    ) -> (String, Arc<AtomicU32>) {
+        let connections = Arc::new(AtomicU32::new(0));
+        let accepted = connections.clone();
+        let make_service = hyper::service::make_service_fn(move |_| {
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let handler = handler.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(hyper::service::service_fn(move |request| {
+                    let response = handler(request);
+                    async move { Ok::<_, std::convert::Infallible>(response) }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (url, connections)
+    }
+
+    /// Creates a DynamoDb instance whose client replays the given (status, body)
+    /// responses in order, along with the connection for inspecting the requests made.
+    fn create_dynamodb(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (DynamoDb, TestConnection<&'static str>) {
+        let connection = TestConnection::new(
+            responses
+                .into_iter()
+                .map(|(status, body)| {
+                    (
+                        http::Request::builder().body(SdkBody::from("")).unwrap(),
+                        http::Response::builder().status(status).body(body).unwrap(),
+                    )
+                })
+                .collect(),
+        );
+
+        (
+            create_dynamodb_with_connector(DynConnector::new(connection.clone())),
+            connection,
+        )
+    }
+
+    /// Creates a DynamoDb instance whose client answers every request with an empty
+    /// response after a short delay, along with the most requests that were in flight at
+    /// once.
+    fn create_counting_dynamodb() -> (DynamoDb, Arc<AtomicU32>) {
+        let in_flight = Arc::new(AtomicU32::new(0));
+        let max_in_flight = Arc::new(AtomicU32::new(0));
+        let counted = max_in_flight.clone();
+        let connector = tower::service_fn(move |_: http::Request<SdkBody>| {
+            let in_flight = in_flight.clone();
+            let max_in_flight = counted.clone();
+            async move {
+                let now_in_flight = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now_in_flight, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok::<_, ConnectorError>(
+                    http::Response::builder()
+                        .status(200)
+                        .body(SdkBody::from("{}"))
+                        .unwrap(),
+                )
+            }
+        });
+
+        (
+            create_dynamodb_with_connector(DynConnector::new(connector)),
+            max_in_flight,
+        )
+    }
+
+    fn create_dynamodb_with_connector(connector: DynConnector) -> DynamoDb {
+        let aws_config = Config::builder()
+            .credentials_provider(Credentials::from_keys("KEY", "PASSWORD", None))
+            .region(Region::new("us-east-1"))
+            .retry_config(RetryConfig::disabled())
+            .build();
+
+        DynamoDb {
+            client: Client::from_conf_conn(aws_config, connector),
+            table_name: "CallRecords".to_string(),
+            key_prefix: String::new(),
+            region: "us-east-1".to_string(),
+            clock: Arc::new(SystemClock),
+            codec: Arc::new(FieldCodec),
+            region_index_shards: 1,
+            scan_segments: 1,
+            scan_concurrency: 1,
+            page_size: None,
+            max_item_bytes: DYNAMODB_MAX_ITEM_BYTES,
+            clock_skew_tolerance: Duration::ZERO,
+            consistent_reads: true,
+            conflict_read_jitter: Duration::ZERO,
+            jitter_rng: Arc::new(Mutex::new(StdRng::seed_from_u64(0))),
+            strict_region_queries: false,
+            request_permits: None,
+            request_permit_timeout: Duration::ZERO,
+            operation_timeout: None,
+            create_table_allowed: false,
+        }
+    }
+
+    fn create_call_record() -> CallRecord {
+        CallRecord {
+            backup_backends: vec![
+                BackendRef {
+                    region: "us-east4".to_string(),
+                    ip: "127.0.0.2".to_string(),
+                },
+                BackendRef {
+                    region: "us-west1".to_string(),
+                    ip: "127.0.0.3".to_string(),
+                },
+            ],
+            ..super::create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1")
+        }
+    }
+
+    #[test]
+    fn test_backup_backends_serialization() {
+        let call = create_call_record();
+
+        let item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        let backups = item.get("backupBackends").unwrap().as_l().unwrap();
+        assert_eq!(backups.len(), 2);
+        let first = backups[0].as_m().unwrap();
+        assert_eq!(first.get("region").unwrap().as_s().unwrap(), "us-east4");
+        assert_eq!(first.get("ip").unwrap().as_s().unwrap(), "127.0.0.2");
+
+        let round_trip: CallRecord = from_item(item).unwrap();
+        assert_eq!(round_trip, call);
+    }
+
+    #[test]
+    fn test_missing_backup_backends_deserializes_as_empty() {
+        let call = CallRecord {
+            backup_backends: vec![],
+            ..create_call_record()
+        };
+
+        let item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        assert!(!item.contains_key("backupBackends"));
+
+        let round_trip: CallRecord = from_item(item).unwrap();
+        assert!(round_trip.backup_backends.is_empty());
+    }
+
+    #[test]
+    fn test_call_record_metadata_round_trip() {
+        let mut call = create_call_record();
+        call.set_metadata("experiment", "blue").unwrap();
+        call.set_metadata("ringing", "true").unwrap();
+
+        let item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        let metadata = item.get("metadata").unwrap().as_m().unwrap();
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata.get("experiment").unwrap().as_s().unwrap(), "blue");
+
+        let round_trip: CallRecord = from_item(item).unwrap();
+        assert_eq!(round_trip.get_metadata("experiment"), Some("blue"));
+        assert_eq!(round_trip.get_metadata("ringing"), Some("true"));
+        assert_eq!(round_trip.get_metadata("missing"), None);
+        assert_eq!(round_trip, call);
+    }
+
+    #[test]
+    fn test_backend_version_round_trip() {
+        let call = CallRecord {
+            backend_version: Some("1.2.3".to_string()),
+            ..create_call_record()
+        };
+
+        let item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        assert_eq!(item.get("backendVersion").unwrap().as_s().unwrap(), "1.2.3");
+        let round_trip: CallRecord = from_item(item).unwrap();
+        assert_eq!(round_trip, call);
+
+        // Records written before the version was tracked don't have the attribute.
+        let item: std::collections::HashMap<String, AttributeValue> =
+            to_item(&create_call_record()).unwrap();
+        assert!(!item.contains_key("backendVersion"));
+        let round_trip: CallRecord = from_item(item).unwrap();
+        assert_eq!(round_trip.backend_version, None);
+    }
+
+    #[tokio::test]
+    async fn test_missing_metadata_deserializes_as_empty() {
+        let item: std::collections::HashMap<String, AttributeValue> =
+            to_item(&create_call_record()).unwrap();
+        assert!(!item.contains_key("metadata"));
+
+        // Records written before metadata was tracked don't have the attribute.
+        let (storage, _) = create_dynamodb(vec![(200, GET_ITEM_RESPONSE)]);
+        let call = storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(call.metadata.is_empty());
+        assert_eq!(call.metadata_size(), 0);
+    }
+
+    #[test]
+    fn test_set_metadata_size_guard() {
+        let mut call = create_call_record();
+        let value = "x".repeat(MAX_CALL_METADATA_BYTES - 3);
+        call.set_metadata("big", value.clone()).unwrap();
+        assert_eq!(call.metadata_size(), MAX_CALL_METADATA_BYTES);
+
+        // Anything more is refused, and leaves the metadata as it was.
+        assert!(matches!(
+            call.set_metadata("a", ""),
+            Err(StorageError::MetadataTooLarge { size, limit: MAX_CALL_METADATA_BYTES })
+                if size == MAX_CALL_METADATA_BYTES + 1
+        ));
+        assert!(call.set_metadata("big", value.clone() + "x").is_err());
+        assert_eq!(call.get_metadata("big"), Some(value.as_str()));
+        assert_eq!(call.get_metadata("a"), None);
+
+        // Replacing a value only counts the new one.
+        call.set_metadata("big", "small").unwrap();
+        assert_eq!(call.metadata_size(), 8);
+        call.set_metadata("a", "b").unwrap();
+        assert_eq!(call.metadata_size(), 10);
+    }
+
+    #[tokio::test]
+    async fn test_get_or_add_conditional_failed_event() {
+        const EVENT: &str = "calling.frontend.storage.get_or_add.conditional_failed";
+
+        let (storage, _) = create_dynamodb(vec![
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, GET_ITEM_RESPONSE),
+        ]);
+        let before = metrics!().peek_event_count(EVENT);
+
+        let call = storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The record that won the race is returned.
+        assert_eq!(call.call_id, "b2b2b2b2");
+        assert!(metrics!().peek_event_count(EVENT) > before);
+    }
+
+    #[tokio::test]
+    async fn test_remove_conditional_failed_event() {
+        const EVENT: &str = "calling.frontend.storage.remove.conditional_failed";
+
+        let (storage, _) =
+            create_dynamodb(vec![(400, CONDITIONAL_CHECK_FAILED_RESPONSE), (200, "{}")]);
+        let before = metrics!().peek_event_count(EVENT);
+
+        let call = create_call_record();
+        assert_eq!(
+            storage
+                .remove_call_record(&call.group_id, &call.call_id)
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert!(metrics!().peek_event_count(EVENT) > before);
+    }
+
+    #[tokio::test]
+    async fn test_remove_call_record_outcomes() {
+        const DELETE_ITEM_RESPONSE: &str = r#"{"Attributes":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}}"#;
+
+        let call = create_call_record();
+
+        let (storage, connection) = create_dynamodb(vec![(200, DELETE_ITEM_RESPONSE)]);
+        let removed = storage
+            .remove_call_record(&call.group_id, &call.call_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed.group_id, call.group_id);
+        assert_eq!(removed.call_id, call.call_id);
+        assert_eq!(removed.backend_ip, "127.0.0.1");
+
+        let body: serde_json::Value =
+            serde_json::from_slice(connection.requests()[0].actual.body().bytes().unwrap())
+                .unwrap();
+        assert_eq!(body["ReturnValues"], "ALL_OLD");
+        assert_eq!(
+            body["ConditionExpression"],
+            "(jvbConferenceId = :value) AND attribute_not_exists(reservedRegion)"
+        );
+
+        // The conditional not matching, and the call read back not having reserved
+        // capacity, means nothing was removed.
+        let (storage, _) = create_dynamodb(vec![
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, GET_ITEM_RESPONSE),
+        ]);
+        assert!(storage
+            .remove_call_record(&call.group_id, "b2b2b2b2")
+            .await
+            .unwrap()
+            .is_none());
+
+        // Any service error other than the conditional check is not masked.
+        let (storage, _) = create_dynamodb(vec![(
+            400,
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#ResourceNotFoundException","message":"Requested resource not found"}"#,
+        )]);
+        assert!(storage
+            .remove_call_record(&call.group_id, &call.call_id)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remove_call_record_reports_call_duration() {
+        const HISTOGRAM: &str = "calling.frontend.call_duration_ms";
+        // Created at 1000 seconds since the Unix epoch.
+        const DELETE_ITEM_RESPONSE: &str = r#"{"Attributes":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"},"createdAt":{"N":"1000"}}}"#;
+        const LEGACY_DELETE_ITEM_RESPONSE: &str = r#"{"Attributes":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}}"#;
+        // An elapsed time that no other test reports.
+        const ELAPSED_MS: usize = 4_321_987;
+
+        let samples = |value| {
+            metrics!()
+                .peek_histogram_samples(HISTOGRAM)
+                .into_iter()
+                .find(|(sample, _)| *sample == value)
+                .map_or(0, |(_, count)| count)
+        };
+        let call = create_call_record();
+        let clock = Arc::new(MockClock::from_secs(1000));
+        clock.advance(std::time::Duration::from_millis(ELAPSED_MS as u64));
+
+        let (storage, _) = create_dynamodb(vec![(200, DELETE_ITEM_RESPONSE)]);
+        let storage = DynamoDb {
+            clock: clock.clone(),
+            ..storage
+        };
+        let before = samples(ELAPSED_MS);
+        assert!(storage
+            .remove_call_record(&call.group_id, &call.call_id)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(samples(ELAPSED_MS), before + 1);
+
+        // Records without created_at have no duration, but are still removed.
+        let (storage, _) = create_dynamodb(vec![(200, LEGACY_DELETE_ITEM_RESPONSE)]);
+        let storage = DynamoDb { clock, ..storage };
+        let removed = storage
+            .remove_call_record(&call.group_id, &call.call_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed.created_at, None);
+    }
+
+    #[tokio::test]
+    async fn test_remove_call_record_best_effort() {
+        const EVENT: &str = "calling.frontend.storage.remove_best_effort.success";
+        const THROTTLED_RESPONSE: &str = r#"{"__type":"com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException","message":"The level of configured provisioned throughput for the table was exceeded"}"#;
+
+        let call = create_call_record();
+        let (storage, connection) = create_dynamodb(vec![(400, THROTTLED_RESPONSE), (200, "{}")]);
+        let before = metrics!().peek_event_count(EVENT);
+
+        storage.remove_call_record_best_effort(&call.group_id, &call.call_id);
+        // Nothing was sent yet, since the removal only runs once this task yields.
+        assert!(connection.requests().is_empty());
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while metrics!().peek_event_count(EVENT) == before {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the removal should eventually succeed");
+
+        // The throttled delete was retried.
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 2);
+        for request in requests.iter() {
+            let body: serde_json::Value =
+                serde_json::from_slice(request.actual.body().bytes().unwrap()).unwrap();
+            assert_eq!(body["Key"]["groupConferenceId"]["S"], "aaaaaaaaaaaaaaaa");
+            assert_eq!(body["ExpressionAttributeValues"][":value"]["S"], "a1a1a1a1");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_best_effort_removal_logs_correlation_id() {
+        const THROTTLED_RESPONSE: &str = r#"{"__type":"com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException","message":"The level of configured provisioned throughput for the table was exceeded"}"#;
+
+        let call = create_call_record();
+        let (storage, connection) = create_dynamodb(vec![(400, THROTTLED_RESPONSE), (200, "{}")]);
+
+        test_logging::start_capture();
+        with_correlation_id("request-1234".to_string(), async {
+            storage.remove_call_record_best_effort(&call.group_id, &call.call_id);
+        })
+        .await;
+
+        // The retry is logged before it is sent.
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while connection.requests().len() < 2 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the removal should be retried");
+
+        // The removal ran in a task of its own, and still logged the caller's id.
+        let logged = test_logging::captured(log::Level::Debug);
+        assert_eq!(logged.len(), 1, "{:?}", logged);
+        assert!(
+            logged[0].starts_with("[request-1234] retrying best-effort removal of call a1a1a1"),
+            "{}",
+            logged[0]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_force_remove_call_record() {
+        const DELETE_ITEM_RESPONSE: &str = r#"{"Attributes":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"2222222222222222"}}}"#;
+
+        let call = create_call_record();
+
+        // The stored call has a different call_id, and is removed anyway.
+        let (storage, connection) = create_dynamodb(vec![(200, DELETE_ITEM_RESPONSE), (200, "{}")]);
+        let removed = storage
+            .force_remove_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed.group_id, call.group_id);
+        assert_eq!(removed.call_id, "b2b2b2b2");
+
+        let body: serde_json::Value =
+            serde_json::from_slice(connection.requests()[0].actual.body().bytes().unwrap())
+                .unwrap();
+        assert_eq!(body["Key"]["groupConferenceId"]["S"], "aaaaaaaaaaaaaaaa");
+        // The only condition is the one that leaves calls with a reservation to a
+        // transaction.
+        assert_eq!(
+            body["ConditionExpression"],
+            "attribute_not_exists(reservedRegion)"
+        );
+        assert_eq!(body["ReturnValues"], "ALL_OLD");
+
+        // Nothing to remove.
+        assert!(storage
+            .force_remove_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_request_error_includes_request_ids() {
+        let service_error = |headers: &[(&str, &str)]| {
+            let mut response = http::Response::builder().status(500);
+            for (name, value) in headers {
+                response = response.header(*name, *value);
+            }
+            SdkError::ServiceError {
+                err: aws_sdk_dynamodb::error::GetItemError::generic(
+                    aws_smithy_types::Error::builder()
+                        .code("InternalServerError")
+                        .build(),
+                ),
+                raw: aws_smithy_http::operation::Response::new(
+                    response.body(SdkBody::from("")).unwrap(),
+                ),
+            }
+        };
+
+        let err = request_error(
+            service_error(&[("x-amzn-RequestId", "ABCDEF123")]),
+            "failed to get_item from storage",
+        );
+        let record = storage_error_log_record("get_call_record", "CallRecords", &err);
+        let record: serde_json::Value = serde_json::from_str(&record).unwrap();
+        assert!(record["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("failed to get_item from storage: request id ABCDEF123"));
+
+        let err = request_error(
+            service_error(&[("x-amzn-RequestId", "ABCDEF123"), ("x-amz-id-2", "XYZ789")]),
+            "failed to get_item from storage",
+        );
+        assert!(format!("{:#}", anyhow::Error::from(err))
+            .contains("request id ABCDEF123, extended request id XYZ789"));
+
+        // Without a response from AWS, there is no request id to add.
+        let err = request_error(service_error(&[]), "failed to get_item from storage");
+        assert!(!format!("{:#}", anyhow::Error::from(err)).contains("request id"));
+    }
+
+    #[test]
+    fn test_storage_error_log_record() {
+        let err = StorageError::UnexpectedError(
+            anyhow!("connection reset").context("failed to get_item from storage"),
+        );
+
+        let record = storage_error_log_record("get_call_record", "CallRecords", &err);
+        assert!(!record.contains('\n'));
+
+        let record: serde_json::Value = serde_json::from_str(&record).unwrap();
+        assert_eq!(record["operation"], "get_call_record");
+        assert_eq!(record["table"], "CallRecords");
+        assert_eq!(record["error_kind"], "unexpected");
+        assert_eq!(
+            record["message"],
+            "failed to get_item from storage: connection reset"
+        );
+        assert!(record["correlation_id"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_storage_error_log_record_includes_correlation_id() {
+        let (storage, _) = create_dynamodb(vec![(
+            500,
+            r#"{"__type":"com.amazon.coral.service#InternalFailure"}"#,
+        )]);
+
+        test_logging::start_capture();
+        let result = with_correlation_id(
+            "request-1234".to_string(),
+            storage.get_call_record(&"aaaaaaaaaaaaaaaa".into()),
+        )
+        .await;
+        assert!(result.is_err());
+
+        // The failure is logged once, as a record with the caller's id.
+        let logged = test_logging::captured(log::Level::Error);
+        assert_eq!(logged.len(), 1, "{:?}", logged);
+        let record: serde_json::Value = serde_json::from_str(&logged[0]).unwrap();
+        assert_eq!(record["operation"], "get_call_record");
+        assert_eq!(record["correlation_id"], "request-1234");
+
+        // The id doesn't leak out of the scope.
+        assert_eq!(current_correlation_id(), None);
+    }
+
+    #[tokio::test]
+    async fn test_create_call_reserving_capacity() {
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+
+        let call = create_call_record();
+        let created = storage
+            .create_call_reserving_capacity(call.clone(), 100)
+            .await
+            .unwrap();
+        assert_eq!(created.call_id, call.call_id);
+        assert!(created.created_at.is_some());
+
+        // Both the put and the update are sent together.
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        let items = body["TransactItems"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0]["Put"]["Item"]["groupConferenceId"]["S"],
+            "aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(
+            items[1]["Update"]["Key"]["groupConferenceId"]["S"],
+            "regionCapacity#us-west1"
+        );
+        assert_eq!(
+            items[1]["Update"]["ExpressionAttributeValues"][":max"]["N"],
+            "100"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_call_reserving_capacity_already_exists() {
+        let (storage, _) = create_dynamodb(vec![(
+            400,
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#TransactionCanceledException","message":"Transaction cancelled, please refer cancellation reasons for specific reasons [ConditionalCheckFailed, None]","CancellationReasons":[{"Code":"ConditionalCheckFailed","Message":"The conditional request failed"},{"Code":"None"}]}"#,
+        )]);
+
+        assert!(matches!(
+            storage
+                .create_call_reserving_capacity(create_call_record(), 100)
+                .await,
+            Err(StorageError::CallAlreadyExists)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_call_reserving_capacity_region_full() {
+        let (storage, _) = create_dynamodb(vec![(
+            400,
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#TransactionCanceledException","message":"Transaction cancelled, please refer cancellation reasons for specific reasons [None, ConditionalCheckFailed]","CancellationReasons":[{"Code":"None"},{"Code":"ConditionalCheckFailed","Message":"The conditional request failed"}]}"#,
+        )]);
+
+        match storage
+            .create_call_reserving_capacity(create_call_record(), 100)
+            .await
+        {
+            Err(StorageError::RegionFull(region)) => assert_eq!(region, "us-west1"),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_removing_call_releases_reserved_capacity() {
+        const RESERVED_RESPONSE: &str = r#"{"Item":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"},"reservedRegion":{"S":"us-west1"}}}"#;
+
+        // Starting the call's era fails without affecting its creation.
+        let (storage, connection) = create_dynamodb(vec![
+            (200, "{}"),
+            (200, "{}"),
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, RESERVED_RESPONSE),
+            (200, "{}"),
+            (200, "{}"),
+        ]);
+
+        // A region with room for one call can take another once the first is removed.
+        let call = create_call_record();
+        storage
+            .create_call_reserving_capacity(call.clone(), 1)
+            .await
+            .unwrap();
+        let removed = storage
+            .remove_call_record(&call.group_id, &call.call_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed.reserved_region.as_deref(), Some("us-west1"));
+        storage
+            .create_call_reserving_capacity(call, 1)
+            .await
+            .unwrap();
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["TransactItems"][0]["Put"]["Item"]["reservedRegion"]["S"],
+            "us-west1"
+        );
+
+        // The call is deleted in the same transaction that gives back its capacity.
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[4].actual.body().bytes().unwrap()).unwrap();
+        let items = body["TransactItems"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0]["Delete"]["Key"]["groupConferenceId"]["S"],
+            "aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(
+            items[0]["Delete"]["ExpressionAttributeValues"][":reserved_call_id"]["S"],
+            "a1a1a1a1"
+        );
+        assert_eq!(
+            items[1]["Update"]["Key"]["groupConferenceId"]["S"],
+            "regionCapacity#us-west1"
+        );
+        assert_eq!(
+            items[1]["Update"]["UpdateExpression"],
+            "ADD activeCalls :minus_one"
+        );
+        assert_eq!(
+            items[1]["Update"]["ExpressionAttributeValues"][":minus_one"]["N"],
+            "-1"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_identity_token_file_mode() {
+        let path = env::temp_dir().join(format!(
+            "calling_frontend_token_mode_{}",
+            std::process::id()
+        ));
+        let fetcher = create_identity_fetcher(path.clone());
+
+        fetcher.write_token(b"token").await.unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read(&path).unwrap(), b"token");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_lifetime_serialization() {
+        let mut call = create_call_record();
+        call.start_lifetime(1000);
+
+        let item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        assert_eq!(item.get("createdAt").unwrap().as_n().unwrap(), "1000");
+        assert_eq!(
+            item.get("expiresAt").unwrap().as_n().unwrap(),
+            &(1000 + CALL_RECORD_TTL.as_secs()).to_string()
+        );
+
+        let round_trip: CallRecord = from_item(item).unwrap();
+        assert_eq!(round_trip, call);
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_issues_request() {
+        let (storage, connection) = create_dynamodb(vec![(
+            200,
+            r#"{"Table":{"TableName":"CallRecords","TableStatus":"ACTIVE"}}"#,
+        )]);
+
+        storage.warm_up().await;
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].actual.headers().get("x-amz-target").unwrap(),
+            "DynamoDB_20120810.DescribeTable"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warm_up_swallows_errors() {
+        let (storage, connection) = create_dynamodb(vec![(
+            500,
+            r#"{"__type":"com.amazon.coral.service#InternalFailure"}"#,
+        )]);
+
+        storage.warm_up().await;
+
+        assert_eq!(connection.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_default_identity_token_path_is_per_process() {
+        let path = test_identity_token_path(&config::default_test_config());
+        assert!(path.starts_with(env::temp_dir()));
+        assert!(path
+            .to_string_lossy()
+            .ends_with(&std::process::id().to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_configured_identity_token_paths_are_not_shared() {
+        let create = |name: &str| {
+            let config: &'static config::Config = Box::leak(Box::new(config::Config {
+                identity_token_path: Some(
+                    env::temp_dir()
+                        .join(format!("{}_{}", name, std::process::id()))
+                        .to_string_lossy()
+                        .to_string(),
+                ),
+                ..config::default_test_config()
+            }));
+            DynamoDb::new(config, Arc::new(SystemClock), Arc::new(FieldCodec))
+        };
+        let (_, fetcher_a) = create("calling_frontend_token_a").await.unwrap();
+        let (_, fetcher_b) = create("calling_frontend_token_b").await.unwrap();
+        assert_ne!(fetcher_a.identity_token_path, fetcher_b.identity_token_path);
+
+        fetcher_a.write_token(b"token a").await.unwrap();
+        fetcher_b.write_token(b"token b").await.unwrap();
+        assert_eq!(
+            std::fs::read(&fetcher_a.identity_token_path).unwrap(),
+            b"token a"
+        );
+        assert_eq!(
+            std::fs::read(&fetcher_b.identity_token_path).unwrap(),
+            b"token b"
+        );
+
+        let _ = std::fs::remove_file(&fetcher_a.identity_token_path);
+        let _ = std::fs::remove_file(&fetcher_b.identity_token_path);
+    }
+
+    #[tokio::test]
+    async fn test_get_call_records_for_region_compact_codec() {
+        // The creator and preferred region are packed into the record attribute.
+        const QUERY_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"record":{"B":"eyJjcmVhdG9yIjoiMTExMTExMTExMTExMTExMSIsInByZWZlcnJlZFJlZ2lvbiI6InVzLXdlc3QxIn0="}}],"Count":1,"ScannedCount":1}"#;
+
+        let (storage, _) = create_dynamodb(vec![(200, QUERY_RESPONSE)]);
+        let storage = DynamoDb {
+            codec: Arc::new(CompactCodec),
+            ..storage
+        };
+
+        let calls = storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].call_id, "a1a1a1a1");
+        assert_eq!(calls[0].backend_ip, "127.0.0.1");
+        assert_eq!(calls[0].creator, "1111111111111111");
+        assert_eq!(calls[0].preferred_region.as_deref(), Some("us-west1"));
+
+        // Calls written with the field codec are still read.
+        let (storage, _) = create_dynamodb(vec![(200, GET_ITEM_RESPONSE)]);
+        let storage = DynamoDb {
+            codec: Arc::new(CompactCodec),
+            ..storage
+        };
+        let call = storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(call.creator, "2222222222222222");
+    }
+
+    #[tokio::test]
+    async fn test_creator_is_stored_encrypted() {
+        const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+        let codec = EncryptingCodec::new(
+            Arc::new(FieldCodec),
+            Some(Arc::new(LocalKeyEncryption::from_hex_key(KEY).unwrap())),
+        );
+        let storage = DynamoDb {
+            codec: Arc::new(codec),
+            ..storage
+        };
+
+        let call = create_call_record();
+        let added = storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(added.call_id, "a1a1a1a1");
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        let stored_creator = body["Item"]["creator"]["S"].as_str().unwrap();
+        assert!(stored_creator.starts_with("enc1:"), "{}", stored_creator);
+        assert!(!stored_creator.contains(&call.creator));
+        assert_eq!(body["Item"]["jvbHost"]["S"], call.backend_ip);
+
+        // Calls read back from the table have the creator decrypted.
+        let mut item = FieldCodec.encode(&call).unwrap();
+        item.insert(
+            "creator".to_string(),
+            AttributeValue::S(stored_creator.to_string()),
+        );
+        assert_eq!(storage.decode(item).unwrap(), call);
+    }
+
+    #[tokio::test]
+    async fn test_locked_by_is_stored_encrypted() {
+        const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+        let codec = EncryptingCodec::new(
+            Arc::new(FieldCodec),
+            Some(Arc::new(LocalKeyEncryption::from_hex_key(KEY).unwrap())),
+        );
+        let storage = DynamoDb {
+            codec: Arc::new(codec),
+            ..storage
+        };
+
+        let call = create_call_record();
+        assert!(storage
+            .set_call_locked(
+                &call.group_id,
+                &call.call_id,
+                true,
+                Some("3333333333333333".to_string())
+            )
+            .await
+            .unwrap());
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        let stored_locked_by = body["ExpressionAttributeValues"][":locked_by"]["S"]
+            .as_str()
+            .unwrap();
+        assert!(
+            stored_locked_by.starts_with("enc1:"),
+            "{}",
+            stored_locked_by
+        );
+        assert!(!stored_locked_by.contains("3333333333333333"));
+
+        // Calls read back from the table have the locker decrypted.
+        let mut item = FieldCodec.encode(&call).unwrap();
+        item.insert("locked".to_string(), AttributeValue::Bool(true));
+        item.insert(
+            "lockedBy".to_string(),
+            AttributeValue::S(stored_locked_by.to_string()),
+        );
+        assert_eq!(
+            storage.decode(item).unwrap().locked_by.as_deref(),
+            Some("3333333333333333")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_call_records_for_region_with_retry_handles_index_lag() {
+        const EMPTY_QUERY_RESPONSE: &str = r#"{"Items":[],"Count":0,"ScannedCount":0}"#;
+        const QUERY_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":1,"ScannedCount":1}"#;
+
+        let (storage, connection) =
+            create_dynamodb(vec![(200, EMPTY_QUERY_RESPONSE), (200, QUERY_RESPONSE)]);
+
+        let calls = storage
+            .get_call_records_for_region_with_retry(
+                "us-west1",
+                &"aaaaaaaaaaaaaaaa".into(),
+                "a1a1a1a1",
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(connection.requests().len(), 2);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].call_id, "a1a1a1a1");
+    }
+
+    #[tokio::test]
+    async fn test_get_call_records_for_region_with_retry_gives_up_at_deadline() {
+        const EMPTY_QUERY_RESPONSE: &str = r#"{"Items":[],"Count":0,"ScannedCount":0}"#;
+
+        let (storage, _) = create_dynamodb(vec![(200, EMPTY_QUERY_RESPONSE); 10]);
+
+        let calls = storage
+            .get_call_records_for_region_with_retry(
+                "us-west1",
+                &"aaaaaaaaaaaaaaaa".into(),
+                "a1a1a1a1",
+                Duration::from_millis(50),
+            )
+            .await
+            .unwrap();
+
+        assert!(calls.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stream_call_records_for_region_pages() {
+        const FIRST_PAGE_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":1,"ScannedCount":1,"LastEvaluatedKey":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"region":{"S":"us-west1"}}}"#;
+        const SECOND_PAGE_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"2222222222222222"}}],"Count":1,"ScannedCount":1}"#;
+
+        let (storage, connection) = create_dynamodb(vec![
+            (200, FIRST_PAGE_RESPONSE),
+            (200, SECOND_PAGE_RESPONSE),
+        ]);
+
+        let calls: Vec<_> = storage
+            .stream_call_records_for_region("us-west1")
+            .collect()
+            .await;
+        let call_ids: Vec<_> = calls
+            .into_iter()
+            .map(|call| call.unwrap().call_id)
+            .collect();
+
+        assert_eq!(call_ids, vec!["a1a1a1a1", "b2b2b2b2"]);
+        assert_eq!(connection.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_dump_region_to_writer() {
+        let storage = InMemoryStorage::new();
+        let mut calls = vec![];
+        for (group_id, region) in [
+            ("aaaaaaaaaaaaaaaa", "us-west1"),
+            ("bbbbbbbbbbbbbbbb", "us-west1"),
+            ("cccccccccccccccc", "us-east4"),
+        ] {
+            let call = CallRecord {
+                group_id: group_id.into(),
+                backend_region: region.to_string(),
+                ..create_call_record()
+            };
+            calls.push(storage.get_or_add_call_record(call).await.unwrap().unwrap());
+        }
+
+        let mut output = vec![];
+        let count = dump_region_to_writer(&storage, "us-west1", &mut output)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+
+        let output = String::from_utf8(output).unwrap();
+        let mut dumped: Vec<CallRecord> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(dumped.len(), 2);
+        dumped.sort_by(|a, b| a.group_id.as_ref().cmp(b.group_id.as_ref()));
+        assert_eq!(dumped, calls[..2]);
+    }
+
+    #[tokio::test]
+    async fn test_count_calls_per_backend() {
+        const QUERY_RESPONSE: &str = r#"{"Items":[{"jvbHost":{"S":"127.0.0.1"}},{"jvbHost":{"S":"127.0.0.2"}},{"jvbHost":{"S":"127.0.0.1"}}],"Count":3,"ScannedCount":3}"#;
+
+        let (storage, connection) = create_dynamodb(vec![(200, QUERY_RESPONSE)]);
+
+        let counts = storage.count_calls_per_backend("us-west1").await.unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["127.0.0.1"], 2);
+        assert_eq!(counts["127.0.0.2"], 1);
+
+        // Only the backend attribute is requested.
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["ProjectionExpression"], "jvbHost");
+        assert_eq!(body["Select"], "SPECIFIC_ATTRIBUTES");
+    }
+
+    #[tokio::test]
+    async fn test_count_calls_by_backend_version() {
+        const QUERY_RESPONSE: &str = r#"{"Items":[{"backendVersion":{"S":"1.2.3"}},{},{"backendVersion":{"S":"1.3.0-canary"}},{"backendVersion":{"S":"1.2.3"}}],"Count":4,"ScannedCount":4}"#;
+
+        let (storage, connection) = create_dynamodb(vec![(200, QUERY_RESPONSE)]);
+
+        assert_eq!(
+            storage
+                .count_calls_by_backend_version("us-west1")
+                .await
+                .unwrap(),
+            HashMap::from([
+                (Some("1.2.3".to_string()), 2),
+                (Some("1.3.0-canary".to_string()), 1),
+                (None, 1),
+            ])
+        );
+
+        // Only the version attribute is requested.
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["ProjectionExpression"], "backendVersion");
+        assert_eq!(body["Select"], "SPECIFIC_ATTRIBUTES");
+    }
+
+    #[tokio::test]
+    async fn test_count_call_records_for_region() {
+        const FIRST_PAGE: &str = r#"{"Count":3,"ScannedCount":3,"LastEvaluatedKey":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"}}}"#;
+        const LAST_PAGE: &str = r#"{"Count":2,"ScannedCount":2}"#;
+
+        let (storage, connection) = create_dynamodb(vec![(200, FIRST_PAGE), (200, LAST_PAGE)]);
+
+        assert_eq!(
+            storage
+                .count_call_records_for_region("us-west1")
+                .await
+                .unwrap(),
+            5
+        );
+
+        // Only the count is requested, page by page.
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 2);
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["Select"], "COUNT");
+    }
+
+    #[tokio::test]
+    async fn test_consumed_capacity_metric() {
+        const EVENT: &str = "calling.frontend.storage.consumed_wcu";
+
+        let (storage, connection) = create_dynamodb(vec![
+            (
+                200,
+                r#"{"ConsumedCapacity":{"TableName":"CallRecords","CapacityUnits":1.0}}"#,
+            ),
+            (200, r#"{"Attributes":{"era":{"N":"1"}}}"#),
+            (200, "{}"),
+            (
+                200,
+                r#"{"ConsumedCapacity":[{"TableName":"CallRecords","CapacityUnits":2.0},{"TableName":"CallRecords","CapacityUnits":1.5}]}"#,
+            ),
+        ]);
+        let before = metrics!().peek_event_count(EVENT);
+
+        storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap();
+        storage
+            .create_call_reserving_capacity(create_call_record(), 100)
+            .await
+            .unwrap();
+
+        // 1 unit for the put and 4 (3.5 rounded up) for the transaction.
+        assert_eq!(metrics!().peek_event_count(EVENT) - before, 5);
+
+        for request in connection.requests().iter() {
+            let body: serde_json::Value =
+                serde_json::from_slice(request.actual.body().bytes().unwrap()).unwrap();
+            assert_eq!(body["ReturnConsumedCapacity"], "TOTAL");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_call_records_for_region_sorted() {
+        const QUERY_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"cccccccccccccccc"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}},{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}},{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"c3c3c3c3"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}},{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":4,"ScannedCount":4}"#;
+
+        let (storage, _) = create_dynamodb(vec![(200, QUERY_RESPONSE)]);
+
+        let calls: Vec<_> = storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|call| (call.group_id.as_ref().to_string(), call.call_id))
+            .collect();
+        assert_eq!(
+            calls,
+            vec![
+                ("aaaaaaaaaaaaaaaa".to_string(), "a1a1a1a1".to_string()),
+                ("aaaaaaaaaaaaaaaa".to_string(), "b2b2b2b2".to_string()),
+                ("bbbbbbbbbbbbbbbb".to_string(), "c3c3c3c3".to_string()),
+                ("cccccccccccccccc".to_string(), "a1a1a1a1".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_call_records_for_region_skips_malformed_items() {
+        const EVENT: &str = "calling.frontend.storage.malformed_item";
+        const TAGS: &[&str] = &[
+            "storage_region:us-east-1",
+            "operation:get_call_records_for_region",
+        ];
+        // The second item is missing its jvbHost.
+        const QUERY_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}},{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}},{"groupConferenceId":{"S":"cccccccccccccccc"},"jvbConferenceId":{"S":"c3c3c3c3"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":3,"ScannedCount":3}"#;
+
+        let (storage, _) = create_dynamodb(vec![(200, QUERY_RESPONSE), (200, QUERY_RESPONSE)]);
+        let before = metrics!().peek_event_count_with_tags(EVENT, TAGS);
+
+        let calls: Vec<_> = storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|call| call.call_id)
+            .collect();
+        assert_eq!(calls, vec!["a1a1a1a1", "c3c3c3c3"]);
+        assert!(metrics!().peek_event_count_with_tags(EVENT, TAGS) > before);
+
+        // Strict region queries fail instead.
+        let storage = DynamoDb {
+            strict_region_queries: true,
+            ..storage
+        };
+        assert!(matches!(
+            storage.get_call_records_for_region("us-west1").await,
+            Err(StorageError::UnexpectedError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_call_records_for_region_skips_malformed_items() {
+        const EVENT: &str = "calling.frontend.storage.malformed_item";
+        const TAGS: &[&str] = &[
+            "storage_region:us-east-1",
+            "operation:stream_call_records_for_region",
+        ];
+        // The second item is missing its jvbHost.
+        const QUERY_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}},{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}},{"groupConferenceId":{"S":"cccccccccccccccc"},"jvbConferenceId":{"S":"c3c3c3c3"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":3,"ScannedCount":3}"#;
+
+        let (storage, _) = create_dynamodb(vec![(200, QUERY_RESPONSE), (200, QUERY_RESPONSE)]);
+        let before = metrics!().peek_event_count_with_tags(EVENT, TAGS);
+
+        let calls: Vec<_> = storage
+            .stream_call_records_for_region("us-west1")
+            .map(|call| call.unwrap().call_id)
+            .collect()
+            .await;
+        assert_eq!(calls, vec!["a1a1a1a1", "c3c3c3c3"]);
+        assert!(metrics!().peek_event_count_with_tags(EVENT, TAGS) > before);
+
+        // Strict region queries fail instead.
+        let storage = DynamoDb {
+            strict_region_queries: true,
+            ..storage
+        };
+        let results: Vec<_> = storage
+            .stream_call_records_for_region("us-west1")
+            .collect()
+            .await;
+        assert!(matches!(results[1], Err(StorageError::UnexpectedError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_region_index_unavailable() {
+        const EVENT: &str = "calling.frontend.storage.region_index_unavailable";
+        const MISSING_INDEX_RESPONSE: &str = r#"{"__type":"com.amazon.coral.validate#ValidationException","message":"The table does not have the specified index: region-index"}"#;
+        const BACKFILLING_INDEX_RESPONSE: &str = r#"{"__type":"com.amazon.coral.validate#ValidationException","message":"Cannot read from backfilling global secondary index: region-shard-index"}"#;
+        const OTHER_VALIDATION_RESPONSE: &str = r#"{"__type":"com.amazon.coral.validate#ValidationException","message":"Invalid KeyConditionExpression"}"#;
+
+        let (storage, _) = create_dynamodb(vec![(400, MISSING_INDEX_RESPONSE)]);
+        let before = metrics!().peek_event_count_with_tags(
+            EVENT,
+            &[
+                "storage_region:us-east-1",
+                "operation:get_call_records_for_region",
+            ],
+        );
+        let err = storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, StorageError::RegionIndexUnavailable(index) if index == "region-index")
+        );
+        assert_eq!(err.kind(), "region_index_unavailable");
+        assert!(err
+            .to_string()
+            .starts_with("the region-index index is missing or still backfilling"));
+        assert!(
+            metrics!().peek_event_count_with_tags(
+                EVENT,
+                &[
+                    "storage_region:us-east-1",
+                    "operation:get_call_records_for_region",
+                ],
+            ) > before
+        );
+
+        // The sharded index is named when it is the one being backfilled.
+        let (storage, _) = create_dynamodb(vec![
+            (400, BACKFILLING_INDEX_RESPONSE),
+            (400, BACKFILLING_INDEX_RESPONSE),
+        ]);
+        let storage = DynamoDb {
+            region_index_shards: 2,
+            ..storage
+        };
+        assert!(matches!(
+            storage.count_call_records_for_region("us-west1").await,
+            Err(StorageError::RegionIndexUnavailable(index)) if index == "region-shard-index"
+        ));
+
+        // Other validation errors are not mistaken for a missing index.
+        let (storage, _) = create_dynamodb(vec![(400, OTHER_VALIDATION_RESPONSE)]);
+        assert!(matches!(
+            storage.get_call_records_for_region("us-west1").await,
+            Err(StorageError::UnexpectedError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let (storage, _) = create_dynamodb(vec![(
+            200,
+            r#"{"Table":{"TableName":"CallRecords","TableStatus":"ACTIVE"}}"#,
+        )]);
+        assert!(storage.health_check().await.is_ok());
+
+        let (storage, _) = create_dynamodb(vec![(
+            200,
+            r#"{"Table":{"TableName":"CallRecords","TableStatus":"DELETING"}}"#,
+        )]);
+        assert!(storage.health_check().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_health_check_broken_client() {
+        let (storage, _) = create_dynamodb(vec![(
+            500,
+            r#"{"__type":"com.amazon.coral.service#InternalFailure"}"#,
+        )]);
+
+        match storage.health_check().await {
+            Err(StorageError::UnexpectedError(err)) => {
+                assert_eq!(err.to_string(), "failed to describe the table")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_table_is_table_not_found() {
+        const RESOURCE_NOT_FOUND_RESPONSE: &str = r#"{"__type":"com.amazonaws.dynamodb.v20120810#ResourceNotFoundException","message":"Requested resource not found: Table: CallRecords not found"}"#;
+
+        let (storage, _) = create_dynamodb(vec![
+            (400, RESOURCE_NOT_FOUND_RESPONSE),
+            (400, RESOURCE_NOT_FOUND_RESPONSE),
+            (400, RESOURCE_NOT_FOUND_RESPONSE),
+        ]);
+
+        let err = storage.check_table_exists().await.unwrap_err();
+        assert!(
+            matches!(&err, StorageError::TableNotFound(table_name) if *table_name == storage.table_name),
+            "{:?}",
+            err
+        );
+        // The table name is in the message that startup fails with.
+        assert!(err.to_string().contains(&storage.table_name), "{}", err);
+        assert_eq!(err.kind(), "table_not_found");
+
+        assert!(matches!(
+            storage.health_check().await,
+            Err(StorageError::TableNotFound(_))
+        ));
+        assert!(matches!(
+            storage.verify_schema().await,
+            Err(StorageError::TableNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_storage_metrics_are_tagged() {
+        const EVENT: &str = "calling.frontend.storage.get_or_add.conditional_failed";
+        const TAGS: &[&str] = &[
+            "storage_region:us-east-1",
+            "operation:get_or_add_call_record",
+            "backend_region:us-west1",
+        ];
+
+        let (storage, _) = create_dynamodb(vec![
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, GET_ITEM_RESPONSE),
+        ]);
+        let before = metrics!().peek_event_count_with_tags(EVENT, TAGS);
+
+        storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap();
+
+        assert!(metrics!().peek_event_count_with_tags(EVENT, TAGS) > before);
+    }
+
+    #[tokio::test]
+    async fn test_update_call_record() {
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+        let call = CallRecord {
+            version: 3,
+            ..create_call_record()
+        };
+
+        let updated = storage.update_call_record(call.clone()).await.unwrap();
+        assert_eq!(updated.version, 4);
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["ConditionExpression"],
+            "jvbConferenceId = :call_id AND #version = :expected"
+        );
+        assert_eq!(body["ExpressionAttributeValues"][":expected"]["N"], "3");
+        assert_eq!(body["Item"]["version"]["N"], "4");
+    }
+
+    #[tokio::test]
+    async fn test_update_call_record_initial_version() {
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+
+        // Records read as version 0 may have no version at all or be stored with 0.
+        let updated = storage
+            .update_call_record(create_call_record())
+            .await
+            .unwrap();
+        assert_eq!(updated.version, 1);
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["ConditionExpression"],
+            "jvbConferenceId = :call_id AND (attribute_not_exists(#version) OR #version = :zero)"
+        );
+        assert_eq!(body["ExpressionAttributeValues"][":zero"]["N"], "0");
+        assert_eq!(body["Item"]["version"]["N"], "1");
+    }
+
+    #[tokio::test]
+    async fn test_update_call_record_version_conflict() {
+        let (storage, _) = create_dynamodb(vec![(400, CONDITIONAL_CHECK_FAILED_RESPONSE)]);
+        let call = CallRecord {
+            version: 3,
+            ..create_call_record()
+        };
+
+        assert!(matches!(
+            storage.update_call_record(call).await,
+            Err(StorageError::VersionConflict)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_add_call_records_chunks() {
+        let (storage, connection) = create_dynamodb(vec![(200, "{}"), (200, "{}")]);
+        let records: Vec<_> = (0..26)
+            .map(|i| CallRecord {
+                group_id: format!("{:016x}", i).into(),
+                ..create_call_record()
+            })
+            .collect();
+
+        storage.add_call_records(records).await.unwrap();
+
+        let item_counts: Vec<_> = connection
+            .requests()
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value =
+                    serde_json::from_slice(request.actual.body().bytes().unwrap()).unwrap();
+                body["RequestItems"]["CallRecords"]
+                    .as_array()
+                    .unwrap()
+                    .len()
+            })
+            .collect();
+        assert_eq!(item_counts, vec![25, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_add_call_records_deduplicates_groups() {
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+        let records = vec![
+            create_call_record(),
+            CallRecord {
+                group_id: "bbbbbbbbbbbbbbbb".into(),
+                ..create_call_record()
+            },
+            CallRecord {
+                call_id: "b2b2b2b2".to_string(),
+                ..create_call_record()
+            },
+        ];
+
+        storage.add_call_records(records).await.unwrap();
+
+        // The last call of the group is written in the place of the first.
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        let items = body["RequestItems"]["CallRecords"].as_array().unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0]["PutRequest"]["Item"]["groupConferenceId"]["S"],
+            "aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(
+            items[0]["PutRequest"]["Item"]["jvbConferenceId"]["S"],
+            "b2b2b2b2"
+        );
+        assert_eq!(
+            items[1]["PutRequest"]["Item"]["groupConferenceId"]["S"],
+            "bbbbbbbbbbbbbbbb"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_call_records_retries_unprocessed_items() {
+        let (storage, connection) = create_dynamodb(vec![
+            (
+                200,
+                r#"{"UnprocessedItems":{"CallRecords":[{"PutRequest":{"Item":{"groupConferenceId":{"S":"0000000000000001"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}}}]}}"#,
+            ),
+            (200, "{}"),
+        ]);
+        let records: Vec<_> = (0..3)
+            .map(|i| CallRecord {
+                group_id: format!("{:016x}", i).into(),
+                ..create_call_record()
+            })
+            .collect();
+
+        storage.add_call_records(records).await.unwrap();
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 2);
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[1].actual.body().bytes().unwrap()).unwrap();
+        let retried = body["RequestItems"]["CallRecords"].as_array().unwrap();
+        assert_eq!(retried.len(), 1);
+        assert_eq!(
+            retried[0]["PutRequest"]["Item"]["groupConferenceId"]["S"],
+            "0000000000000001"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_add_call_records_gives_up_resending_at_deadline() {
+        let (storage, connection) = create_dynamodb(vec![
+            (
+                200,
+                r#"{"UnprocessedItems":{"CallRecords":[{"PutRequest":{"Item":{"groupConferenceId":{"S":"0000000000000001"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}}}]}}"#,
+            ),
+            (200, "{}"),
+        ]);
+        let records: Vec<_> = (0..3)
+            .map(|i| CallRecord {
+                group_id: format!("{:016x}", i).into(),
+                ..create_call_record()
+            })
+            .collect();
+
+        // The deadline passes before the first wait to resend is over.
+        assert!(matches!(
+            with_deadline(
+                tokio::time::Instant::now() + std::time::Duration::from_millis(10),
+                storage.add_call_records(records)
+            )
+            .await,
+            Err(StorageError::DeadlineExceeded)
+        ));
+        assert_eq!(connection.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_disabled() {
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_disabled_{}", std::process::id()));
+        let _ = std::fs::remove_file(&identity_token_path);
+        let fetcher = create_identity_fetcher(identity_token_path.clone());
+
+        fetcher.fetch_token().await.unwrap();
+        assert!(!identity_token_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_gcp_metadata() {
+        let url = serve_metadata(|request| {
+            let token = if request.headers().get("Metadata-Flavor").unwrap() == "Google" {
+                "gcp-token"
+            } else {
+                ""
+            };
+            hyper::Response::new(Body::from(token))
+        });
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_gcp_{}", std::process::id()));
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::GcpMetadata {
+                url: format!("{}/identity?audience=test", url),
+            },
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+
+        fetcher.fetch_token().await.unwrap();
+        assert_eq!(std::fs::read(&identity_token_path).unwrap(), b"gcp-token");
+        let _ = std::fs::remove_file(&identity_token_path);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_gcp_metadata_configured_headers() {
+        let url = serve_metadata(|request| {
+            let headers = request.headers();
+            let token = if headers.get("Authorization").unwrap() == "Bearer broker"
+                && headers.get("Metadata-Flavor").unwrap() == "Broker"
+                && headers.get_all("Metadata-Flavor").iter().count() == 1
+                && request.uri().query() == Some("format=full&audience=broker")
+            {
+                "broker-token"
+            } else {
+                ""
+            };
+            hyper::Response::new(Body::from(token))
+        });
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_broker_{}", std::process::id()));
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::GcpMetadata {
+                url: format!("{}/identity?audience=test&format=full", url),
+            },
+            fetch_headers: vec![
+                "Authorization=Bearer broker".parse().unwrap(),
+                "Metadata-Flavor=Broker".parse().unwrap(),
+            ],
+            fetch_query_params: vec!["audience=broker".parse().unwrap()],
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+
+        fetcher.fetch_token().await.unwrap();
+        assert_eq!(
+            std::fs::read(&identity_token_path).unwrap(),
+            b"broker-token"
+        );
+        let _ = std::fs::remove_file(&identity_token_path);
+    }
+
+    #[tokio::test]
+    async fn test_fetches_reuse_the_connection() {
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_reuse_{}", std::process::id()));
+
+        for (pool_idle_timeout_ms, expected_connections) in [(60000, 1), (0, 3)] {
+            let (url, connections) = serve_metadata_counting_connections(|_| {
+                hyper::Response::new(Body::from("gcp-token"))
+            });
+            let fetcher = IdentityFetcher {
+                client: create_identity_client(pool_idle_timeout_ms, 60000),
+                identity_source: config::IdentitySource::GcpMetadata {
+                    url: format!("{}/identity", url),
+                },
+                ..create_identity_fetcher(identity_token_path.clone())
+            };
+
+            for _ in 0..3 {
+                fetcher.fetch_token().await.unwrap();
+                // Gives the client a moment to put the connection back in the pool.
+                tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            }
+            assert_eq!(
+                connections.load(Ordering::SeqCst),
+                expected_connections,
+                "pool_idle_timeout_ms: {}",
+                pool_idle_timeout_ms
+            );
+        }
+        let _ = std::fs::remove_file(&identity_token_path);
+    }
+
+    #[test]
+    fn test_with_query_params() {
+        let params: Vec<config::NameValue> = vec!["audience=b".parse().unwrap()];
+        assert_eq!(with_query_params("http://host/id", &[]), "http://host/id");
+        assert_eq!(
+            with_query_params("http://host/id", &params),
+            "http://host/id?audience=b"
+        );
+        assert_eq!(
+            with_query_params("http://host/id?audience=a&format=full", &params),
+            "http://host/id?format=full&audience=b"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_aws_imds() {
+        // The client gets its credentials from the instance metadata service, so nothing
+        // is fetched and no token file is written.
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_aws_{}", std::process::id()));
+        let _ = std::fs::remove_file(&identity_token_path);
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::AwsImds,
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+
+        assert_eq!(fetcher.read_token().await.unwrap(), None);
+        fetcher.fetch_token().await.unwrap();
+        assert!(!identity_token_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_file() {
+        let source_path =
+            std::env::temp_dir().join(format!("identity_source_{}", std::process::id()));
+        std::fs::write(&source_path, b"file-token").unwrap();
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_file_{}", std::process::id()));
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::File {
+                path: source_path.to_str().unwrap().to_string(),
+            },
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+
+        fetcher.fetch_token().await.unwrap();
+        assert_eq!(std::fs::read(&identity_token_path).unwrap(), b"file-token");
+        let _ = std::fs::remove_file(&identity_token_path);
+        let _ = std::fs::remove_file(&source_path);
+    }
+
+    #[tokio::test]
+    async fn test_subscribers_see_token_refresh() {
+        // A JWT whose payload is {"exp":2000000000}.
+        let token = format!(
+            "e30.{}.c2ln",
+            base64::encode_config(r#"{"exp":2000000000}"#, base64::URL_SAFE_NO_PAD)
+        );
+        let source_path =
+            std::env::temp_dir().join(format!("identity_refresh_source_{}", std::process::id()));
+        std::fs::write(&source_path, &token).unwrap();
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_refresh_{}", std::process::id()));
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::File {
+                path: source_path.to_str().unwrap().to_string(),
+            },
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+        let mut first = fetcher.subscribe();
+        let mut second = fetcher.subscribe();
+        assert_eq!(first.latest(), None);
+
+        fetcher.fetch_token().await.unwrap();
+        let expected_expiry = UNIX_EPOCH + std::time::Duration::from_secs(2000000000);
+        for subscriber in [&mut first, &mut second] {
+            let refresh = subscriber.changed().await.unwrap();
+            assert_eq!(refresh.expires_at, Some(expected_expiry));
+            assert_eq!(subscriber.latest_expiry(), Some(expected_expiry));
+        }
+
+        // A subscriber that arrives late still sees the latest refresh.
+        assert_eq!(fetcher.subscribe().latest_expiry(), Some(expected_expiry));
+
+        drop(fetcher);
+        assert_eq!(first.changed().await, None);
+        let _ = std::fs::remove_file(&identity_token_path);
+        let _ = std::fs::remove_file(&source_path);
+    }
+
+    #[test]
+    fn test_next_fetch_delay_follows_token_lifetime() {
+        let fetcher = create_identity_fetcher(PathBuf::from("unused"));
+        let now = SystemTime::now();
+
+        // Without a token of known expiry, the interval is used.
+        assert_eq!(fetcher.next_fetch_delay(now), Duration::from_millis(1000));
+        fetcher.refreshed.send_replace(Some(TokenRefresh {
+            refreshed_at: now,
+            expires_at: None,
+        }));
+        assert_eq!(fetcher.next_fetch_delay(now), Duration::from_millis(1000));
+
+        fetcher.refreshed.send_replace(Some(TokenRefresh {
+            refreshed_at: now,
+            expires_at: Some(now + std::time::Duration::from_secs(3600)),
+        }));
+        for _ in 0..100 {
+            let delay = fetcher.next_fetch_delay(now);
+            assert!(delay >= Duration::from_secs(1800), "{:?}", delay);
+            assert!(delay <= Duration::from_secs(2700), "{:?}", delay);
+        }
+        // Later on, less of the lifetime remains.
+        let later = now + std::time::Duration::from_secs(3000);
+        let delay = fetcher.next_fetch_delay(later);
+        assert!(delay >= Duration::from_secs(300), "{:?}", delay);
+        assert!(delay <= Duration::from_secs(450), "{:?}", delay);
+
+        // A token that is about to expire, or has, isn't fetched in a tight loop.
+        let expired = now + std::time::Duration::from_secs(4000);
+        assert_eq!(fetcher.next_fetch_delay(expired), MIN_IDENTITY_FETCH_DELAY);
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        assert_eq!(token_expiry(b"aws-token"), None);
+        assert_eq!(token_expiry(b"a.not-base64!.c"), None);
+        let payload = base64::encode_config(r#"{"sub":"x"}"#, base64::URL_SAFE_NO_PAD);
+        assert_eq!(
+            token_expiry(format!("e30.{}.c2ln", payload).as_bytes()),
+            None
+        );
+        let payload = base64::encode_config(r#"{"exp":10}"#, base64::URL_SAFE_NO_PAD);
+        assert_eq!(
+            token_expiry(format!("e30.{}.c2ln\n", payload).as_bytes()),
+            Some(UNIX_EPOCH + std::time::Duration::from_secs(10))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_disabled_fetcher_ends_without_fetching() {
+        const TIMER: &str = "calling.frontend.identity_fetcher.timed";
+
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_unused_{}", std::process::id()));
+        let fetcher = IdentityFetcher {
+            fetch_interval: Duration::from_millis(1000),
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+        let before = metrics!().peek_timer_count(TIMER);
+
+        // Many intervals pass without a single fetch, and start keeps waiting to be ended.
+        let (ender_tx, ender_rx) = tokio::sync::oneshot::channel();
+        let handle = tokio::spawn(fetcher.start(ender_rx));
+        for _ in 0..10 {
+            tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+            assert_eq!(metrics!().peek_timer_count(TIMER), before);
+        }
+        assert!(!handle.is_finished());
+
+        ender_tx.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+        assert_eq!(metrics!().peek_timer_count(TIMER), before);
+        assert!(!identity_token_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_arc_is_storage() {
+        async fn add_through_trait(storage: impl Storage, call: CallRecord) {
+            storage.get_or_add_call_record(call).await.unwrap();
+        }
+
+        let storage = Arc::new(InMemoryStorage::new());
+        add_through_trait(storage.clone(), create_call_record()).await;
+
+        let found = storage
+            .get_call_record(&create_call_record().group_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.call_id, "a1a1a1a1");
+
+        let shared: DynStorage = storage;
+        assert!(Storage::get_call_record(&shared, &found.group_id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_call_record_debug_redacts_creator() {
+        let call = CallRecord {
+            creator: "0123456789abcdef".to_string(),
+            ..create_call_record()
+        };
+
+        let rendered = format!("{:?}", call);
+        assert!(!rendered.contains("0123456789abcdef"), "{}", rendered);
+        assert!(!rendered.contains(call.group_id.as_ref()), "{}", rendered);
+        assert!(rendered.contains("creator: 0123"), "{}", rendered);
+
+        let rendered = format!("{:?}", Ok::<_, StorageError>(Some(call)));
+        assert!(!rendered.contains("0123456789abcdef"), "{}", rendered);
+    }
+
+    #[tokio::test]
+    async fn test_get_call_records_for_region_projected() {
+        const QUERY_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbHost":{"S":"127.0.0.1"}}],"Count":1,"ScannedCount":1}"#;
+
+        let (storage, connection) = create_dynamodb(vec![(200, QUERY_RESPONSE)]);
+
+        let calls = storage
+            .get_call_records_for_region_projected(
+                "us-west1",
+                &["groupConferenceId", "jvbHost"],
+                Some(10),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            calls,
+            vec![CallRecordSummary {
+                group_id: Some("aaaaaaaaaaaaaaaa".into()),
+                backend_ip: Some("127.0.0.1".to_string()),
+                ..Default::default()
+            }]
+        );
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["Select"], "SPECIFIC_ATTRIBUTES");
+        assert_eq!(body["ProjectionExpression"], "#a0, #a1");
+        assert_eq!(body["ExpressionAttributeNames"]["#a0"], "groupConferenceId");
+        assert_eq!(body["ExpressionAttributeNames"]["#a1"], "jvbHost");
+        assert_eq!(body["Limit"], 10);
+    }
+
+    #[tokio::test]
+    async fn test_get_call_records_for_region_projected_paginates() {
+        const FIRST_PAGE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"}}],"Count":1,"ScannedCount":2,"LastEvaluatedKey":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"region":{"S":"us-west1"}}}"#;
+        const SECOND_PAGE: &str = r#"{"Items":[{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"}},{"groupConferenceId":{"S":"cccccccccccccccc"}}],"Count":2,"ScannedCount":2,"LastEvaluatedKey":{"groupConferenceId":{"S":"cccccccccccccccc"},"region":{"S":"us-west1"}}}"#;
+
+        // The second shard isn't read once the first has filled the limit.
+        let (storage, connection) = create_dynamodb(vec![(200, FIRST_PAGE), (200, SECOND_PAGE)]);
+        let storage = DynamoDb {
+            region_index_shards: 2,
+            ..storage
+        };
+
+        let calls = storage
+            .get_call_records_for_region_projected("us-west1", &["groupConferenceId"], Some(3))
+            .await
+            .unwrap();
+        let group_ids: Vec<_> = calls
+            .into_iter()
+            .map(|call| call.group_id.unwrap())
+            .collect();
+        assert_eq!(
+            group_ids,
+            vec![
+                GroupId::from("aaaaaaaaaaaaaaaa"),
+                GroupId::from("bbbbbbbbbbbbbbbb"),
+                GroupId::from("cccccccccccccccc"),
+            ]
+        );
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 2);
+        let first: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(first["Limit"], 3);
+        assert!(first.get("ExclusiveStartKey").is_none());
+        // The next page continues where the first ended and only asks for what is left.
+        let second: serde_json::Value =
+            serde_json::from_slice(requests[1].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(second["Limit"], 2);
+        assert_eq!(
+            second["ExclusiveStartKey"]["groupConferenceId"]["S"],
+            "aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(
+            second["ExpressionAttributeValues"],
+            first["ExpressionAttributeValues"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_throttling_is_transient() {
+        const THROTTLED_RESPONSE: &str = r#"{"__type":"com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException","message":"The level of configured provisioned throughput for the table was exceeded"}"#;
+
+        let (storage, _) = create_dynamodb(vec![(400, THROTTLED_RESPONSE)]);
+        let err = storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap_err();
+        assert!(err.is_transient(), "{:?}", err);
+        assert_eq!(err.kind(), "throttled");
+
+        let (storage, _) = create_dynamodb(vec![(400, CONDITIONAL_CHECK_FAILED_RESPONSE)]);
+        let err = storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap_err();
+        assert!(!err.is_transient(), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_throttled_get_or_add_is_retried() {
+        const THROTTLED_RESPONSE: &str = r#"{"__type":"com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException","message":"The level of configured provisioned throughput for the table was exceeded"}"#;
+
+        // Writes are throttled like reads.
+        let (storage, _) = create_dynamodb(vec![(400, THROTTLED_RESPONSE)]);
+        let err = storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), "throttled");
+
+        // So the put is sent again rather than failing the call.
+        let (storage, connection) = create_dynamodb(vec![(400, THROTTLED_RESPONSE), (200, "{}")]);
+        let storage = RetryingStorage::new(storage, 3, Duration::from_millis(1));
+        let call = storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(call.call_id, "a1a1a1a1");
+
+        let requests = connection.requests();
+        for request in &requests[..2] {
+            assert_eq!(
+                request.actual.headers()["x-amz-target"],
+                "DynamoDB_20120810.PutItem"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_http_failure_metric() {
+        const EVENT: &str = "calling.frontend.identity_fetcher.fetch_http.error";
+
+        let url = serve_metadata(|_| {
+            hyper::Response::builder()
+                .status(404)
+                .body(Body::from("not found"))
+                .unwrap()
+        });
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_http_error_{}", std::process::id()));
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::GcpMetadata { url },
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+        let before = metrics!().peek_event_count(EVENT);
+
+        assert!(fetcher.fetch_token().await.is_err());
+        assert!(metrics!().peek_event_count(EVENT) > before);
+        assert!(!identity_token_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_file_read_failure_metric() {
+        const EVENT: &str = "calling.frontend.identity_fetcher.file_read.error";
+
+        let source_path =
+            std::env::temp_dir().join(format!("identity_missing_source_{}", std::process::id()));
+        let _ = std::fs::remove_file(&source_path);
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_read_{}", std::process::id()));
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::File {
+                path: source_path.to_str().unwrap().to_string(),
+            },
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+        let before = metrics!().peek_event_count(EVENT);
+
+        assert!(fetcher.fetch_token().await.is_err());
+        assert!(metrics!().peek_event_count(EVENT) > before);
+        assert!(!identity_token_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_validate_failure_metric() {
+        const EVENT: &str = "calling.frontend.identity_fetcher.validate.error";
+
+        let source_path =
+            std::env::temp_dir().join(format!("identity_empty_source_{}", std::process::id()));
+        std::fs::write(&source_path, b"\n").unwrap();
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_validate_{}", std::process::id()));
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::File {
+                path: source_path.to_str().unwrap().to_string(),
+            },
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+        let before = metrics!().peek_event_count(EVENT);
+
+        assert!(fetcher.fetch_token().await.is_err());
+        assert!(metrics!().peek_event_count(EVENT) > before);
+        assert!(!identity_token_path.exists());
+        let _ = std::fs::remove_file(&source_path);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_file_write_failure_cleans_up() {
+        const EVENT: &str = "calling.frontend.identity_fetcher.file_write.error";
+
+        let source_path =
+            std::env::temp_dir().join(format!("identity_write_source_{}", std::process::id()));
+        std::fs::write(&source_path, b"file-token").unwrap();
+        // Moving the token into place fails because a non-empty directory is in the way.
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_write_{}", std::process::id()));
+        std::fs::create_dir_all(identity_token_path.join("occupied")).unwrap();
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::File {
+                path: source_path.to_str().unwrap().to_string(),
+            },
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+        let before = metrics!().peek_event_count(EVENT);
+
+        assert!(fetcher.fetch_token().await.is_err());
+        assert!(metrics!().peek_event_count(EVENT) > before);
+        assert!(!identity_token_path.with_extension("bak").exists());
+
+        let _ = std::fs::remove_dir_all(&identity_token_path);
+        let _ = std::fs::remove_file(&source_path);
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_config_uses_test_credentials() {
+        use aws_types::credentials::ProvideCredentials;
+
+        let config = config::Config {
+            storage_region: "eu-west-2".to_string(),
+            storage_test_access_key_id: Some("LOCALSTACK_KEY".to_string()),
+            storage_test_secret_access_key: Some("LOCALSTACK_SECRET".to_string()),
+            storage_test_session_token: Some("LOCALSTACK_SESSION".to_string()),
+            ..config::default_test_config()
+        };
+
+        let aws_config = test_endpoint_aws_config(&config, "http://localhost:4566")
+            .unwrap()
+            .build();
+        assert_eq!(aws_config.region(), Some(&Region::new("eu-west-2")));
+        let credentials = aws_config
+            .credentials_provider()
+            .unwrap()
+            .provide_credentials()
+            .await
+            .unwrap();
+        assert_eq!(credentials.access_key_id(), "LOCALSTACK_KEY");
+        assert_eq!(credentials.secret_access_key(), "LOCALSTACK_SECRET");
+        assert_eq!(credentials.session_token(), Some("LOCALSTACK_SESSION"));
+
+        // Without configured credentials, dummies are used.
+        let aws_config =
+            test_endpoint_aws_config(&config::default_test_config(), "http://localhost:4566")
+                .unwrap()
+                .build();
+        let credentials = aws_config
+            .credentials_provider()
+            .unwrap()
+            .provide_credentials()
+            .await
+            .unwrap();
+        assert_eq!(credentials.access_key_id(), "DUMMY_KEY");
+        assert_eq!(credentials.session_token(), None);
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_call() {
+        let (storage, connection) =
+            create_dynamodb(vec![(200, "{}"), (400, CONDITIONAL_CHECK_FAILED_RESPONSE)]);
+
+        assert!(storage
+            .heartbeat_call(&"aaaaaaaaaaaaaaaa".into(), "a1a1a1a1")
+            .await
+            .unwrap());
+        assert!(!storage
+            .heartbeat_call(&"aaaaaaaaaaaaaaaa".into(), "b2b2b2b2")
+            .await
+            .unwrap());
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["UpdateExpression"],
+            "SET lastHeartbeatAt = :now, #version = if_not_exists(#version, :zero) + :one"
+        );
+        assert_eq!(body["ConditionExpression"], "jvbConferenceId = :value");
+        assert_eq!(body["ExpressionAttributeValues"][":value"]["S"], "a1a1a1a1");
+    }
+
+    #[tokio::test]
+    async fn test_promote_backup_backend() {
+        const UPDATE_ITEM_RESPONSE: &str = r#"{"Attributes":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.2"},"region":{"S":"us-east4"},"creator":{"S":"1111111111111111"},"backupBackends":{"L":[{"M":{"ip":{"S":"127.0.0.3"},"region":{"S":"us-west1"}}}]},"version":{"N":"2"}}}"#;
+
+        // With a single region index shard, the promotion is the only request.
+        let (storage, connection) = create_dynamodb(vec![
+            (200, UPDATE_ITEM_RESPONSE),
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+        ]);
+
+        let call = storage
+            .promote_backup_backend(&"aaaaaaaaaaaaaaaa".into(), "a1a1a1a1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(call.backend_ip, "127.0.0.2");
+        assert_eq!(call.backend_region, "us-east4");
+        assert_eq!(
+            call.backup_backends,
+            vec![BackendRef {
+                region: "us-west1".to_string(),
+                ip: "127.0.0.3".to_string(),
+            }]
+        );
+        assert_eq!(call.version, 2);
+
+        // A call that changed or has no backup left isn't promoted.
+        assert_eq!(
+            storage
+                .promote_backup_backend(&"aaaaaaaaaaaaaaaa".into(), "b2b2b2b2")
+                .await
+                .unwrap(),
+            None
+        );
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 2);
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["Key"]["groupConferenceId"]["S"], "aaaaaaaaaaaaaaaa");
+        assert_eq!(
+            body["UpdateExpression"],
+            "SET jvbHost = backupBackends[0].ip, #region = backupBackends[0].#region, \
+             #version = if_not_exists(#version, :zero) + :one \
+             REMOVE backupBackends[0]"
+        );
+        assert_eq!(
+            body["ConditionExpression"],
+            "jvbConferenceId = :value AND size(backupBackends) > :zero"
+        );
+        assert_eq!(body["ExpressionAttributeNames"]["#region"], "region");
+        assert_eq!(body["ExpressionAttributeNames"]["#version"], "version");
+        assert_eq!(body["ExpressionAttributeValues"][":value"]["S"], "a1a1a1a1");
+        assert_eq!(body["ExpressionAttributeValues"][":zero"]["N"], "0");
+        assert_eq!(body["ExpressionAttributeValues"][":one"]["N"], "1");
+        assert_eq!(body["ReturnValues"], "ALL_NEW");
+    }
+
+    #[tokio::test]
+    async fn test_reap_dead_calls() {
+        const SCAN_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"}},{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"}}],"Count":2,"ScannedCount":5}"#;
+
+        // The second call sent a heartbeat between the scan and its removal.
+        let (storage, connection) = create_dynamodb(vec![
+            (200, SCAN_RESPONSE),
+            (200, "{}"),
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, "{}"),
+        ]);
+        let storage = DynamoDb {
+            clock: Arc::new(MockClock::from_secs(1000)),
+            ..storage
+        };
+
+        let reaped = storage
+            .reap_dead_calls(Duration::from_secs(60), false)
+            .await
+            .unwrap();
+        assert_eq!(reaped, vec![GroupId::from("aaaaaaaaaaaaaaaa")]);
+
+        let requests = connection.requests();
+        let scan: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(scan["ExpressionAttributeValues"][":threshold"]["N"], "940");
+        let delete: serde_json::Value =
+            serde_json::from_slice(requests[1].actual.body().bytes().unwrap()).unwrap();
+        assert!(delete["ConditionExpression"]
+            .as_str()
+            .unwrap()
+            .starts_with("(jvbConferenceId = :value AND (lastHeartbeatAt < :threshold"));
+    }
+
+    #[tokio::test]
+    async fn test_key_prefix_is_added_to_keys_and_removed_from_calls() {
+        const PREFIXED_GET_ITEM_RESPONSE: &str = r#"{"Item":{"groupConferenceId":{"S":"tenant-a#aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}}"#;
+
+        let (storage, connection) = create_dynamodb(vec![
+            (200, "{}"),
+            (200, r#"{"Attributes":{"era":{"N":"1"}}}"#),
+            (200, "{}"),
+            (200, PREFIXED_GET_ITEM_RESPONSE),
+        ]);
+        let storage = DynamoDb {
+            key_prefix: "tenant-a#".to_string(),
+            ..storage
+        };
+
+        let call = create_call_record();
+        let added = storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(added.group_id, call.group_id);
+        let read = storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.group_id, call.group_id);
+
+        let requests = connection.requests();
+        let body = |index: usize| -> serde_json::Value {
+            serde_json::from_slice(requests[index].actual.body().bytes().unwrap()).unwrap()
+        };
+        assert_eq!(
+            body(0)["Item"]["groupConferenceId"]["S"],
+            "tenant-a#aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(
+            body(1)["Key"]["groupConferenceId"]["S"],
+            "tenant-a#callEra#aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(
+            body(2)["Key"]["groupConferenceId"]["S"],
+            "tenant-a#aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(
+            body(3)["Key"]["groupConferenceId"]["S"],
+            "tenant-a#aaaaaaaaaaaaaaaa"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_key_prefix_restricts_region_queries_and_scans() {
+        const QUERY_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"tenant-a#aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":1,"ScannedCount":2}"#;
+        const SCAN_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"tenant-a#aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"}}],"Count":1,"ScannedCount":3}"#;
+
+        let (storage, connection) = create_dynamodb(vec![
+            (200, QUERY_RESPONSE),
+            (200, SCAN_RESPONSE),
+            (200, "{}"),
+        ]);
+        let storage = DynamoDb {
+            key_prefix: "tenant-a#".to_string(),
+            ..storage
+        };
+
+        let calls = storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap();
+        assert_eq!(
+            calls.iter().map(|call| &call.group_id).collect::<Vec<_>>(),
+            vec![&GroupId::from("aaaaaaaaaaaaaaaa")]
+        );
+        let reaped = storage
+            .reap_dead_calls(Duration::from_secs(60), false)
+            .await
+            .unwrap();
+        assert_eq!(reaped, vec![GroupId::from("aaaaaaaaaaaaaaaa")]);
+
+        let requests = connection.requests();
+        let body = |index: usize| -> serde_json::Value {
+            serde_json::from_slice(requests[index].actual.body().bytes().unwrap()).unwrap()
+        };
+        // Items of other prefixes are filtered out by DynamoDB.
+        assert_eq!(body(0)["FilterExpression"], KEY_PREFIX_CONDITION);
+        assert_eq!(
+            body(0)["ExpressionAttributeValues"][":key_prefix"]["S"],
+            "tenant-a#"
+        );
+        assert!(body(1)["FilterExpression"]
+            .as_str()
+            .unwrap()
+            .ends_with(&format!(") AND {}", KEY_PREFIX_CONDITION)));
+        assert_eq!(
+            body(1)["ExpressionAttributeValues"][":key_prefix"]["S"],
+            "tenant-a#"
+        );
+        assert_eq!(
+            body(2)["Key"]["groupConferenceId"]["S"],
+            "tenant-a#aaaaaaaaaaaaaaaa"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_token_times_out() {
+        const EVENT: &str = "calling.frontend.identity_fetcher.fetch_http.error";
+
+        // Connections are accepted by the OS but nothing ever responds.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let fetcher = IdentityFetcher {
+            fetch_timeout: Duration::from_millis(100),
+            identity_source: config::IdentitySource::GcpMetadata {
+                url: format!("http://{}/identity", listener.local_addr().unwrap()),
+            },
+            ..create_identity_fetcher(
+                std::env::temp_dir().join(format!("identity_timeout_{}", std::process::id())),
+            )
+        };
+        let before = metrics!().peek_event_count(EVENT);
+
+        let started = std::time::Instant::now();
+        assert!(fetcher.fetch_token().await.is_err());
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert!(metrics!().peek_event_count(EVENT) > before);
+    }
+
+    #[tokio::test]
+    async fn test_count_all_regions() {
+        const FIRST_PAGE_RESPONSE: &str = r#"{"Items":[{"region":{"S":"us-west1"}},{"region":{"S":"us-east4"}},{"region":{"S":"us-west1"}}],"Count":3,"ScannedCount":3,"LastEvaluatedKey":{"groupConferenceId":{"S":"cccccccccccccccc"},"region":{"S":"us-west1"}}}"#;
+        const SECOND_PAGE_RESPONSE: &str = r#"{"Items":[{"region":{"S":"europe-west3"}},{"region":{"S":"us-west1"}}],"Count":2,"ScannedCount":2}"#;
+
+        let (storage, connection) = create_dynamodb(vec![
+            (200, FIRST_PAGE_RESPONSE),
+            (200, SECOND_PAGE_RESPONSE),
+        ]);
+
+        assert_eq!(
+            storage.count_all_regions().await.unwrap(),
+            HashMap::from([
+                ("us-west1".to_string(), 3),
+                ("us-east4".to_string(), 1),
+                ("europe-west3".to_string(), 1),
+            ])
+        );
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 2);
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["IndexName"], REGION_INDEX_NAME);
+        assert_eq!(body["ProjectionExpression"], "#region");
+    }
+
+    #[tokio::test]
+    async fn test_page_size_is_applied_to_each_page() {
+        const FIRST_PAGE_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":1,"ScannedCount":1,"LastEvaluatedKey":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"region":{"S":"us-west1"}}}"#;
+        const LAST_PAGE_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"2222222222222222"}}],"Count":1,"ScannedCount":1}"#;
+
+        let (storage, connection) = create_dynamodb(vec![
+            (200, FIRST_PAGE_RESPONSE),
+            (200, LAST_PAGE_RESPONSE),
+            (200, FIRST_PAGE_RESPONSE),
+            (200, LAST_PAGE_RESPONSE),
+            (200, LAST_PAGE_RESPONSE),
+        ]);
+        let storage = DynamoDb {
+            page_size: Some(1),
+            ..storage
+        };
+
+        assert_eq!(
+            storage
+                .stream_call_records_for_region("us-west1")
+                .count()
+                .await,
+            2
+        );
+        assert_eq!(storage.export_all().count().await, 2);
+        // A query that is sent only once reads all of the calls.
+        storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap();
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 5);
+        let body = |index: usize| -> serde_json::Value {
+            serde_json::from_slice(requests[index].actual.body().bytes().unwrap()).unwrap()
+        };
+        for index in 0..4 {
+            assert_eq!(body(index)["Limit"], 1, "request {}", index);
+        }
+        assert!(body(4).get("Limit").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_export_all_pages() {
+        const FIRST_PAGE_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}},{"groupConferenceId":{"S":"regionCapacity#us-west1"},"reserved":{"N":"2"}}],"Count":2,"ScannedCount":2,"LastEvaluatedKey":{"groupConferenceId":{"S":"regionCapacity#us-west1"}}}"#;
+        const SECOND_PAGE_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.2"},"region":{"S":"us-east4"},"creator":{"S":"2222222222222222"}}],"Count":1,"ScannedCount":1}"#;
+
+        let (storage, connection) = create_dynamodb(vec![
+            (200, FIRST_PAGE_RESPONSE),
+            (200, SECOND_PAGE_RESPONSE),
+        ]);
+
+        let exported: Vec<_> = storage
+            .export_all()
+            .map(|call| call.unwrap().call_id)
+            .collect()
+            .await;
+        assert_eq!(exported, vec!["a1a1a1a1", "b2b2b2b2"]);
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 2);
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[1].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["ExclusiveStartKey"]["groupConferenceId"]["S"],
+            "regionCapacity#us-west1"
+        );
+    }
+
+    #[test]
+    fn test_call_record_sets() {
+        let first = create_call_record();
+        let second = CallRecord {
+            call_id: "b2b2b2b2".to_string(),
+            ..create_call_record()
+        };
+        let other_group = CallRecord {
+            group_id: "0000000000000000".into(),
+            call_id: "c3c3c3c3".to_string(),
+            ..create_call_record()
+        };
+        let records = vec![
+            second.clone(),
+            first.clone(),
+            other_group.clone(),
+            first.clone(),
+        ];
+
+        let hashed: std::collections::HashSet<_> = records.iter().cloned().collect();
+        assert_eq!(hashed.len(), 3);
+        assert!(hashed.contains(&first));
+
+        let ordered: std::collections::BTreeSet<_> = records.into_iter().collect();
+        assert_eq!(
+            ordered.into_iter().collect::<Vec<_>>(),
+            vec![other_group, first, second]
+        );
+    }
+
+    #[test]
+    fn test_region_index_shard_is_stable() {
+        let mut used_shards = std::collections::HashSet::new();
+        for i in 0..64 {
+            let group_id: GroupId = format!("{:016x}", i).into();
+            let shard = region_index_shard(&group_id, 4);
+            assert!(shard < 4);
+            assert_eq!(shard, region_index_shard(&group_id, 4));
+            used_shards.insert(shard);
+        }
+        assert_eq!(used_shards.len(), 4);
+        assert_eq!(region_index_shard(&"aaaaaaaaaaaaaaaa".into(), 1), 0);
+
+        let (storage, _) = create_dynamodb(vec![]);
+        let storage = DynamoDb {
+            region_index_shards: 4,
+            ..storage
+        };
+        let call = create_call_record();
+        let item = storage.call_item(&call).unwrap();
+        assert_eq!(
+            item[REGION_SHARD_ATTRIBUTE].as_s().unwrap(),
+            &format!("us-west1#{}", region_index_shard(&call.group_id, 4))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_call_records_for_region_merges_shards() {
+        const FIRST_SHARD_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"2222222222222222"}}],"Count":1,"ScannedCount":1}"#;
+        const EMPTY_SHARD_RESPONSE: &str = r#"{"Items":[],"Count":0,"ScannedCount":0}"#;
+        const THIRD_SHARD_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":1,"ScannedCount":1}"#;
+
+        let (storage, connection) = create_dynamodb(vec![
+            (200, FIRST_SHARD_RESPONSE),
+            (200, EMPTY_SHARD_RESPONSE),
+            (200, THIRD_SHARD_RESPONSE),
+        ]);
+        let storage = DynamoDb {
+            region_index_shards: 3,
+            ..storage
+        };
+
+        let calls = storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap();
+        assert_eq!(
+            calls
+                .iter()
+                .map(|call| call.call_id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a1a1a1a1", "b2b2b2b2"]
+        );
+
+        let mut shard_keys: Vec<_> = connection
+            .requests()
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value =
+                    serde_json::from_slice(request.actual.body().bytes().unwrap()).unwrap();
+                assert_eq!(body["IndexName"], REGION_SHARD_INDEX_NAME);
+                body["ExpressionAttributeValues"][":value"]["S"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect();
+        shard_keys.sort();
+        assert_eq!(shard_keys, vec!["us-west1#0", "us-west1#1", "us-west1#2"]);
+    }
+
+    #[tokio::test]
+    async fn test_region_index_shard_change_requires_backfill() {
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+        let storage = DynamoDb {
+            region_index_shards: 2,
+            ..storage
+        };
+
+        assert!(storage.check_region_index_shards(false).await.is_err());
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 1);
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["Key"]["groupConferenceId"]["S"],
+            REGION_INDEX_SHARDS_KEY
+        );
+    }
+
+    #[tokio::test]
+    async fn test_region_index_shard_change_backfills_calls() {
+        let moved_group_id: GroupId = "aaaaaaaaaaaaaaaa".into();
+        let kept_group_id: GroupId = "bbbbbbbbbbbbbbbb".into();
+        let recorded_response =
+            r#"{"Item":{"groupConferenceId":{"S":"regionIndexShards"},"shards":{"N":"2"}}}"#;
+        let scan_response: &'static str = Box::leak(
+            format!(
+            r#"{{"Items":[{{"groupConferenceId":{{"S":"{}"}},"jvbConferenceId":{{"S":"a1a1a1a1"}},"region":{{"S":"us-west1"}},"regionShard":{{"S":"us-west1#9"}}}},{{"groupConferenceId":{{"S":"{}"}},"jvbConferenceId":{{"S":"b2b2b2b2"}},"region":{{"S":"us-west1"}},"regionShard":{{"S":"us-west1#{}"}}}}],"Count":2,"ScannedCount":2}}"#,
+            moved_group_id.as_ref(),
+            kept_group_id.as_ref(),
+            region_index_shard(&kept_group_id, 4)
+            )
+            .into_boxed_str(),
+        );
+        let (storage, connection) = create_dynamodb(vec![
+            (200, recorded_response),
+            (200, scan_response),
+            (200, "{}"),
+            (200, "{}"),
+        ]);
+        let storage = DynamoDb {
+            region_index_shards: 4,
+            ..storage
+        };
+
+        storage.check_region_index_shards(true).await.unwrap();
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 4);
+        let body = |index: usize| -> serde_json::Value {
+            serde_json::from_slice(requests[index].actual.body().bytes().unwrap()).unwrap()
+        };
+        // Only the call under a stale shard key is moved.
+        assert_eq!(body(2)["Key"]["groupConferenceId"]["S"], "aaaaaaaaaaaaaaaa");
+        assert_eq!(
+            body(2)["ExpressionAttributeValues"][":shard"]["S"],
+            format!("us-west1#{}", region_index_shard(&moved_group_id, 4))
+        );
+        assert_eq!(
+            body(2)["UpdateExpression"],
+            "SET regionShard = :shard, #version = if_not_exists(#version, :zero) + :one"
+        );
+        assert_eq!(
+            body(3)["Item"]["groupConferenceId"]["S"],
+            REGION_INDEX_SHARDS_KEY
+        );
+        assert_eq!(body(3)["Item"]["shards"]["N"], "4");
+    }
+
+    #[test]
+    fn test_storage_error_kind_serialization() {
+        for (err, code) in [
+            (StorageError::CallAlreadyExists, "call_already_exists"),
+            (
+                StorageError::RegionFull("us-west1".to_string()),
+                "region_full",
+            ),
+            (StorageError::VersionConflict, "version_conflict"),
+            (
+                StorageError::ItemTooLarge {
+                    size: 500000,
+                    limit: 358400,
+                },
+                "item_too_large",
+            ),
+            (
+                StorageError::MetadataTooLarge {
+                    size: 2000,
+                    limit: 1024,
+                },
+                "metadata_too_large",
+            ),
+            (
+                StorageError::SchemaMismatch(vec!["the region-index index is missing".into()]),
+                "schema_mismatch",
+            ),
+            (
+                StorageError::TableNotFound("CallRecords".to_string()),
+                "table_not_found",
+            ),
+            (StorageError::ReadOnly, "read_only"),
+            (StorageError::DeadlineExceeded, "deadline_exceeded"),
+            (StorageError::ShuttingDown, "shutting_down"),
+            (
+                StorageError::RegionIndexUnavailable("region-index".to_string()),
+                "region_index_unavailable",
+            ),
+            (
+                StorageError::Throttled(anyhow!("throttled on table CallRecords")),
+                "throttled",
+            ),
+            (
+                StorageError::UnexpectedError(anyhow!("secret table CallRecords")),
+                "unexpected",
+            ),
+        ] {
+            let kind = StorageErrorKind::from(&err);
+            assert_eq!(kind.code(), code);
+            assert_eq!(err.kind(), code);
+            assert_eq!(
+                serde_json::to_value(kind).unwrap(),
+                serde_json::json!({ "code": code })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_consecutive_fetch_failures_trip_readiness() {
+        let source_path =
+            std::env::temp_dir().join(format!("identity_flaky_source_{}", std::process::id()));
+        let identity_token_path =
+            std::env::temp_dir().join(format!("identity_flaky_{}", std::process::id()));
+        let _ = std::fs::remove_file(&source_path);
+        let fetcher = IdentityFetcher {
+            identity_source: config::IdentitySource::File {
+                path: source_path.to_str().unwrap().to_string(),
+            },
+            ..create_identity_fetcher(identity_token_path.clone())
+        };
+        let readiness = fetcher.readiness();
+
+        // The source doesn't exist yet, so every fetch fails.
+        for _ in 0..2 {
+            assert!(!fetcher.fetch_token_and_update_health().await);
+            assert!(readiness.is_healthy());
+        }
+        assert!(!fetcher.fetch_token_and_update_health().await);
+        assert!(!readiness.is_healthy());
+
+        std::fs::write(&source_path, b"file-token").unwrap();
+        assert!(!fetcher.fetch_token_and_update_health().await);
+        assert!(readiness.is_healthy());
+
+        // Once configured to, the fetcher gives up when it becomes unhealthy.
+        std::fs::remove_file(&source_path).unwrap();
+        let fetcher = IdentityFetcher {
+            max_consecutive_failures: 1,
+            exit_when_unhealthy: true,
+            ..fetcher
+        };
+        assert!(fetcher.fetch_token_and_update_health().await);
+        let _ = std::fs::remove_file(&identity_token_path);
+    }
+
+    #[test]
+    fn test_preferred_region_serialization() {
+        let call = CallRecord {
+            backend_region: "us-east4".to_string(),
+            preferred_region: Some("us-west1".to_string()),
+            ..create_call_record()
+        };
+        let item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        assert_eq!(
+            item.get("preferredRegion").unwrap().as_s().unwrap(),
+            "us-west1"
+        );
+        let round_trip: CallRecord = from_item(item).unwrap();
+        assert_eq!(round_trip, call);
+        assert_eq!(round_trip.preferred_region(), "us-west1");
+
+        // Records written before the preferred region was tracked prefer their own region.
+        let mut item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        item.remove("preferredRegion");
+        let old: CallRecord = from_item(item).unwrap();
+        assert_eq!(old.preferred_region, None);
+        assert_eq!(old.preferred_region(), "us-east4");
+    }
+
+    #[tokio::test]
+    async fn test_export_segments_visits_each_record_once() {
+        const SEGMENT_RESPONSES: [&str; 3] = [
+            r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":1,"ScannedCount":1}"#,
+            r#"{"Items":[{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"2222222222222222"}}],"Count":1,"ScannedCount":1}"#,
+            r#"{"Items":[{"groupConferenceId":{"S":"cccccccccccccccc"},"jvbConferenceId":{"S":"c3c3c3c3"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"3333333333333333"}}],"Count":1,"ScannedCount":1}"#,
+        ];
+
+        let (storage, connection) = create_dynamodb(
+            SEGMENT_RESPONSES
+                .iter()
+                .map(|response| (200, *response))
+                .collect(),
+        );
+
+        let mut exported: Vec<_> = storage
+            .export_segments(3, 2)
+            .map(|call| call.unwrap().call_id)
+            .collect()
+            .await;
+        exported.sort();
+        assert_eq!(exported, vec!["a1a1a1a1", "b2b2b2b2", "c3c3c3c3"]);
+
+        let mut segments: Vec<_> = connection
+            .requests()
+            .iter()
+            .map(|request| {
+                let body: serde_json::Value =
+                    serde_json::from_slice(request.actual.body().bytes().unwrap()).unwrap();
+                assert_eq!(body["TableName"], "CallRecords");
+                assert_eq!(body["TotalSegments"], 3);
+                body["Segment"].as_u64().unwrap()
+            })
+            .collect();
+        segments.sort_unstable();
+        assert_eq!(segments, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_export_segments_pages_through_each_segment() {
+        const FIRST_PAGE_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"}}],"Count":1,"ScannedCount":1,"LastEvaluatedKey":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"}}}"#;
+        const LAST_PAGE_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.2"},"region":{"S":"us-east4"},"creator":{"S":"2222222222222222"}}],"Count":1,"ScannedCount":1}"#;
+        const COUNTER_SEGMENT_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"callEra#cccccccccccccccc"},"era":{"N":"3"}},{"groupConferenceId":{"S":"cccccccccccccccc"},"jvbConferenceId":{"S":"c3c3c3c3"},"jvbHost":{"S":"127.0.0.3"},"region":{"S":"us-west1"},"creator":{"S":"3333333333333333"}}],"Count":2,"ScannedCount":2}"#;
+
+        // With one worker, the segments are scanned one after the other, so the responses
+        // are consumed in a known order.
+        let (storage, connection) = create_dynamodb(vec![
+            (200, FIRST_PAGE_RESPONSE),
+            (200, LAST_PAGE_RESPONSE),
+            (200, COUNTER_SEGMENT_RESPONSE),
+        ]);
+
+        let exported: Vec<_> = storage
+            .export_segments(2, 1)
+            .map(|call| call.unwrap())
+            .collect()
+            .await;
+        assert_eq!(
+            exported
+                .iter()
+                .map(|call| (
+                    call.group_id.as_ref(),
+                    call.call_id.as_str(),
+                    call.backend_ip.as_str(),
+                    call.backend_region.as_str()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("aaaaaaaaaaaaaaaa", "a1a1a1a1", "127.0.0.1", "us-west1"),
+                ("bbbbbbbbbbbbbbbb", "b2b2b2b2", "127.0.0.2", "us-east4"),
+                ("cccccccccccccccc", "c3c3c3c3", "127.0.0.3", "us-west1"),
+            ]
+        );
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 3);
+        let body = |index: usize| -> serde_json::Value {
+            serde_json::from_slice(requests[index].actual.body().bytes().unwrap()).unwrap()
+        };
+        for index in 0..3 {
+            assert_eq!(body(index)["TableName"], "CallRecords");
+            assert_eq!(body(index)["TotalSegments"], 2);
+        }
+        assert_eq!(body(0)["Segment"], 0);
+        assert!(body(0).get("ExclusiveStartKey").is_none());
+        // The next page of a segment starts where the last one ended.
+        assert_eq!(body(1)["Segment"], 0);
+        assert_eq!(
+            body(1)["ExclusiveStartKey"]["groupConferenceId"]["S"],
+            "aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(body(2)["Segment"], 1);
+        assert!(body(2).get("ExclusiveStartKey").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_remove_call_records_created_before() {
+        const SCAN_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"}},{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"}}],"Count":2,"ScannedCount":5}"#;
+
+        // The second call was replaced between the scan and its removal.
+        let (storage, connection) = create_dynamodb(vec![
+            (200, SCAN_RESPONSE),
+            (200, "{}"),
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, "{}"),
+        ]);
+
+        assert_eq!(
+            storage
+                .remove_call_records_created_before(1000, false)
+                .await
+                .unwrap(),
+            vec![GroupId::from("aaaaaaaaaaaaaaaa")]
+        );
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["FilterExpression"], "createdAt < :cutoff");
+        assert_eq!(body["ExpressionAttributeValues"][":cutoff"]["N"], "1000");
+    }
+
+    #[tokio::test]
+    async fn test_remove_call_records_created_before_dry_run() {
+        const EVENT: &str =
+            "calling.frontend.storage.remove_call_records_created_before.would_remove";
+        const SCAN_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"}},{"groupConferenceId":{"S":"bbbbbbbbbbbbbbbb"},"jvbConferenceId":{"S":"b2b2b2b2"}}],"Count":2,"ScannedCount":5}"#;
+
+        let (storage, connection) = create_dynamodb(vec![(200, SCAN_RESPONSE)]);
+        let before = metrics!().peek_event_count(EVENT);
+
+        assert_eq!(
+            storage
+                .remove_call_records_created_before(1000, true)
+                .await
+                .unwrap(),
+            vec![
+                GroupId::from("aaaaaaaaaaaaaaaa"),
+                GroupId::from("bbbbbbbbbbbbbbbb")
+            ]
+        );
+
+        // Only the scan was sent.
+        assert_eq!(connection.requests().len(), 1);
+        assert!(metrics!().peek_event_count(EVENT) > before);
+    }
+
+    #[tokio::test]
+    async fn test_get_or_add_rejects_oversized_record() {
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+        let storage = DynamoDb {
+            max_item_bytes: 1000,
+            ..storage
+        };
+
+        let call = CallRecord {
+            backup_backends: (0..100)
+                .map(|i| BackendRef {
+                    region: "us-west1".to_string(),
+                    ip: format!("10.0.0.{}", i),
+                })
+                .collect(),
+            ..create_call_record()
+        };
+        let size = item_size(&to_item(&call).unwrap());
+        assert!(size > 1000, "{}", size);
+
+        assert!(matches!(
+            storage.get_or_add_call_record(call).await,
+            Err(StorageError::ItemTooLarge { limit: 1000, .. })
+        ));
+        assert!(connection.requests().is_empty());
+
+        assert!(storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_every_write_rejects_oversized_record() {
+        let (storage, connection) = create_dynamodb(vec![]);
+        let storage = DynamoDb {
+            max_item_bytes: 1000,
+            ..storage
+        };
+        let oversized = CallRecord {
+            backup_backends: (0..100)
+                .map(|i| BackendRef {
+                    region: "us-west1".to_string(),
+                    ip: format!("10.0.0.{}", i),
+                })
+                .collect(),
+            ..create_call_record()
+        };
+
+        assert!(matches!(
+            storage.update_call_record(oversized.clone()).await,
+            Err(StorageError::ItemTooLarge { limit: 1000, .. })
+        ));
+        assert!(matches!(
+            storage
+                .create_call_reserving_capacity(oversized.clone(), 10)
+                .await,
+            Err(StorageError::ItemTooLarge { limit: 1000, .. })
+        ));
+        // Nothing in the batch is written if any of it is too large.
+        assert!(matches!(
+            storage
+                .add_call_records(vec![
+                    CallRecord {
+                        group_id: "bbbbbbbbbbbbbbbb".into(),
+                        ..create_call_record()
+                    },
+                    oversized
+                ])
+                .await,
+            Err(StorageError::ItemTooLarge { limit: 1000, .. })
+        ));
+        assert!(connection.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_every_write_rejects_oversized_metadata() {
+        let (storage, connection) = create_dynamodb(vec![]);
+        // The metadata is filled in directly, past the guard of set_metadata.
+        let mut oversized = create_call_record();
+        oversized
+            .metadata
+            .insert("big".to_string(), "x".repeat(MAX_CALL_METADATA_BYTES));
+
+        assert!(matches!(
+            storage.get_or_add_call_record(oversized.clone()).await,
+            Err(StorageError::MetadataTooLarge {
+                limit: MAX_CALL_METADATA_BYTES,
+                ..
+            })
+        ));
+        assert!(matches!(
+            storage.update_call_record(oversized.clone()).await,
+            Err(StorageError::MetadataTooLarge {
+                limit: MAX_CALL_METADATA_BYTES,
+                ..
+            })
+        ));
+        assert!(matches!(
+            storage
+                .create_call_reserving_capacity(oversized.clone(), 10)
+                .await,
+            Err(StorageError::MetadataTooLarge {
+                limit: MAX_CALL_METADATA_BYTES,
+                ..
+            })
+        ));
+        assert!(matches!(
+            storage
+                .add_call_records(vec![
+                    CallRecord {
+                        group_id: "bbbbbbbbbbbbbbbb".into(),
+                        ..create_call_record()
+                    },
+                    oversized
+                ])
+                .await,
+            Err(StorageError::MetadataTooLarge {
+                limit: MAX_CALL_METADATA_BYTES,
+                ..
+            })
+        ));
+        assert!(connection.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reap_dead_calls_allows_clock_skew() {
+        const SCAN_RESPONSE: &str = r#"{"Items":[],"Count":0,"ScannedCount":5}"#;
+
+        let (storage, connection) = create_dynamodb(vec![(200, SCAN_RESPONSE)]);
+        let storage = DynamoDb {
+            clock: Arc::new(MockClock::from_secs(1000)),
+            clock_skew_tolerance: Duration::from_secs(5),
+            ..storage
+        };
+
+        storage
+            .reap_dead_calls(Duration::from_secs(60), false)
+            .await
+            .unwrap();
+
+        let body: serde_json::Value =
+            serde_json::from_slice(connection.requests()[0].actual.body().bytes().unwrap())
+                .unwrap();
+        assert_eq!(body["ExpressionAttributeValues"][":threshold"]["N"], "935");
+    }
+
+    #[tokio::test]
+    async fn test_reap_dead_calls_dry_run() {
+        const SCAN_RESPONSE: &str = r#"{"Items":[{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"}}],"Count":1,"ScannedCount":5}"#;
+
+        let (storage, connection) = create_dynamodb(vec![(200, SCAN_RESPONSE)]);
+
+        assert_eq!(
+            storage
+                .reap_dead_calls(Duration::from_secs(60), true)
+                .await
+                .unwrap(),
+            vec![GroupId::from("aaaaaaaaaaaaaaaa")]
+        );
+
+        // Only the scan was sent.
+        assert_eq!(connection.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_locked_serialization() {
+        let call = create_call_record();
+        let item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        assert!(!item.contains_key("locked"));
+        assert!(!item.contains_key("lockedBy"));
+
+        let call = CallRecord {
+            locked: true,
+            locked_by: Some("1111111111111111".to_string()),
+            ..call
+        };
+        let item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        assert_eq!(item.get("locked").unwrap().as_bool().unwrap(), &true);
+        assert_eq!(
+            item.get("lockedBy").unwrap().as_s().unwrap(),
+            "1111111111111111"
+        );
+        let round_trip: CallRecord = from_item(item).unwrap();
+        assert_eq!(round_trip, call);
+
+        // Records written before calls could be locked are unlocked.
+        let mut item: std::collections::HashMap<String, AttributeValue> = to_item(&call).unwrap();
+        item.remove("locked");
+        item.remove("lockedBy");
+        let old: CallRecord = from_item(item).unwrap();
+        assert!(!old.locked);
+        assert_eq!(old.locked_by, None);
+    }
+
+    #[tokio::test]
+    async fn test_set_call_locked() {
+        let (storage, connection) = create_dynamodb(vec![
+            (200, "{}"),
+            (200, "{}"),
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+        ]);
+        let group_id = GroupId::from("aaaaaaaaaaaaaaaa");
+
+        assert!(storage
+            .set_call_locked(
+                &group_id,
+                "a1a1a1a1",
+                true,
+                Some("1111111111111111".to_string())
+            )
+            .await
+            .unwrap());
+        assert!(storage
+            .set_call_locked(&group_id, "a1a1a1a1", false, None)
+            .await
+            .unwrap());
+        assert!(!storage
+            .set_call_locked(&group_id, "b2b2b2b2", true, None)
+            .await
+            .unwrap());
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["UpdateExpression"],
+            "SET locked = :locked, lockedBy = :locked_by, \
+             #version = if_not_exists(#version, :zero) + :one"
+        );
+        assert_eq!(body["ConditionExpression"], "jvbConferenceId = :value");
+        assert_eq!(
+            body["ExpressionAttributeValues"][":locked_by"]["S"],
+            "1111111111111111"
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[1].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(
+            body["UpdateExpression"],
+            "SET #version = if_not_exists(#version, :zero) + :one REMOVE locked, lockedBy"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_or_add_overwrites_expired_record() {
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+        let storage = DynamoDb {
+            clock: Arc::new(MockClock::from_secs(1000)),
+            clock_skew_tolerance: Duration::from_secs(5),
+            ..storage
+        };
+
+        // The put succeeds whether or not an expired record was replaced, and the new
+        // record is returned either way.
+        let call = storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(call.call_id, "a1a1a1a1");
+        assert_eq!(call.created_at, Some(1000));
+
+        let body: serde_json::Value =
+            serde_json::from_slice(connection.requests()[0].actual.body().bytes().unwrap())
+                .unwrap();
+        assert_eq!(
+            body["ConditionExpression"],
+            "attribute_not_exists(groupConferenceId) OR \
+             (expiresAt <= :expired_by AND attribute_not_exists(reservedRegion))"
+        );
+        assert_eq!(body["ExpressionAttributeValues"][":expired_by"]["N"], "995");
+    }
+
+    #[tokio::test]
+    async fn test_get_or_add_releases_expired_reserved_record() {
+        const EXPIRED_RESPONSE: &str = r#"{"Item":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"b2b2b2b2"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"2222222222222222"},"expiresAt":{"N":"900"},"reservedRegion":{"S":"us-west1"}}}"#;
+
+        let (storage, connection) = create_dynamodb(vec![
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, "{}"),
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, EXPIRED_RESPONSE),
+            (200, "{}"),
+            (200, "{}"),
+        ]);
+        let storage = DynamoDb {
+            clock: Arc::new(MockClock::from_secs(1000)),
+            ..storage
+        };
+
+        // The expired call still holds its reservation, so it is removed together with
+        // releasing it before the new call is added.
+        let call = storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(call.call_id, "a1a1a1a1");
+
+        let requests = connection.requests();
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[4].actual.body().bytes().unwrap()).unwrap();
+        let items = body["TransactItems"].as_array().unwrap();
+        assert_eq!(
+            items[0]["Delete"]["ConditionExpression"],
+            "(expiresAt <= :expired_by) AND \
+             jvbConferenceId = :reserved_call_id AND reservedRegion = :reserved_region"
+        );
+        assert_eq!(
+            items[1]["Update"]["Key"]["groupConferenceId"]["S"],
+            "regionCapacity#us-west1"
+        );
+        assert_eq!(
+            items[1]["Update"]["ExpressionAttributeValues"][":minus_one"]["N"],
+            "-1"
+        );
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[5].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["Item"]["jvbConferenceId"]["S"], "a1a1a1a1");
+    }
+
+    #[test]
+    fn test_region_endpoint() {
+        let config = config::Config {
+            storage_endpoint: None,
+            ..config::default_test_config()
+        };
+        assert_eq!(region_endpoint(&config, "us-east-1").unwrap(), None);
+
+        for (region, fips, dualstack, expected) in [
+            (
+                "us-east-1",
+                true,
+                false,
+                "https://dynamodb-fips.us-east-1.amazonaws.com",
+            ),
+            (
+                "us-east-1",
+                false,
+                true,
+                "https://dynamodb.us-east-1.api.aws",
+            ),
+            (
+                "us-east-1",
+                true,
+                true,
+                "https://dynamodb-fips.us-east-1.api.aws",
+            ),
+            (
+                "us-gov-west-1",
+                true,
+                false,
+                "https://dynamodb-fips.us-gov-west-1.amazonaws.com",
+            ),
+            (
+                "cn-north-1",
+                false,
+                true,
+                "https://dynamodb.cn-north-1.api.amazonwebservices.com.cn",
+            ),
+            (
+                "us-iso-east-1",
+                true,
+                false,
+                "https://dynamodb-fips.us-iso-east-1.c2s.ic.gov",
+            ),
+            (
+                "us-isob-east-1",
+                true,
+                false,
+                "https://dynamodb-fips.us-isob-east-1.sc2s.sgov.gov",
+            ),
+        ] {
+            let config = config::Config {
+                storage_use_fips: fips,
+                storage_use_dualstack: dualstack,
+                ..config.clone()
+            };
+            assert!(config.validate_storage().is_ok());
+            assert_eq!(
+                region_endpoint(&config, region).unwrap().as_deref(),
+                Some(expected),
+                "{}",
+                region
+            );
+        }
+
+        // Partitions without dualstack endpoints can't use them.
+        let config = config::Config {
+            storage_use_dualstack: true,
+            ..config
+        };
+        assert!(region_endpoint(&config, "us-iso-east-1").is_err());
+
+        // The endpoint for testing can't be combined with them.
+        let config = config::Config {
+            storage_use_fips: true,
+            ..config::default_test_config()
+        };
+        assert!(config.validate_storage().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fips_requests_go_to_fips_endpoint() {
+        let connection = TestConnection::new(vec![(
+            http::Request::builder().body(SdkBody::from("")).unwrap(),
+            http::Response::builder()
+                .status(200)
+                .body(GET_ITEM_RESPONSE)
+                .unwrap(),
+        )]);
+        let config = config::Config {
+            storage_endpoint: None,
+            storage_use_fips: true,
+            ..config::default_test_config()
+        };
+        let builder = Config::builder()
+            .credentials_provider(Credentials::from_keys("KEY", "PASSWORD", None))
+            .region(Region::new("us-east-1"))
+            .retry_config(RetryConfig::disabled());
+        let aws_config = with_region_endpoint(&config, "us-east-1", builder)
+            .unwrap()
+            .build();
+
+        let (storage, _) = create_dynamodb(vec![]);
+        let storage = DynamoDb {
+            client: Client::from_conf_conn(aws_config, connection.clone()),
+            ..storage
+        };
+        assert!(storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(
+            connection.requests()[0].actual.uri().host(),
+            Some("dynamodb-fips.us-east-1.amazonaws.com")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ensure_table_exists_refused_without_endpoint() {
+        let (storage, connection) = create_dynamodb(vec![]);
+        assert!(storage.ensure_table_exists().await.is_err());
+        assert!(connection.requests().is_empty());
+
+        let config = config::Config {
+            storage_create_table: true,
+            ..config::default_test_config()
+        };
+        assert!(config.validate_storage().is_ok());
+        let config = config::Config {
+            storage_endpoint: None,
+            ..config
+        };
+        assert!(config.validate_storage().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_table_exists_for_shard_table() {
+        let (storage, connection) = create_dynamodb(vec![(200, "{}")]);
+        let storage = DynamoDb {
+            create_table_allowed: true,
+            ..storage
+        };
+
+        // Each shard table is created on its own at startup.
+        storage
+            .for_table("CallRecords-1".to_string())
+            .ensure_table_exists()
+            .await
+            .unwrap();
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 1);
+        let body: serde_json::Value =
+            serde_json::from_slice(requests[0].actual.body().bytes().unwrap()).unwrap();
+        assert_eq!(body["TableName"], "CallRecords-1");
+    }
+
+    /// Needs a local DynamoDB that it is free to create tables in, at DYNAMODB_ENDPOINT or
+    /// else at the default port on localhost. Run it with `cargo test -- --ignored`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_ensure_table_exists_creates_usable_table() {
+        let endpoint =
+            env::var("DYNAMODB_ENDPOINT").unwrap_or_else(|_| "http://127.0.0.1:8000".to_string());
+        let config: &'static config::Config = Box::leak(Box::new(config::Config {
+            storage_table: format!("CallRecords{}", std::process::id()),
+            storage_endpoint: Some(endpoint),
+            storage_create_table: true,
+            ..config::default_test_config()
+        }));
+        let (storage, _) = DynamoDb::new(config, Arc::new(SystemClock), Arc::new(FieldCodec))
+            .await
+            .unwrap();
+
+        storage.ensure_table_exists().await.unwrap();
+        // It's a no-op once the table exists.
+        storage.ensure_table_exists().await.unwrap();
+        assert!(storage.health_check().await.is_ok());
+
+        let call = create_call_record();
+        let added = storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(added.call_id, "a1a1a1a1");
+        let calls = storage
+            .get_call_records_for_region_with_retry(
+                &call.backend_region,
+                &call.group_id,
+                &call.call_id,
+                Duration::from_secs(5),
+            )
+            .await
+            .unwrap();
+        assert_eq!(calls.len(), 1);
+
+        storage
+            .client
+            .delete_table()
+            .table_name(&config.storage_table)
+            .send()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_key_prefixes_share_a_local_table() {
+        let endpoint =
+            env::var("DYNAMODB_ENDPOINT").unwrap_or_else(|_| "http://127.0.0.1:8000".to_string());
+        let config: &'static config::Config = Box::leak(Box::new(config::Config {
+            storage_table: format!("PrefixedCallRecords{}", std::process::id()),
+            storage_key_prefix: Some("tenant-a#".to_string()),
+            storage_endpoint: Some(endpoint),
+            storage_create_table: true,
+            ..config::default_test_config()
+        }));
+        let (tenant_a, _) = DynamoDb::new(config, Arc::new(SystemClock), Arc::new(FieldCodec))
+            .await
+            .unwrap();
+        tenant_a.ensure_table_exists().await.unwrap();
+        let tenant_b = DynamoDb {
+            key_prefix: "tenant-b#".to_string(),
+            ..tenant_a.for_table(config.storage_table.clone())
+        };
+
+        let call = create_call_record();
+        assert!(tenant_a
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .is_some());
+
+        // The call of the other prefix is invisible, so the same group gets its own.
+        assert_eq!(
+            tenant_b.get_call_record(&call.group_id).await.unwrap(),
+            None
+        );
+        assert!(tenant_b
+            .get_call_records_for_region(&call.backend_region)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(tenant_b.export_all().count().await, 0);
+        assert!(tenant_b
+            .reap_dead_calls(Duration::ZERO, true)
+            .await
+            .unwrap()
+            .is_empty());
+        let other_call = CallRecord {
+            call_id: "b2b2b2b2".to_string(),
+            ..create_call_record()
+        };
+        let added = tenant_b
+            .get_or_add_call_record(other_call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(added.call_id, other_call.call_id);
+
+        let read = tenant_a
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.call_id, call.call_id);
+        assert_eq!(read.group_id, call.group_id);
+        let exported: Vec<_> = tenant_a.export_all().collect().await;
+        assert_eq!(exported.len(), 1);
+
+        tenant_a
+            .client
+            .delete_table()
+            .table_name(&config.storage_table)
+            .send()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verify_schema() {
+        const DESCRIBE_TABLE_RESPONSE: &str = r#"{"Table":{"TableName":"CallRecords","TableStatus":"ACTIVE","KeySchema":[{"AttributeName":"groupConferenceId","KeyType":"HASH"}],"GlobalSecondaryIndexes":[{"IndexName":"region-index","KeySchema":[{"AttributeName":"region","KeyType":"HASH"}],"IndexStatus":"ACTIVE"}]}}"#;
+        const DESCRIBE_TTL_RESPONSE: &str = r#"{"TimeToLiveDescription":{"AttributeName":"expiresAt","TimeToLiveStatus":"ENABLED"}}"#;
+
+        let (storage, _) = create_dynamodb(vec![
+            (200, DESCRIBE_TABLE_RESPONSE),
+            (200, DESCRIBE_TTL_RESPONSE),
+        ]);
+        assert!(storage.verify_schema().await.is_ok());
+
+        // With write sharding, the region-shard-index is needed too.
+        let (storage, _) = create_dynamodb(vec![
+            (200, DESCRIBE_TABLE_RESPONSE),
+            (200, DESCRIBE_TTL_RESPONSE),
+        ]);
+        let storage = DynamoDb {
+            region_index_shards: 2,
+            ..storage
+        };
+        match storage.verify_schema().await {
+            Err(StorageError::SchemaMismatch(mismatches)) => assert_eq!(
+                mismatches,
+                vec!["the region-shard-index index is missing".to_string()]
+            ),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_schema_reports_every_mismatch() {
+        let (storage, _) = create_dynamodb(vec![
+            (
+                200,
+                r#"{"Table":{"TableName":"CallRecords","TableStatus":"ACTIVE","KeySchema":[{"AttributeName":"groupId","KeyType":"HASH"}],"GlobalSecondaryIndexes":[{"IndexName":"region-index","KeySchema":[{"AttributeName":"jvbHost","KeyType":"HASH"}],"IndexStatus":"ACTIVE"}]}}"#,
+            ),
+            (
+                200,
+                r#"{"TimeToLiveDescription":{"TimeToLiveStatus":"DISABLED"}}"#,
+            ),
+        ]);
+
+        match storage.verify_schema().await {
+            Err(StorageError::SchemaMismatch(mismatches)) => assert_eq!(
+                mismatches,
+                vec![
+                    r#"the partition key is "groupId" rather than "groupConferenceId""#,
+                    r#"the partition key of the region-index index is "jvbHost" rather than "region""#,
+                    r#"TTL isn't enabled on "expiresAt""#,
+                ]
+            ),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    /// Needs a local DynamoDB that it is free to create tables in, like
+    /// test_ensure_table_exists_creates_usable_table.
+    #[tokio::test]
+    #[ignore]
+    async fn test_verify_schema_of_wrong_local_table() {
+        let endpoint =
+            env::var("DYNAMODB_ENDPOINT").unwrap_or_else(|_| "http://127.0.0.1:8000".to_string());
+        let config: &'static config::Config = Box::leak(Box::new(config::Config {
+            storage_table: format!("WrongCallRecords{}", std::process::id()),
+            storage_endpoint: Some(endpoint),
+            ..config::default_test_config()
+        }));
+        let (storage, _) = DynamoDb::new(config, Arc::new(SystemClock), Arc::new(FieldCodec))
+            .await
+            .unwrap();
+
+        // Keyed by the wrong attribute, without region indexes or TTL.
+        storage
+            .client
+            .create_table()
+            .table_name(&config.storage_table)
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name("groupId")
+                    .attribute_type(ScalarAttributeType::S)
+                    .build(),
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name("groupId")
+                    .key_type(KeyType::Hash)
+                    .build(),
+            )
+            .billing_mode(BillingMode::PayPerRequest)
+            .send()
+            .await
+            .unwrap();
+
+        let result = storage.verify_schema().await;
+        storage
+            .client
+            .delete_table()
+            .table_name(&config.storage_table)
+            .send()
+            .await
+            .unwrap();
+
+        match result {
+            Err(StorageError::SchemaMismatch(mismatches)) => assert_eq!(
+                mismatches,
+                vec![
+                    r#"the partition key is "groupId" rather than "groupConferenceId""#,
+                    "the region-index index is missing",
+                    r#"TTL isn't enabled on "expiresAt""#,
+                ]
+            ),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_invalid_certs_only_allowed_with_endpoint() {
+        assert!(!allows_invalid_certs(&config::default_test_config()));
+
+        let config = config::Config {
+            storage_endpoint_allow_invalid_certs: true,
+            ..config::default_test_config()
+        };
+        // Only builds that can skip verification accept the setting.
+        assert_eq!(
+            config.validate_storage().is_ok(),
+            cfg!(feature = "insecure-test-endpoint")
+        );
+        assert!(allows_invalid_certs(&config));
+        assert_eq!(
+            insecure_connector(&config).is_some(),
+            cfg!(feature = "insecure-test-endpoint")
+        );
+
+        // Without an endpoint, production settings are used no matter what.
+        let config = config::Config {
+            storage_endpoint: None,
+            ..config
+        };
+        assert!(config.validate_storage().is_err());
+        assert!(!allows_invalid_certs(&config));
+    }
+
+    #[tokio::test]
+    async fn test_get_call_record_with_meta() {
+        for consistent_reads in [true, false] {
+            let (storage, connection) = create_dynamodb(vec![(200, GET_ITEM_RESPONSE)]);
+            let storage = DynamoDb {
+                consistent_reads,
+                ..storage
+            };
+
+            let (call, meta) = storage
+                .get_call_record_with_meta(&"aaaaaaaaaaaaaaaa".into())
+                .await
+                .unwrap();
+            assert_eq!(call.unwrap().call_id, "b2b2b2b2");
+            assert_eq!(
+                meta,
+                ReadMeta {
+                    consistent: consistent_reads,
+                    failover: false,
+                }
+            );
+
+            let body: serde_json::Value =
+                serde_json::from_slice(connection.requests()[0].actual.body().bytes().unwrap())
+                    .unwrap();
+            assert_eq!(body["ConsistentRead"], consistent_reads);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_or_add_rereads_consistently() {
+        let (storage, connection) = create_dynamodb(vec![
+            (200, GET_ITEM_RESPONSE),
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, GET_ITEM_RESPONSE),
+        ]);
+        let storage = DynamoDb {
+            consistent_reads: false,
+            ..storage
+        };
+
+        let call = create_call_record();
+        storage.get_call_record(&call.group_id).await.unwrap();
+        let existing = storage.get_or_add_call_record(call).await.unwrap().unwrap();
+        assert_eq!(existing.call_id, "b2b2b2b2");
+
+        let requests = connection.requests();
+        let consistent_read = |index: usize| {
+            let body: serde_json::Value =
+                serde_json::from_slice(requests[index].actual.body().bytes().unwrap()).unwrap();
+            body["ConsistentRead"].clone()
+        };
+        // Plain gets follow the instance default, but the read after losing a race doesn't.
+        assert_eq!(consistent_read(0), false);
+        assert_eq!(consistent_read(2), true);
+    }
+
+    #[tokio::test]
+    async fn test_conflict_read_is_jittered() {
+        const JITTER: Duration = Duration::from_millis(20);
+        let next_delay = |rng: &mut StdRng| Duration::from_millis(rng.gen_range(0..=20));
+        let mut expected = StdRng::seed_from_u64(7);
+
+        // Adding a call without a race doesn't wait.
+        let (storage, _) = create_dynamodb(vec![
+            (200, "{}"),
+            (200, r#"{"Attributes":{"era":{"N":"1"}}}"#),
+            (200, "{}"),
+        ]);
+        let storage = DynamoDb {
+            conflict_read_jitter: JITTER,
+            jitter_rng: Arc::new(Mutex::new(StdRng::seed_from_u64(7))),
+            ..storage
+        };
+        storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap();
+        // No delay was drawn.
+        assert_eq!(storage.conflict_read_delay(), next_delay(&mut expected));
+
+        // Losing the race waits for the next delay before reading the winner.
+        let (storage, _) = create_dynamodb(vec![
+            (400, CONDITIONAL_CHECK_FAILED_RESPONSE),
+            (200, GET_ITEM_RESPONSE),
+        ]);
+        let storage = DynamoDb {
+            conflict_read_jitter: JITTER,
+            jitter_rng: Arc::new(Mutex::new(StdRng::seed_from_u64(7))),
+            ..storage
+        };
+        let mut expected = StdRng::seed_from_u64(7);
+        let delay = next_delay(&mut expected);
+        let start = std::time::Instant::now();
+        let existing = storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(existing.call_id, "b2b2b2b2");
+        assert!(start.elapsed() >= delay.into());
+        assert_eq!(storage.conflict_read_delay(), next_delay(&mut expected));
+    }
+
+    #[tokio::test]
+    async fn test_created_call_starts_next_era() {
+        let (storage, connection) = create_dynamodb(vec![
+            (200, "{}"),
+            (200, r#"{"Attributes":{"era":{"N":"3"}}}"#),
+            (200, "{}"),
+        ]);
+
+        let call = storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(call.era, 3);
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 3);
+        let body = |index: usize| -> serde_json::Value {
+            serde_json::from_slice(requests[index].actual.body().bytes().unwrap()).unwrap()
+        };
+        assert_eq!(
+            body(1)["Key"]["groupConferenceId"]["S"],
+            "callEra#aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(
+            body(1)["UpdateExpression"],
+            "SET expiresAt = :expires_at ADD era :one"
+        );
+        assert!(
+            body(1)["ExpressionAttributeValues"][":expires_at"]["N"]
+                .as_str()
+                .unwrap()
+                .parse::<u64>()
+                .unwrap()
+                > call.expires_at.unwrap()
+        );
+        assert_eq!(body(1)["ReturnValues"], "UPDATED_NEW");
+        assert_eq!(body(2)["Key"]["groupConferenceId"]["S"], "aaaaaaaaaaaaaaaa");
+        assert_eq!(
+            body(2)["UpdateExpression"],
+            "SET era = :era, #version = if_not_exists(#version, :zero) + :one"
+        );
+        assert_eq!(body(2)["ExpressionAttributeValues"][":era"]["N"], "3");
+        assert_eq!(
+            body(2)["ExpressionAttributeValues"][":call_id"]["S"],
+            "a1a1a1a1"
+        );
+        // Recording the era is a change to the call like any other.
+        assert_eq!(call.version, 2);
+    }
+
+    #[tokio::test]
+    async fn test_recreated_call_starts_next_era() {
+        // The first call is created, then removed, and then a new call is created for the
+        // same group, which the counter gives the next era.
+        let (storage, connection) = create_dynamodb(vec![
+            (200, "{}"),
+            (200, r#"{"Attributes":{"era":{"N":"3"}}}"#),
+            (200, "{}"),
+            (
+                200,
+                r#"{"Attributes":{"groupConferenceId":{"S":"aaaaaaaaaaaaaaaa"},"jvbConferenceId":{"S":"a1a1a1a1"},"jvbHost":{"S":"127.0.0.1"},"region":{"S":"us-west1"},"creator":{"S":"1111111111111111"},"era":{"N":"3"}}}"#,
+            ),
+            (200, "{}"),
+            (200, r#"{"Attributes":{"era":{"N":"4"}}}"#),
+            (200, "{}"),
+        ]);
+
+        let first = storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.era, 3);
+        storage
+            .remove_call_record(&first.group_id, &first.call_id)
+            .await
+            .unwrap();
+        let second = storage
+            .get_or_add_call_record(CallRecord {
+                call_id: "b2b2b2b2".to_string(),
+                ..create_call_record()
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.call_id, "b2b2b2b2");
+        assert_eq!(second.era, 4);
+
+        let requests = connection.requests();
+        assert_eq!(requests.len(), 7);
+        let body = |index: usize| -> serde_json::Value {
+            serde_json::from_slice(requests[index].actual.body().bytes().unwrap()).unwrap()
+        };
+        assert_eq!(
+            body(5)["Key"]["groupConferenceId"]["S"],
+            "callEra#aaaaaaaaaaaaaaaa"
+        );
+        assert_eq!(body(6)["ExpressionAttributeValues"][":era"]["N"], "4");
+        assert_eq!(
+            body(6)["ExpressionAttributeValues"][":call_id"]["S"],
+            "b2b2b2b2"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_era_failure_does_not_fail_creation() {
+        const EVENT: &str = "calling.frontend.storage.start_era.error";
+
+        let (storage, _) = create_dynamodb(vec![
+            (200, "{}"),
+            (
+                400,
+                r#"{"__type":"com.amazonaws.dynamodb.v20120810#ResourceNotFoundException","message":"Requested resource not found"}"#,
+            ),
+        ]);
+        let before = metrics!().peek_event_count(EVENT);
+
+        let call = storage
+            .get_or_add_call_record(create_call_record())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(call.era, 0);
+        assert!(metrics!().peek_event_count(EVENT) > before);
+    }
+
+    #[tokio::test]
+    async fn test_request_permits_bound_concurrency() {
+        const MAX_CONCURRENT_REQUESTS: usize = 3;
+
+        let (storage, max_in_flight) = create_counting_dynamodb();
+        let storage = DynamoDb {
+            region_index_shards: 4,
+            request_permits: Some(Arc::new(Semaphore::new(MAX_CONCURRENT_REQUESTS))),
+            request_permit_timeout: Duration::from_secs(10),
+            ..storage
+        };
+
+        // Region queries send a request per shard, each of which needs a permit too.
+        try_join_all((0..12).map(|i| {
+            let storage = &storage;
+            async move {
+                match i % 3 {
+                    0 => storage
+                        .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+                        .await
+                        .map(|_| ()),
+                    1 => storage
+                        .get_call_records_for_region("us-west1")
+                        .await
+                        .map(|_| ()),
+                    _ => storage
+                        .count_call_records_for_region("us-west1")
+                        .await
+                        .map(|_| ()),
+                }
+            }
+        }))
+        .await
+        .unwrap();
+
+        assert_eq!(
+            max_in_flight.load(Ordering::SeqCst),
+            MAX_CONCURRENT_REQUESTS as u32
+        );
+    }
+
+    #[tokio::test]
+    async fn test_saturated_request_permits_throttle() {
+        let (storage, connection) = create_dynamodb(vec![(200, GET_ITEM_RESPONSE)]);
+        let permits = Arc::new(Semaphore::new(1));
+        let storage = DynamoDb {
+            request_permits: Some(permits.clone()),
+            request_permit_timeout: Duration::from_millis(10),
+            ..storage
+        };
+
+        let permit = permits.try_acquire().unwrap();
+        assert!(matches!(
+            storage.get_call_record(&"aaaaaaaaaaaaaaaa".into()).await,
+            Err(StorageError::Throttled(_))
+        ));
+        assert!(connection.requests().is_empty());
+
+        // Once the permit is released, requests go through again.
+        drop(permit);
+        assert!(storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_waiting_for_request_permit_stops_at_deadline() {
+        let (storage, connection) = create_dynamodb(vec![]);
+        let permits = Arc::new(Semaphore::new(1));
+        let storage = DynamoDb {
+            request_permits: Some(permits.clone()),
+            request_permit_timeout: Duration::from_secs(10),
+            ..storage
+        };
+
+        let _permit = permits.try_acquire().unwrap();
+        assert!(matches!(
+            with_deadline(
+                tokio::time::Instant::now() + std::time::Duration::from_millis(10),
+                storage.get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            )
+            .await,
+            Err(StorageError::DeadlineExceeded)
+        ));
+        assert!(connection.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_passed_deadline_abandons_operation() {
+        let (storage, connection) = create_dynamodb(vec![(200, GET_ITEM_RESPONSE)]);
+
+        assert!(matches!(
+            with_deadline(
+                tokio::time::Instant::now(),
+                storage.get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            )
+            .await,
+            Err(StorageError::DeadlineExceeded)
+        ));
+        assert!(connection.requests().is_empty());
+
+        // Without a deadline, the request goes through.
+        assert!(storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap()
+            .is_some());
     }
 }
@@ -0,0 +1,511 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::{collections::HashMap, time::SystemTime};
+
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::stream::BoxStream;
+use log::*;
+use serde::{Serialize, Serializer};
+
+use crate::{
+    frontend::{GroupId, UserId},
+    storage::{CallRecord, CallRecordSummary, ReadMeta, Storage, StorageError},
+};
+
+/// The result of a mutating storage operation as recorded in the audit trail.
+#[derive(Clone, Copy, Debug, Serialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    /// The mutation was applied to storage.
+    Applied,
+    /// The operation succeeded but storage was left unchanged, for example because a
+    /// different call already existed for the group.
+    NotApplied,
+    /// The operation returned an error.
+    Failed,
+}
+
+/// A single entry in the audit trail of CallRecord mutations.
+#[derive(Clone, Debug, Serialize, Eq, PartialEq)]
+pub struct AuditEntry {
+    /// Seconds since the Unix epoch at which the operation completed.
+    pub timestamp: u64,
+    pub operation: &'static str,
+    /// Serialized redacted, as it is in logs, since the audit trail is written to stdout.
+    #[serde(serialize_with = "serialize_redacted")]
+    pub group_id: GroupId,
+    pub call_id: String,
+    pub outcome: AuditOutcome,
+}
+
+fn serialize_redacted<S: Serializer>(group_id: &GroupId, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(group_id)
+}
+
+/// Destination for audit entries. Implementations must not block for long since they
+/// are invoked inline with every mutating storage operation.
+pub trait AuditSink: Sync + Send {
+    fn record(&self, entry: &AuditEntry);
+}
+
+/// Writes each audit entry to stdout as a single line of JSON.
+pub struct JsonStdoutAuditSink;
+
+impl AuditSink for JsonStdoutAuditSink {
+    fn record(&self, entry: &AuditEntry) {
+        match serde_json::to_string(entry) {
+            Ok(line) => println!("{}", line),
+            Err(err) => storage_log!(Level::Error, "failed to serialize audit entry: {}", err),
+        }
+    }
+}
+
+/// A Storage decorator that writes an audit entry for every mutating operation after
+/// delegating it to the inner storage. Reads pass through untouched.
+pub struct AuditingStorage<S: Storage> {
+    inner: S,
+    sink: Box<dyn AuditSink>,
+}
+
+impl<S: Storage> AuditingStorage<S> {
+    pub fn new(inner: S, sink: Box<dyn AuditSink>) -> Self {
+        Self { inner, sink }
+    }
+
+    fn audit(
+        &self,
+        operation: &'static str,
+        group_id: &GroupId,
+        call_id: &str,
+        outcome: AuditOutcome,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        self.sink.record(&AuditEntry {
+            timestamp,
+            operation,
+            group_id: group_id.clone(),
+            call_id: call_id.to_string(),
+            outcome,
+        });
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for AuditingStorage<S> {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.inner.get_call_record(group_id).await
+    }
+
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        self.inner.get_call_record_with_meta(group_id).await
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let group_id = call.group_id.clone();
+        let call_id = call.call_id.clone();
+
+        let result = self.inner.get_or_add_call_record(call).await;
+
+        let outcome = match &result {
+            Ok(Some(existing)) if existing.call_id == call_id => AuditOutcome::Applied,
+            Ok(_) => AuditOutcome::NotApplied,
+            Err(_) => AuditOutcome::Failed,
+        };
+        self.audit("get_or_add_call_record", &group_id, &call_id, outcome);
+
+        result
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let result = self.inner.remove_call_record(group_id, call_id).await;
+
+        let outcome = match &result {
+            Ok(Some(_)) => AuditOutcome::Applied,
+            Ok(None) => AuditOutcome::NotApplied,
+            Err(_) => AuditOutcome::Failed,
+        };
+        self.audit("remove_call_record", group_id, call_id, outcome);
+
+        result
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        // Through a DynStorage, each attempt is audited by remove_call_record. Held
+        // directly, the removal runs on the inner storage where it can't be audited.
+        self.inner.remove_call_record_best_effort(group_id, call_id)
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let result = self.inner.force_remove_call_record(group_id).await;
+
+        // The call_id isn't given, so the one of the removed call is recorded if any.
+        let (call_id, outcome) = match &result {
+            Ok(Some(call)) => (call.call_id.as_str(), AuditOutcome::Applied),
+            Ok(None) => ("", AuditOutcome::NotApplied),
+            Err(_) => ("", AuditOutcome::Failed),
+        };
+        self.audit("force_remove_call_record", group_id, call_id, outcome);
+
+        result
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        self.inner.get_call_records_for_region(region).await
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        self.inner.count_calls_per_backend(region).await
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        self.inner.count_call_records_for_region(region).await
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        self.inner.count_calls_by_backend_version(region).await
+    }
+
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        self.inner.count_all_regions().await
+    }
+
+    async fn least_loaded_backend(
+        &self,
+        region: &str,
+        candidate_ips: &[&str],
+    ) -> Result<Option<String>, StorageError> {
+        self.inner.least_loaded_backend(region, candidate_ips).await
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        self.inner
+            .get_call_records_for_region_projected(region, attributes, limit)
+            .await
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.inner.stream_call_records_for_region(region)
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let result = self.inner.promote_backup_backend(group_id, call_id).await;
+
+        let outcome = match &result {
+            Ok(Some(_)) => AuditOutcome::Applied,
+            Ok(None) => AuditOutcome::NotApplied,
+            Err(_) => AuditOutcome::Failed,
+        };
+        self.audit("promote_backup_backend", group_id, call_id, outcome);
+
+        result
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        let group_id = call.group_id.clone();
+        let call_id = call.call_id.clone();
+
+        let result = self
+            .inner
+            .create_call_reserving_capacity(call, max_calls_per_region)
+            .await;
+
+        let outcome = match &result {
+            Ok(_) => AuditOutcome::Applied,
+            Err(StorageError::CallAlreadyExists) | Err(StorageError::RegionFull(_)) => {
+                AuditOutcome::NotApplied
+            }
+            Err(_) => AuditOutcome::Failed,
+        };
+        self.audit(
+            "create_call_reserving_capacity",
+            &group_id,
+            &call_id,
+            outcome,
+        );
+
+        result
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        self.inner.health_check().await
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        let group_id = call.group_id.clone();
+        let call_id = call.call_id.clone();
+
+        let result = self.inner.update_call_record(call).await;
+
+        let outcome = match &result {
+            Ok(_) => AuditOutcome::Applied,
+            Err(StorageError::VersionConflict) => AuditOutcome::NotApplied,
+            Err(_) => AuditOutcome::Failed,
+        };
+        self.audit("update_call_record", &group_id, &call_id, outcome);
+
+        result
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        let keys: Vec<_> = records
+            .iter()
+            .map(|call| (call.group_id.clone(), call.call_id.clone()))
+            .collect();
+
+        let result = self.inner.add_call_records(records).await;
+
+        for (group_id, call_id) in keys {
+            let outcome = match &result {
+                Ok(_) => AuditOutcome::Applied,
+                Err(_) => AuditOutcome::Failed,
+            };
+            self.audit("add_call_records", &group_id, &call_id, outcome);
+        }
+
+        result
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        let result = self.inner.heartbeat_call(group_id, call_id).await;
+
+        let outcome = match &result {
+            Ok(true) => AuditOutcome::Applied,
+            Ok(false) => AuditOutcome::NotApplied,
+            Err(_) => AuditOutcome::Failed,
+        };
+        self.audit("heartbeat_call", group_id, call_id, outcome);
+
+        result
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        let result = self
+            .inner
+            .set_call_locked(group_id, call_id, locked, locked_by)
+            .await;
+
+        let outcome = match &result {
+            Ok(true) => AuditOutcome::Applied,
+            Ok(false) => AuditOutcome::NotApplied,
+            Err(_) => AuditOutcome::Failed,
+        };
+        self.audit("set_call_locked", group_id, call_id, outcome);
+
+        result
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        let result = self.inner.reap_dead_calls(max_silence, dry_run).await;
+        if dry_run {
+            // Nothing was changed, so there is nothing to audit.
+            return result;
+        }
+
+        // The call_ids of reaped calls aren't reported, so the entries leave them empty.
+        if let Ok(reaped) = &result {
+            for group_id in reaped {
+                self.audit("reap_dead_calls", group_id, "", AuditOutcome::Applied);
+            }
+        }
+
+        result
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.inner.export_all()
+    }
+}
+
+#[cfg(test)]
+mod auditing_storage_tests {
+    use std::sync::Arc;
+
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::storage::{create_call_record, BackendRef, DynStorage, InMemoryStorage};
+
+    #[derive(Clone, Default)]
+    struct RecordingAuditSink(Arc<Mutex<Vec<AuditEntry>>>);
+
+    impl AuditSink for RecordingAuditSink {
+        fn record(&self, entry: &AuditEntry) {
+            self.0.lock().push(entry.clone());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_entry_per_mutation() {
+        let sink = RecordingAuditSink::default();
+        let storage = AuditingStorage::new(InMemoryStorage::new(), Box::new(sink.clone()));
+
+        let call = CallRecord {
+            backup_backends: vec![BackendRef {
+                region: "us-east4".to_string(),
+                ip: "127.0.0.2".to_string(),
+            }],
+            ..create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1")
+        };
+        storage.get_or_add_call_record(call.clone()).await.unwrap();
+        storage
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "b2b2b2b2"))
+            .await
+            .unwrap();
+        storage
+            .promote_backup_backend(&call.group_id, &call.call_id)
+            .await
+            .unwrap();
+        storage
+            .remove_call_record(&call.group_id, &call.call_id)
+            .await
+            .unwrap();
+        storage
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "c3c3c3c3"))
+            .await
+            .unwrap();
+        storage
+            .force_remove_call_record(&call.group_id)
+            .await
+            .unwrap();
+        storage
+            .force_remove_call_record(&call.group_id)
+            .await
+            .unwrap();
+
+        // Reads are not audited.
+        storage.get_call_record(&call.group_id).await.unwrap();
+        storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap();
+
+        let entries = sink.0.lock();
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.operation, entry.call_id.as_str(), entry.outcome))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("get_or_add_call_record", "a1a1a1a1", AuditOutcome::Applied),
+                (
+                    "get_or_add_call_record",
+                    "b2b2b2b2",
+                    AuditOutcome::NotApplied
+                ),
+                ("promote_backup_backend", "a1a1a1a1", AuditOutcome::Applied),
+                ("remove_call_record", "a1a1a1a1", AuditOutcome::Applied),
+                ("get_or_add_call_record", "c3c3c3c3", AuditOutcome::Applied),
+                (
+                    "force_remove_call_record",
+                    "c3c3c3c3",
+                    AuditOutcome::Applied
+                ),
+                ("force_remove_call_record", "", AuditOutcome::NotApplied),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_best_effort_removal_is_audited() {
+        let sink = RecordingAuditSink::default();
+        let storage: DynStorage = Arc::new(AuditingStorage::new(
+            InMemoryStorage::new(),
+            Box::new(sink.clone()),
+        ));
+        let call = create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1");
+        storage.get_or_add_call_record(call.clone()).await.unwrap();
+
+        storage.remove_call_record_best_effort(&call.group_id, &call.call_id);
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while sink.0.lock().len() < 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the removal should eventually be audited");
+
+        let entries = sink.0.lock();
+        assert_eq!(entries[1].operation, "remove_call_record");
+        assert_eq!(entries[1].call_id, "a1a1a1a1");
+        assert_eq!(entries[1].outcome, AuditOutcome::Applied);
+    }
+
+    #[test]
+    fn test_audit_entry_serializes_to_json() {
+        let entry = AuditEntry {
+            timestamp: 1000,
+            operation: "remove_call_record",
+            group_id: "aaaaaaaaaaaaaaaa".into(),
+            call_id: "a1a1a1a1".to_string(),
+            outcome: AuditOutcome::NotApplied,
+        };
+
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            r#"{"timestamp":1000,"operation":"remove_call_record","group_id":"aaaa","call_id":"a1a1a1a1","outcome":"not_applied"}"#
+        );
+    }
+}
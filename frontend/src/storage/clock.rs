@@ -0,0 +1,78 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::time::SystemTime;
+
+#[cfg(test)]
+use parking_lot::Mutex;
+
+/// A source of the current wall-clock time, used by storage when setting and checking
+/// the timestamps of call records so that tests can control the passage of time.
+pub trait Clock: Sync + Send {
+    fn now(&self) -> SystemTime;
+
+    /// Returns the current time as seconds since the Unix epoch.
+    fn now_secs(&self) -> u64 {
+        self.now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
+}
+
+/// A Clock that reads the system time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A Clock that only moves when told to, for tests.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    /// Creates a clock set to the given number of seconds since the Unix epoch.
+    pub fn from_secs(secs: u64) -> Self {
+        Self {
+            now: Mutex::new(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn advance(&self, duration: std::time::Duration) {
+        *self.now.lock() += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock()
+    }
+}
+
+#[cfg(test)]
+mod clock_tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock_advance() {
+        let clock = MockClock::from_secs(1000);
+        assert_eq!(clock.now_secs(), 1000);
+
+        clock.advance(std::time::Duration::from_millis(1500));
+        assert_eq!(clock.now_secs(), 1001);
+        assert_eq!(
+            clock.now(),
+            SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1_001_500)
+        );
+    }
+}
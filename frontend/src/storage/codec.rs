@@ -0,0 +1,228 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context, Result};
+use aws_sdk_dynamodb::{model::AttributeValue, types::Blob};
+use serde_dynamo::{from_item, to_attribute_value, to_item};
+
+use crate::storage::CallRecord;
+
+/// The attribute that CompactCodec packs the fields of a call into.
+pub const PACKED_RECORD_ATTRIBUTE: &str = "record";
+
+/// The attributes that CompactCodec never packs, because they are the key of the table or
+/// of the region index, because region queries project them, or because the conditions
+/// and updates of DynamoDb refer to them. An update changes only the attribute itself, so
+/// a packed copy would go stale.
+const UNPACKED_ATTRIBUTES: &[&str] = &[
+    "groupConferenceId",
+    "jvbConferenceId",
+    "jvbHost",
+    "region",
+    "backendVersion",
+    "backupBackends",
+    "reservedRegion",
+    "createdAt",
+    "expiresAt",
+    "lastHeartbeatAt",
+    "locked",
+    "lockedBy",
+    "version",
+    "era",
+];
+
+/// Converts calls to and from the items that they are stored as in DynamoDB.
+///
+/// Every codec can decode the items written by any other, so that the codec of a table
+/// can be changed, and changed back, while it holds calls.
+pub trait RecordCodec: Send + Sync {
+    fn encode(&self, call: &CallRecord) -> Result<HashMap<String, AttributeValue>>;
+
+    fn decode(&self, item: HashMap<String, AttributeValue>) -> Result<CallRecord> {
+        decode_item(item)
+    }
+
+    /// Returns the lockedBy attribute for the given user, for set_call_locked, which
+    /// updates the attribute without encoding the whole call.
+    fn encode_locked_by(&self, locked_by: &str) -> Result<String> {
+        Ok(locked_by.to_string())
+    }
+}
+
+/// Stores each field of a call as its own attribute, named as in CallRecord. This is how
+/// calls have always been stored.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FieldCodec;
+
+impl RecordCodec for FieldCodec {
+    fn encode(&self, call: &CallRecord) -> Result<HashMap<String, AttributeValue>> {
+        to_item(call).context("failed to convert CallRecord to item")
+    }
+}
+
+/// Packs the fields of a call that DynamoDb never refers to in an expression into a
+/// single binary attribute, PACKED_RECORD_ATTRIBUTE, and stores the rest like
+/// FieldCodec, so that items are smaller. The region indexes must project the packed
+/// attribute for region queries to return whole calls.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompactCodec;
+
+impl RecordCodec for CompactCodec {
+    fn encode(&self, call: &CallRecord) -> Result<HashMap<String, AttributeValue>> {
+        let mut item: HashMap<String, AttributeValue> =
+            to_item(call).context("failed to convert CallRecord to item")?;
+        item.retain(|name, _| UNPACKED_ATTRIBUTES.contains(&name.as_str()));
+
+        let mut packed = match serde_json::to_value(call)? {
+            serde_json::Value::Object(fields) => fields,
+            _ => return Err(anyhow!("CallRecord didn't serialize to an object")),
+        };
+        packed.retain(|name, _| !UNPACKED_ATTRIBUTES.contains(&name.as_str()));
+        item.insert(
+            PACKED_RECORD_ATTRIBUTE.to_string(),
+            AttributeValue::B(Blob::new(serde_json::to_vec(&packed)?)),
+        );
+        Ok(item)
+    }
+}
+
+/// Converts an item to a call, unpacking the fields of PACKED_RECORD_ATTRIBUTE if it has
+/// one. Attributes stored on their own are always the latest, so they win over packed
+/// ones.
+fn decode_item(mut item: HashMap<String, AttributeValue>) -> Result<CallRecord> {
+    match item.remove(PACKED_RECORD_ATTRIBUTE) {
+        Some(AttributeValue::B(packed)) => {
+            let packed: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_slice(packed.as_ref())
+                    .context("failed to unpack the record attribute")?;
+            for (name, value) in packed {
+                if !UNPACKED_ATTRIBUTES.contains(&name.as_str()) && !item.contains_key(&name) {
+                    item.insert(name, to_attribute_value(value)?);
+                }
+            }
+        }
+        Some(_) => return Err(anyhow!("the record attribute isn't binary")),
+        None => {}
+    }
+    from_item(item).context("failed to convert item to CallRecord")
+}
+
+#[cfg(test)]
+mod codec_tests {
+    use super::*;
+    use crate::storage::{self, BackendRef};
+
+    fn create_call_record() -> CallRecord {
+        CallRecord {
+            backup_backends: vec![BackendRef {
+                region: "us-east4".to_string(),
+                ip: "127.0.0.2".to_string(),
+            }],
+            backend_version: Some("1.2.3".to_string()),
+            created_at: Some(1000),
+            expires_at: Some(2000),
+            last_heartbeat_at: Some(1500),
+            preferred_region: Some("us-west1".to_string()),
+            locked: true,
+            locked_by: Some("1111111111111111".to_string()),
+            version: 3,
+            era: 2,
+            ..storage::create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1")
+        }
+    }
+
+    #[test]
+    fn test_field_codec_round_trip() {
+        let call = create_call_record();
+        let item = FieldCodec.encode(&call).unwrap();
+        assert_eq!(item["creator"].as_s().unwrap(), "1111111111111111");
+        assert!(!item.contains_key(PACKED_RECORD_ATTRIBUTE));
+        assert_eq!(FieldCodec.decode(item).unwrap(), call);
+
+        let call = CallRecord::default();
+        assert_eq!(
+            FieldCodec
+                .decode(FieldCodec.encode(&call).unwrap())
+                .unwrap(),
+            call
+        );
+    }
+
+    #[test]
+    fn test_compact_codec_round_trip() {
+        let call = create_call_record();
+        let item = CompactCodec.encode(&call).unwrap();
+        assert!(item[PACKED_RECORD_ATTRIBUTE].as_b().is_ok());
+        assert!(!item.contains_key("creator"));
+        assert!(!item.contains_key("preferredRegion"));
+        for name in ["groupConferenceId", "jvbConferenceId", "jvbHost", "region"] {
+            assert!(item.contains_key(name), "{}", name);
+        }
+        assert_eq!(CompactCodec.decode(item).unwrap(), call);
+
+        let call = CallRecord::default();
+        assert_eq!(
+            CompactCodec
+                .decode(CompactCodec.encode(&call).unwrap())
+                .unwrap(),
+            call
+        );
+    }
+
+    #[test]
+    fn test_codecs_decode_each_other() {
+        let call = create_call_record();
+        assert_eq!(
+            CompactCodec
+                .decode(FieldCodec.encode(&call).unwrap())
+                .unwrap(),
+            call
+        );
+        assert_eq!(
+            FieldCodec
+                .decode(CompactCodec.encode(&call).unwrap())
+                .unwrap(),
+            call
+        );
+    }
+
+    #[test]
+    fn test_compact_codec_sees_updates() {
+        let call = create_call_record();
+        let mut item = CompactCodec.encode(&call).unwrap();
+
+        // As if by heartbeat_call and by unlocking the call.
+        item.insert(
+            "lastHeartbeatAt".to_string(),
+            AttributeValue::N("1600".to_string()),
+        );
+        item.remove("lockedBy");
+        item.remove("locked");
+
+        let decoded = CompactCodec.decode(item).unwrap();
+        assert_eq!(decoded.last_heartbeat_at, Some(1600));
+        assert!(!decoded.locked);
+        assert_eq!(decoded.locked_by, None);
+        assert_eq!(decoded.creator, call.creator);
+    }
+
+    #[test]
+    fn test_compact_codec_rejects_bad_record() {
+        let mut item = CompactCodec.encode(&create_call_record()).unwrap();
+        item.insert(
+            PACKED_RECORD_ATTRIBUTE.to_string(),
+            AttributeValue::S("not binary".to_string()),
+        );
+        assert!(CompactCodec.decode(item.clone()).is_err());
+
+        item.insert(
+            PACKED_RECORD_ATTRIBUTE.to_string(),
+            AttributeValue::B(Blob::new("not json")),
+        );
+        assert!(CompactCodec.decode(item).is_err());
+    }
+}
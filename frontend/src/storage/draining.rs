@@ -0,0 +1,414 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::{stream::BoxStream, StreamExt};
+use tokio::sync::Notify;
+
+use crate::{
+    frontend::{GroupId, UserId},
+    storage::{CallRecord, CallRecordSummary, ReadMeta, Storage, StorageError},
+};
+
+/// The operations that are running, shared with the guards of the operations so that
+/// streams can hold on to it after the call that created them returns.
+#[derive(Default)]
+struct InFlight {
+    shutting_down: AtomicBool,
+    count: AtomicUsize,
+    idle: Notify,
+}
+
+/// Counts an operation as in flight until it is dropped.
+struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// A Storage decorator that keeps track of the operations that are in flight, so that on
+/// shutdown new operations can be refused with ShuttingDown while the ones that already
+/// started, such as a get_or_add_call_record halfway through its write, are allowed to
+/// finish.
+pub struct DrainingStorage<S: Storage> {
+    inner: S,
+    in_flight: Arc<InFlight>,
+}
+
+impl<S: Storage> DrainingStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Refuses every operation that starts from now on with ShuttingDown.
+    pub fn begin_shutdown(&self) {
+        self.in_flight.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns the number of operations that are in flight, including streams that
+    /// haven't been dropped yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.count.load(Ordering::SeqCst)
+    }
+
+    /// Waits until no operations are in flight, for at most timeout. Returns false if
+    /// some were still in flight when it gave up. This only ends once begin_shutdown has
+    /// been called, since new operations keep starting otherwise.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout.into(), async {
+            loop {
+                // Registered before the count is checked so that the last operation
+                // finishing in between isn't missed.
+                let idle = self.in_flight.idle.notified();
+                if self.in_flight() == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    fn enter(&self) -> Result<InFlightGuard, StorageError> {
+        // The operation is counted before the flag is checked, so that drain never misses
+        // an operation that got past the check.
+        self.in_flight.count.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlightGuard(self.in_flight.clone());
+        if self.in_flight.shutting_down.load(Ordering::SeqCst) {
+            return Err(StorageError::ShuttingDown);
+        }
+        Ok(guard)
+    }
+
+    fn enter_stream(
+        &self,
+        stream: impl FnOnce() -> BoxStream<'static, Result<CallRecord, StorageError>>,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        match self.enter() {
+            // The stream is in flight until it is dropped.
+            Ok(guard) => stream()
+                .map(move |item| {
+                    let _ = &guard;
+                    item
+                })
+                .boxed(),
+            Err(err) => futures::stream::iter(vec![Err(err)]).boxed(),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for DrainingStorage<S> {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.get_call_record(group_id).await
+    }
+
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        let _guard = self.enter()?;
+        self.inner.get_call_record_with_meta(group_id).await
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.get_or_add_call_record(call).await
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.remove_call_record(group_id, call_id).await
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        // Once shutting down, the removal is dropped like any other new operation. Without
+        // a shared handle the removal runs in the background on the inner storage, so
+        // drain can only wait for it when this is used through a DynStorage.
+        if self.enter().is_ok() {
+            self.inner.remove_call_record_best_effort(group_id, call_id)
+        }
+    }
+
+    async fn remove_call_record_with_retries(&self, group_id: &GroupId, call_id: &str) {
+        // The removal is in flight until its last attempt, including the waits between
+        // attempts, so drain doesn't cut it off halfway.
+        if let Ok(_guard) = self.enter() {
+            self.inner
+                .remove_call_record_with_retries(group_id, call_id)
+                .await
+        }
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.force_remove_call_record(group_id).await
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.get_call_records_for_region(region).await
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.enter_stream(|| self.inner.stream_call_records_for_region(region))
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.count_calls_per_backend(region).await
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.count_call_records_for_region(region).await
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.count_calls_by_backend_version(region).await
+    }
+
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.count_all_regions().await
+    }
+
+    async fn least_loaded_backend(
+        &self,
+        region: &str,
+        candidate_ips: &[&str],
+    ) -> Result<Option<String>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.least_loaded_backend(region, candidate_ips).await
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner
+            .get_call_records_for_region_projected(region, attributes, limit)
+            .await
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.promote_backup_backend(group_id, call_id).await
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        let _guard = self.enter()?;
+        self.inner
+            .create_call_reserving_capacity(call, max_calls_per_region)
+            .await
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.heartbeat_call(group_id, call_id).await
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        let _guard = self.enter()?;
+        self.inner
+            .set_call_locked(group_id, call_id, locked, locked_by)
+            .await
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.reap_dead_calls(max_silence, dry_run).await
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        let _guard = self.enter()?;
+        self.inner.add_call_records(records).await
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        let _guard = self.enter()?;
+        self.inner.update_call_record(call).await
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        let _guard = self.enter()?;
+        self.inner.health_check().await
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.enter_stream(|| self.inner.export_all())
+    }
+}
+
+#[cfg(test)]
+mod draining_storage_tests {
+    use super::*;
+    use crate::storage::{create_call_record, InMemoryStorage, MockStorage};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_in_flight_operation_completes_after_shutdown_begins() {
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let mut inner = MockStorage::new();
+        inner
+            .expect_get_or_add_call_record()
+            .times(1)
+            .returning(move |_| {
+                entered_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+                Ok(None)
+            });
+        let storage = Arc::new(DrainingStorage::new(inner));
+
+        let in_flight = tokio::spawn({
+            let storage = storage.clone();
+            async move {
+                storage
+                    .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+                    .await
+            }
+        });
+        tokio::task::spawn_blocking(move || entered_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(storage.in_flight(), 1);
+
+        storage.begin_shutdown();
+        assert!(matches!(
+            storage.get_call_record(&"aaaaaaaaaaaaaaaa".into()).await,
+            Err(StorageError::ShuttingDown)
+        ));
+        assert!(!storage.drain(Duration::from_millis(10)).await);
+
+        release_tx.send(()).unwrap();
+        assert!(storage.drain(Duration::from_secs(5)).await);
+        assert_eq!(in_flight.await.unwrap().unwrap(), None);
+        assert_eq!(storage.in_flight(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_drain_waits_for_best_effort_removal() {
+        let (entered_tx, entered_rx) = std::sync::mpsc::channel();
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let mut inner = MockStorage::new();
+        inner
+            .expect_remove_call_record_with_retries()
+            .times(1)
+            .returning(move |_, _| {
+                entered_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            });
+        let storage = Arc::new(DrainingStorage::new(inner));
+
+        // The removal runs in the background on the shared handle.
+        storage.remove_call_record_best_effort(&"aaaaaaaaaaaaaaaa".into(), "a1a1a1a1");
+        tokio::task::spawn_blocking(move || entered_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(storage.in_flight(), 1);
+
+        storage.begin_shutdown();
+        assert!(!storage.drain(Duration::from_millis(10)).await);
+
+        release_tx.send(()).unwrap();
+        assert!(storage.drain(Duration::from_secs(5)).await);
+        assert_eq!(storage.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_streams_are_in_flight_until_dropped() {
+        let storage = DrainingStorage::new(InMemoryStorage::new());
+        storage
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap();
+
+        let stream = storage.export_all();
+        assert_eq!(storage.in_flight(), 1);
+        storage.begin_shutdown();
+        assert!(!storage.drain(Duration::from_millis(10)).await);
+
+        // The stream that was already started still yields its calls.
+        assert_eq!(stream.count().await, 1);
+        assert!(storage.drain(Duration::from_millis(10)).await);
+
+        let results: Vec<_> = storage.export_all().collect().await;
+        assert!(matches!(
+            results.as_slice(),
+            [Err(StorageError::ShuttingDown)]
+        ));
+    }
+}
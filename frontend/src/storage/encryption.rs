@@ -0,0 +1,277 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::{collections::HashMap, sync::Arc};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, Context, Result};
+use aws_sdk_dynamodb::model::AttributeValue;
+use rand::{thread_rng, RngCore};
+
+use crate::storage::{CallRecord, RecordCodec};
+
+/// Marks a creator or locker as encrypted. It is followed by the base64 of the
+/// ciphertext.
+const ENCRYPTED_VALUE_PREFIX: &str = "enc1:";
+
+/// The length of the random nonce that LocalKeyEncryption puts in front of each
+/// ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// Encrypts and decrypts attributes of calls, so that access to the table alone doesn't
+/// reveal them.
+///
+/// Providers are synchronous because calls are decoded inside streams. A provider backed
+/// by KMS would unwrap its data key once at startup rather than calling KMS for every
+/// call.
+pub trait EncryptionProvider: Send + Sync {
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>>;
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Encrypts with AES-256-GCM under a key from the config. A random nonce is put in front
+/// of each ciphertext.
+pub struct LocalKeyEncryption {
+    cipher: Aes256Gcm,
+}
+
+impl LocalKeyEncryption {
+    pub fn from_hex_key(key: &str) -> Result<Self> {
+        let key = hex::decode(key).context("the encryption key isn't hex")?;
+        let cipher = Aes256Gcm::new_from_slice(&key)
+            .map_err(|_| anyhow!("the encryption key must be 32 bytes"))?;
+        Ok(Self { cipher })
+    }
+}
+
+impl EncryptionProvider for LocalKeyEncryption {
+    fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill_bytes(&mut nonce);
+        let ciphertext = self
+            .cipher
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| anyhow!("failed to encrypt"))?;
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        if ciphertext.len() < NONCE_LEN {
+            return Err(anyhow!("the ciphertext is too short"));
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("failed to decrypt"))
+    }
+}
+
+/// Encrypts the creator and the locker of each call with the provider before encoding
+/// the call with the inner codec, and decrypts them after decoding. Without a provider,
+/// they are stored in plaintext as they always have been.
+///
+/// Values stored in plaintext are read as they are, so that encryption can be turned on
+/// for a table that holds calls. Encrypted values can't be read without the provider, so
+/// it can't be turned off again until those calls have ended.
+pub struct EncryptingCodec {
+    inner: Arc<dyn RecordCodec>,
+    provider: Option<Arc<dyn EncryptionProvider>>,
+}
+
+impl EncryptingCodec {
+    pub fn new(inner: Arc<dyn RecordCodec>, provider: Option<Arc<dyn EncryptionProvider>>) -> Self {
+        Self { inner, provider }
+    }
+
+    fn encrypt(
+        &self,
+        provider: &dyn EncryptionProvider,
+        value: &str,
+        what: &str,
+    ) -> Result<String> {
+        let ciphertext = provider
+            .encrypt(value.as_bytes())
+            .with_context(|| format!("failed to encrypt the {}", what))?;
+        Ok(format!(
+            "{}{}",
+            ENCRYPTED_VALUE_PREFIX,
+            base64::encode(ciphertext)
+        ))
+    }
+
+    fn decrypt(&self, value: &mut String, what: &str) -> Result<()> {
+        if let Some(encoded) = value.strip_prefix(ENCRYPTED_VALUE_PREFIX) {
+            let provider = self
+                .provider
+                .as_ref()
+                .ok_or_else(|| anyhow!("the {} is encrypted but there is no provider", what))?;
+            let ciphertext = base64::decode(encoded)
+                .with_context(|| format!("the encrypted {} isn't base64", what))?;
+            let plaintext = provider
+                .decrypt(&ciphertext)
+                .with_context(|| format!("failed to decrypt the {}", what))?;
+            *value = String::from_utf8(plaintext)
+                .with_context(|| format!("the decrypted {} isn't UTF-8", what))?;
+        }
+        Ok(())
+    }
+}
+
+impl RecordCodec for EncryptingCodec {
+    fn encode(&self, call: &CallRecord) -> Result<HashMap<String, AttributeValue>> {
+        let provider = match &self.provider {
+            Some(provider) => provider,
+            None => return self.inner.encode(call),
+        };
+        let locked_by = match &call.locked_by {
+            Some(locked_by) => Some(self.encrypt(&**provider, locked_by, "locker")?),
+            None => None,
+        };
+        self.inner.encode(&CallRecord {
+            creator: self.encrypt(&**provider, &call.creator, "creator")?,
+            locked_by,
+            ..call.clone()
+        })
+    }
+
+    fn decode(&self, item: HashMap<String, AttributeValue>) -> Result<CallRecord> {
+        let mut call = self.inner.decode(item)?;
+        self.decrypt(&mut call.creator, "creator")?;
+        if let Some(locked_by) = &mut call.locked_by {
+            self.decrypt(locked_by, "locker")?;
+        }
+        Ok(call)
+    }
+
+    fn encode_locked_by(&self, locked_by: &str) -> Result<String> {
+        match &self.provider {
+            Some(provider) => self.encrypt(&**provider, locked_by, "locker"),
+            None => self.inner.encode_locked_by(locked_by),
+        }
+    }
+}
+
+#[cfg(test)]
+mod encryption_tests {
+    use super::*;
+    use crate::storage::{self, CompactCodec, FieldCodec, PACKED_RECORD_ATTRIBUTE};
+
+    /// Reverses the bytes, so that the ciphertext is easy to predict.
+    struct ReversingProvider;
+
+    impl EncryptionProvider for ReversingProvider {
+        fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+            Ok(plaintext.iter().rev().copied().collect())
+        }
+
+        fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+            Ok(ciphertext.iter().rev().copied().collect())
+        }
+    }
+
+    fn create_call_record() -> CallRecord {
+        CallRecord {
+            creator: "1111111122222222".to_string(),
+            preferred_region: Some("us-west1".to_string()),
+            locked: true,
+            locked_by: Some("3333333344444444".to_string()),
+            ..storage::create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1")
+        }
+    }
+
+    fn create_codec(inner: Arc<dyn RecordCodec>) -> EncryptingCodec {
+        EncryptingCodec::new(inner, Some(Arc::new(ReversingProvider)))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let call = create_call_record();
+        for inner in [
+            Arc::new(FieldCodec) as Arc<dyn RecordCodec>,
+            Arc::new(CompactCodec),
+        ] {
+            let codec = create_codec(inner);
+            assert_eq!(codec.decode(codec.encode(&call).unwrap()).unwrap(), call);
+        }
+    }
+
+    #[test]
+    fn test_stored_creator_is_ciphertext() {
+        let call = create_call_record();
+
+        let item = create_codec(Arc::new(FieldCodec)).encode(&call).unwrap();
+        assert_eq!(
+            item["creator"].as_s().unwrap(),
+            &format!(
+                "{}{}",
+                ENCRYPTED_VALUE_PREFIX,
+                base64::encode("2222222211111111")
+            )
+        );
+        assert_eq!(
+            item["lockedBy"].as_s().unwrap(),
+            &format!(
+                "{}{}",
+                ENCRYPTED_VALUE_PREFIX,
+                base64::encode("4444444433333333")
+            )
+        );
+        // Nothing else is encrypted.
+        assert_eq!(item["jvbHost"].as_s().unwrap(), "127.0.0.1");
+
+        let item = create_codec(Arc::new(CompactCodec)).encode(&call).unwrap();
+        let packed = item[PACKED_RECORD_ATTRIBUTE].as_b().unwrap().as_ref();
+        assert!(!String::from_utf8_lossy(packed).contains(&call.creator));
+    }
+
+    #[test]
+    fn test_no_provider_stores_plaintext() {
+        let call = create_call_record();
+        let codec = EncryptingCodec::new(Arc::new(FieldCodec), None);
+
+        let item = codec.encode(&call).unwrap();
+        assert_eq!(item, FieldCodec.encode(&call).unwrap());
+        assert_eq!(codec.decode(item).unwrap(), call);
+
+        // But encrypted creators can't be read.
+        let item = create_codec(Arc::new(FieldCodec)).encode(&call).unwrap();
+        assert!(codec.decode(item).is_err());
+    }
+
+    #[test]
+    fn test_plaintext_creators_are_still_read() {
+        let call = create_call_record();
+        let codec = create_codec(Arc::new(FieldCodec));
+        assert_eq!(
+            codec.decode(FieldCodec.encode(&call).unwrap()).unwrap(),
+            call
+        );
+    }
+
+    #[test]
+    fn test_local_key_encryption() {
+        const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+        const OTHER_KEY: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+        let provider = LocalKeyEncryption::from_hex_key(KEY).unwrap();
+        let ciphertext = provider.encrypt(b"1111111122222222").unwrap();
+        assert_ne!(&ciphertext[NONCE_LEN..], b"1111111122222222");
+        assert_eq!(provider.decrypt(&ciphertext).unwrap(), b"1111111122222222");
+
+        // Every encryption uses a new nonce.
+        assert_ne!(provider.encrypt(b"1111111122222222").unwrap(), ciphertext);
+
+        let other = LocalKeyEncryption::from_hex_key(OTHER_KEY).unwrap();
+        assert!(other.decrypt(&ciphertext).is_err());
+        assert!(provider.decrypt(&ciphertext[..NONCE_LEN - 1]).is_err());
+
+        assert!(LocalKeyEncryption::from_hex_key("0001").is_err());
+        assert!(LocalKeyEncryption::from_hex_key("not hex").is_err());
+    }
+}
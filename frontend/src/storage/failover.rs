@@ -0,0 +1,426 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::{collections::HashMap, future::Future};
+
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::stream::BoxStream;
+use log::*;
+
+use crate::{
+    frontend::{GroupId, UserId},
+    storage::{CallRecord, CallRecordSummary, ReadMeta, Storage, StorageError},
+};
+
+/// A Storage decorator that serves reads from the storage of a failover region when the
+/// storage of the primary region fails with Throttled or an unexpected error, such as
+/// when DynamoDB is unavailable in the primary region. Writes always go to the primary,
+/// so that the two regions never disagree about who created a call, and fail if it
+/// does.
+///
+/// Streams aren't failed over, since a stream that fails part way through can't be
+/// resumed in the other region without repeating calls.
+pub struct FailoverStorage<S: Storage> {
+    primary: S,
+    failover: S,
+    failover_region: String,
+}
+
+impl<S: Storage> FailoverStorage<S> {
+    pub fn new(primary: S, failover: S, failover_region: String) -> Self {
+        Self {
+            primary,
+            failover,
+            failover_region,
+        }
+    }
+
+    /// Like get_call_records_for_region, but also returns how the calls were read, so
+    /// that callers can tell when they came from the failover region. Region queries
+    /// are never strongly consistent.
+    pub async fn get_call_records_for_region_with_meta(
+        &self,
+        region: &str,
+    ) -> Result<(Vec<CallRecord>, ReadMeta), StorageError> {
+        let (calls, failover) = self
+            .read("get_call_records_for_region", |storage| {
+                storage.get_call_records_for_region(region)
+            })
+            .await?;
+        Ok((
+            calls,
+            ReadMeta {
+                consistent: false,
+                failover,
+            },
+        ))
+    }
+
+    /// Runs the read against the primary, and against the failover if the primary
+    /// failed in a way that the failover region might not have. Returns the result along
+    /// with whether it came from the failover.
+    async fn read<'a, T, F, Fut>(
+        &'a self,
+        operation: &'static str,
+        f: F,
+    ) -> Result<(T, bool), StorageError>
+    where
+        T: Send,
+        F: Fn(&'a S) -> Fut + Send,
+        Fut: Future<Output = Result<T, StorageError>> + Send,
+    {
+        match f(&self.primary).await {
+            Ok(value) => Ok((value, false)),
+            Err(err @ (StorageError::Throttled(_) | StorageError::UnexpectedError(_))) => {
+                storage_log!(
+                    Level::Warn,
+                    "reading {} from failover region {} after the primary failed: {}",
+                    operation,
+                    self.failover_region,
+                    err
+                );
+                tagged_event!(
+                    "calling.frontend.storage.failover.read",
+                    vec![
+                        format!("operation:{}", operation),
+                        format!("failover_region:{}", self.failover_region),
+                    ]
+                );
+                Ok((f(&self.failover).await?, true))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for FailoverStorage<S> {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let (call, _) = self
+            .read("get_call_record", |storage| {
+                storage.get_call_record(group_id)
+            })
+            .await?;
+        Ok(call)
+    }
+
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        let ((call, meta), failover) = self
+            .read("get_call_record_with_meta", |storage| {
+                storage.get_call_record_with_meta(group_id)
+            })
+            .await?;
+        Ok((
+            call,
+            ReadMeta {
+                failover: meta.failover || failover,
+                ..meta
+            },
+        ))
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.primary.get_or_add_call_record(call).await
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.primary.remove_call_record(group_id, call_id).await
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        self.primary
+            .remove_call_record_best_effort(group_id, call_id)
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.primary.force_remove_call_record(group_id).await
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        let (calls, _) = self
+            .read("get_call_records_for_region", |storage| {
+                storage.get_call_records_for_region(region)
+            })
+            .await?;
+        Ok(calls)
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.primary.stream_call_records_for_region(region)
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        let (counts, _) = self
+            .read("count_calls_per_backend", |storage| {
+                storage.count_calls_per_backend(region)
+            })
+            .await?;
+        Ok(counts)
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        let (count, _) = self
+            .read("count_call_records_for_region", |storage| {
+                storage.count_call_records_for_region(region)
+            })
+            .await?;
+        Ok(count)
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        let (counts, _) = self
+            .read("count_calls_by_backend_version", |storage| {
+                storage.count_calls_by_backend_version(region)
+            })
+            .await?;
+        Ok(counts)
+    }
+
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        let (counts, _) = self
+            .read("count_all_regions", |storage| storage.count_all_regions())
+            .await?;
+        Ok(counts)
+    }
+
+    async fn least_loaded_backend(
+        &self,
+        region: &str,
+        candidate_ips: &[&str],
+    ) -> Result<Option<String>, StorageError> {
+        let (backend, _) = self
+            .read("least_loaded_backend", |storage| {
+                storage.least_loaded_backend(region, candidate_ips)
+            })
+            .await?;
+        Ok(backend)
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        let (summaries, _) = self
+            .read("get_call_records_for_region_projected", |storage| {
+                storage.get_call_records_for_region_projected(region, attributes, limit)
+            })
+            .await?;
+        Ok(summaries)
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.primary.promote_backup_backend(group_id, call_id).await
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        self.primary
+            .create_call_reserving_capacity(call, max_calls_per_region)
+            .await
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.primary.export_all()
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        self.primary.heartbeat_call(group_id, call_id).await
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        self.primary
+            .set_call_locked(group_id, call_id, locked, locked_by)
+            .await
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        self.primary.reap_dead_calls(max_silence, dry_run).await
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        self.primary.add_call_records(records).await
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        self.primary.update_call_record(call).await
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        // Reports the health of the primary, which all writes depend on.
+        self.primary.health_check().await
+    }
+}
+
+#[cfg(test)]
+mod failover_storage_tests {
+    use std::sync::Arc;
+
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::storage::{create_call_record, DynStorage, InMemoryStorage, MockStorage};
+
+    async fn create_failover(call_id: &str) -> InMemoryStorage {
+        let failover = InMemoryStorage::new();
+        failover
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", call_id))
+            .await
+            .unwrap();
+        failover
+    }
+
+    #[tokio::test]
+    async fn test_primary_failure_reads_from_failover() {
+        let mut primary = MockStorage::new();
+        primary
+            .expect_get_call_records_for_region()
+            .times(1)
+            .returning(|_| Err(StorageError::Throttled(anyhow!("throttled"))));
+        primary
+            .expect_get_call_record()
+            .times(1)
+            .returning(|_| Err(StorageError::UnexpectedError(anyhow!("unavailable"))));
+        let storage = FailoverStorage::new(
+            Arc::new(primary) as DynStorage,
+            Arc::new(create_failover("f1f1f1f1").await),
+            "us-east-2".to_string(),
+        );
+
+        let (calls, meta) = storage
+            .get_call_records_for_region_with_meta("us-west1")
+            .await
+            .unwrap();
+        assert_eq!(
+            calls,
+            vec![create_call_record("aaaaaaaaaaaaaaaa", "f1f1f1f1")]
+        );
+        assert!(meta.failover);
+
+        let call = storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            call,
+            Some(create_call_record("aaaaaaaaaaaaaaaa", "f1f1f1f1"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_primary_success_never_reads_from_failover() {
+        let primary = InMemoryStorage::new();
+        primary
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap();
+        // Any use of the failover fails the test.
+        let storage = FailoverStorage::new(
+            Arc::new(primary) as DynStorage,
+            Arc::new(MockStorage::new()),
+            "us-east-2".to_string(),
+        );
+
+        let (calls, meta) = storage
+            .get_call_records_for_region_with_meta("us-west1")
+            .await
+            .unwrap();
+        assert_eq!(
+            calls,
+            vec![create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1")]
+        );
+        assert!(!meta.failover);
+
+        let (call, meta) = storage
+            .get_call_record_with_meta(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            call,
+            Some(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+        );
+        assert!(!meta.failover);
+    }
+
+    #[tokio::test]
+    async fn test_writes_and_other_errors_stay_on_primary() {
+        let mut primary = MockStorage::new();
+        primary
+            .expect_get_or_add_call_record()
+            .times(1)
+            .returning(|_| Err(StorageError::Throttled(anyhow!("throttled"))));
+        primary
+            .expect_count_call_records_for_region()
+            .times(1)
+            .returning(|_| Err(StorageError::RegionIndexUnavailable("region-index".into())));
+        let storage = FailoverStorage::new(
+            Arc::new(primary) as DynStorage,
+            Arc::new(MockStorage::new()),
+            "us-east-2".to_string(),
+        );
+
+        assert!(matches!(
+            storage
+                .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+                .await,
+            Err(StorageError::Throttled(_))
+        ));
+        assert!(matches!(
+            storage.count_call_records_for_region("us-west1").await,
+            Err(StorageError::RegionIndexUnavailable(_))
+        ));
+    }
+}
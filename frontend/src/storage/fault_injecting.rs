@@ -0,0 +1,400 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::{stream::BoxStream, StreamExt};
+use parking_lot::Mutex;
+use rand::{thread_rng, Rng};
+
+use crate::{
+    frontend::{GroupId, UserId},
+    storage::{CallRecord, CallRecordSummary, ReadMeta, Storage, StorageError},
+};
+
+/// The kind of error that a FaultInjectingStorage fails an operation with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Fault {
+    CallAlreadyExists,
+    RegionFull,
+    VersionConflict,
+    Throttled,
+    Unexpected,
+}
+
+impl Fault {
+    fn to_error(self, operation: &str) -> StorageError {
+        match self {
+            Fault::CallAlreadyExists => StorageError::CallAlreadyExists,
+            Fault::RegionFull => StorageError::RegionFull("injected".to_string()),
+            Fault::VersionConflict => StorageError::VersionConflict,
+            Fault::Throttled => StorageError::Throttled(anyhow!("injected fault in {}", operation)),
+            Fault::Unexpected => {
+                StorageError::UnexpectedError(anyhow!("injected fault in {}", operation))
+            }
+        }
+    }
+}
+
+enum Trigger {
+    /// Fails this many more times and then stops.
+    Next(usize),
+    /// Fails each time with this probability.
+    Probability(f64),
+}
+
+struct Rule {
+    operation: &'static str,
+    trigger: Trigger,
+    fault: Fault,
+}
+
+/// A Storage decorator for tests that fails operations on demand and otherwise delegates
+/// to the inner storage. Operations are named like the Storage methods, for example
+/// "get_or_add_call_record". When several rules apply to an operation, the first one
+/// that triggers wins.
+pub struct FaultInjectingStorage<S: Storage> {
+    inner: S,
+    rules: Mutex<Vec<Rule>>,
+}
+
+impl<S: Storage> FaultInjectingStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            rules: Mutex::new(vec![]),
+        }
+    }
+
+    /// Fails the next count calls of the operation with the fault.
+    pub fn fail_next(&self, operation: &'static str, count: usize, fault: Fault) {
+        self.rules.lock().push(Rule {
+            operation,
+            trigger: Trigger::Next(count),
+            fault,
+        });
+    }
+
+    /// Fails each call of the operation with the fault with the given probability.
+    pub fn fail_with_probability(&self, operation: &'static str, probability: f64, fault: Fault) {
+        self.rules.lock().push(Rule {
+            operation,
+            trigger: Trigger::Probability(probability),
+            fault,
+        });
+    }
+
+    /// Removes all rules so that every operation is delegated again.
+    pub fn clear(&self) {
+        self.rules.lock().clear();
+    }
+
+    fn inject(&self, operation: &'static str) -> Result<(), StorageError> {
+        let mut rules = self.rules.lock();
+        let fault = rules.iter_mut().find_map(|rule| {
+            if rule.operation != operation {
+                return None;
+            }
+            let triggered = match &mut rule.trigger {
+                Trigger::Next(0) => false,
+                Trigger::Next(remaining) => {
+                    *remaining -= 1;
+                    true
+                }
+                Trigger::Probability(probability) => {
+                    thread_rng().gen_bool(probability.clamp(0.0, 1.0))
+                }
+            };
+            triggered.then(|| rule.fault)
+        });
+        rules.retain(|rule| !matches!(rule.trigger, Trigger::Next(0)));
+
+        match fault {
+            Some(fault) => Err(fault.to_error(operation)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for FaultInjectingStorage<S> {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.inject("get_call_record")?;
+        self.inner.get_call_record(group_id).await
+    }
+
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        self.inject("get_call_record_with_meta")?;
+        self.inner.get_call_record_with_meta(group_id).await
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.inject("get_or_add_call_record")?;
+        self.inner.get_or_add_call_record(call).await
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.inject("remove_call_record")?;
+        self.inner.remove_call_record(group_id, call_id).await
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        // A fault can't be returned, so an injected one drops the removal.
+        if self.inject("remove_call_record_best_effort").is_ok() {
+            self.inner.remove_call_record_best_effort(group_id, call_id)
+        }
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.inject("force_remove_call_record")?;
+        self.inner.force_remove_call_record(group_id).await
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        self.inject("get_call_records_for_region")?;
+        self.inner.get_call_records_for_region(region).await
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        match self.inject("stream_call_records_for_region") {
+            Ok(()) => self.inner.stream_call_records_for_region(region),
+            Err(err) => futures::stream::iter(vec![Err(err)]).boxed(),
+        }
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        self.inject("count_calls_per_backend")?;
+        self.inner.count_calls_per_backend(region).await
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        self.inject("count_call_records_for_region")?;
+        self.inner.count_call_records_for_region(region).await
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        self.inject("count_calls_by_backend_version")?;
+        self.inner.count_calls_by_backend_version(region).await
+    }
+
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        self.inject("count_all_regions")?;
+        self.inner.count_all_regions().await
+    }
+
+    async fn least_loaded_backend(
+        &self,
+        region: &str,
+        candidate_ips: &[&str],
+    ) -> Result<Option<String>, StorageError> {
+        self.inject("least_loaded_backend")?;
+        self.inner.least_loaded_backend(region, candidate_ips).await
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        self.inject("get_call_records_for_region_projected")?;
+        self.inner
+            .get_call_records_for_region_projected(region, attributes, limit)
+            .await
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.inject("promote_backup_backend")?;
+        self.inner.promote_backup_backend(group_id, call_id).await
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        self.inject("create_call_reserving_capacity")?;
+        self.inner
+            .create_call_reserving_capacity(call, max_calls_per_region)
+            .await
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        self.inject("heartbeat_call")?;
+        self.inner.heartbeat_call(group_id, call_id).await
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        self.inject("set_call_locked")?;
+        self.inner
+            .set_call_locked(group_id, call_id, locked, locked_by)
+            .await
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        self.inject("reap_dead_calls")?;
+        self.inner.reap_dead_calls(max_silence, dry_run).await
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        self.inject("add_call_records")?;
+        self.inner.add_call_records(records).await
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        self.inject("update_call_record")?;
+        self.inner.update_call_record(call).await
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        self.inject("health_check")?;
+        self.inner.health_check().await
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        match self.inject("export_all") {
+            Ok(()) => self.inner.export_all(),
+            Err(err) => futures::stream::iter(vec![Err(err)]).boxed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod fault_injecting_storage_tests {
+    use super::*;
+    use crate::storage::{create_call_record, InMemoryStorage};
+
+    #[tokio::test]
+    async fn test_fail_next() {
+        let storage = FaultInjectingStorage::new(InMemoryStorage::new());
+        storage.fail_next("get_or_add_call_record", 2, Fault::Throttled);
+
+        for _ in 0..2 {
+            assert!(matches!(
+                storage
+                    .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+                    .await,
+                Err(StorageError::Throttled(_))
+            ));
+        }
+        // Other operations aren't affected.
+        assert_eq!(
+            storage
+                .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+                .await
+                .unwrap(),
+            None
+        );
+
+        // And the operation recovers after the configured failures.
+        assert!(storage
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap()
+            .is_some());
+        assert!(storage.rules.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fail_with_probability() {
+        let storage = FaultInjectingStorage::new(InMemoryStorage::new());
+        storage.fail_with_probability("health_check", 1.0, Fault::Unexpected);
+        storage.fail_with_probability("get_call_record", 0.0, Fault::Unexpected);
+
+        for _ in 0..10 {
+            assert!(matches!(
+                storage.health_check().await,
+                Err(StorageError::UnexpectedError(_))
+            ));
+            assert!(storage
+                .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+                .await
+                .is_ok());
+        }
+        let streamed: Vec<_> = storage
+            .stream_call_records_for_region("us-west1")
+            .collect()
+            .await;
+        assert!(streamed.is_empty());
+
+        storage.clear();
+        assert!(storage.health_check().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stream_fault() {
+        let storage = FaultInjectingStorage::new(InMemoryStorage::new());
+        storage
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap();
+        storage.fail_next("stream_call_records_for_region", 1, Fault::VersionConflict);
+
+        let streamed: Vec<_> = storage
+            .stream_call_records_for_region("us-west1")
+            .collect()
+            .await;
+        assert!(matches!(
+            streamed.as_slice(),
+            [Err(StorageError::VersionConflict)]
+        ));
+
+        let streamed: Vec<_> = storage
+            .stream_call_records_for_region("us-west1")
+            .collect()
+            .await;
+        assert_eq!(streamed.len(), 1);
+        assert!(streamed[0].is_ok());
+    }
+}
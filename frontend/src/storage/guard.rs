@@ -0,0 +1,154 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use anyhow::anyhow;
+use log::*;
+
+use crate::storage::{CallRecord, DynStorage, GetOrAddOutcome, Storage, StorageError};
+
+/// The guard keeps a shared handle to the storage to remove the call with, which only a
+/// DynStorage can give it, so this isn't part of the Storage trait itself.
+impl dyn Storage {
+    /// Adds the given call like get_or_add_call_record, and returns a guard that removes
+    /// it again when it is released, so that the call can't be forgotten on one of the
+    /// ways out of the caller. Fails with CallAlreadyExists if a different call is stored
+    /// for the group, since that call isn't the caller's to remove.
+    pub async fn create_call_guarded(
+        self: DynStorage,
+        call: CallRecord,
+    ) -> Result<CallRecordGuard, StorageError> {
+        match self.get_or_add_call_record_expecting(call, true).await? {
+            GetOrAddOutcome::Matched(call) => Ok(CallRecordGuard {
+                storage: self,
+                call,
+                released: false,
+            }),
+            GetOrAddOutcome::Mismatched(_) => Err(StorageError::CallAlreadyExists),
+            GetOrAddOutcome::Missing => Err(StorageError::UnexpectedError(anyhow!(
+                "the call for the group was removed while the call was being added"
+            ))),
+        }
+    }
+}
+
+/// Holds a call created by create_call_guarded until release removes it.
+///
+/// Async Drop isn't available, so the call is only removed by release. A guard that is
+/// dropped without being released logs a warning, and the call is left until it expires.
+#[must_use = "the call is only removed by release"]
+pub struct CallRecordGuard {
+    storage: DynStorage,
+    call: CallRecord,
+    released: bool,
+}
+
+impl CallRecordGuard {
+    /// The call as it was stored.
+    pub fn call(&self) -> &CallRecord {
+        &self.call
+    }
+
+    /// Removes the call, as long as it is still the call stored for the group. Returns
+    /// the record that was removed like remove_call_record.
+    pub async fn release(mut self) -> Result<Option<CallRecord>, StorageError> {
+        // The guard is used up even if the removal fails, since the caller gets the error.
+        self.released = true;
+        self.storage
+            .remove_call_record(&self.call.group_id, &self.call.call_id)
+            .await
+    }
+}
+
+impl Drop for CallRecordGuard {
+    fn drop(&mut self) {
+        if !self.released {
+            storage_log!(
+                Level::Warn,
+                "call {:.6} of group {} was dropped without being released, so it is left until it expires",
+                self.call.call_id, self.call.group_id
+            );
+            event!("calling.frontend.storage.call_guard.leaked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod guard_tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        storage::{create_call_record, InMemoryStorage},
+        test_logging,
+    };
+
+    const LEAKED_EVENT: &str = "calling.frontend.storage.call_guard.leaked";
+
+    #[tokio::test]
+    async fn test_release_removes_the_call() {
+        let storage: DynStorage = Arc::new(InMemoryStorage::new());
+        let group_id = "aaaaaaaaaaaaaaaa".into();
+
+        let guard = storage
+            .clone()
+            .create_call_guarded(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap();
+        assert_eq!(guard.call().call_id, "a1a1a1a1");
+        assert!(storage.get_call_record(&group_id).await.unwrap().is_some());
+
+        // Nobody else can take the group while the guard is held.
+        assert!(matches!(
+            storage
+                .clone()
+                .create_call_guarded(create_call_record("aaaaaaaaaaaaaaaa", "b2b2b2b2"))
+                .await,
+            Err(StorageError::CallAlreadyExists)
+        ));
+
+        let removed = guard.release().await.unwrap().unwrap();
+        assert_eq!(removed.call_id, "a1a1a1a1");
+        assert_eq!(storage.get_call_record(&group_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_drop_without_release_warns() {
+        let storage: DynStorage = Arc::new(InMemoryStorage::new());
+        let before = metrics!().peek_event_count(LEAKED_EVENT);
+        test_logging::start_capture();
+
+        let guard = storage
+            .clone()
+            .create_call_guarded(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap();
+        drop(guard);
+
+        assert_eq!(metrics!().peek_event_count(LEAKED_EVENT), before + 1);
+        let warnings = test_logging::captured(log::Level::Warn);
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(
+            warnings[0].contains("call a1a1a1 of group aaaa was dropped without being released"),
+            "{}",
+            warnings[0]
+        );
+        // The call is left in storage.
+        assert!(storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap()
+            .is_some());
+
+        // Released guards don't warn.
+        let guard = storage
+            .clone()
+            .create_call_guarded(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap();
+        guard.release().await.unwrap();
+        assert_eq!(metrics!().peek_event_count(LEAKED_EVENT), before + 1);
+        assert_eq!(test_logging::captured(log::Level::Warn).len(), 1);
+    }
+}
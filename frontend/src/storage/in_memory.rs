@@ -0,0 +1,1126 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::{collections::HashMap, sync::Arc};
+
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::{stream::BoxStream, StreamExt};
+use parking_lot::Mutex;
+
+use crate::{
+    frontend::{GroupId, UserId},
+    storage::{
+        report_call_duration, sort_call_records, CallRecord, Clock, Storage, StorageError,
+        SystemClock,
+    },
+};
+
+/// A Storage implementation that keeps all calls in memory, for use by tests and
+/// local development where a DynamoDB instance isn't available.
+pub struct InMemoryStorage {
+    /// The calls being tracked, keyed by group_id.
+    calls: Mutex<HashMap<String, CallRecord>>,
+    /// The era of the latest call created for each group_id, kept after it is removed.
+    eras: Mutex<HashMap<String, u64>>,
+    clock: Arc<dyn Clock>,
+    clock_skew_tolerance: Duration,
+}
+
+impl Default for InMemoryStorage {
+    fn default() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            calls: Default::default(),
+            eras: Default::default(),
+            clock,
+            clock_skew_tolerance: Duration::ZERO,
+        }
+    }
+
+    /// Allows for the clock being up to the given amount ahead of the one that wrote
+    /// the calls when judging whether they expired or died.
+    pub fn with_clock_skew_tolerance(mut self, clock_skew_tolerance: Duration) -> Self {
+        self.clock_skew_tolerance = clock_skew_tolerance;
+        self
+    }
+
+    /// Removes the call of the group if it has the given call_id.
+    fn remove(&self, group_id: &GroupId, call_id: &str) -> Option<CallRecord> {
+        let mut calls = self.calls.lock();
+        if calls
+            .get(group_id.as_ref())
+            .map_or(false, |call| call.call_id == call_id)
+        {
+            let call = calls.remove(group_id.as_ref());
+            if let Some(call) = &call {
+                report_call_duration(call, self.clock.now());
+            }
+            call
+        } else {
+            None
+        }
+    }
+
+    /// Gives a call that is about to be created the next era of its group.
+    fn start_era(&self, call: &mut CallRecord) {
+        let mut eras = self.eras.lock();
+        let era = eras.entry(call.group_id.as_ref().to_string()).or_insert(0);
+        *era += 1;
+        call.era = *era;
+    }
+}
+
+#[async_trait]
+impl Storage for InMemoryStorage {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let now = self.clock.now_secs();
+        Ok(self
+            .calls
+            .lock()
+            .get(group_id.as_ref())
+            .filter(|call| !call.is_expired(now, self.clock_skew_tolerance))
+            .cloned())
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        mut call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let now = self.clock.now_secs();
+        call.start_lifetime(now);
+
+        let mut calls = self.calls.lock();
+        match calls.get(call.group_id.as_ref()) {
+            Some(existing) if !existing.is_expired(now, self.clock_skew_tolerance) => {
+                Ok(Some(existing.clone()))
+            }
+            _ => {
+                self.start_era(&mut call);
+                calls.insert(call.group_id.as_ref().to_string(), call.clone());
+                Ok(Some(call))
+            }
+        }
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        Ok(self.remove(group_id, call_id))
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        // Removing from memory never has to wait, so it is done right away.
+        self.remove(group_id, call_id);
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        Ok(self.calls.lock().remove(group_id.as_ref()))
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        let mut calls: Vec<_> = self
+            .calls
+            .lock()
+            .values()
+            .filter(|call| call.backend_region == region)
+            .cloned()
+            .collect();
+        sort_call_records(&mut calls);
+        Ok(calls)
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        let calls: Vec<_> = self
+            .calls
+            .lock()
+            .values()
+            .filter(|call| call.backend_region == region)
+            .cloned()
+            .map(Ok)
+            .collect();
+        futures::stream::iter(calls).boxed()
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        let mut counts = HashMap::new();
+        for call in self
+            .calls
+            .lock()
+            .values()
+            .filter(|call| call.backend_region == region)
+        {
+            *counts.entry(call.backend_ip.clone()).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let mut calls = self.calls.lock();
+        match calls.get_mut(group_id.as_ref()) {
+            Some(call) if call.call_id == call_id && !call.backup_backends.is_empty() => {
+                let backup = call.backup_backends.remove(0);
+                call.backend_ip = backup.ip;
+                call.backend_region = backup.region;
+                call.version += 1;
+                Ok(Some(call.clone()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        mut call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        call.start_lifetime(self.clock.now_secs());
+
+        let mut calls = self.calls.lock();
+        if calls.contains_key(call.group_id.as_ref()) {
+            return Err(StorageError::CallAlreadyExists);
+        }
+
+        let calls_in_region = calls
+            .values()
+            .filter(|existing| existing.backend_region == call.backend_region)
+            .count() as u64;
+        if calls_in_region >= max_calls_per_region {
+            return Err(StorageError::RegionFull(call.backend_region));
+        }
+
+        self.start_era(&mut call);
+        call.reserved_region = Some(call.backend_region.clone());
+        calls.insert(call.group_id.as_ref().to_string(), call.clone());
+        Ok(call)
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        let mut calls = self.calls.lock();
+        match calls.get_mut(call.group_id.as_ref()) {
+            Some(existing)
+                if existing.call_id == call.call_id && existing.version == call.version =>
+            {
+                *existing = CallRecord {
+                    version: call.version + 1,
+                    ..call
+                };
+                Ok(existing.clone())
+            }
+            _ => Err(StorageError::VersionConflict),
+        }
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        let now = self.clock.now_secs();
+        let mut calls = self.calls.lock();
+        for mut call in records {
+            if call.created_at.is_none() {
+                call.start_lifetime(now);
+            }
+            calls.insert(call.group_id.as_ref().to_string(), call);
+        }
+        Ok(())
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        match self.calls.lock().get_mut(group_id.as_ref()) {
+            Some(call) if call.call_id == call_id => {
+                call.last_heartbeat_at = Some(self.clock.now_secs());
+                call.version += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        match self.calls.lock().get_mut(group_id.as_ref()) {
+            Some(call) if call.call_id == call_id => {
+                call.locked = locked;
+                call.locked_by = if locked { locked_by } else { None };
+                call.version += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        let now = self.clock.now_secs();
+        let mut reaped = vec![];
+        self.calls.lock().retain(|_, call| {
+            let dead = call.is_dead(now, max_silence, self.clock_skew_tolerance);
+            if dead {
+                reaped.push(call.group_id.clone());
+            }
+            !dead || dry_run
+        });
+        reaped.sort();
+        Ok(reaped)
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        let mut calls: Vec<_> = self.calls.lock().values().cloned().collect();
+        sort_call_records(&mut calls);
+        futures::stream::iter(calls.into_iter().map(Ok)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod in_memory_storage_tests {
+    use super::*;
+    use crate::storage::{
+        self, BackendRef, CallRecordSummary, GetOrAddOutcome, MockClock, RegionDiff,
+        CALL_RECORD_TTL,
+    };
+    use std::collections::HashSet;
+
+    fn create_call_record(backup_backends: Vec<BackendRef>) -> CallRecord {
+        CallRecord {
+            backup_backends,
+            ..storage::create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_promote_backup_backend() {
+        let storage = InMemoryStorage::new();
+        let call = create_call_record(vec![
+            BackendRef {
+                region: "us-east4".to_string(),
+                ip: "127.0.0.2".to_string(),
+            },
+            BackendRef {
+                region: "us-west1".to_string(),
+                ip: "127.0.0.3".to_string(),
+            },
+        ]);
+        storage.get_or_add_call_record(call.clone()).await.unwrap();
+
+        // A different call_id doesn't promote anything.
+        assert_eq!(
+            storage
+                .promote_backup_backend(&call.group_id, "b2b2b2b2")
+                .await
+                .unwrap(),
+            None
+        );
+
+        let promoted = storage
+            .promote_backup_backend(&call.group_id, &call.call_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(promoted.backend_ip, "127.0.0.2");
+        assert_eq!(promoted.backend_region, "us-east4");
+        assert_eq!(promoted.backup_backends.len(), 1);
+
+        let promoted = storage
+            .promote_backup_backend(&call.group_id, &call.call_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(promoted.backend_ip, "127.0.0.3");
+        assert_eq!(promoted.backend_region, "us-west1");
+        assert!(promoted.backup_backends.is_empty());
+
+        // Once the backups are exhausted, there is nothing left to promote.
+        assert_eq!(
+            storage
+                .promote_backup_backend(&call.group_id, &call.call_id)
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            storage.get_call_record(&call.group_id).await.unwrap(),
+            Some(promoted)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_call_record_outcomes() {
+        let storage = InMemoryStorage::new();
+        let call = create_call_record(vec![]);
+        storage.get_or_add_call_record(call.clone()).await.unwrap();
+
+        // Wrong call_id.
+        assert_eq!(
+            storage
+                .remove_call_record(&call.group_id, "b2b2b2b2")
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .is_some());
+
+        // Deleted, returning the record as it was stored.
+        let removed = storage
+            .remove_call_record(&call.group_id, &call.call_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed.call_id, call.call_id);
+        assert!(removed.created_at.is_some());
+        assert!(storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Missing item.
+        assert_eq!(
+            storage
+                .remove_call_record(&call.group_id, &call.call_id)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_force_remove_call_record_ignores_call_id() {
+        let storage = InMemoryStorage::new();
+        let call = create_call_record(vec![]);
+        storage.get_or_add_call_record(call.clone()).await.unwrap();
+
+        let removed = storage
+            .force_remove_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(removed.call_id, call.call_id);
+        assert!(storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Missing item.
+        assert_eq!(
+            storage
+                .force_remove_call_record(&call.group_id)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_call_reserving_capacity() {
+        let storage = InMemoryStorage::new();
+        let call = create_call_record(vec![]);
+
+        storage
+            .create_call_reserving_capacity(call.clone(), 1)
+            .await
+            .unwrap();
+        assert!(matches!(
+            storage
+                .create_call_reserving_capacity(call.clone(), 2)
+                .await,
+            Err(StorageError::CallAlreadyExists)
+        ));
+
+        let other_call = CallRecord {
+            group_id: "bbbbbbbbbbbbbbbb".into(),
+            ..call
+        };
+        assert!(matches!(
+            storage
+                .create_call_reserving_capacity(other_call.clone(), 1)
+                .await,
+            Err(StorageError::RegionFull(_))
+        ));
+        storage
+            .create_call_reserving_capacity(other_call, 2)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_call_record_expires_at_boundary() {
+        let clock = Arc::new(MockClock::from_secs(1000));
+        let storage = InMemoryStorage::with_clock(clock.clone());
+        let call = create_call_record(vec![]);
+
+        let added = storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(added.created_at, Some(1000));
+        assert_eq!(added.expires_at, Some(1000 + CALL_RECORD_TTL.as_secs()));
+
+        // One second before the boundary the call is still live.
+        clock.advance(std::time::Duration::from_secs(
+            CALL_RECORD_TTL.as_secs() - 1,
+        ));
+        assert!(!added.is_expired(clock.now_secs(), Duration::ZERO));
+        assert_eq!(
+            storage.get_call_record(&call.group_id).await.unwrap(),
+            Some(added.clone())
+        );
+
+        // At the boundary it has expired.
+        clock.advance(std::time::Duration::from_secs(1));
+        assert!(added.is_expired(clock.now_secs(), Duration::ZERO));
+        assert_eq!(storage.get_call_record(&call.group_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_count_calls_per_backend() {
+        let storage = InMemoryStorage::new();
+        for (group_id, backend_ip, backend_region) in [
+            ("aaaaaaaaaaaaaaaa", "127.0.0.1", "us-west1"),
+            ("bbbbbbbbbbbbbbbb", "127.0.0.1", "us-west1"),
+            ("cccccccccccccccc", "127.0.0.2", "us-west1"),
+            ("dddddddddddddddd", "127.0.0.3", "us-east4"),
+        ] {
+            let call = CallRecord {
+                group_id: group_id.into(),
+                backend_ip: backend_ip.to_string(),
+                backend_region: backend_region.to_string(),
+                ..create_call_record(vec![])
+            };
+            storage.get_or_add_call_record(call).await.unwrap();
+        }
+
+        let counts = storage.count_calls_per_backend("us-west1").await.unwrap();
+        assert_eq!(
+            counts,
+            HashMap::from([("127.0.0.1".to_string(), 2), ("127.0.0.2".to_string(), 1)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_count_calls_by_backend_version() {
+        let storage = InMemoryStorage::new();
+        for (group_id, backend_version, backend_region) in [
+            ("aaaaaaaaaaaaaaaa", Some("1.2.3"), "us-west1"),
+            ("bbbbbbbbbbbbbbbb", Some("1.3.0-canary"), "us-west1"),
+            ("cccccccccccccccc", Some("1.2.3"), "us-west1"),
+            ("dddddddddddddddd", None, "us-west1"),
+            ("eeeeeeeeeeeeeeee", Some("1.3.0-canary"), "us-east4"),
+        ] {
+            let call = CallRecord {
+                group_id: group_id.into(),
+                backend_version: backend_version.map(str::to_string),
+                backend_region: backend_region.to_string(),
+                ..create_call_record(vec![])
+            };
+            storage.get_or_add_call_record(call).await.unwrap();
+        }
+
+        assert_eq!(
+            storage
+                .count_calls_by_backend_version("us-west1")
+                .await
+                .unwrap(),
+            HashMap::from([
+                (Some("1.2.3".to_string()), 2),
+                (Some("1.3.0-canary".to_string()), 1),
+                (None, 1),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_or_add_call_record_expecting() {
+        const EVENT: &str = "calling.frontend.storage.get_or_add_call_record.unexpected_call";
+        let storage = InMemoryStorage::new();
+        let call = create_call_record(vec![]);
+
+        let outcome = storage
+            .get_or_add_call_record_expecting(call.clone(), true)
+            .await
+            .unwrap();
+        let existing = storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(outcome, GetOrAddOutcome::Matched(existing.clone()));
+
+        // Adding the same call again finds it.
+        assert_eq!(
+            storage
+                .get_or_add_call_record_expecting(call.clone(), true)
+                .await
+                .unwrap(),
+            GetOrAddOutcome::Matched(existing.clone())
+        );
+
+        // A call with a different call_id isn't taken for the existing one.
+        let other = CallRecord {
+            call_id: "b2b2b2b2".to_string(),
+            ..call.clone()
+        };
+        let before = metrics!().peek_event_count(EVENT);
+        assert_eq!(
+            storage
+                .get_or_add_call_record_expecting(other.clone(), false)
+                .await
+                .unwrap(),
+            GetOrAddOutcome::Mismatched(existing.clone())
+        );
+        assert_eq!(metrics!().peek_event_count(EVENT), before);
+        assert_eq!(
+            storage
+                .get_or_add_call_record_expecting(other, true)
+                .await
+                .unwrap(),
+            GetOrAddOutcome::Mismatched(existing)
+        );
+        assert!(metrics!().peek_event_count(EVENT) > before);
+    }
+
+    #[tokio::test]
+    async fn test_least_loaded_backend() {
+        let storage = InMemoryStorage::new();
+        for (group_id, backend_ip, backend_region) in [
+            ("aaaaaaaaaaaaaaaa", "127.0.0.1", "us-west1"),
+            ("bbbbbbbbbbbbbbbb", "127.0.0.1", "us-west1"),
+            ("cccccccccccccccc", "127.0.0.2", "us-west1"),
+            ("dddddddddddddddd", "127.0.0.3", "us-east4"),
+            ("eeeeeeeeeeeeeeee", "127.0.0.3", "us-east4"),
+            ("ffffffffffffffff", "127.0.0.4", "us-east4"),
+            ("gggggggggggggggg", "127.0.0.4", "us-east4"),
+        ] {
+            let call = CallRecord {
+                group_id: group_id.into(),
+                backend_ip: backend_ip.to_string(),
+                backend_region: backend_region.to_string(),
+                ..create_call_record(vec![])
+            };
+            storage.get_or_add_call_record(call).await.unwrap();
+        }
+
+        let least_loaded = |region, candidate_ips: &'static [&'static str]| {
+            let storage = &storage;
+            async move {
+                storage
+                    .least_loaded_backend(region, candidate_ips)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // The backend with the fewest calls wins.
+        assert_eq!(
+            least_loaded("us-west1", &["127.0.0.1", "127.0.0.2"]).await,
+            Some("127.0.0.2".to_string())
+        );
+        // A candidate without any calls has the fewest.
+        assert_eq!(
+            least_loaded("us-west1", &["127.0.0.1", "127.0.0.2", "127.0.0.5"]).await,
+            Some("127.0.0.5".to_string())
+        );
+        // Only the candidates are considered.
+        assert_eq!(
+            least_loaded("us-west1", &["127.0.0.1"]).await,
+            Some("127.0.0.1".to_string())
+        );
+        // Ties go to the first candidate, whatever the order.
+        assert_eq!(
+            least_loaded("us-east4", &["127.0.0.4", "127.0.0.3"]).await,
+            Some("127.0.0.4".to_string())
+        );
+        assert_eq!(
+            least_loaded("us-east4", &["127.0.0.3", "127.0.0.4"]).await,
+            Some("127.0.0.3".to_string())
+        );
+        // In an empty region, every candidate is tied.
+        assert_eq!(
+            least_loaded("us-central1", &["127.0.0.2", "127.0.0.1"]).await,
+            Some("127.0.0.2".to_string())
+        );
+        assert_eq!(least_loaded("us-west1", &[]).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_update_call_record_keeps_concurrent_heartbeat() {
+        let storage = InMemoryStorage::new();
+        let call = storage
+            .get_or_add_call_record(create_call_record(vec![]))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // A heartbeat between the read and the update is a change the update didn't see.
+        assert!(storage
+            .heartbeat_call(&call.group_id, &call.call_id)
+            .await
+            .unwrap());
+        assert!(matches!(
+            storage
+                .update_call_record(CallRecord {
+                    backend_ip: "127.0.0.2".to_string(),
+                    ..call.clone()
+                })
+                .await,
+            Err(StorageError::VersionConflict)
+        ));
+        assert!(storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .last_heartbeat_at
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_update_call_record_versions() {
+        let storage = InMemoryStorage::new();
+        let call = storage
+            .get_or_add_call_record(create_call_record(vec![]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(call.version, 1);
+
+        let updated = storage
+            .update_call_record(CallRecord {
+                backend_ip: "127.0.0.2".to_string(),
+                ..call.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(updated.version, 2);
+        assert_eq!(
+            storage.get_call_record(&call.group_id).await.unwrap(),
+            Some(updated)
+        );
+
+        // A writer that read the first version is too late.
+        assert!(matches!(
+            storage
+                .update_call_record(CallRecord {
+                    backend_ip: "127.0.0.3".to_string(),
+                    ..call.clone()
+                })
+                .await,
+            Err(StorageError::VersionConflict)
+        ));
+        assert_eq!(
+            storage
+                .get_call_record(&call.group_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .backend_ip,
+            "127.0.0.2"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_call_records_for_region_projected() {
+        let storage = InMemoryStorage::new();
+        for group_id in ["aaaaaaaaaaaaaaaa", "bbbbbbbbbbbbbbbb"] {
+            storage
+                .get_or_add_call_record(CallRecord {
+                    group_id: group_id.into(),
+                    ..create_call_record(vec![])
+                })
+                .await
+                .unwrap();
+        }
+
+        let calls = storage
+            .get_call_records_for_region_projected(
+                &create_call_record(vec![]).backend_region,
+                &["groupConferenceId", "jvbHost"],
+                Some(1),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            calls,
+            vec![CallRecordSummary {
+                group_id: Some("aaaaaaaaaaaaaaaa".into()),
+                backend_ip: Some(create_call_record(vec![]).backend_ip),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reap_dead_calls() {
+        let clock = Arc::new(MockClock::from_secs(1000));
+        let storage = InMemoryStorage::with_clock(clock.clone());
+        for group_id in ["aaaaaaaaaaaaaaaa", "bbbbbbbbbbbbbbbb", "cccccccccccccccc"] {
+            storage
+                .get_or_add_call_record(CallRecord {
+                    group_id: group_id.into(),
+                    ..create_call_record(vec![])
+                })
+                .await
+                .unwrap();
+            clock.advance(std::time::Duration::from_secs(30));
+        }
+        // At 1090, the first call is 90s silent, the second 60s and the third 30s.
+        assert!(storage
+            .heartbeat_call(&"aaaaaaaaaaaaaaaa".into(), "a1a1a1a1")
+            .await
+            .unwrap());
+        assert!(!storage
+            .heartbeat_call(&"bbbbbbbbbbbbbbbb".into(), "x9x9x9x9")
+            .await
+            .unwrap());
+
+        clock.advance(std::time::Duration::from_secs(1));
+
+        // A dry run finds the same calls but leaves them in place.
+        let would_reap = storage
+            .reap_dead_calls(Duration::from_secs(60), true)
+            .await
+            .unwrap();
+        assert_eq!(would_reap, vec![GroupId::from("bbbbbbbbbbbbbbbb")]);
+        assert!(storage
+            .get_call_record(&"bbbbbbbbbbbbbbbb".into())
+            .await
+            .unwrap()
+            .is_some());
+
+        let reaped = storage
+            .reap_dead_calls(Duration::from_secs(60), false)
+            .await
+            .unwrap();
+        assert_eq!(reaped, would_reap);
+
+        for (group_id, alive) in [
+            ("aaaaaaaaaaaaaaaa", true),
+            ("bbbbbbbbbbbbbbbb", false),
+            ("cccccccccccccccc", true),
+        ] {
+            assert_eq!(
+                storage
+                    .get_call_record(&group_id.into())
+                    .await
+                    .unwrap()
+                    .is_some(),
+                alive,
+                "{}",
+                group_id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_clock_skew_tolerance() {
+        let clock = Arc::new(MockClock::from_secs(1000));
+        let storage = InMemoryStorage::with_clock(clock.clone())
+            .with_clock_skew_tolerance(Duration::from_secs(5));
+        let call = storage
+            .get_or_add_call_record(create_call_record(vec![]))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 64s of silence is past max_silence but within the skew window.
+        clock.advance(std::time::Duration::from_secs(64));
+        assert!(call.is_dead(clock.now_secs(), Duration::from_secs(60), Duration::ZERO));
+        assert!(storage
+            .reap_dead_calls(Duration::from_secs(60), false)
+            .await
+            .unwrap()
+            .is_empty());
+
+        clock.advance(std::time::Duration::from_secs(2));
+        assert_eq!(
+            storage
+                .reap_dead_calls(Duration::from_secs(60), false)
+                .await
+                .unwrap(),
+            vec![call.group_id.clone()]
+        );
+
+        // Expiration gets the same allowance.
+        let expires_at = call.expires_at.unwrap();
+        assert!(call.is_expired(expires_at, Duration::ZERO));
+        assert!(!call.is_expired(expires_at + 4, Duration::from_secs(5)));
+        assert!(call.is_expired(expires_at + 5, Duration::from_secs(5)));
+    }
+
+    #[tokio::test]
+    async fn test_set_call_locked() {
+        let storage = InMemoryStorage::new();
+        let call = storage
+            .get_or_add_call_record(create_call_record(vec![]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!call.locked);
+
+        assert!(storage
+            .set_call_locked(
+                &call.group_id,
+                &call.call_id,
+                true,
+                Some(call.creator.clone())
+            )
+            .await
+            .unwrap());
+        let locked = storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(locked.locked);
+        assert_eq!(locked.locked_by, Some(call.creator.clone()));
+        assert_eq!(locked.version, call.version + 1);
+
+        // A lock only applies to the call it was set on.
+        assert!(!storage
+            .set_call_locked(&call.group_id, "x9x9x9x9", false, None)
+            .await
+            .unwrap());
+
+        assert!(storage
+            .set_call_locked(&call.group_id, &call.call_id, false, None)
+            .await
+            .unwrap());
+        let unlocked = storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!unlocked.locked);
+        assert_eq!(unlocked.locked_by, None);
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_region() {
+        let storage = InMemoryStorage::new();
+        for (group_id, call_id, backend_region) in [
+            ("aaaaaaaaaaaaaaaa", "a1a1a1a1", "us-west1"),
+            ("bbbbbbbbbbbbbbbb", "b2b2b2b2", "us-west1"),
+            ("cccccccccccccccc", "c3c3c3c3", "us-east4"),
+        ] {
+            storage
+                .get_or_add_call_record(CallRecord {
+                    group_id: group_id.into(),
+                    call_id: call_id.to_string(),
+                    backend_region: backend_region.to_string(),
+                    ..create_call_record(vec![])
+                })
+                .await
+                .unwrap();
+        }
+        let expected = |calls: &[(&str, &str)]| -> HashSet<(GroupId, String)> {
+            calls
+                .iter()
+                .map(|(group_id, call_id)| ((*group_id).into(), call_id.to_string()))
+                .collect()
+        };
+        let call_ids = |diff: &RegionDiff| -> Vec<String> {
+            diff.orphaned
+                .iter()
+                .map(|call| call.call_id.clone())
+                .collect()
+        };
+
+        // Only orphans, including a call whose group has moved on to another call.
+        let diff = storage
+            .reconcile_region("us-west1", &expected(&[("aaaaaaaaaaaaaaaa", "a1a1a1a1")]))
+            .await
+            .unwrap();
+        assert_eq!(call_ids(&diff), vec!["b2b2b2b2"]);
+        assert!(diff.missing.is_empty());
+
+        // Only missing calls.
+        let diff = storage
+            .reconcile_region(
+                "us-west1",
+                &expected(&[
+                    ("aaaaaaaaaaaaaaaa", "a1a1a1a1"),
+                    ("bbbbbbbbbbbbbbbb", "b2b2b2b2"),
+                    ("dddddddddddddddd", "d4d4d4d4"),
+                ]),
+            )
+            .await
+            .unwrap();
+        assert!(diff.orphaned.is_empty());
+        assert_eq!(
+            diff.missing,
+            vec![("dddddddddddddddd".into(), "d4d4d4d4".to_string())]
+        );
+
+        // Both, where a call in another region counts as missing from this one.
+        let diff = storage
+            .reconcile_region(
+                "us-west1",
+                &expected(&[
+                    ("aaaaaaaaaaaaaaaa", "x9x9x9x9"),
+                    ("cccccccccccccccc", "c3c3c3c3"),
+                ]),
+            )
+            .await
+            .unwrap();
+        assert_eq!(call_ids(&diff), vec!["a1a1a1a1", "b2b2b2b2"]);
+        assert_eq!(
+            diff.missing,
+            vec![
+                ("aaaaaaaaaaaaaaaa".into(), "x9x9x9x9".to_string()),
+                ("cccccccccccccccc".into(), "c3c3c3c3".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_or_add_replaces_expired_record() {
+        let clock = Arc::new(MockClock::from_secs(1000));
+        let storage = InMemoryStorage::with_clock(clock.clone());
+
+        // A fresh insert returns the new call.
+        let first = storage
+            .get_or_add_call_record(create_call_record(vec![]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.call_id, "a1a1a1a1");
+
+        // A live call blocks another one for the group.
+        let second = CallRecord {
+            call_id: "b2b2b2b2".to_string(),
+            ..create_call_record(vec![])
+        };
+        assert_eq!(
+            storage
+                .get_or_add_call_record(second.clone())
+                .await
+                .unwrap(),
+            Some(first)
+        );
+
+        // Once it expires, the new call replaces it and is returned.
+        clock.advance(CALL_RECORD_TTL.into());
+        let replaced = storage
+            .get_or_add_call_record(second)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(replaced.call_id, "b2b2b2b2");
+        assert_eq!(replaced.created_at, Some(1000 + CALL_RECORD_TTL.as_secs()));
+        assert_eq!(
+            storage.get_call_record(&replaced.group_id).await.unwrap(),
+            Some(replaced)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_recreated_call_starts_next_era() {
+        let storage = InMemoryStorage::new();
+        let call = create_call_record(vec![]);
+
+        let first = storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.era, 1);
+
+        // Joining the existing call doesn't change its era.
+        let existing = storage
+            .get_or_add_call_record(CallRecord {
+                call_id: "b2b2b2b2".to_string(),
+                ..call.clone()
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(existing.era, 1);
+
+        storage
+            .remove_call_record(&call.group_id, &call.call_id)
+            .await
+            .unwrap();
+        let second = storage
+            .get_or_add_call_record(CallRecord {
+                call_id: "c3c3c3c3".to_string(),
+                ..call.clone()
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.era, 2);
+        assert_eq!(
+            storage
+                .get_call_record(&call.group_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .era,
+            2
+        );
+
+        // Other groups count their own eras.
+        let other = storage
+            .create_call_reserving_capacity(
+                CallRecord {
+                    group_id: "bbbbbbbbbbbbbbbb".into(),
+                    ..call
+                },
+                10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(other.era, 1);
+    }
+}
@@ -0,0 +1,527 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::{HashMap, HashSet};
+
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::{stream::BoxStream, StreamExt};
+
+use crate::{
+    frontend::{GroupId, UserId},
+    metrics::Timer,
+    storage::{
+        CallRecord, CallRecordSummary, GetOrAddOutcome, ReadMeta, RegionDiff, Storage, StorageError,
+    },
+};
+
+/// Times an operation of the inner storage as calling.frontend.storage.<operation>.timed
+/// and counts its outcome. Each use registers its own timer, so the operation must be a
+/// literal that isn't used anywhere else.
+macro_rules! measure {
+    ($operation:literal, $call:expr) => {{
+        let timer = start_timer_us!(concat!("calling.frontend.storage.", $operation, ".timed"));
+        let result = $call.await;
+        timer.stop();
+        count_outcome($operation, &result);
+        result
+    }};
+}
+
+/// Counts the outcome of an operation as calling.frontend.storage.outcome, tagged with the
+/// operation and either "ok" or the kind of error.
+fn count_outcome<T>(operation: &str, result: &Result<T, StorageError>) {
+    let outcome = match result {
+        Ok(_) => "ok",
+        Err(err) => err.kind(),
+    };
+    tagged_event!(
+        "calling.frontend.storage.outcome",
+        vec![
+            format!("operation:{}", operation),
+            format!("outcome:{}", outcome),
+        ]
+    );
+}
+
+/// A Storage decorator that times every operation and counts its outcome, so that any
+/// implementation it wraps reports the same metrics. Streams aren't timed since they are
+/// consumed at the caller's pace, but the outcome of each item they yield is counted.
+pub struct MeasuredStorage<S: Storage> {
+    inner: S,
+}
+
+impl<S: Storage> MeasuredStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for MeasuredStorage<S> {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        measure!("get_call_record", self.inner.get_call_record(group_id))
+    }
+
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        measure!(
+            "get_call_record_with_meta",
+            self.inner.get_call_record_with_meta(group_id)
+        )
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        measure!(
+            "get_or_add_call_record",
+            self.inner.get_or_add_call_record(call)
+        )
+    }
+
+    async fn get_or_add_call_record_expecting(
+        &self,
+        call: CallRecord,
+        expected_absent: bool,
+    ) -> Result<GetOrAddOutcome, StorageError> {
+        measure!(
+            "get_or_add_call_record_expecting",
+            self.inner
+                .get_or_add_call_record_expecting(call, expected_absent)
+        )
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        measure!(
+            "remove_call_record",
+            self.inner.remove_call_record(group_id, call_id)
+        )
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        self.inner.remove_call_record_best_effort(group_id, call_id)
+    }
+
+    async fn remove_call_record_with_retries(&self, group_id: &GroupId, call_id: &str) {
+        // The removal is timed as a whole, and its outcome is counted where the retries are
+        // made.
+        let timer =
+            start_timer_us!("calling.frontend.storage.remove_call_record_with_retries.timed");
+        self.inner
+            .remove_call_record_with_retries(group_id, call_id)
+            .await;
+        timer.stop();
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        measure!(
+            "force_remove_call_record",
+            self.inner.force_remove_call_record(group_id)
+        )
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        measure!(
+            "get_call_records_for_region",
+            self.inner.get_call_records_for_region(region)
+        )
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.inner
+            .stream_call_records_for_region(region)
+            .inspect(|call| count_outcome("stream_call_records_for_region", call))
+            .boxed()
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        measure!(
+            "count_calls_per_backend",
+            self.inner.count_calls_per_backend(region)
+        )
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        measure!(
+            "count_call_records_for_region",
+            self.inner.count_call_records_for_region(region)
+        )
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        measure!(
+            "count_calls_by_backend_version",
+            self.inner.count_calls_by_backend_version(region)
+        )
+    }
+
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        measure!("count_all_regions", self.inner.count_all_regions())
+    }
+
+    async fn least_loaded_backend(
+        &self,
+        region: &str,
+        candidate_ips: &[&str],
+    ) -> Result<Option<String>, StorageError> {
+        measure!(
+            "least_loaded_backend",
+            self.inner.least_loaded_backend(region, candidate_ips)
+        )
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        measure!(
+            "get_call_records_for_region_projected",
+            self.inner
+                .get_call_records_for_region_projected(region, attributes, limit)
+        )
+    }
+
+    async fn reconcile_region(
+        &self,
+        region: &str,
+        expected: &HashSet<(GroupId, String)>,
+    ) -> Result<RegionDiff, StorageError> {
+        measure!(
+            "reconcile_region",
+            self.inner.reconcile_region(region, expected)
+        )
+    }
+
+    async fn get_call_records_for_region_with_retry(
+        &self,
+        region: &str,
+        group_id: &GroupId,
+        call_id: &str,
+        timeout: Duration,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        measure!(
+            "get_call_records_for_region_with_retry",
+            self.inner
+                .get_call_records_for_region_with_retry(region, group_id, call_id, timeout)
+        )
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        measure!(
+            "promote_backup_backend",
+            self.inner.promote_backup_backend(group_id, call_id)
+        )
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        measure!(
+            "create_call_reserving_capacity",
+            self.inner
+                .create_call_reserving_capacity(call, max_calls_per_region)
+        )
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        measure!("add_call_records", self.inner.add_call_records(records))
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        measure!("update_call_record", self.inner.update_call_record(call))
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        measure!("health_check", self.inner.health_check())
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        measure!(
+            "heartbeat_call",
+            self.inner.heartbeat_call(group_id, call_id)
+        )
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        measure!(
+            "set_call_locked",
+            self.inner
+                .set_call_locked(group_id, call_id, locked, locked_by)
+        )
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        measure!(
+            "reap_dead_calls",
+            self.inner.reap_dead_calls(max_silence, dry_run)
+        )
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.inner
+            .export_all()
+            .inspect(|call| count_outcome("export_all", call))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod measured_storage_tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::storage::{create_call_record, DynStorage, InMemoryStorage};
+
+    fn outcome_count(operation: &str, outcome: &str) -> usize {
+        let operation_tag = format!("operation:{}", operation);
+        let outcome_tag = format!("outcome:{}", outcome);
+        metrics!().peek_event_count_with_tags(
+            "calling.frontend.storage.outcome",
+            &[&operation_tag, &outcome_tag],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_operations_are_measured() {
+        const TIMED_OPERATIONS: &[&str] = &[
+            "get_call_record",
+            "get_call_record_with_meta",
+            "get_or_add_call_record",
+            "get_or_add_call_record_expecting",
+            "remove_call_record",
+            "force_remove_call_record",
+            "get_call_records_for_region",
+            "count_calls_per_backend",
+            "count_call_records_for_region",
+            "count_calls_by_backend_version",
+            "count_all_regions",
+            "least_loaded_backend",
+            "get_call_records_for_region_projected",
+            "reconcile_region",
+            "get_call_records_for_region_with_retry",
+            "promote_backup_backend",
+            "create_call_reserving_capacity",
+            "add_call_records",
+            "update_call_record",
+            "health_check",
+            "heartbeat_call",
+            "set_call_locked",
+            "reap_dead_calls",
+        ];
+        const STREAMED_OPERATIONS: &[&str] = &["stream_call_records_for_region", "export_all"];
+
+        let timer_counts_before: Vec<_> = TIMED_OPERATIONS
+            .iter()
+            .map(|operation| {
+                metrics!()
+                    .peek_timer_count(&format!("calling.frontend.storage.{}.timed", operation))
+            })
+            .collect();
+        let outcome_counts_before: Vec<_> = TIMED_OPERATIONS
+            .iter()
+            .chain(STREAMED_OPERATIONS)
+            .map(|operation| outcome_count(operation, "ok"))
+            .collect();
+
+        let storage = MeasuredStorage::new(InMemoryStorage::new());
+        let call = create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1");
+        let group_id = call.group_id.clone();
+
+        storage.get_or_add_call_record(call.clone()).await.unwrap();
+        storage
+            .get_or_add_call_record_expecting(call.clone(), false)
+            .await
+            .unwrap();
+        storage.get_call_record(&group_id).await.unwrap();
+        storage.get_call_record_with_meta(&group_id).await.unwrap();
+        storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .stream_call_records_for_region("us-west1")
+                .count()
+                .await,
+            1
+        );
+        storage.count_calls_per_backend("us-west1").await.unwrap();
+        storage
+            .count_call_records_for_region("us-west1")
+            .await
+            .unwrap();
+        storage
+            .count_calls_by_backend_version("us-west1")
+            .await
+            .unwrap();
+        storage.count_all_regions().await.unwrap();
+        storage
+            .least_loaded_backend("us-west1", &["127.0.0.1"])
+            .await
+            .unwrap();
+        storage
+            .get_call_records_for_region_projected("us-west1", &["groupConferenceId"], None)
+            .await
+            .unwrap();
+        storage
+            .reconcile_region("us-west1", &HashSet::new())
+            .await
+            .unwrap();
+        storage
+            .get_call_records_for_region_with_retry(
+                "us-west1",
+                &group_id,
+                "a1a1a1a1",
+                Duration::from_secs(1),
+            )
+            .await
+            .unwrap();
+        storage.heartbeat_call(&group_id, "a1a1a1a1").await.unwrap();
+        storage
+            .set_call_locked(&group_id, "a1a1a1a1", true, None)
+            .await
+            .unwrap();
+        storage
+            .promote_backup_backend(&group_id, "a1a1a1a1")
+            .await
+            .unwrap();
+        let call = storage.get_call_record(&group_id).await.unwrap().unwrap();
+        storage.update_call_record(call).await.unwrap();
+        assert_eq!(storage.export_all().count().await, 1);
+        storage.health_check().await.unwrap();
+        storage
+            .remove_call_record(&group_id, "a1a1a1a1")
+            .await
+            .unwrap();
+        storage.force_remove_call_record(&group_id).await.unwrap();
+        storage
+            .create_call_reserving_capacity(create_call_record("aaaaaaaaaaaaaaaa", "b2b2b2b2"), 10)
+            .await
+            .unwrap();
+        storage
+            .add_call_records(vec![create_call_record("aaaaaaaaaaaaaaaa", "c3c3c3c3")])
+            .await
+            .unwrap();
+        storage
+            .reap_dead_calls(Duration::from_secs(60), false)
+            .await
+            .unwrap();
+
+        for (operation, before) in TIMED_OPERATIONS.iter().zip(timer_counts_before) {
+            assert!(
+                metrics!()
+                    .peek_timer_count(&format!("calling.frontend.storage.{}.timed", operation))
+                    > before,
+                "{} wasn't timed",
+                operation
+            );
+        }
+        for (operation, before) in TIMED_OPERATIONS
+            .iter()
+            .chain(STREAMED_OPERATIONS)
+            .zip(outcome_counts_before)
+        {
+            assert!(
+                outcome_count(operation, "ok") > before,
+                "{} outcome wasn't counted",
+                operation
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_outcome_is_counted_by_kind() {
+        let before = outcome_count("update_call_record", "version_conflict");
+
+        let storage = MeasuredStorage::new(InMemoryStorage::new());
+        let call = create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1");
+        storage.get_or_add_call_record(call).await.unwrap();
+
+        // After the first update, the stored record is at a newer version than this one.
+        let call = storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap()
+            .unwrap();
+        storage.update_call_record(call.clone()).await.unwrap();
+        assert!(matches!(
+            storage.update_call_record(call).await,
+            Err(StorageError::VersionConflict)
+        ));
+
+        assert!(outcome_count("update_call_record", "version_conflict") > before);
+    }
+
+    #[tokio::test]
+    async fn test_best_effort_removal_through_shared_handle_is_measured() {
+        const TIMER: &str = "calling.frontend.storage.remove_call_record_with_retries.timed";
+        let before = metrics!().peek_timer_count(TIMER);
+
+        let storage: DynStorage = Arc::new(MeasuredStorage::new(InMemoryStorage::new()));
+        let call = create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1");
+        storage.get_or_add_call_record(call.clone()).await.unwrap();
+
+        // The removal runs in the background on the shared handle, so it goes through the
+        // decorator rather than straight to the storage behind it.
+        storage.remove_call_record_best_effort(&call.group_id, &call.call_id);
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while metrics!().peek_timer_count(TIMER) == before {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("the removal should eventually be timed");
+
+        assert_eq!(storage.get_call_record(&call.group_id).await.unwrap(), None);
+    }
+}
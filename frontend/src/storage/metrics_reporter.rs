@@ -0,0 +1,200 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use anyhow::Result;
+use calling_common::Duration;
+use log::*;
+use tokio::sync::oneshot::Receiver;
+
+use crate::{metrics::Timer, storage::DynStorage};
+
+/// Periodically counts the active calls in each of the given regions and reports them as
+/// the calling.frontend.storage.region.calls gauge, tagged with the region, so that
+/// capacity dashboards don't need to query storage themselves.
+pub struct StorageMetricsReporter {
+    storage: DynStorage,
+    regions: Vec<String>,
+    interval: Duration,
+}
+
+impl StorageMetricsReporter {
+    /// Counts the calls in each region once, setting the gauge of each region that could
+    /// be counted. A region that fails keeps its previous value until the next interval.
+    async fn report(&self) {
+        for region in &self.regions {
+            match self.storage.count_call_records_for_region(region).await {
+                Ok(count) => {
+                    tagged_gauge!(
+                        "calling.frontend.storage.region.calls",
+                        vec![format!("region:{}", region)],
+                        count as f64
+                    );
+                }
+                Err(err) => {
+                    storage_log!(
+                        Level::Warn,
+                        "failed to count the calls in region {}: {:?}",
+                        region,
+                        err
+                    );
+                }
+            }
+        }
+    }
+
+    pub async fn start(
+        storage: DynStorage,
+        regions: Vec<String>,
+        interval: Duration,
+        ender_rx: Receiver<()>,
+    ) -> Result<()> {
+        if regions.is_empty() {
+            // There is nothing to count, so don't poll and just wait to be ended.
+            let _ = ender_rx.await;
+            info!("storage metrics reporter shutdown");
+            return Ok(());
+        }
+
+        let reporter = Self {
+            storage,
+            regions,
+            interval,
+        };
+
+        let mut reporter_handle = tokio::spawn(async move {
+            loop {
+                let timer = start_timer_us!("calling.frontend.storage.metrics_reporter.timed");
+                reporter.report().await;
+                timer.stop();
+
+                // Use sleep() instead of interval() so that we never wait *less* than one
+                // interval to do the next tick.
+                tokio::time::sleep(reporter.interval.into()).await;
+            }
+        });
+
+        info!("storage metrics reporter ready");
+
+        tokio::select!(
+            _ = &mut reporter_handle => {},
+            _ = ender_rx => {},
+        );
+        // Otherwise the spawned task would keep counting calls after shutdown.
+        reporter_handle.abort();
+
+        info!("storage metrics reporter shutdown");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod storage_metrics_reporter_tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::storage::{self, CallRecord, InMemoryStorage, MockStorage, Storage};
+
+    fn create_call_record(group_id: &str, region: &str) -> CallRecord {
+        CallRecord {
+            backend_region: region.to_string(),
+            ..storage::create_call_record(group_id, "a1a1a1a1")
+        }
+    }
+
+    fn region_calls(region: &str) -> Option<f64> {
+        metrics!().peek_gauge_with_tags(
+            "calling.frontend.storage.region.calls",
+            &[&format!("region:{}", region)],
+        )
+    }
+
+    #[tokio::test]
+    async fn test_reports_each_region_and_stops() {
+        let storage = Arc::new(InMemoryStorage::new());
+        storage
+            .add_call_records(vec![
+                create_call_record("aaaaaaaaaaaaaaaa", "reporter-west"),
+                create_call_record("bbbbbbbbbbbbbbbb", "reporter-west"),
+                create_call_record("cccccccccccccccc", "reporter-east"),
+            ])
+            .await
+            .unwrap();
+
+        let (ender_tx, ender_rx) = oneshot::channel();
+        let reporter = tokio::spawn(StorageMetricsReporter::start(
+            storage,
+            vec![
+                "reporter-west".to_string(),
+                "reporter-east".to_string(),
+                "reporter-empty".to_string(),
+            ],
+            Duration::from_millis(10),
+            ender_rx,
+        ));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while region_calls("reporter-empty").is_none() {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(region_calls("reporter-west"), Some(2.0));
+        assert_eq!(region_calls("reporter-east"), Some(1.0));
+        assert_eq!(region_calls("reporter-empty"), Some(0.0));
+
+        ender_tx.send(()).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), reporter)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
+    #[tokio::test]
+    async fn test_no_reports_after_ended() {
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let mut storage = MockStorage::new();
+        let counted = ticks.clone();
+        storage
+            .expect_count_call_records_for_region()
+            .returning(move |_| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Ok(0)
+            });
+
+        let (ender_tx, ender_rx) = oneshot::channel();
+        let reporter = tokio::spawn(StorageMetricsReporter::start(
+            Arc::new(storage),
+            vec!["reporter-ended".to_string()],
+            Duration::from_millis(5),
+            ender_rx,
+        ));
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), async {
+            while ticks.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        ender_tx.send(()).unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), reporter)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+
+        // Several intervals pass without another tick.
+        let ended_at = ticks.load(Ordering::SeqCst);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(ticks.load(Ordering::SeqCst), ended_at);
+    }
+}
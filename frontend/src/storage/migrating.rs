@@ -0,0 +1,438 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::{future, stream::BoxStream, StreamExt};
+use parking_lot::Mutex;
+
+use crate::{
+    frontend::{GroupId, UserId},
+    storage::{sort_call_records, CallRecord, Storage, StorageError},
+};
+
+/// A Storage decorator for migrating calls from an old table to a new one. Reads look in
+/// the new storage first and fall back to the old one, while new calls are only ever
+/// written to the new storage. Removals apply to both so that calls that end during the
+/// migration don't linger in the old table.
+pub struct MigratingStorage {
+    pub new: Box<dyn Storage>,
+    pub old: Box<dyn Storage>,
+}
+
+impl MigratingStorage {
+    pub fn new(new: Box<dyn Storage>, old: Box<dyn Storage>) -> Self {
+        Self { new, old }
+    }
+}
+
+#[async_trait]
+impl Storage for MigratingStorage {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        if let Some(call) = self.new.get_call_record(group_id).await? {
+            event!("calling.frontend.storage.migrating.read_new");
+            return Ok(Some(call));
+        }
+
+        match self.old.get_call_record(group_id).await? {
+            Some(call) => {
+                event!("calling.frontend.storage.migrating.read_old");
+                Ok(Some(call))
+            }
+            None => {
+                event!("calling.frontend.storage.migrating.read_miss");
+                Ok(None)
+            }
+        }
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        // A call that started before the migration must still be joined rather than
+        // replaced by a new one.
+        if let Some(existing) = self.old.get_call_record(&call.group_id).await? {
+            return Ok(Some(existing));
+        }
+        self.new.get_or_add_call_record(call).await
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let new_removed = self.new.remove_call_record(group_id, call_id).await?;
+        let old_removed = self.old.remove_call_record(group_id, call_id).await?;
+
+        // The new storage has the most recent version of the call if it has one at all.
+        Ok(new_removed.or(old_removed))
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        self.new.remove_call_record_best_effort(group_id, call_id);
+        self.old.remove_call_record_best_effort(group_id, call_id);
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let new_removed = self.new.force_remove_call_record(group_id).await?;
+        let old_removed = self.old.force_remove_call_record(group_id).await?;
+
+        Ok(new_removed.or(old_removed))
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        let mut calls = self.new.get_call_records_for_region(region).await?;
+        let new_group_ids: HashSet<_> = calls
+            .iter()
+            .map(|call| call.group_id.as_ref().to_string())
+            .collect();
+
+        calls.extend(
+            self.old
+                .get_call_records_for_region(region)
+                .await?
+                .into_iter()
+                .filter(|call| !new_group_ids.contains(call.group_id.as_ref())),
+        );
+        sort_call_records(&mut calls);
+        Ok(calls)
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        // The new calls are streamed first so that any old call for the same group can
+        // be skipped.
+        let new_group_ids = Arc::new(Mutex::new(HashSet::new()));
+        let seen_group_ids = new_group_ids.clone();
+
+        self.new
+            .stream_call_records_for_region(region)
+            .inspect(move |call| {
+                if let Ok(call) = call {
+                    new_group_ids
+                        .lock()
+                        .insert(call.group_id.as_ref().to_string());
+                }
+            })
+            .chain(
+                self.old
+                    .stream_call_records_for_region(region)
+                    .filter(move |call| {
+                        future::ready(match call {
+                            Ok(call) => !seen_group_ids.lock().contains(call.group_id.as_ref()),
+                            Err(_) => true,
+                        })
+                    }),
+            )
+            .boxed()
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        // Counting each storage separately would count calls that are in both twice.
+        let mut counts = HashMap::new();
+        for call in self.get_call_records_for_region(region).await? {
+            *counts.entry(call.backend_ip).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        // Calls in the old storage can't be updated, so they have nothing to promote.
+        self.new.promote_backup_backend(group_id, call_id).await
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        if self.old.get_call_record(&call.group_id).await?.is_some() {
+            return Err(StorageError::CallAlreadyExists);
+        }
+        self.new
+            .create_call_reserving_capacity(call, max_calls_per_region)
+            .await
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        // Both are needed while reads can fall back to the old storage.
+        self.new.health_check().await?;
+        self.old.health_check().await
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        // Like promotions, updates only apply to calls in the new storage.
+        self.new.update_call_record(call).await
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        self.new.add_call_records(records).await
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        // Calls that started before the migration must be kept alive too.
+        if self.new.heartbeat_call(group_id, call_id).await? {
+            return Ok(true);
+        }
+        self.old.heartbeat_call(group_id, call_id).await
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        // Calls that started before the migration can be locked too.
+        if self
+            .new
+            .set_call_locked(group_id, call_id, locked, locked_by.clone())
+            .await?
+        {
+            return Ok(true);
+        }
+        self.old
+            .set_call_locked(group_id, call_id, locked, locked_by)
+            .await
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        let mut reaped = self.new.reap_dead_calls(max_silence, dry_run).await?;
+        reaped.extend(self.old.reap_dead_calls(max_silence, dry_run).await?);
+        Ok(reaped)
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        // Like region streams, old calls are skipped for groups that have a new call.
+        let new_group_ids = Arc::new(Mutex::new(HashSet::new()));
+        let seen_group_ids = new_group_ids.clone();
+
+        self.new
+            .export_all()
+            .inspect(move |call| {
+                if let Ok(call) = call {
+                    new_group_ids
+                        .lock()
+                        .insert(call.group_id.as_ref().to_string());
+                }
+            })
+            .chain(self.old.export_all().filter(move |call| {
+                future::ready(match call {
+                    Ok(call) => !seen_group_ids.lock().contains(call.group_id.as_ref()),
+                    Err(_) => true,
+                })
+            }))
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod migrating_storage_tests {
+    use super::*;
+    use crate::storage::{create_call_record, InMemoryStorage};
+
+    fn create_migrating_storage() -> MigratingStorage {
+        MigratingStorage::new(
+            Box::new(InMemoryStorage::new()),
+            Box::new(InMemoryStorage::new()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_read_new_hit() {
+        const EVENT: &str = "calling.frontend.storage.migrating.read_new";
+
+        let storage = create_migrating_storage();
+        let call = create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1");
+        storage
+            .old
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "b2b2b2b2"))
+            .await
+            .unwrap();
+        storage.new.get_or_add_call_record(call).await.unwrap();
+        let before = metrics!().peek_event_count(EVENT);
+
+        let found = storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.call_id, "a1a1a1a1");
+        assert!(metrics!().peek_event_count(EVENT) > before);
+    }
+
+    #[tokio::test]
+    async fn test_read_old_fallback() {
+        const EVENT: &str = "calling.frontend.storage.migrating.read_old";
+
+        let storage = create_migrating_storage();
+        storage
+            .old
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "b2b2b2b2"))
+            .await
+            .unwrap();
+        let before = metrics!().peek_event_count(EVENT);
+
+        let found = storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.call_id, "b2b2b2b2");
+        assert!(metrics!().peek_event_count(EVENT) > before);
+    }
+
+    #[tokio::test]
+    async fn test_read_miss() {
+        const EVENT: &str = "calling.frontend.storage.migrating.read_miss";
+
+        let storage = create_migrating_storage();
+        let before = metrics!().peek_event_count(EVENT);
+
+        assert_eq!(
+            storage
+                .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(metrics!().peek_event_count(EVENT) > before);
+    }
+
+    #[tokio::test]
+    async fn test_write_only_to_new() {
+        let storage = create_migrating_storage();
+        let call = create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1");
+
+        storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(storage
+            .new
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(storage
+            .old
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .is_none());
+
+        // A call that is still in the old storage is joined instead of replaced.
+        storage
+            .old
+            .get_or_add_call_record(create_call_record("bbbbbbbbbbbbbbbb", "b2b2b2b2"))
+            .await
+            .unwrap();
+        let joined = storage
+            .get_or_add_call_record(create_call_record("bbbbbbbbbbbbbbbb", "c3c3c3c3"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(joined.call_id, "b2b2b2b2");
+        assert!(storage
+            .new
+            .get_call_record(&"bbbbbbbbbbbbbbbb".into())
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_region_reads_merge_both_storages() {
+        let storage = create_migrating_storage();
+        storage
+            .new
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap();
+        for call in [
+            create_call_record("aaaaaaaaaaaaaaaa", "b2b2b2b2"),
+            create_call_record("bbbbbbbbbbbbbbbb", "c3c3c3c3"),
+        ] {
+            storage.old.get_or_add_call_record(call).await.unwrap();
+        }
+
+        let mut call_ids: Vec<_> = storage
+            .get_call_records_for_region("us-west1")
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|call| call.call_id)
+            .collect();
+        call_ids.sort();
+        assert_eq!(call_ids, vec!["a1a1a1a1", "c3c3c3c3"]);
+
+        let mut streamed: Vec<_> = storage
+            .stream_call_records_for_region("us-west1")
+            .map(|call| call.unwrap().call_id)
+            .collect()
+            .await;
+        streamed.sort();
+        assert_eq!(streamed, call_ids);
+    }
+
+    #[tokio::test]
+    async fn test_export_all_merges_both_storages() {
+        let storage = create_migrating_storage();
+        storage
+            .new
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap();
+        for call in [
+            create_call_record("aaaaaaaaaaaaaaaa", "b2b2b2b2"),
+            create_call_record("bbbbbbbbbbbbbbbb", "c3c3c3c3"),
+        ] {
+            storage.old.get_or_add_call_record(call).await.unwrap();
+        }
+
+        let exported: Vec<_> = storage
+            .export_all()
+            .map(|call| call.unwrap().call_id)
+            .collect()
+            .await;
+        assert_eq!(exported, vec!["a1a1a1a1", "c3c3c3c3"]);
+    }
+}
@@ -0,0 +1,333 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::stream::BoxStream;
+use log::*;
+
+use crate::{
+    frontend::{GroupId, UserId},
+    storage::{CallRecord, CallRecordSummary, ReadMeta, Storage, StorageError},
+};
+
+/// A Storage decorator that serves reads from the inner storage but fails every write
+/// with ReadOnly, so that the calls in storage stay frozen during maintenance.
+pub struct ReadOnlyStorage<S: Storage> {
+    inner: S,
+}
+
+impl<S: Storage> ReadOnlyStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for ReadOnlyStorage<S> {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.inner.get_call_record(group_id).await
+    }
+
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        self.inner.get_call_record_with_meta(group_id).await
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        _call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    async fn remove_call_record(
+        &self,
+        _group_id: &GroupId,
+        _call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        // There is no error to return, so the removal is dropped and the call is left for
+        // its TTL.
+        storage_log!(
+            Level::Debug,
+            "not removing call {:.6} of group {} while storage is read only",
+            call_id,
+            group_id
+        );
+    }
+
+    async fn remove_call_record_with_retries(&self, group_id: &GroupId, call_id: &str) {
+        self.remove_call_record_best_effort(group_id, call_id)
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        _group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        self.inner.get_call_records_for_region(region).await
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.inner.stream_call_records_for_region(region)
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        self.inner.count_calls_per_backend(region).await
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        self.inner.count_call_records_for_region(region).await
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        self.inner.count_calls_by_backend_version(region).await
+    }
+
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        self.inner.count_all_regions().await
+    }
+
+    async fn least_loaded_backend(
+        &self,
+        region: &str,
+        candidate_ips: &[&str],
+    ) -> Result<Option<String>, StorageError> {
+        self.inner.least_loaded_backend(region, candidate_ips).await
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        self.inner
+            .get_call_records_for_region_projected(region, attributes, limit)
+            .await
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        _group_id: &GroupId,
+        _call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        _call: CallRecord,
+        _max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.inner.export_all()
+    }
+
+    async fn heartbeat_call(
+        &self,
+        _group_id: &GroupId,
+        _call_id: &str,
+    ) -> Result<bool, StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    async fn set_call_locked(
+        &self,
+        _group_id: &GroupId,
+        _call_id: &str,
+        _locked: bool,
+        _locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        // A dry run changes nothing, so it is still allowed.
+        if dry_run {
+            self.inner.reap_dead_calls(max_silence, true).await
+        } else {
+            Err(StorageError::ReadOnly)
+        }
+    }
+
+    async fn add_call_records(&self, _records: Vec<CallRecord>) -> Result<(), StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    async fn update_call_record(&self, _call: CallRecord) -> Result<CallRecord, StorageError> {
+        Err(StorageError::ReadOnly)
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        self.inner.health_check().await
+    }
+}
+
+#[cfg(test)]
+mod read_only_storage_tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::storage::{create_call_record, InMemoryStorage};
+
+    async fn create_read_only_storage() -> ReadOnlyStorage<InMemoryStorage> {
+        let inner = InMemoryStorage::new();
+        inner
+            .get_or_add_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+            .await
+            .unwrap();
+        ReadOnlyStorage::new(inner)
+    }
+
+    #[tokio::test]
+    async fn test_reads_are_served() {
+        let storage = create_read_only_storage().await;
+        let group_id = "aaaaaaaaaaaaaaaa".into();
+
+        assert!(storage.get_call_record(&group_id).await.unwrap().is_some());
+        assert_eq!(
+            storage
+                .get_call_records_for_region("us-west1")
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            storage
+                .stream_call_records_for_region("us-west1")
+                .count()
+                .await,
+            1
+        );
+        assert_eq!(
+            storage.count_calls_per_backend("us-west1").await.unwrap()["127.0.0.1"],
+            1
+        );
+        assert_eq!(
+            storage
+                .count_call_records_for_region("us-west1")
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            storage
+                .get_call_records_for_region_projected("us-west1", &["groupConferenceId"], None)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(storage.export_all().count().await, 1);
+        assert!(storage.health_check().await.is_ok());
+        assert!(storage
+            .reap_dead_calls(Duration::ZERO, true)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_rejected() {
+        let storage = create_read_only_storage().await;
+        let group_id = "aaaaaaaaaaaaaaaa".into();
+        let call = storage.get_call_record(&group_id).await.unwrap().unwrap();
+
+        assert!(matches!(
+            storage
+                .get_or_add_call_record(create_call_record("bbbbbbbbbbbbbbbb", "a1a1a1a1"))
+                .await,
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage.remove_call_record(&group_id, "a1a1a1a1").await,
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage.force_remove_call_record(&group_id).await,
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage.promote_backup_backend(&group_id, "a1a1a1a1").await,
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage
+                .create_call_reserving_capacity(
+                    create_call_record("bbbbbbbbbbbbbbbb", "a1a1a1a1"),
+                    10
+                )
+                .await,
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage.heartbeat_call(&group_id, "a1a1a1a1").await,
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage
+                .set_call_locked(&group_id, "a1a1a1a1", true, None)
+                .await,
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage.reap_dead_calls(Duration::ZERO, false).await,
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage
+                .add_call_records(vec![create_call_record("bbbbbbbbbbbbbbbb", "a1a1a1a1")])
+                .await,
+            Err(StorageError::ReadOnly)
+        ));
+        assert!(matches!(
+            storage.update_call_record(call.clone()).await,
+            Err(StorageError::ReadOnly)
+        ));
+        storage.remove_call_record_best_effort(&group_id, "a1a1a1a1");
+
+        // Nothing was changed.
+        assert_eq!(
+            storage.get_call_record(&group_id).await.unwrap(),
+            Some(call)
+        );
+        assert_eq!(storage.export_all().count().await, 1);
+    }
+}
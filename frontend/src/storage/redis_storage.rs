@@ -0,0 +1,1124 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::{stream::BoxStream, StreamExt};
+use log::*;
+use once_cell::sync::Lazy;
+use redis::{aio::ConnectionManager, AsyncCommands, RedisError, Script};
+
+use crate::{
+    frontend::{GroupId, UserId},
+    storage::{
+        sort_call_records, spawn_best_effort_removal, storage_error_log_record, CallRecord, Clock,
+        Storage, StorageError, CALL_ERA_TTL, CALL_RECORD_TTL, HEALTH_CHECK_TIMEOUT,
+    },
+};
+
+/// How many keys to ask for in each step of a SCAN over all calls.
+const SCAN_COUNT: usize = 100;
+
+/// Removes the call stored at KEYS[1] and its entry in the set of its region as long as
+/// its call_id is ARGV[1], releasing the capacity it reserved, if any. ARGV[2] is the
+/// group_id. Returns the removed value, if any.
+static REMOVE_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+local value = redis.call('GET', KEYS[1])
+if not value then
+  return false
+end
+local call = cjson.decode(value)
+if call['jvbConferenceId'] ~= ARGV[1] then
+  return false
+end
+redis.call('DEL', KEYS[1])
+redis.call('SREM', 'region:' .. call['region'], ARGV[2])
+if type(call['reservedRegion']) == 'string' then
+  redis.call('ZREM', 'regionCapacity:' .. call['reservedRegion'], ARGV[2])
+end
+return value
+",
+    )
+});
+
+/// Like REMOVE_SCRIPT, but removes the call whatever its call_id. ARGV[1] is the group_id.
+static FORCE_REMOVE_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+local value = redis.call('GET', KEYS[1])
+if not value then
+  return false
+end
+local call = cjson.decode(value)
+redis.call('DEL', KEYS[1])
+redis.call('SREM', 'region:' .. call['region'], ARGV[1])
+if type(call['reservedRegion']) == 'string' then
+  redis.call('ZREM', 'regionCapacity:' .. call['reservedRegion'], ARGV[1])
+end
+return value
+",
+    )
+});
+
+/// Adds the call ARGV[1] at KEYS[1] with a TTL of ARGV[2] seconds unless a call that
+/// expires after ARGV[4] is already stored there, in which case nothing is changed and
+/// that call is returned. A call that expired, but whose key is still there, is replaced
+/// like when removing it. A new call is also added to the set of its region, KEYS[2], as
+/// ARGV[3]. Returns nothing if the call was added.
+static ADD_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+local value = redis.call('GET', KEYS[1])
+if value then
+  local call = cjson.decode(value)
+  local expires_at = tonumber(call['expiresAt'])
+  if not expires_at or expires_at > tonumber(ARGV[4]) then
+    return value
+  end
+  redis.call('SREM', 'region:' .. call['region'], ARGV[3])
+  if type(call['reservedRegion']) == 'string' then
+    redis.call('ZREM', 'regionCapacity:' .. call['reservedRegion'], ARGV[3])
+  end
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+redis.call('SADD', KEYS[2], ARGV[3])
+return false
+",
+    )
+});
+
+/// Like ADD_SCRIPT, but only adds the call if fewer than ARGV[4] calls hold a
+/// reservation in its region, and then reserves capacity for it. The reservations are
+/// the sorted set KEYS[3] of group_ids scored by when their calls expire, so that calls
+/// that expire release theirs too. ARGV[5] is when the call expires, ARGV[6] is now and
+/// ARGV[7] is what ADD_SCRIPT takes as ARGV[4], so that a call that expired is replaced
+/// the same way. Returns "ok", "exists" or "full".
+static ADD_RESERVING_CAPACITY_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+local value = redis.call('GET', KEYS[1])
+if value then
+  local call = cjson.decode(value)
+  local expires_at = tonumber(call['expiresAt'])
+  if not expires_at or expires_at > tonumber(ARGV[7]) then
+    return 'exists'
+  end
+  redis.call('SREM', 'region:' .. call['region'], ARGV[3])
+  if type(call['reservedRegion']) == 'string' then
+    redis.call('ZREM', 'regionCapacity:' .. call['reservedRegion'], ARGV[3])
+  end
+end
+redis.call('ZREMRANGEBYSCORE', KEYS[3], '-inf', ARGV[6])
+if redis.call('ZCARD', KEYS[3]) >= tonumber(ARGV[4]) then
+  return 'full'
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+redis.call('SADD', KEYS[2], ARGV[3])
+redis.call('ZADD', KEYS[3], ARGV[5], ARGV[3])
+return 'ok'
+",
+    )
+});
+
+/// Replaces the call stored at KEYS[1] with ARGV[3], keeping its TTL, as long as the
+/// stored call has the call_id ARGV[1] and the version ARGV[2]. If the region of the call
+/// changes, its group_id ARGV[4] is moved to the set of the new region. Returns 1 if the
+/// call was replaced.
+static REPLACE_SCRIPT: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+local value = redis.call('GET', KEYS[1])
+if not value then
+  return 0
+end
+local call = cjson.decode(value)
+if call['jvbConferenceId'] ~= ARGV[1] or (call['version'] or 0) ~= tonumber(ARGV[2]) then
+  return 0
+end
+redis.call('SET', KEYS[1], ARGV[3], 'KEEPTTL')
+local region = cjson.decode(ARGV[3])['region']
+if region ~= call['region'] then
+  redis.call('SREM', 'region:' .. call['region'], ARGV[4])
+  redis.call('SADD', 'region:' .. region, ARGV[4])
+end
+return 1
+",
+    )
+});
+
+fn call_key(group_id: &GroupId) -> String {
+    format!("call:{}", group_id.as_ref())
+}
+
+fn region_key(region: &str) -> String {
+    format!("region:{}", region)
+}
+
+fn era_key(group_id: &GroupId) -> String {
+    format!("callEra:{}", group_id.as_ref())
+}
+
+fn region_capacity_key(region: &str) -> String {
+    format!("regionCapacity:{}", region)
+}
+
+/// Failures to reach Redis may go away by themselves, anything else is unexpected.
+fn redis_error(err: RedisError, context: &'static str) -> StorageError {
+    let transient = err.is_timeout()
+        || err.is_connection_dropped()
+        || err.is_connection_refusal()
+        || err.is_io_error();
+
+    let err = anyhow::Error::from(err).context(context);
+    if transient {
+        StorageError::Throttled(err)
+    } else {
+        StorageError::UnexpectedError(err)
+    }
+}
+
+fn to_json(call: &CallRecord) -> Result<String, StorageError> {
+    Ok(serde_json::to_string(call).context("failed to convert CallRecord to JSON")?)
+}
+
+fn from_json(value: &str) -> Result<CallRecord, StorageError> {
+    Ok(serde_json::from_str(value).context("failed to convert JSON to CallRecord")?)
+}
+
+/// Returns the calls stored at the given keys, skipping keys that have no call.
+async fn read_calls(
+    connection: &mut ConnectionManager,
+    keys: &[String],
+) -> Result<Vec<CallRecord>, StorageError> {
+    if keys.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let values: Vec<Option<String>> = redis::cmd("MGET")
+        .arg(keys)
+        .query_async(connection)
+        .await
+        .map_err(|err| redis_error(err, "failed to MGET calls"))?;
+    values
+        .iter()
+        .flatten()
+        .map(|value| from_json(value))
+        .collect()
+}
+
+/// Returns the calls in the given region, sorted. The set of a region may still name
+/// calls that have expired or moved to another region, which are left out and removed
+/// from the set.
+async fn read_region(
+    mut connection: ConnectionManager,
+    region: &str,
+) -> Result<Vec<CallRecord>, StorageError> {
+    let group_ids: Vec<String> = connection
+        .smembers(region_key(region))
+        .await
+        .map_err(|err| redis_error(err, "failed to SMEMBERS a region"))?;
+    let keys: Vec<_> = group_ids
+        .iter()
+        .map(|group_id| format!("call:{}", group_id))
+        .collect();
+    let mut calls: Vec<_> = read_calls(&mut connection, &keys)
+        .await?
+        .into_iter()
+        .filter(|call| call.backend_region == region)
+        .collect();
+
+    let stale: Vec<_> = group_ids
+        .iter()
+        .filter(|group_id| !calls.iter().any(|call| call.group_id.as_ref() == *group_id))
+        .collect();
+    if !stale.is_empty() {
+        // Pruning is best-effort, the next read tries again.
+        let result: Result<(), RedisError> = connection.srem(region_key(region), stale).await;
+        if let Err(err) = result {
+            storage_log!(Level::Warn, "failed to prune region {}: {:?}", region, err);
+        }
+    }
+
+    sort_call_records(&mut calls);
+    Ok(calls)
+}
+
+/// Returns every call stored, sorted, using SCAN so that Redis isn't blocked.
+async fn read_all(mut connection: ConnectionManager) -> Result<Vec<CallRecord>, StorageError> {
+    let mut calls = vec![];
+    let mut cursor = 0u64;
+    loop {
+        let (next_cursor, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg("call:*")
+            .arg("COUNT")
+            .arg(SCAN_COUNT)
+            .query_async(&mut connection)
+            .await
+            .map_err(|err| redis_error(err, "failed to SCAN calls"))?;
+        calls.extend(read_calls(&mut connection, &keys).await?);
+
+        if next_cursor == 0 {
+            break;
+        }
+        cursor = next_cursor;
+    }
+
+    sort_call_records(&mut calls);
+    Ok(calls)
+}
+
+/// A Storage implementation backed by Redis, for deployments that can't reach DynamoDB
+/// with low enough latency. Each call is stored as JSON at `call:<group_id>` with a TTL,
+/// and the group_ids of the calls of each region are kept in a set at `region:<region>`.
+/// Writes that must be atomic are done by Lua scripts, which assume that all keys live
+/// on a single Redis server rather than a cluster.
+#[derive(Clone)]
+pub struct RedisStorage {
+    connection: ConnectionManager,
+    clock: Arc<dyn Clock>,
+    clock_skew_tolerance: Duration,
+}
+
+impl RedisStorage {
+    /// Connects to the Redis server at the given URL, such as "redis://127.0.0.1:6379".
+    /// The connection is reestablished as needed after it is lost.
+    pub async fn new(url: &str, clock: Arc<dyn Clock>) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .with_context(|| format!("failed to connect to Redis at {}", url))?;
+        Ok(Self {
+            connection,
+            clock,
+            clock_skew_tolerance: Duration::ZERO,
+        })
+    }
+
+    /// Allows for the clock being up to the given amount ahead of the one that wrote
+    /// the calls when judging whether they expired or died.
+    pub fn with_clock_skew_tolerance(mut self, clock_skew_tolerance: Duration) -> Self {
+        self.clock_skew_tolerance = clock_skew_tolerance;
+        self
+    }
+
+    /// Logs the error as a structured record for the given operation and returns it.
+    fn log_error(&self, operation: &str, err: StorageError) -> StorageError {
+        error!("{}", storage_error_log_record(operation, "redis", &err));
+        err
+    }
+
+    /// Gets the call for the given group_id, if it hasn't expired.
+    async fn read_call(&self, group_id: &GroupId) -> Result<Option<CallRecord>, StorageError> {
+        let value: Option<String> = self
+            .connection
+            .clone()
+            .get(call_key(group_id))
+            .await
+            .map_err(|err| redis_error(err, "failed to GET a call"))?;
+        let now = self.clock.now_secs();
+        Ok(value
+            .map(|value| from_json(&value))
+            .transpose()?
+            .filter(|call| !call.is_expired(now, self.clock_skew_tolerance)))
+    }
+
+    /// Gives a call that is about to be created the next era of its group. An era that
+    /// is taken by a call that then isn't created is skipped. The counter expires
+    /// CALL_ERA_TTL after the last call of the group was created.
+    async fn start_era(&self, call: &mut CallRecord) -> Result<(), StorageError> {
+        let key = era_key(&call.group_id);
+        let (era,): (u64,) = redis::pipe()
+            .atomic()
+            .incr(&key, 1)
+            .expire(&key, CALL_ERA_TTL.as_secs() as usize)
+            .ignore()
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(|err| redis_error(err, "failed to INCR an era"))?;
+        call.era = era;
+        Ok(())
+    }
+
+    /// Replaces the current call with the changed one as long as the stored call still
+    /// has the call_id and version of the current one. Returns false otherwise.
+    async fn replace(
+        &self,
+        current: &CallRecord,
+        changed: &CallRecord,
+    ) -> Result<bool, StorageError> {
+        let replaced: i32 = REPLACE_SCRIPT
+            .key(call_key(&current.group_id))
+            .arg(&current.call_id)
+            .arg(current.version)
+            .arg(to_json(changed)?)
+            .arg(current.group_id.as_ref())
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|err| redis_error(err, "failed to replace a call"))?;
+        Ok(replaced == 1)
+    }
+
+    /// Applies the change to the stored call with the given call_id, rereading and trying
+    /// again if the call is changed by someone else in the meantime. Returns the changed
+    /// call, or None if there is no such call or the change returns false.
+    async fn modify(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        change: impl Fn(&mut CallRecord) -> bool + Send,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        loop {
+            let current = match self.read_call(group_id).await? {
+                Some(call) if call.call_id == call_id => call,
+                _ => return Ok(None),
+            };
+            let mut changed = current.clone();
+            if !change(&mut changed) {
+                return Ok(None);
+            }
+            if self.replace(&current, &changed).await? {
+                return Ok(Some(changed));
+            }
+        }
+    }
+
+    /// Removes the call with the given call_id, returning it if it was removed.
+    async fn remove(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        let removed: Option<String> = REMOVE_SCRIPT
+            .key(call_key(group_id))
+            .arg(call_id)
+            .arg(group_id.as_ref())
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|err| redis_error(err, "failed to remove a call"))?;
+        removed.map(|value| from_json(&value)).transpose()
+    }
+
+    async fn add(&self, mut call: CallRecord) -> Result<Option<CallRecord>, StorageError> {
+        let now = self.clock.now_secs();
+        call.start_lifetime(now);
+
+        if let Some(existing) = self.read_call(&call.group_id).await? {
+            return Ok(Some(existing));
+        }
+
+        // The script judges expiry like is_expired does.
+        self.start_era(&mut call).await?;
+        let existing: Option<String> = ADD_SCRIPT
+            .key(call_key(&call.group_id))
+            .key(region_key(&call.backend_region))
+            .arg(to_json(&call)?)
+            .arg(CALL_RECORD_TTL.as_secs())
+            .arg(call.group_id.as_ref())
+            .arg(now.saturating_sub(self.clock_skew_tolerance.as_secs()))
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|err| redis_error(err, "failed to add a call"))?;
+        match existing {
+            // Someone else added a call for the group in the meantime.
+            Some(existing) => Ok(Some(from_json(&existing)?)),
+            None => Ok(Some(call)),
+        }
+    }
+
+    async fn add_reserving_capacity(
+        &self,
+        mut call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        let now = self.clock.now_secs();
+        call.start_lifetime(now);
+        call.reserved_region = Some(call.backend_region.clone());
+        self.start_era(&mut call).await?;
+
+        let outcome: String = ADD_RESERVING_CAPACITY_SCRIPT
+            .key(call_key(&call.group_id))
+            .key(region_key(&call.backend_region))
+            .key(region_capacity_key(&call.backend_region))
+            .arg(to_json(&call)?)
+            .arg(CALL_RECORD_TTL.as_secs())
+            .arg(call.group_id.as_ref())
+            .arg(max_calls_per_region)
+            .arg(call.expires_at.unwrap_or(now + CALL_RECORD_TTL.as_secs()))
+            .arg(now)
+            .arg(now.saturating_sub(self.clock_skew_tolerance.as_secs()))
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|err| redis_error(err, "failed to add a call reserving capacity"))?;
+        match outcome.as_str() {
+            "ok" => Ok(call),
+            "exists" => Err(StorageError::CallAlreadyExists),
+            "full" => Err(StorageError::RegionFull(call.backend_region)),
+            _ => Err(anyhow!("unexpected outcome {} of adding a call", outcome).into()),
+        }
+    }
+
+    async fn add_all(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        let now = self.clock.now_secs();
+        let mut pipeline = redis::pipe();
+        for mut call in records {
+            if call.created_at.is_none() {
+                call.start_lifetime(now);
+            }
+            let ttl = call
+                .expires_at
+                .map_or(CALL_RECORD_TTL.as_secs(), |expires_at| {
+                    expires_at.saturating_sub(now)
+                })
+                .max(1);
+            pipeline
+                .set_ex(call_key(&call.group_id), to_json(&call)?, ttl as usize)
+                .ignore()
+                .sadd(region_key(&call.backend_region), call.group_id.as_ref())
+                .ignore();
+        }
+        pipeline
+            .query_async(&mut self.connection.clone())
+            .await
+            .map_err(|err| redis_error(err, "failed to add calls"))
+    }
+
+    async fn reap(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        let now = self.clock.now_secs();
+        let mut reaped = vec![];
+        for call in read_all(self.connection.clone()).await? {
+            if call.is_dead(now, max_silence, self.clock_skew_tolerance)
+                && (dry_run || self.remove(&call.group_id, &call.call_id).await?.is_some())
+            {
+                reaped.push(call.group_id);
+            }
+        }
+        reaped.sort();
+        Ok(reaped)
+    }
+
+    async fn ping(&self) -> Result<(), StorageError> {
+        let mut connection = self.connection.clone();
+        let ping = redis::cmd("PING");
+        match tokio::time::timeout(
+            HEALTH_CHECK_TIMEOUT.into(),
+            ping.query_async::<_, String>(&mut connection),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => Err(redis_error(err, "failed to PING")),
+            Err(_) => Err(StorageError::Throttled(anyhow!(
+                "timed out waiting for PING"
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for RedisStorage {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.read_call(group_id)
+            .await
+            .map_err(|err| self.log_error("get_call_record", err))
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.add(call)
+            .await
+            .map_err(|err| self.log_error("get_or_add_call_record", err))
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.remove(group_id, call_id)
+            .await
+            .map_err(|err| self.log_error("remove_call_record", err))
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        spawn_best_effort_removal(self.clone(), group_id, call_id);
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        storage_log!(
+            Level::Warn,
+            "forcing the removal of the call for group {}",
+            group_id
+        );
+
+        let removed: Option<String> = FORCE_REMOVE_SCRIPT
+            .key(call_key(group_id))
+            .arg(group_id.as_ref())
+            .invoke_async(&mut self.connection.clone())
+            .await
+            .map_err(|err| {
+                self.log_error(
+                    "force_remove_call_record",
+                    redis_error(err, "failed to remove a call"),
+                )
+            })?;
+        removed
+            .map(|value| from_json(&value))
+            .transpose()
+            .map_err(|err| self.log_error("force_remove_call_record", err))
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        read_region(self.connection.clone(), region)
+            .await
+            .map_err(|err| self.log_error("get_call_records_for_region", err))
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        // The calls of a region are read with a single MGET, so there are no pages to
+        // stream. The stream outlives the borrow of self, so errors are logged without it.
+        let connection = self.connection.clone();
+        let region = region.to_string();
+        futures::stream::once(async move { read_region(connection, &region).await })
+            .flat_map(|calls| match calls {
+                Ok(calls) => futures::stream::iter(calls.into_iter().map(Ok)).boxed(),
+                Err(err) => {
+                    error!(
+                        "{}",
+                        storage_error_log_record("stream_call_records_for_region", "redis", &err)
+                    );
+                    futures::stream::iter(vec![Err(err)]).boxed()
+                }
+            })
+            .boxed()
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        let mut counts = HashMap::new();
+        for call in self.get_call_records_for_region(region).await? {
+            *counts.entry(call.backend_ip).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.modify(group_id, call_id, |call| {
+            if call.backup_backends.is_empty() {
+                return false;
+            }
+            let backup = call.backup_backends.remove(0);
+            call.backend_ip = backup.ip;
+            call.backend_region = backup.region;
+            call.version += 1;
+            true
+        })
+        .await
+        .map_err(|err| self.log_error("promote_backup_backend", err))
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        self.add_reserving_capacity(call, max_calls_per_region)
+            .await
+            .map_err(|err| self.log_error("create_call_reserving_capacity", err))
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        let connection = self.connection.clone();
+        futures::stream::once(read_all(connection))
+            .flat_map(|calls| match calls {
+                Ok(calls) => futures::stream::iter(calls.into_iter().map(Ok)).boxed(),
+                Err(err) => {
+                    error!("{}", storage_error_log_record("export_all", "redis", &err));
+                    futures::stream::iter(vec![Err(err)]).boxed()
+                }
+            })
+            .boxed()
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        let now = self.clock.now_secs();
+        self.modify(group_id, call_id, |call| {
+            call.last_heartbeat_at = Some(now);
+            call.version += 1;
+            true
+        })
+        .await
+        .map(|call| call.is_some())
+        .map_err(|err| self.log_error("heartbeat_call", err))
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        self.modify(group_id, call_id, |call| {
+            call.locked = locked;
+            call.locked_by = if locked { locked_by.clone() } else { None };
+            call.version += 1;
+            true
+        })
+        .await
+        .map(|call| call.is_some())
+        .map_err(|err| self.log_error("set_call_locked", err))
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        self.reap(max_silence, dry_run)
+            .await
+            .map_err(|err| self.log_error("reap_dead_calls", err))
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        self.add_all(records)
+            .await
+            .map_err(|err| self.log_error("add_call_records", err))
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        let updated = CallRecord {
+            version: call.version + 1,
+            ..call.clone()
+        };
+        match self.replace(&call, &updated).await {
+            Ok(true) => Ok(updated),
+            Ok(false) => Err(StorageError::VersionConflict),
+            Err(err) => Err(self.log_error("update_call_record", err)),
+        }
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        self.ping()
+            .await
+            .map_err(|err| self.log_error("health_check", err))
+    }
+}
+
+/// These tests need a Redis server that they are free to write to, at REDIS_URL or else
+/// at the default port on localhost, so they only run with `cargo test --features redis`,
+/// which CI does against a Redis service.
+#[cfg(test)]
+mod redis_storage_tests {
+    use rand::{distributions::Alphanumeric, thread_rng, Rng};
+
+    use super::*;
+    use crate::storage::{self, BackendRef, MockClock, SystemClock};
+
+    async fn create_redis_storage() -> RedisStorage {
+        create_redis_storage_with_clock(Arc::new(SystemClock)).await
+    }
+
+    async fn create_redis_storage_with_clock(clock: Arc<dyn Clock>) -> RedisStorage {
+        let url =
+            std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
+        RedisStorage::new(&url, clock).await.unwrap()
+    }
+
+    /// Returns a call with a random group_id and region so that tests don't see each
+    /// other's calls or those of earlier runs.
+    fn create_call_record(call_id: &str) -> CallRecord {
+        let random: String = thread_rng()
+            .sample_iter(Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        CallRecord {
+            backend_region: format!("region-{}", random),
+            ..storage::create_call_record(&random, call_id)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_or_add_only_adds_once() {
+        let storage = create_redis_storage().await;
+        let call = create_call_record("a1a1a1a1");
+
+        let added = storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(added.call_id, "a1a1a1a1");
+        assert_eq!(added.era, 1);
+
+        // A second call for the group gets the first one back.
+        let existing = storage
+            .get_or_add_call_record(CallRecord {
+                call_id: "b2b2b2b2".to_string(),
+                ..call.clone()
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(existing, added);
+        assert_eq!(
+            storage.get_call_record(&call.group_id).await.unwrap(),
+            Some(added.clone())
+        );
+        assert_eq!(
+            storage
+                .get_call_records_for_region(&call.backend_region)
+                .await
+                .unwrap(),
+            vec![added]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_or_add_replaces_expired_call() {
+        // Expiry is judged by the clock of the storage, so the key of the expired call is
+        // still there, like it is until Redis gets around to expiring it.
+        let clock = Arc::new(MockClock::from_secs(SystemClock.now_secs()));
+        let storage = create_redis_storage_with_clock(clock.clone()).await;
+        let call = create_call_record("a1a1a1a1");
+        storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+
+        clock.advance(CALL_RECORD_TTL.into());
+        let added = storage
+            .get_or_add_call_record(CallRecord {
+                call_id: "b2b2b2b2".to_string(),
+                ..call.clone()
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(added.call_id, "b2b2b2b2");
+        assert_eq!(
+            storage.get_call_record(&call.group_id).await.unwrap(),
+            Some(added.clone())
+        );
+        assert_eq!(
+            storage
+                .get_call_records_for_region(&call.backend_region)
+                .await
+                .unwrap(),
+            vec![added]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_only_removes_matching_call_id() {
+        let storage = create_redis_storage().await;
+        let call = create_call_record("a1a1a1a1");
+        let added = storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            storage
+                .remove_call_record(&call.group_id, "b2b2b2b2")
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .is_some());
+
+        assert_eq!(
+            storage
+                .remove_call_record(&call.group_id, "a1a1a1a1")
+                .await
+                .unwrap(),
+            Some(added)
+        );
+        assert_eq!(storage.get_call_record(&call.group_id).await.unwrap(), None);
+        assert!(storage
+            .get_call_records_for_region(&call.backend_region)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // A new call for the group starts the next era.
+        let readded = storage
+            .get_or_add_call_record(CallRecord {
+                call_id: "c3c3c3c3".to_string(),
+                ..call
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(readded.call_id, "c3c3c3c3");
+        assert_eq!(readded.era, 2);
+    }
+
+    #[tokio::test]
+    async fn test_force_remove_ignores_call_id() {
+        let storage = create_redis_storage().await;
+        let call = create_call_record("a1a1a1a1");
+        let added = storage
+            .get_or_add_call_record(call.clone())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            storage
+                .force_remove_call_record(&call.group_id)
+                .await
+                .unwrap(),
+            Some(added)
+        );
+        assert_eq!(storage.get_call_record(&call.group_id).await.unwrap(), None);
+        assert!(storage
+            .get_call_records_for_region(&call.backend_region)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage
+                .force_remove_call_record(&call.group_id)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_call_record_checks_version() {
+        let storage = create_redis_storage().await;
+        let call = storage
+            .get_or_add_call_record(create_call_record("a1a1a1a1"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let updated = storage
+            .update_call_record(CallRecord {
+                locked: true,
+                ..call.clone()
+            })
+            .await
+            .unwrap();
+        assert_eq!(updated.version, call.version + 1);
+
+        assert!(matches!(
+            storage.update_call_record(call).await,
+            Err(StorageError::VersionConflict)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_update_call_record_keeps_concurrent_heartbeat() {
+        let storage = create_redis_storage().await;
+        let call = storage
+            .get_or_add_call_record(create_call_record("a1a1a1a1"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // A heartbeat between the read and the update is a change the update didn't see.
+        assert!(storage
+            .heartbeat_call(&call.group_id, &call.call_id)
+            .await
+            .unwrap());
+        assert!(matches!(
+            storage
+                .update_call_record(CallRecord {
+                    locked: true,
+                    ..call.clone()
+                })
+                .await,
+            Err(StorageError::VersionConflict)
+        ));
+        assert!(storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .last_heartbeat_at
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_promote_backup_backend_moves_region() {
+        let storage = create_redis_storage().await;
+        let backup_region = create_call_record("").backend_region;
+        let call = storage
+            .get_or_add_call_record(CallRecord {
+                backup_backends: vec![BackendRef {
+                    region: backup_region.clone(),
+                    ip: "127.0.0.2".to_string(),
+                }],
+                ..create_call_record("a1a1a1a1")
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        let promoted = storage
+            .promote_backup_backend(&call.group_id, "a1a1a1a1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(promoted.backend_ip, "127.0.0.2");
+        assert!(storage
+            .get_call_records_for_region(&call.backend_region)
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage
+                .get_call_records_for_region(&backup_region)
+                .await
+                .unwrap(),
+            vec![promoted]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_call_reserving_capacity() {
+        let storage = create_redis_storage().await;
+        let call = create_call_record("a1a1a1a1");
+
+        storage
+            .create_call_reserving_capacity(call.clone(), 1)
+            .await
+            .unwrap();
+        assert!(matches!(
+            storage
+                .create_call_reserving_capacity(call.clone(), 10)
+                .await,
+            Err(StorageError::CallAlreadyExists)
+        ));
+        assert!(matches!(
+            storage
+                .create_call_reserving_capacity(
+                    CallRecord {
+                        backend_region: call.backend_region.clone(),
+                        ..create_call_record("b2b2b2b2")
+                    },
+                    1
+                )
+                .await,
+            Err(StorageError::RegionFull(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_call_reserving_capacity_replaces_expired_call() {
+        // Like for get_or_add_call_record, the key of the expired call is still there.
+        let clock = Arc::new(MockClock::from_secs(SystemClock.now_secs()));
+        let storage = create_redis_storage_with_clock(clock.clone()).await;
+        let call = create_call_record("a1a1a1a1");
+        storage
+            .create_call_reserving_capacity(call.clone(), 1)
+            .await
+            .unwrap();
+
+        // The expired call neither exists nor holds the only reservation of the region.
+        clock.advance(CALL_RECORD_TTL.into());
+        let created = storage
+            .create_call_reserving_capacity(
+                CallRecord {
+                    call_id: "b2b2b2b2".to_string(),
+                    ..call.clone()
+                },
+                1,
+            )
+            .await
+            .unwrap();
+        assert_eq!(created.call_id, "b2b2b2b2");
+        assert_eq!(
+            storage.get_call_record(&call.group_id).await.unwrap(),
+            Some(created.clone())
+        );
+        assert_eq!(
+            storage
+                .get_call_records_for_region(&call.backend_region)
+                .await
+                .unwrap(),
+            vec![created]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_removing_call_releases_reserved_capacity() {
+        let storage = create_redis_storage().await;
+        let call = create_call_record("a1a1a1a1");
+
+        // A region with room for one call can take another once the first is removed,
+        // however it is removed.
+        let created = storage
+            .create_call_reserving_capacity(call.clone(), 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            created.reserved_region.as_deref(),
+            Some(call.backend_region.as_str())
+        );
+        storage
+            .remove_call_record(&call.group_id, &call.call_id)
+            .await
+            .unwrap()
+            .unwrap();
+        storage
+            .create_call_reserving_capacity(call.clone(), 1)
+            .await
+            .unwrap();
+        storage
+            .force_remove_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .unwrap();
+        storage
+            .create_call_reserving_capacity(call, 1)
+            .await
+            .unwrap();
+    }
+}
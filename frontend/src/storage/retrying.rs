@@ -0,0 +1,486 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::stream::BoxStream;
+use log::*;
+use rand::{thread_rng, Rng};
+
+use crate::{
+    frontend::{GroupId, UserId},
+    storage::{CallRecord, CallRecordSummary, ReadMeta, Storage, StorageError},
+};
+
+/// A Storage decorator that retries operations that failed transiently, but only those
+/// that are safe to apply more than once. Reads are always safe, as are writes that are
+/// conditional on the state they are meant to produce, such as get_or_add_call_record.
+/// Writes that change state relative to what is stored, such as promoting a backup or
+/// reserving capacity, are never retried since a retry after an unseen success could
+/// apply them twice.
+pub struct RetryingStorage<S: Storage> {
+    inner: S,
+    max_attempts: usize,
+    initial_backoff: Duration,
+}
+
+impl<S: Storage> RetryingStorage<S> {
+    /// Creates a decorator that makes up to max_attempts attempts at each safe operation,
+    /// waiting a random time of up to initial_backoff before the first retry and twice as
+    /// long at most before each later one.
+    pub fn new(inner: S, max_attempts: usize, initial_backoff: Duration) -> Self {
+        Self {
+            inner,
+            max_attempts,
+            initial_backoff,
+        }
+    }
+
+    async fn retry<T, F, Fut>(&self, operation: &'static str, mut f: F) -> Result<T, StorageError>
+    where
+        T: Send,
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T, StorageError>> + Send,
+    {
+        let mut backoff = self.initial_backoff;
+        let mut attempt = 1;
+
+        loop {
+            match f().await {
+                Err(err) if err.is_transient() && attempt < self.max_attempts => {
+                    storage_log!(
+                        Level::Warn,
+                        "retrying {} after attempt {} failed: {}",
+                        operation,
+                        attempt,
+                        err
+                    );
+                    tagged_event!(
+                        "calling.frontend.storage.retrying.retry",
+                        vec![format!("operation:{}", operation)]
+                    );
+
+                    // Full jitter keeps retries from many callers from arriving together.
+                    let jitter_ms = thread_rng().gen_range(0..=backoff.as_millis() as u64);
+                    tokio::time::sleep(Duration::from_millis(jitter_ms).into()).await;
+                    backoff = backoff * 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for RetryingStorage<S> {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.retry("get_call_record", move || {
+            self.inner.get_call_record(group_id)
+        })
+        .await
+    }
+
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        self.retry("get_call_record_with_meta", move || {
+            self.inner.get_call_record_with_meta(group_id)
+        })
+        .await
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        // If an earlier attempt did add the call, a retry returns it as the existing call.
+        self.retry("get_or_add_call_record", move || {
+            self.inner.get_or_add_call_record(call.clone())
+        })
+        .await
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.retry("remove_call_record", move || {
+            self.inner.remove_call_record(group_id, call_id)
+        })
+        .await
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        // Best-effort removals are retried on their own.
+        self.inner.remove_call_record_best_effort(group_id, call_id)
+    }
+
+    async fn remove_call_record_with_retries(&self, group_id: &GroupId, call_id: &str) {
+        // The attempts aren't retried again here.
+        self.inner
+            .remove_call_record_with_retries(group_id, call_id)
+            .await
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        // An attempt that failed may still have removed the call, and then a retry finds
+        // nothing. The call that was there beforehand is what was removed in that case,
+        // so it is read first.
+        let existing = self.get_call_record(group_id).await?;
+
+        let attempts = AtomicUsize::new(0);
+        let removed = self
+            .retry("force_remove_call_record", || {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                self.inner.force_remove_call_record(group_id)
+            })
+            .await?;
+        if removed.is_none() && attempts.load(Ordering::Relaxed) > 1 {
+            return Ok(existing);
+        }
+        Ok(removed)
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        self.retry("get_call_records_for_region", move || {
+            self.inner.get_call_records_for_region(region)
+        })
+        .await
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        // A stream can't be restarted without repeating calls that were already yielded.
+        self.inner.stream_call_records_for_region(region)
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        self.retry("count_calls_per_backend", move || {
+            self.inner.count_calls_per_backend(region)
+        })
+        .await
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        self.retry("count_call_records_for_region", move || {
+            self.inner.count_call_records_for_region(region)
+        })
+        .await
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        self.retry("count_calls_by_backend_version", move || {
+            self.inner.count_calls_by_backend_version(region)
+        })
+        .await
+    }
+
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        self.retry("count_all_regions", move || self.inner.count_all_regions())
+            .await
+    }
+
+    async fn least_loaded_backend(
+        &self,
+        region: &str,
+        candidate_ips: &[&str],
+    ) -> Result<Option<String>, StorageError> {
+        self.retry("least_loaded_backend", move || {
+            self.inner.least_loaded_backend(region, candidate_ips)
+        })
+        .await
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        self.retry("get_call_records_for_region_projected", move || {
+            self.inner
+                .get_call_records_for_region_projected(region, attributes, limit)
+        })
+        .await
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.inner.promote_backup_backend(group_id, call_id).await
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        self.inner
+            .create_call_reserving_capacity(call, max_calls_per_region)
+            .await
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        // Records are put unconditionally, so putting them again has the same result.
+        self.retry("add_call_records", move || {
+            self.inner.add_call_records(records.clone())
+        })
+        .await
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        self.inner.update_call_record(call).await
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        self.retry("health_check", move || self.inner.health_check())
+            .await
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        // Setting the heartbeat time again has the same effect.
+        self.retry("heartbeat_call", move || {
+            self.inner.heartbeat_call(group_id, call_id)
+        })
+        .await
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        // Setting the lock again has the same effect, other than on the version.
+        self.retry("set_call_locked", move || {
+            self.inner
+                .set_call_locked(group_id, call_id, locked, locked_by.clone())
+        })
+        .await
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        if dry_run {
+            // Nothing is removed, so every attempt reports the same calls.
+            return self
+                .retry("reap_dead_calls", move || {
+                    self.inner.reap_dead_calls(max_silence, true)
+                })
+                .await;
+        }
+        // Removals are conditional on the call still being dead, but an earlier attempt
+        // may have removed calls that a retry then wouldn't report.
+        self.inner.reap_dead_calls(max_silence, false).await
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        self.inner.export_all()
+    }
+}
+
+#[cfg(test)]
+mod retrying_storage_tests {
+    use super::*;
+    use crate::{
+        storage::{create_call_record, with_correlation_id, MockStorage},
+        test_logging,
+    };
+    use anyhow::anyhow;
+    use mockall::Sequence;
+
+    fn create_retrying_storage(inner: MockStorage) -> RetryingStorage<MockStorage> {
+        RetryingStorage::new(inner, 3, Duration::from_millis(1))
+    }
+
+    #[tokio::test]
+    async fn test_transient_read_error_is_retried() {
+        let mut inner = MockStorage::new();
+        let mut sequence = Sequence::new();
+        inner
+            .expect_get_call_record()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Err(StorageError::Throttled(anyhow!("throttled"))));
+        inner
+            .expect_get_call_record()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Ok(Some(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))));
+        let storage = create_retrying_storage(inner);
+
+        let call = storage
+            .get_call_record(&"aaaaaaaaaaaaaaaa".into())
+            .await
+            .unwrap();
+        assert_eq!(
+            call,
+            Some(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_is_logged_with_correlation_id() {
+        let mut inner = MockStorage::new();
+        let mut sequence = Sequence::new();
+        inner
+            .expect_get_call_record()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Err(StorageError::Throttled(anyhow!("throttled"))));
+        inner
+            .expect_get_call_record()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Ok(None));
+        let storage = create_retrying_storage(inner);
+
+        test_logging::start_capture();
+        with_correlation_id(
+            "request-1234".to_string(),
+            storage.get_call_record(&"aaaaaaaaaaaaaaaa".into()),
+        )
+        .await
+        .unwrap();
+
+        let logged = test_logging::captured(log::Level::Warn);
+        assert_eq!(
+            logged,
+            vec!["[request-1234] retrying get_call_record after attempt 1 failed: the storage request was throttled or failed transiently: throttled"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transient_read_error_gives_up() {
+        let mut inner = MockStorage::new();
+        inner
+            .expect_get_call_records_for_region()
+            .times(3)
+            .returning(|_| Err(StorageError::Throttled(anyhow!("throttled"))));
+        let storage = create_retrying_storage(inner);
+
+        assert!(matches!(
+            storage.get_call_records_for_region("us-west1").await,
+            Err(StorageError::Throttled(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_force_removal_not_found_on_retry_succeeds() {
+        let mut inner = MockStorage::new();
+        inner
+            .expect_get_call_record()
+            .times(1)
+            .returning(|_| Ok(Some(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))));
+        // The first attempt removes the call but its response is lost.
+        let mut sequence = Sequence::new();
+        inner
+            .expect_force_remove_call_record()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Err(StorageError::Throttled(anyhow!("timed out"))));
+        inner
+            .expect_force_remove_call_record()
+            .times(1)
+            .in_sequence(&mut sequence)
+            .returning(|_| Ok(None));
+        let storage = create_retrying_storage(inner);
+
+        assert_eq!(
+            storage
+                .force_remove_call_record(&"aaaaaaaaaaaaaaaa".into())
+                .await
+                .unwrap(),
+            Some(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_force_removal_not_found_on_first_attempt() {
+        let mut inner = MockStorage::new();
+        inner
+            .expect_get_call_record()
+            .times(1)
+            .returning(|_| Ok(Some(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))));
+        // Someone else removed the call in between.
+        inner
+            .expect_force_remove_call_record()
+            .times(1)
+            .returning(|_| Ok(None));
+        let storage = create_retrying_storage(inner);
+
+        assert_eq!(
+            storage
+                .force_remove_call_record(&"aaaaaaaaaaaaaaaa".into())
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conditional_write_failure_is_not_retried() {
+        let mut inner = MockStorage::new();
+        inner
+            .expect_update_call_record()
+            .times(1)
+            .returning(|_| Err(StorageError::VersionConflict));
+        // Unsafe writes aren't retried even when they fail transiently.
+        inner
+            .expect_promote_backup_backend()
+            .times(1)
+            .returning(|_, _| Err(StorageError::Throttled(anyhow!("throttled"))));
+        let storage = create_retrying_storage(inner);
+
+        assert!(matches!(
+            storage
+                .update_call_record(create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1"))
+                .await,
+            Err(StorageError::VersionConflict)
+        ));
+        assert!(matches!(
+            storage
+                .promote_backup_backend(&"aaaaaaaaaaaaaaaa".into(), "a1a1a1a1")
+                .await,
+            Err(StorageError::Throttled(_))
+        ));
+    }
+}
@@ -0,0 +1,428 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use calling_common::Duration;
+use futures::{future::try_join_all, stream::BoxStream, StreamExt};
+
+use crate::{
+    config,
+    frontend::{GroupId, UserId},
+    storage::{
+        sort_call_records, CallRecord, CallRecordSummary, DynamoDb, ReadMeta, Storage, StorageError,
+    },
+};
+
+/// A Storage implementation that spreads calls across several shards, each normally a
+/// separate table. Operations on a single call go to the shard chosen by a stable hash
+/// of its group_id, while region queries are sent to every shard and merged.
+pub struct ShardedStorage<S: Storage> {
+    shards: Vec<S>,
+}
+
+impl<S: Storage> ShardedStorage<S> {
+    pub fn new(shards: Vec<S>) -> Result<Self> {
+        if shards.is_empty() {
+            return Err(anyhow!("sharded storage needs at least one shard"));
+        }
+        Ok(Self { shards })
+    }
+
+    /// Returns the index of the shard that holds calls for the given group_id. This must
+    /// never change for a given number of shards, so it uses the stable hash of the group.
+    fn shard_index(&self, group_id: &GroupId) -> usize {
+        (group_id.stable_hash() % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, group_id: &GroupId) -> &S {
+        &self.shards[self.shard_index(group_id)]
+    }
+}
+
+impl ShardedStorage<DynamoDb> {
+    /// Creates a shard for each of the tables named in the config, all sharing the
+    /// connection of the given storage.
+    pub fn from_config(config: &config::Config, storage: &DynamoDb) -> Result<Self> {
+        Self::new(
+            config
+                .storage_shard_table_names()
+                .into_iter()
+                .map(|table_name| storage.for_table(table_name))
+                .collect(),
+        )
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for ShardedStorage<S> {
+    async fn get_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.shard(group_id).get_call_record(group_id).await
+    }
+
+    async fn get_call_record_with_meta(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<(Option<CallRecord>, ReadMeta), StorageError> {
+        self.shard(group_id)
+            .get_call_record_with_meta(group_id)
+            .await
+    }
+
+    async fn get_or_add_call_record(
+        &self,
+        call: CallRecord,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.shard(&call.group_id)
+            .get_or_add_call_record(call)
+            .await
+    }
+
+    async fn remove_call_record(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.shard(group_id)
+            .remove_call_record(group_id, call_id)
+            .await
+    }
+
+    fn remove_call_record_best_effort(&self, group_id: &GroupId, call_id: &str) {
+        self.shard(group_id)
+            .remove_call_record_best_effort(group_id, call_id)
+    }
+
+    async fn force_remove_call_record(
+        &self,
+        group_id: &GroupId,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.shard(group_id)
+            .force_remove_call_record(group_id)
+            .await
+    }
+
+    async fn get_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> Result<Vec<CallRecord>, StorageError> {
+        let mut calls: Vec<_> = try_join_all(
+            self.shards
+                .iter()
+                .map(|shard| shard.get_call_records_for_region(region)),
+        )
+        .await?
+        .into_iter()
+        .flatten()
+        .collect();
+        sort_call_records(&mut calls);
+        Ok(calls)
+    }
+
+    fn stream_call_records_for_region(
+        &self,
+        region: &str,
+    ) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        futures::stream::select_all(
+            self.shards
+                .iter()
+                .map(|shard| shard.stream_call_records_for_region(region)),
+        )
+        .boxed()
+    }
+
+    async fn count_calls_per_backend(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<String, usize>, StorageError> {
+        let mut counts = HashMap::new();
+        for shard_counts in try_join_all(
+            self.shards
+                .iter()
+                .map(|shard| shard.count_calls_per_backend(region)),
+        )
+        .await?
+        {
+            for (backend_ip, count) in shard_counts {
+                *counts.entry(backend_ip).or_insert(0) += count;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn count_call_records_for_region(&self, region: &str) -> Result<usize, StorageError> {
+        Ok(try_join_all(
+            self.shards
+                .iter()
+                .map(|shard| shard.count_call_records_for_region(region)),
+        )
+        .await?
+        .into_iter()
+        .sum())
+    }
+
+    async fn count_calls_by_backend_version(
+        &self,
+        region: &str,
+    ) -> Result<HashMap<Option<String>, usize>, StorageError> {
+        let mut counts = HashMap::new();
+        for shard_counts in try_join_all(
+            self.shards
+                .iter()
+                .map(|shard| shard.count_calls_by_backend_version(region)),
+        )
+        .await?
+        {
+            for (backend_version, count) in shard_counts {
+                *counts.entry(backend_version).or_insert(0) += count;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn count_all_regions(&self) -> Result<HashMap<String, usize>, StorageError> {
+        let mut counts = HashMap::new();
+        for shard_counts in
+            try_join_all(self.shards.iter().map(|shard| shard.count_all_regions())).await?
+        {
+            for (region, count) in shard_counts {
+                *counts.entry(region).or_insert(0) += count;
+            }
+        }
+        Ok(counts)
+    }
+
+    async fn get_call_records_for_region_projected(
+        &self,
+        region: &str,
+        attributes: &[&str],
+        limit: Option<usize>,
+    ) -> Result<Vec<CallRecordSummary>, StorageError> {
+        // Any shard may hold all of the calls, so each is asked for up to the limit.
+        Ok(try_join_all(
+            self.shards.iter().map(|shard| {
+                shard.get_call_records_for_region_projected(region, attributes, limit)
+            }),
+        )
+        .await?
+        .into_iter()
+        .flatten()
+        .take(limit.unwrap_or(usize::MAX))
+        .collect())
+    }
+
+    async fn promote_backup_backend(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<Option<CallRecord>, StorageError> {
+        self.shard(group_id)
+            .promote_backup_backend(group_id, call_id)
+            .await
+    }
+
+    async fn create_call_reserving_capacity(
+        &self,
+        call: CallRecord,
+        max_calls_per_region: u64,
+    ) -> Result<CallRecord, StorageError> {
+        // Capacity is reserved in the shard of the call, so each shard gets an equal part
+        // of the region's capacity.
+        let shard_count = self.shards.len() as u64;
+        let max_calls_per_shard = (max_calls_per_region + shard_count - 1) / shard_count;
+
+        self.shard(&call.group_id)
+            .create_call_reserving_capacity(call, max_calls_per_shard)
+            .await
+    }
+
+    async fn health_check(&self) -> Result<(), StorageError> {
+        try_join_all(self.shards.iter().map(|shard| shard.health_check())).await?;
+        Ok(())
+    }
+
+    async fn update_call_record(&self, call: CallRecord) -> Result<CallRecord, StorageError> {
+        self.shard(&call.group_id).update_call_record(call).await
+    }
+
+    async fn add_call_records(&self, records: Vec<CallRecord>) -> Result<(), StorageError> {
+        let mut records_per_shard: Vec<Vec<CallRecord>> = vec![vec![]; self.shards.len()];
+        for call in records {
+            records_per_shard[self.shard_index(&call.group_id)].push(call);
+        }
+
+        try_join_all(
+            self.shards
+                .iter()
+                .zip(records_per_shard)
+                .filter(|(_, records)| !records.is_empty())
+                .map(|(shard, records)| shard.add_call_records(records)),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn heartbeat_call(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+    ) -> Result<bool, StorageError> {
+        self.shard(group_id).heartbeat_call(group_id, call_id).await
+    }
+
+    async fn set_call_locked(
+        &self,
+        group_id: &GroupId,
+        call_id: &str,
+        locked: bool,
+        locked_by: Option<UserId>,
+    ) -> Result<bool, StorageError> {
+        self.shard(group_id)
+            .set_call_locked(group_id, call_id, locked, locked_by)
+            .await
+    }
+
+    async fn reap_dead_calls(
+        &self,
+        max_silence: Duration,
+        dry_run: bool,
+    ) -> Result<Vec<GroupId>, StorageError> {
+        Ok(try_join_all(
+            self.shards
+                .iter()
+                .map(|shard| shard.reap_dead_calls(max_silence, dry_run)),
+        )
+        .await?
+        .into_iter()
+        .flatten()
+        .collect())
+    }
+
+    fn export_all(&self) -> BoxStream<'static, Result<CallRecord, StorageError>> {
+        futures::stream::select_all(self.shards.iter().map(|shard| shard.export_all())).boxed()
+    }
+}
+
+#[cfg(test)]
+mod sharded_storage_tests {
+    use super::*;
+    use crate::storage::{create_call_record, InMemoryStorage};
+
+    fn create_sharded_storage(shard_count: usize) -> ShardedStorage<InMemoryStorage> {
+        ShardedStorage::new((0..shard_count).map(|_| InMemoryStorage::new()).collect()).unwrap()
+    }
+
+    #[test]
+    fn test_no_shards() {
+        assert!(ShardedStorage::<InMemoryStorage>::new(vec![]).is_err());
+    }
+
+    #[test]
+    fn test_group_id_maps_to_same_shard() {
+        let storage = create_sharded_storage(4);
+        let other_storage = create_sharded_storage(4);
+
+        let mut used_shards = std::collections::HashSet::new();
+        for i in 0..64 {
+            let group_id: GroupId = format!("{:016x}", i).into();
+            let index = storage.shard_index(&group_id);
+            assert_eq!(index, storage.shard_index(&group_id));
+            assert_eq!(index, other_storage.shard_index(&group_id));
+            used_shards.insert(index);
+        }
+        // The hash spreads groups across all of the shards.
+        assert_eq!(used_shards.len(), 4);
+
+        // Calls already stored in a shard would be lost if it changed.
+        assert_eq!(storage.shard_index(&"aaaaaaaaaaaaaaaa".into()), 3);
+    }
+
+    #[tokio::test]
+    async fn test_call_is_stored_in_its_shard() {
+        let storage = create_sharded_storage(4);
+        let call = create_call_record("aaaaaaaaaaaaaaaa", "a1a1a1a1");
+
+        storage.get_or_add_call_record(call.clone()).await.unwrap();
+
+        for (index, shard) in storage.shards.iter().enumerate() {
+            let found = shard.get_call_record(&call.group_id).await.unwrap();
+            assert_eq!(
+                found.is_some(),
+                index == storage.shard_index(&call.group_id)
+            );
+        }
+        assert!(storage
+            .get_call_record(&call.group_id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_region_queries_aggregate_across_shards() {
+        let storage = create_sharded_storage(4);
+        for i in 0..16 {
+            let call = CallRecord {
+                backend_ip: format!("127.0.0.{}", i % 2),
+                ..create_call_record(&format!("{:016x}", i), "a1a1a1a1")
+            };
+            storage.get_or_add_call_record(call).await.unwrap();
+        }
+        storage
+            .get_or_add_call_record(CallRecord {
+                backend_region: "us-east4".to_string(),
+                ..create_call_record("eeeeeeeeeeeeeeee", "a1a1a1a1")
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(
+            storage
+                .get_call_records_for_region("us-west1")
+                .await
+                .unwrap()
+                .len(),
+            16
+        );
+        assert_eq!(
+            storage
+                .stream_call_records_for_region("us-west1")
+                .count()
+                .await,
+            16
+        );
+        assert_eq!(
+            storage.count_calls_per_backend("us-west1").await.unwrap(),
+            HashMap::from([("127.0.0.0".to_string(), 8), ("127.0.0.1".to_string(), 8)])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_count_all_regions_sums_across_shards() {
+        let storage = create_sharded_storage(4);
+        let regions = ["us-west1", "us-east4", "us-east4", "europe-west3"];
+        for i in 0..16 {
+            let call = CallRecord {
+                backend_region: regions[i % regions.len()].to_string(),
+                ..create_call_record(&format!("{:016x}", i), "a1a1a1a1")
+            };
+            storage.get_or_add_call_record(call).await.unwrap();
+        }
+
+        assert_eq!(
+            storage.count_all_regions().await.unwrap(),
+            HashMap::from([
+                ("us-west1".to_string(), 4),
+                ("us-east4".to_string(), 8),
+                ("europe-west3".to_string(), 4),
+            ])
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//
+// Copyright 2022 Signal Messenger, LLC
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! A logger for tests that keeps what each thread logs, so that tests can check their
+//! logs without seeing those of tests running on other threads.
+
+use std::cell::RefCell;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+thread_local! {
+    static CAPTURED: RefCell<Vec<(Level, String)>> = RefCell::new(vec![]);
+}
+
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with("calling_frontend")
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            CAPTURED.with(|captured| {
+                captured
+                    .borrow_mut()
+                    .push((record.level(), record.args().to_string()))
+            });
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: CapturingLogger = CapturingLogger;
+
+/// Installs the capturing logger if it isn't already and forgets what the current thread
+/// logged so far. Tests that log must use this rather than installing another logger.
+pub fn start_capture() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(LevelFilter::Debug);
+    }
+    CAPTURED.with(|captured| captured.borrow_mut().clear());
+}
+
+/// Returns the messages that the current thread logged at the given level since
+/// start_capture was last called.
+pub fn captured(level: Level) -> Vec<String> {
+    CAPTURED.with(|captured| {
+        captured
+            .borrow()
+            .iter()
+            .filter(|(captured_level, _)| *captured_level == level)
+            .map(|(_, message)| message.clone())
+            .collect()
+    })
+}